@@ -3,24 +3,116 @@
 
 from ._core import (
     create_snap,
+    create_tar_to_writer,
     dry_run_snap,
     restore_snap,
     check_integrity,
     get_metadata,
     count_locs,
+    loc_tree,
     scan_locs_dir,
     read_snapshot_text,
+    trace_exclusion,
+    rename_entries,
+    analyze_dedup_potential,
+    check_portability,
+    VeghFormatError,
+    read_files,
+    metadata_diff,
+    overlap_ratio,
+    entries_after_creation,
+    permission_diff,
+    create_snap_from_map,
+    backfill_manifest,
+    verify_snapshot,
+    verify_all,
+    resolve_chain,
+    restore_chain,
+    get_metadata_dict,
+    prune_expired,
+    incompressible_files,
+    extract_metadata,
+    read_file_indexed,
+    structure_fingerprint,
+    structure_equal,
+    verify_contents,
+    estimate_snap,
+    diff_snaps,
+    read_file_from_snap,
+    snapshot_stats,
+    count_locs_by_language,
+    create_snap_to_writer,
+    verify_snap,
+    merge_snaps,
+    prune_snaps,
+    update_snap,
+    create_incremental_snap,
+    sign_snap,
+    verify_signature,
+    restore_snap_filtered,
+    recover_snap,
+    create_multi_snap,
+    diff_snap_against_dir,
+    SnapshotWriter,
+    restore_subtree,
+    train_dictionary,
 )
 
-__version__ = "0.8.0"
+__version__ = "0.10.0"
 __all__ = [
     "create_snap",
+    "create_tar_to_writer",
     "dry_run_snap",
     "restore_snap",
     "check_integrity",
     "get_metadata",
     "count_locs",
+    "loc_tree",
     "scan_locs_dir",
     "read_snapshot_text",
+    "trace_exclusion",
+    "rename_entries",
+    "analyze_dedup_potential",
+    "check_portability",
+    "VeghFormatError",
+    "read_files",
+    "metadata_diff",
+    "overlap_ratio",
+    "entries_after_creation",
+    "permission_diff",
+    "create_snap_from_map",
+    "backfill_manifest",
+    "verify_snapshot",
+    "verify_all",
+    "resolve_chain",
+    "restore_chain",
+    "get_metadata_dict",
+    "prune_expired",
+    "incompressible_files",
+    "extract_metadata",
+    "read_file_indexed",
+    "structure_fingerprint",
+    "structure_equal",
+    "verify_contents",
+    "estimate_snap",
+    "diff_snaps",
+    "read_file_from_snap",
+    "snapshot_stats",
+    "count_locs_by_language",
+    "create_snap_to_writer",
+    "verify_snap",
+    "merge_snaps",
+    "prune_snaps",
+    "update_snap",
+    "create_incremental_snap",
+    "sign_snap",
+    "verify_signature",
+    "restore_snap_filtered",
+    "recover_snap",
+    "create_multi_snap",
+    "diff_snap_against_dir",
+    "SnapshotWriter",
+    "restore_subtree",
+    "train_dictionary",
     "__version__",
 ]
@@ -0,0 +1,417 @@
+use anyhow::{Result, bail};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::crypt::{DecryptReader, ENCRYPTION_MAGIC};
+
+// --- Output Codecs ---
+
+/// Compression applied to the tar stream on its way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    /// For consumers that only have gzip/tar tooling.
+    Gzip,
+    /// Plain tar, for piping into another compressor.
+    None,
+}
+
+impl Codec {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "zstd" => Ok(Self::Zstd),
+            "gzip" => Ok(Self::Gzip),
+            "none" => Ok(Self::None),
+            other => bail!(
+                "Invalid codec '{}' (expected 'zstd', 'gzip' or 'none')",
+                other
+            ),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::None => "none",
+        }
+    }
+
+    /// Levels follow each codec's own scale; `None` ignores the level.
+    /// zstd takes its fast negative levels down to -7 (and `0`, its default).
+    pub fn check_level(self, level: i32) -> Result<()> {
+        let range = match self {
+            Self::Zstd => -7..=22,
+            Self::Gzip => 1..=9,
+            Self::None => return Ok(()),
+        };
+        if !range.contains(&level) {
+            bail!(
+                "compression level {} out of range ({}..={})",
+                level,
+                range.start(),
+                range.end()
+            );
+        }
+        Ok(())
+    }
+}
+
+// --- Input Decoders ---
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+// POSIX and GNU tar headers both carry "ustar" at this offset
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The file is neither zstd, gzip nor a plain tar archive.
+#[derive(Debug)]
+pub struct UnrecognizedFormat;
+
+impl std::fmt::Display for UnrecognizedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not a snapshot (expected a zstd, gzip or tar archive)")
+    }
+}
+
+impl std::error::Error for UnrecognizedFormat {}
+
+/// Opens a snapshot for reading, picking the decoder from its magic bytes.
+/// Anything that is not zstd, gzip or tar fails with `UnrecognizedFormat`.
+pub fn open_decoder<R: Read + 'static>(input: R) -> io::Result<Box<dyn Read>> {
+    open_decoder_with(input, None)
+}
+
+/// `open_decoder` for snapshots that may be password-encrypted, read from any
+/// byte source. Encrypted ones fail with a `CryptError` when `password` is
+/// missing or wrong. For callers that cannot take a dictionary, so one that
+/// needs a dictionary fails with `DictionaryError::Unsupported`.
+pub fn open_decoder_with<R: Read + 'static>(
+    input: R,
+    password: Option<&str>,
+) -> io::Result<Box<dyn Read>> {
+    open_decoder_using(input, password, None).map_err(dictionary_unsupported)
+}
+
+/// `open_decoder_with` that loads `dictionary` into the zstd decoder. Without
+/// one, a snapshot that needs a trained dictionary fails with a
+/// `DictionaryError`.
+pub fn open_decoder_using<R: Read + 'static>(
+    input: R,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(input);
+    if reader.fill_buf()?.starts_with(ENCRYPTION_MAGIC) {
+        let plain = DecryptReader::new(reader, password)?;
+        return sniff_codec(BufReader::new(plain), dictionary);
+    }
+    sniff_codec(reader, dictionary)
+}
+
+fn sniff_codec<R: BufRead + 'static>(
+    mut reader: R,
+    dictionary: Option<&[u8]>,
+) -> io::Result<Box<dyn Read>> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(&ZSTD_MAGIC) {
+        let needed = zstd::zstd_safe::get_dict_id_from_frame(head);
+        if let Some(dictionary) = dictionary {
+            if needed
+                .is_some_and(|id| zstd::zstd_safe::get_dict_id_from_dict(dictionary) != Some(id))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    DictionaryError::Mismatch,
+                ));
+            }
+            return Ok(Box::new(zstd::stream::read::Decoder::with_dictionary(
+                reader, dictionary,
+            )?));
+        }
+        if let Some(id) = needed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                DictionaryError::Required(id.get()),
+            ));
+        }
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?))
+    } else if head.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader)))
+    } else if head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+        Ok(Box::new(reader))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            UnrecognizedFormat,
+        ))
+    }
+}
+
+/// Whether `e` (possibly wrapped) says the input was not a snapshot at all.
+pub fn is_unrecognized(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<UnrecognizedFormat>())
+}
+
+// --- zstd Dictionaries ---
+
+/// The snapshot was compressed with a zstd dictionary that was not given,
+/// or `.vegh.json` names a different one than the one given.
+#[derive(Debug)]
+pub enum DictionaryError {
+    /// The frame header's dictionary ID; `0` for a raw content dictionary,
+    /// which carries none.
+    Required(u32),
+    /// Like `Required`, for a reader that has no `dictionary` argument.
+    Unsupported(u32),
+    Mismatch,
+}
+
+impl std::fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let which = |id: u32| match id {
+            0 => "a zstd dictionary".to_string(),
+            id => format!("zstd dictionary {}", id),
+        };
+        match self {
+            Self::Required(id) => write!(
+                f,
+                "Snapshot was compressed with {}; pass it as dictionary=...",
+                which(*id)
+            ),
+            Self::Unsupported(id) => write!(
+                f,
+                "Snapshot was compressed with {}, which this function cannot load; \
+                 read it with one that takes dictionary=..., such as restore_snap",
+                which(*id)
+            ),
+            Self::Mismatch => write!(
+                f,
+                "Snapshot was compressed with a different zstd dictionary"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DictionaryError {}
+
+/// Rewrites a `DictionaryError::Required` from `e` for a caller that has no
+/// `dictionary` argument to pass it through; other errors are returned as-is.
+pub fn dictionary_unsupported(e: io::Error) -> io::Error {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<DictionaryError>())
+    {
+        Some(DictionaryError::Required(id)) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            DictionaryError::Unsupported(*id),
+        ),
+        _ => e,
+    }
+}
+/// Whether `e` (possibly wrapped) is a missing or wrong dictionary.
+pub fn is_dictionary_error(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<DictionaryError>())
+}
+
+/// What `.vegh.json` records for a dictionary: its BLAKE3 hash, so a raw
+/// content dictionary (which has no ID of its own) is told apart too.
+pub fn dictionary_id(dictionary: &[u8]) -> String {
+    blake3::hash(dictionary).to_hex().to_string()
+}
+
+/// Uncompressed bytes per zstd frame in seekable snapshots.
+pub const SEEKABLE_FRAME_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Counts the bytes that reach the real sink (compressed offsets).
+pub struct Counted<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> Counted<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Stream<W: Write> {
+    Zstd(zstd::stream::write::Encoder<'static, Counted<W>>),
+    Gzip(flate2::write::GzEncoder<Counted<W>>),
+    Plain(Counted<W>),
+    /// Between frames; only seen if starting the next frame failed.
+    Closed,
+}
+
+/// Writer that applies the selected codec in front of the real sink, keeping
+/// track of how far into the uncompressed stream it is. `threads` is the
+/// number of zstd worker threads; `0` compresses on the calling thread.
+pub struct CodecWriter<W: Write> {
+    stream: Stream<W>,
+    position: u64,
+    frame_start: u64,
+    level: i32,
+    threads: usize,
+    /// Loaded into every frame's encoder.
+    dictionary: Option<Vec<u8>>,
+    busy: Duration,
+}
+
+fn zstd_stream<W: Write>(
+    sink: Counted<W>,
+    level: i32,
+    threads: usize,
+    dictionary: Option<&[u8]>,
+) -> Result<zstd::stream::write::Encoder<'static, Counted<W>>> {
+    let mut encoder = match dictionary {
+        Some(dictionary) => zstd::stream::write::Encoder::with_dictionary(sink, level, dictionary)?,
+        None => zstd::stream::write::Encoder::new(sink, level)?,
+    };
+    if threads > 0 && encoder.multithread(threads as u32).is_err() {
+        // libzstd built without threading: the stream stays valid, just slower.
+        // Seekable snapshots open an encoder per frame, so only say it once.
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| {
+            eprintln!("Warning: zstd multithreading unavailable, compressing on one thread")
+        });
+    }
+    Ok(encoder)
+}
+
+impl<W: Write> CodecWriter<W> {
+    pub fn new(sink: W, codec: Codec, level: i32, threads: usize) -> Result<Self> {
+        Self::with_dictionary(sink, codec, level, threads, None)
+    }
+
+    /// `new` with a zstd dictionary; other codecs cannot use one.
+    pub fn with_dictionary(
+        sink: W,
+        codec: Codec,
+        level: i32,
+        threads: usize,
+        dictionary: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        if dictionary.is_some() && codec != Codec::Zstd {
+            bail!("A dictionary needs the zstd codec, not {}", codec.name());
+        }
+        let counted = Counted::new(sink);
+        let stream = match codec {
+            Codec::Zstd => {
+                Stream::Zstd(zstd_stream(counted, level, threads, dictionary.as_deref())?)
+            }
+            Codec::Gzip => Stream::Gzip(flate2::write::GzEncoder::new(
+                counted,
+                flate2::Compression::new(level.clamp(0, 9) as u32),
+            )),
+            Codec::None => Stream::Plain(counted),
+        };
+        Ok(CodecWriter {
+            stream,
+            position: 0,
+            frame_start: 0,
+            level,
+            threads,
+            dictionary,
+            busy: Duration::ZERO,
+        })
+    }
+
+    /// Offset of the next byte in the uncompressed stream.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Time spent inside the codec so far, writing its output included.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    /// Uncompressed bytes written since the current frame started.
+    pub fn frame_len(&self) -> u64 {
+        self.position - self.frame_start
+    }
+
+    /// Closes the current zstd frame and opens a new one, so decoding can
+    /// start here. Returns the new frame's (uncompressed, compressed) offsets.
+    pub fn end_frame(&mut self) -> Result<(u64, u64)> {
+        let start = Instant::now();
+        let Stream::Zstd(encoder) = std::mem::replace(&mut self.stream, Stream::Closed) else {
+            bail!("Frame boundaries need the zstd codec");
+        };
+        let sink = encoder.finish()?;
+        let compressed = sink.count;
+        self.stream = Stream::Zstd(zstd_stream(
+            sink,
+            self.level,
+            self.threads,
+            self.dictionary.as_deref(),
+        )?);
+        self.frame_start = self.position;
+        self.busy += start.elapsed();
+        Ok((self.position, compressed))
+    }
+
+    /// Flushes any pending frame data and hands back the sink.
+    pub fn finish(self) -> Result<W> {
+        match self.stream {
+            Stream::Zstd(encoder) => Ok(encoder.finish()?.inner),
+            Stream::Gzip(encoder) => Ok(encoder.finish()?.inner),
+            Stream::Plain(mut sink) => {
+                sink.flush()?;
+                Ok(sink.inner)
+            }
+            Stream::Closed => bail!("Compressed stream was left unfinished"),
+        }
+    }
+}
+
+impl<W: Write> Write for CodecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let n = match &mut self.stream {
+            Stream::Zstd(e) => e.write(buf),
+            Stream::Gzip(e) => e.write(buf),
+            Stream::Plain(w) => w.write(buf),
+            Stream::Closed => Err(closed()),
+        };
+        self.busy += start.elapsed();
+        let n = n?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        let result = match &mut self.stream {
+            Stream::Zstd(e) => e.flush(),
+            Stream::Gzip(e) => e.flush(),
+            Stream::Plain(w) => w.flush(),
+            Stream::Closed => Err(closed()),
+        };
+        self.busy += start.elapsed();
+        result
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::other("Compressed stream is closed")
+}
@@ -1,30 +1,51 @@
-use anyhow::{Context, Result};
-use chrono::Utc;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
 use crossbeam_channel::bounded;
-use ignore::{WalkBuilder, overrides::OverrideBuilder};
+use ignore::{
+    DirEntry, WalkBuilder, WalkState,
+    overrides::{Override, OverrideBuilder},
+};
 use indicatif::{ProgressBar, ProgressStyle}; // Added for smooth UI
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 use std::sync::{
     Arc,
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
 };
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::hash::{compute_chunks, compute_file_hash, compute_sparse_hash};
+use crate::codec::{
+    Codec, CodecWriter, Counted, DictionaryError, SEEKABLE_FRAME_SIZE, dictionary_id, open_decoder,
+    open_decoder_using, open_decoder_with,
+};
+use crate::crypt::{ENCRYPTION_MAGIC, SnapshotSink};
+use crate::hash::{HashingWriter, compute_chunks, compute_file_hash, compute_sparse_hash};
+use crate::names;
+use crate::offsets::{OFFSETS_ENTRY, OffsetIndex, write_trailer};
 use crate::storage::{
-    CACHE_DIR, CacheDB, FileCacheEntry, ManifestEntry, SnapshotManifest, StoredChunk,
+    CACHE_DIR, CacheDB, FileCacheEntry, ManifestEntry, ManifestFormat, SnapshotManifest,
+    StoredChunk,
+};
+use crate::volume::{
+    SnapshotFile, VolumeWriter, is_output_or_volume, is_snapshot_name, open_snapshot,
+    remove_volumes, snapshot_hash, volume_base, volume_path,
 };
 
 // --- CONSTANTS from Vegh 0.4.0 ---
 const PRESERVED_FILES: &[&str] = &[".veghignore", ".gitignore", ".npmignore", ".dockerignore"];
 const SNAPSHOT_FORMAT_VERSION: &str = "3"; // Synced with CLI
-const CDC_THRESHOLD: u64 = 1024 * 1024; // 1MB
-const CDC_AVG_SIZE: usize = 1024 * 1024; // 1MB
+pub const CDC_AVG_SIZE: usize = 1024 * 1024; // 1MB
+// FastCDC needs min (avg/2) <= 1MB and max (avg*2) >= 1KB
+const CDC_AVG_RANGE: std::ops::RangeInclusive<usize> = 1024..=2 * 1024 * 1024;
 const CACHE_RETENTION_SEC: u64 = 30 * 24 * 60 * 60; // 30 Days
 const BATCH_COMMIT_SIZE: usize = 1000;
+const DEFAULT_AUTHOR: &str = "CodeTease (PyVegh)";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VeghMetadata {
@@ -33,18 +54,164 @@ pub struct VeghMetadata {
     #[serde(default)]
     pub timestamp_human: Option<String>,
     pub comment: String,
+    /// PyVegh version that wrote the snapshot.
     pub tool_version: String,
+    /// Snapshot layout version (`SNAPSHOT_FORMAT_VERSION`), independent of the tool.
     pub format_version: String,
+    /// Version of the application that asked PyVegh to produce the snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<String>,
+    /// Compression wrapped around the tar stream (`zstd`, `gzip` or `none`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Only known up front when the metadata is written as the last entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tool_version: Option<String>,
+    /// Set when every entry is nested under the source directory's own name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_name: Option<String>,
+    /// Integrity hash (as `check_integrity` reports it) of the snapshot this one builds on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Unix time after which the snapshot may be pruned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_until: Option<i64>,
+    /// The zstd stream is cut into independent frames, so readers holding
+    /// the offset index can start decoding near any entry.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub seekable: bool,
+    /// Integrity hash of the snapshot `update_snap` rewrote into this one.
+    /// Unlike `base` it is informational: the update is self-contained.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Source directories of a `create_multi_snap` snapshot, with the prefix
+    /// each one's entries are stored under.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceRoot>,
+    /// `codec::dictionary_id` of the zstd dictionary the stream needs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<String>,
+}
+
+/// One source directory of a multi-root snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SourceRoot {
+    pub path: String,
+    pub prefix: String,
+}
+
+// --- Format Requirements ---
+
+/// Features that older readers would silently misread, with the first
+/// tool version that understands each one.
+const FEATURE_VERSIONS: &[(&str, &str)] = &[
+    ("symlinks", "0.10.0"),
+    ("manifest-msgpack", "0.10.0"),
+    ("incremental", "0.10.0"),
+    ("escaped-names", "0.10.0"),
+];
+
+/// A snapshot needs a newer tool than this build.
+#[derive(Debug)]
+pub struct FormatError {
+    pub required: String,
+    pub feature: String,
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "this snapshot requires PyVegh >= {} to read feature {}",
+            self.required, self.feature
+        )
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+fn parse_version(v: &str) -> Vec<u64> {
+    v.split('.')
+        .map(|part| {
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse().unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Lowest tool version able to read every feature in `features`.
+pub fn min_version_for(features: &[String]) -> Option<String> {
+    FEATURE_VERSIONS
+        .iter()
+        .filter(|(name, _)| features.iter().any(|f| f == name))
+        .map(|(_, version)| *version)
+        .max_by_key(|v| parse_version(v))
+        .map(str::to_string)
+}
+
+/// Errors with `FormatError` when `min_tool_version` is newer than this build.
+pub fn check_requirements(min_tool_version: Option<&str>, features: &[String]) -> Result<()> {
+    let Some(required) = min_tool_version else {
+        return Ok(());
+    };
+    if parse_version(required) <= parse_version(env!("CARGO_PKG_VERSION")) {
+        return Ok(());
+    }
+    let feature = if features.is_empty() {
+        "unknown".to_string()
+    } else {
+        features.join(", ")
+    };
+    Err(FormatError {
+        required: required.to_string(),
+        feature,
+    }
+    .into())
+}
+
+/// Errors unless `version` is a snapshot layout this build can read, i.e. its
+/// major number is not newer than `SNAPSHOT_FORMAT_VERSION`.
+pub fn check_format_version(version: &str) -> Result<()> {
+    let supported: u32 = SNAPSHOT_FORMAT_VERSION.parse().unwrap_or(0);
+    match version.split('.').next().map(|m| m.trim().parse::<u32>()) {
+        Some(Ok(major)) if major <= supported => Ok(()),
+        Some(Ok(_)) => bail!(
+            "Snapshot format version {} was made by a newer pyvegh (this build reads up to {})",
+            version,
+            SNAPSHOT_FORMAT_VERSION
+        ),
+        _ => bail!("Unknown snapshot format version '{}'", version),
+    }
 }
 
 // Pipeline Messages
 enum WorkerResult {
     Processed(Box<ProcessedMessage>),
     Error(String),
+    TimedOut(String),
+}
+
+impl WorkerResult {
+    /// Order reproducible runs replay results in: problems first, then files by path.
+    fn sort_key(&self) -> (u8, usize, &str) {
+        match self {
+            Self::Error(e) => (0, 0, e),
+            Self::TimedOut(name) => (1, 0, name),
+            Self::Processed(pm) => (2, pm.root, &pm.path_str),
+        }
+    }
 }
 
 struct ProcessedMessage {
+    /// Index of the source root `path_str` is relative to.
+    root: usize,
     path_str: String,
+    /// `path_str` is escaped (the name on disk is not UTF-8).
+    escaped: bool,
     abs_path: PathBuf,
     metadata_info: MetadataInfo,
     entry: FileCacheEntry,
@@ -64,53 +231,831 @@ enum DataAction {
     WriteChunks(Vec<StoredChunk>),
 }
 
+/// What to do with a symlink whose target resolves outside the source tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalSymlinks {
+    Strip,
+    Keep,
+    Error,
+}
+
+impl ExternalSymlinks {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "strip" => Ok(Self::Strip),
+            "keep" => Ok(Self::Keep),
+            "error" => Ok(Self::Error),
+            other => bail!(
+                "Invalid external_symlinks '{}' (expected 'strip', 'keep' or 'error')",
+                other
+            ),
+        }
+    }
+}
+
+/// Which files a walk of the source picks up, shared by `create_snap` and the
+/// previews (`dry_run_snap`) so both see the same tree.
+#[derive(Debug, Clone)]
+pub struct WalkSettings {
+    /// Deepest level to descend to; top-level files are depth 1.
+    pub max_depth: Option<usize>,
+    /// Apply `.gitignore` rules (and git's global/exclude files).
+    pub respect_gitignore: bool,
+    /// Walk into dotfiles and dot-directories such as `.env`.
+    pub include_hidden: bool,
+    /// Per-directory ignore files read with gitignore syntax.
+    pub ignore_files: Vec<String>,
+    /// Files smaller than this many bytes are left out.
+    pub min_size: Option<u64>,
+    /// Files larger than this many bytes are left out.
+    pub max_size: Option<u64>,
+    /// Leave out other snapshots (`*.vegh` and their volumes) found in the tree.
+    pub exclude_snapshots: bool,
+    /// Read directories with this many threads; entries then arrive in no
+    /// particular order. `None` walks on the scanner thread alone.
+    pub threads: Option<usize>,
+}
+
+impl Default for WalkSettings {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            respect_gitignore: true,
+            include_hidden: false,
+            ignore_files: PRESERVED_FILES.iter().map(|f| f.to_string()).collect(),
+            min_size: None,
+            max_size: None,
+            exclude_snapshots: true,
+            threads: None,
+        }
+    }
+}
+
+impl WalkSettings {
+    pub fn check(&self) -> Result<()> {
+        if self.max_depth == Some(0) {
+            bail!("max_depth must be at least 1 (top-level files are depth 1)");
+        }
+        if self.threads == Some(0) {
+            bail!("walk_threads must be at least 1");
+        }
+        if let Some(bad) = self
+            .ignore_files
+            .iter()
+            .find(|f| f.is_empty() || f.contains(['/', '\\']))
+        {
+            bail!("ignore_files takes bare file names, got '{}'", bad);
+        }
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size)
+            && min > max
+        {
+            bail!("min_size ({}) is larger than max_size ({})", min, max);
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is a snapshot that `exclude_snapshots` leaves out.
+    pub fn skips_snapshot(&self, path: &Path) -> bool {
+        self.exclude_snapshots
+            && path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_snapshot_name)
+    }
+
+    /// Whether a file of `len` bytes falls inside `[min_size, max_size]`.
+    pub fn size_allowed(&self, len: u64) -> bool {
+        self.min_size.is_none_or(|min| len >= min) && self.max_size.is_none_or(|max| len <= max)
+    }
+
+    /// Ignore files, hidden-file and gitignore handling, depth limit and the
+    /// internal cache exclusion, applied to a walker rooted at the source.
+    pub fn configure(&self, builder: &mut WalkBuilder) {
+        for f in &self.ignore_files {
+            if f == ".gitignore" && !self.respect_gitignore {
+                continue;
+            }
+            builder.add_custom_ignore_filename(f);
+        }
+        // Exclude internal cache
+        builder.filter_entry(|entry| !entry.path().to_string_lossy().contains(CACHE_DIR));
+        builder
+            .hidden(!self.include_hidden)
+            .git_ignore(self.respect_gitignore)
+            .git_global(self.respect_gitignore)
+            .git_exclude(self.respect_gitignore)
+            .max_depth(self.max_depth);
+    }
+}
+
+/// A walk read by several threads, its entries handed over a channel as they
+/// are found. Dropping it stops the walk and waits for the threads.
+struct ParallelWalk {
+    entries: Option<crossbeam_channel::Receiver<Result<DirEntry, ignore::Error>>>,
+    walker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ParallelWalk {
+    fn start(builder: &mut WalkBuilder, threads: usize) -> Self {
+        let (tx, rx) = bounded(1024);
+        let walk = builder.threads(threads).build_parallel();
+        let walker = std::thread::spawn(move || {
+            walk.run(|| {
+                let tx = tx.clone();
+                Box::new(move |result| match tx.send(result) {
+                    Ok(()) => WalkState::Continue,
+                    // The scanner stopped reading
+                    Err(_) => WalkState::Quit,
+                })
+            })
+        });
+        Self {
+            entries: Some(rx),
+            walker: Some(walker),
+        }
+    }
+}
+
+impl Iterator for ParallelWalk {
+    type Item = Result<DirEntry, ignore::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for ParallelWalk {
+    fn drop(&mut self) {
+        self.entries = None;
+        if let Some(walker) = self.walker.take() {
+            let _ = walker.join();
+        }
+    }
+}
+
+/// Include or exclude globs that did not parse, with the parser's reason.
+#[derive(Debug)]
+pub struct InvalidPatterns(pub Vec<String>);
+
+impl std::fmt::Display for InvalidPatterns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid glob pattern(s): {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for InvalidPatterns {}
+
+/// Override rules for a walk rooted at `root`: `include` globs whitelist,
+/// `exclude` globs are negated. The last matching glob decides, so excludes
+/// go in first and an include always wins over an exclude it overlaps with.
+/// Every pattern is tried, so one error lists all the bad ones instead of
+/// the first.
+///
+/// Anchoring follows gitignore: a pattern with a leading or inner `/` is
+/// matched against the path from `root` (`/build` is only the top-level
+/// one), anything else against every path component (`build` at any depth).
+/// A leading `./` counts as `/`.
+pub fn override_builder(
+    root: &Path,
+    include: &[String],
+    exclude: &[String],
+) -> Result<OverrideBuilder> {
+    let mut builder = OverrideBuilder::new(root);
+    let mut bad = Vec::new();
+    for pattern in exclude {
+        // A matched directory is never descended, so its files go with it
+        if let Err(e) = builder.add(&format!("!{}", anchored(pattern))) {
+            bad.push(format!("'{}' ({})", pattern, e));
+        }
+    }
+    for pattern in include {
+        let glob = anchored(pattern);
+        if let Err(e) = builder.add(&glob) {
+            bad.push(format!("'{}' ({})", pattern, e));
+            continue;
+        }
+        // Whitelisting a directory does not whitelist what is in it; this does,
+        // at the same anchoring (an inner `/` would anchor a bare name)
+        let dir = glob.trim_end_matches('/');
+        let contents = if dir.contains('/') {
+            format!("{}/**", dir)
+        } else {
+            format!("**/{}/**", dir)
+        };
+        let _ = builder.add(&contents);
+    }
+    if !bad.is_empty() {
+        return Err(InvalidPatterns(bad).into());
+    }
+    Ok(builder)
+}
+
+/// `pattern` with a leading `./` turned into the `/` the glob syntax anchors on.
+fn anchored(pattern: &str) -> Cow<'_, str> {
+    match pattern.strip_prefix("./") {
+        Some(rest) => Cow::Owned(format!("/{}", rest.trim_start_matches('/'))),
+        None => Cow::Borrowed(pattern),
+    }
+}
+
+/// How file content is split into blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Files larger than the average chunk size are cut with FastCDC, so a small
+    /// edit to a big file only stores the chunks around it. Smaller files stay whole.
+    Cdc,
+    /// One blob per file; dedup only catches identical files.
+    File,
+}
+
+impl Chunking {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "cdc" => Ok(Self::Cdc),
+            "file" => Ok(Self::File),
+            other => bail!("Invalid chunking '{}' (expected 'cdc' or 'file')", other),
+        }
+    }
+
+    pub fn check_avg_size(avg_size: usize) -> Result<()> {
+        if !CDC_AVG_RANGE.contains(&avg_size) {
+            bail!(
+                "chunk_avg_size must be between {} and {} bytes",
+                CDC_AVG_RANGE.start(),
+                CDC_AVG_RANGE.end()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Where `.vegh.json` goes in the tar stream.
+///
+/// `First` keeps metadata reads cheap (they stop at the first entry). `Last` lets
+/// the metadata carry final counts, but readers must scan the whole archive to find it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataPosition {
+    First,
+    Last,
+}
+
+impl MetadataPosition {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            other => bail!(
+                "Invalid metadata_position '{}' (expected 'first' or 'last')",
+                other
+            ),
+        }
+    }
+}
+
 // --- Main Packing Logic ---
 
-#[allow(clippy::too_many_arguments)]
+/// Metadata a new snapshot starts from, before the writer fills in the rest.
+pub fn new_metadata(comment: Option<String>) -> VeghMetadata {
+    VeghMetadata {
+        author: DEFAULT_AUTHOR.to_string(),
+        timestamp: Utc::now().timestamp(),
+        timestamp_human: Some(Utc::now().to_rfc3339()),
+        comment: comment.unwrap_or_default(),
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: SNAPSHOT_FORMAT_VERSION.to_string(),
+        app_version: None,
+        codec: None,
+        file_count: None,
+        features: Vec::new(),
+        min_tool_version: None,
+        root_name: None,
+        base: None,
+        keep_until: None,
+        seekable: false,
+        parent: None,
+        sources: Vec::new(),
+        dictionary: None,
+    }
+}
+
+/// Knobs shared by every snapshot producer (file, writer, ...).
+pub struct SnapOptions {
+    pub level: i32,
+    pub comment: Option<String>,
+    /// Recorded as `author`; defaults to PyVegh itself.
+    pub author: Option<String>,
+    pub app_version: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub no_cache: bool,
+    pub verbose: bool, // Added flag to control UI output
+    pub codec: Codec,
+    pub external_symlinks: ExternalSymlinks,
+    /// Machine-specific directories to skip, matched on canonical paths.
+    pub exclude_abs_paths: Vec<PathBuf>,
+    pub metadata_position: MetadataPosition,
+    pub manifest_format: ManifestFormat,
+    /// Give up on a file whose hashing read takes longer than this and keep
+    /// going. Storing a file reads it again, and that read is not limited.
+    pub per_file_timeout: Option<Duration>,
+    /// Nest every entry under the source directory's name (`coolapp/...`).
+    pub keep_root_name: bool,
+    pub chunking: Chunking,
+    /// Target FastCDC chunk size in bytes (chunks range from half to twice this).
+    pub chunk_avg_size: usize,
+    /// Snapshot this one builds on; recorded by integrity hash as `base`.
+    pub base: Option<PathBuf>,
+    /// Only store files that differ from `base` (content or mode), plus the
+    /// base paths that are gone. Restoring then needs `base` first.
+    pub incremental: bool,
+    pub keep_until: Option<i64>,
+    /// Write a `.vegh.offsets` index (and trailer) for random-access reads.
+    pub build_offset_index: bool,
+    /// Start a new zstd frame every `SEEKABLE_FRAME_SIZE` bytes.
+    pub seekable: bool,
+    /// Files between progress hook calls.
+    pub progress_interval: usize,
+    /// Byte-identical output for identical trees: entries in path order,
+    /// normalized modes and headers, and a fixed metadata timestamp.
+    pub reproducible: bool,
+    /// Recorded instead of the current time (`0` when reproducible and unset).
+    pub timestamp: Option<i64>,
+    /// Abort on the first file that cannot be walked or read instead of skipping it.
+    pub strict: bool,
+    /// Archive what symlinks point at as regular files and directories instead
+    /// of storing the links themselves.
+    pub follow_symlinks: bool,
+    pub walk: WalkSettings,
+    /// Seal the whole snapshot with a key derived from this password.
+    pub password: Option<String>,
+    /// zstd worker threads; one per CPU when unset.
+    pub compression_threads: Option<u32>,
+    /// Cut the output into `<output>.001`, `.002`, ... of at most this many
+    /// bytes. Only `create_snap_logic` writes volumes; other sinks ignore it.
+    pub split_bytes: Option<u64>,
+    /// Record directories that end up with no files as directory entries, so
+    /// restores recreate them.
+    pub preserve_empty_dirs: bool,
+    /// Plain ustar/PAX archive any `tar` extracts: files under their own
+    /// paths, no blobs or manifest, and the metadata in a PAX global header.
+    pub interop: bool,
+    /// Trained zstd dictionary to compress with (see `train_dictionary`);
+    /// readers then need the same one.
+    pub dictionary: Option<Vec<u8>>,
+}
+
+/// What a snapshot run produced, beyond the archive itself.
+#[derive(Debug, Default)]
+pub struct SnapSummary {
+    pub count: usize,
+    /// Files skipped because they live under `exclude_abs_paths`.
+    pub abs_skipped: usize,
+    /// Files left out because reading them exceeded `per_file_timeout`.
+    pub timed_out: Vec<String>,
+    /// Files the walk or the workers could not read, and so left out.
+    pub warnings: Vec<String>,
+    /// BLAKE3 of the written output (what `check_integrity` reports), hashed
+    /// on the way out. Only set when writing to a file.
+    pub digest: Option<[u8; 32]>,
+    pub profile: SnapProfile,
+}
+
+/// Where a snapshot run spent its time, measured with monotonic clocks.
+/// The phases overlap (they run on different threads), so they need not add
+/// up to `total`.
+#[derive(Debug, Default)]
+pub struct SnapProfile {
+    /// Walking the tree, not counting waits for the workers to catch up.
+    pub walk: Duration,
+    /// Stat-ing, reading and hashing files on the workers, summed over them.
+    pub hash: Duration,
+    /// Reading file contents into the archive on the writer thread.
+    pub read: Duration,
+    /// Inside the compressor, writing its output included.
+    pub compress: Duration,
+    pub total: Duration,
+    /// Uncompressed tar bytes handed to the codec.
+    pub bytes_in: u64,
+    /// Bytes written to the output, after compression and encryption.
+    pub bytes_out: u64,
+}
+
+#[derive(Default)]
+struct WalkOutcome {
+    /// Paths below come with the index of the source root they were found in.
+    symlinks: Vec<(usize, PathBuf)>,
+    abs_skipped: usize,
+    /// Files outside the size limits, with their sizes.
+    size_skipped: Vec<(usize, String, u64)>,
+    /// Directories with nothing archived beneath them (`preserve_empty_dirs`).
+    empty_dirs: Vec<(usize, PathBuf)>,
+    walk_time: Duration,
+}
+
+/// Canonical location of a walk entry itself (not its symlink target).
+fn canonical_entry_path(path: &Path) -> Option<PathBuf> {
+    let parent = fs::canonicalize(path.parent()?).ok()?;
+    Some(parent.join(path.file_name()?))
+}
+
+fn is_under_any(path: &Path, prefixes: &[PathBuf]) -> bool {
+    canonical_entry_path(path)
+        .map(|abs| prefixes.iter().any(|p| abs.starts_with(p)))
+        .unwrap_or(false)
+}
+
+impl Default for SnapOptions {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            comment: None,
+            author: None,
+            app_version: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            no_cache: false,
+            verbose: true,
+            codec: Codec::Zstd,
+            external_symlinks: ExternalSymlinks::Strip,
+            exclude_abs_paths: Vec::new(),
+            metadata_position: MetadataPosition::First,
+            manifest_format: ManifestFormat::Json,
+            per_file_timeout: None,
+            keep_root_name: false,
+            chunking: Chunking::Cdc,
+            chunk_avg_size: CDC_AVG_SIZE,
+            base: None,
+            incremental: false,
+            keep_until: None,
+            build_offset_index: false,
+            seekable: false,
+            progress_interval: 50,
+            reproducible: false,
+            timestamp: None,
+            strict: false,
+            follow_symlinks: false,
+            walk: WalkSettings::default(),
+            password: None,
+            compression_threads: None,
+            split_bytes: None,
+            preserve_empty_dirs: false,
+            interop: false,
+            dictionary: None,
+        }
+    }
+}
+
+/// Running totals handed to a progress hook every `progress_interval` files
+/// and once more when packing (or restoring) is done. Restores leave
+/// `dedup_saved_bytes` at 0.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnapProgress {
+    pub files: usize,
+    /// Source bytes packed so far, deduplicated or not.
+    pub bytes: u64,
+    /// Bytes not stored because identical content (file or chunk) already was.
+    pub dedup_saved_bytes: u64,
+}
+
+/// Returning `Err(Cancelled)` (or any error) stops the snapshot.
+pub type ProgressHook<'a> = &'a mut dyn FnMut(SnapProgress) -> Result<()>;
+
+/// Walker and worker threads of one snapshot run. If the writer bails out
+/// early they are stopped and joined on drop, so nothing keeps reading the
+/// tree or holding the cache once the call has returned.
+struct Pipeline {
+    running: Arc<AtomicBool>,
+    scanner: Option<std::thread::JoinHandle<WalkOutcome>>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl Pipeline {
+    fn join(&mut self) -> WalkOutcome {
+        let outcome = self
+            .scanner
+            .take()
+            .and_then(|h| h.join().ok())
+            .unwrap_or_default();
+        for h in self.workers.drain(..) {
+            let _ = h.join();
+        }
+        outcome
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.join();
+    }
+}
+
 pub fn create_snap_logic(
     source: &Path,
     output: &Path,
-    level: i32,
-    comment: Option<String>,
-    include: Vec<String>,
-    exclude: Vec<String>,
-    no_cache: bool,
-    verbose: bool, // Added flag to control UI output
-) -> Result<usize> {
-    let running = Arc::new(AtomicBool::new(true));
+    opts: SnapOptions,
+    progress: Option<ProgressHook>,
+) -> Result<SnapSummary> {
+    write_snapshot_file(&[(source.to_path_buf(), None)], output, opts, progress)
+}
+
+/// One snapshot built from several trees, each `(source, prefix)` pair stored
+/// under its own prefix. Walk options, ignore files and include/exclude
+/// patterns apply to every tree separately, relative to its own root. The
+/// roots and prefixes are recorded in the metadata as `sources`.
+pub fn create_multi_snap_logic(
+    sources: &[(PathBuf, String)],
+    output: &Path,
+    opts: SnapOptions,
+    progress: Option<ProgressHook>,
+) -> Result<SnapSummary> {
+    if sources.is_empty() {
+        bail!("create_multi_snap needs at least one source");
+    }
+    let mut seen = HashSet::new();
+    for (source, prefix) in sources {
+        if !source.is_dir() {
+            bail!("Source '{}' is not a directory", source.display());
+        }
+        validate_relative_path(prefix).with_context(|| format!("Invalid prefix '{}'", prefix))?;
+        let prefix = prefix.trim_end_matches('/');
+        // Nested prefixes would let one tree's paths land inside another's
+        if let Some(clash) = seen.iter().find(|other: &&String| {
+            prefix == other.as_str()
+                || prefix.starts_with(&format!("{}/", other))
+                || other.starts_with(&format!("{}/", prefix))
+        }) {
+            bail!("Prefixes '{}' and '{}' overlap", clash, prefix);
+        }
+        seen.insert(prefix.to_string());
+    }
+    let roots: Vec<(PathBuf, Option<String>)> = sources
+        .iter()
+        .map(|(source, prefix)| {
+            let prefix = prefix.trim_end_matches('/').to_string();
+            (source.clone(), Some(prefix))
+        })
+        .collect();
+    write_snapshot_file(&roots, output, opts, progress)
+}
 
-    // Initialize Redb Cache
-    let mut cache_db = CacheDB::open(source)?;
+fn write_snapshot_file(
+    roots: &[(PathBuf, Option<String>)],
+    output: &Path,
+    opts: SnapOptions,
+    progress: Option<ProgressHook>,
+) -> Result<SnapSummary> {
+    if let Some(limit) = opts.split_bytes {
+        let volumes = VolumeWriter::new(output, limit).context("Output file creation failed")?;
+        let output_abs = canonical_entry_path(output).unwrap_or(output.to_path_buf());
+        // Hashed before the cut, like check_integrity reads volumes back
+        let mut sink = HashingWriter::new(volumes);
+        let result = write_roots(roots, &mut sink, Some(output_abs), opts, progress);
+        if result.is_err() {
+            remove_volumes(output);
+        }
+        return result.map(|summary| SnapSummary {
+            digest: Some(sink.digest()),
+            ..summary
+        });
+    }
 
     let file = File::create(output).context("Output file creation failed")?;
     let output_abs = fs::canonicalize(output).unwrap_or(output.to_path_buf());
 
+    let mut sink = HashingWriter::new(file);
+    let result = write_roots(roots, &mut sink, Some(output_abs), opts, progress);
+    if result.is_err() {
+        // A cancelled or failed run must not leave a truncated archive behind
+        let _ = fs::remove_file(output);
+    }
+    result.map(|summary| SnapSummary {
+        digest: Some(sink.digest()),
+        ..summary
+    })
+}
+
+/// Packs `source` into any writer. `skip_path` is the canonical path of the
+/// output file (if any) so the walk never archives its own output.
+pub fn create_snap_to_writer_logic<W: Write>(
+    source: &Path,
+    sink: W,
+    skip_path: Option<PathBuf>,
+    opts: SnapOptions,
+    progress: Option<ProgressHook>,
+) -> Result<SnapSummary> {
+    write_roots(
+        &[(source.to_path_buf(), None)],
+        sink,
+        skip_path,
+        opts,
+        progress,
+    )
+}
+
+/// The snapshot writer proper, over one or more source roots. A root with a
+/// prefix has its entries nested under it; without one they sit at the top
+/// (or under the root's name with `keep_root_name`).
+fn write_roots<W: Write>(
+    roots: &[(PathBuf, Option<String>)],
+    sink: W,
+    skip_path: Option<PathBuf>,
+    opts: SnapOptions,
+    mut progress: Option<ProgressHook>,
+) -> Result<SnapSummary> {
+    let SnapOptions {
+        level,
+        comment,
+        author,
+        app_version,
+        include,
+        exclude,
+        no_cache,
+        verbose,
+        codec,
+        external_symlinks,
+        exclude_abs_paths,
+        metadata_position,
+        manifest_format,
+        per_file_timeout,
+        keep_root_name,
+        chunking,
+        chunk_avg_size,
+        base,
+        incremental,
+        keep_until,
+        build_offset_index,
+        seekable,
+        progress_interval,
+        reproducible,
+        timestamp,
+        strict,
+        follow_symlinks,
+        walk,
+        password,
+        compression_threads,
+        split_bytes: _,
+        preserve_empty_dirs,
+        interop,
+        dictionary,
+    } = opts;
+    Chunking::check_avg_size(chunk_avg_size)?;
+    walk.check()?;
+    codec.check_level(level)?;
+    if (build_offset_index || seekable) && codec != Codec::Zstd {
+        bail!("build_offset_index and seekable need a zstd-compressed snapshot");
+    }
+    if (build_offset_index || seekable) && password.is_some() {
+        bail!("build_offset_index and seekable cannot be combined with a password");
+    }
+    if interop
+        && (password.is_some()
+            || incremental
+            || build_offset_index
+            || seekable
+            || metadata_position == MetadataPosition::Last
+            || manifest_format != ManifestFormat::Json
+            || dictionary.is_some())
+    {
+        bail!(
+            "interop cannot be combined with a password, incremental, build_offset_index, \
+             seekable, metadata_position='last', a msgpack manifest or a dictionary"
+        );
+    }
+    // The offset index reader opens frames without one
+    if dictionary.is_some() && (build_offset_index || seekable) {
+        bail!("A dictionary cannot be combined with build_offset_index or seekable");
+    }
+    let mut offsets = build_offset_index.then(OffsetIndex::default);
+    let started = Instant::now();
+    let running = Arc::new(AtomicBool::new(true));
+    // Declared before the channels so it drops after them, unblocking the threads
+    let mut pipeline = Pipeline {
+        running: running.clone(),
+        scanner: None,
+        workers: Vec::new(),
+    };
+
+    // Initialize Redb Cache, one per source root
+    let mut caches = roots
+        .iter()
+        .map(|(root, _)| CacheDB::open(root))
+        .collect::<Result<Vec<_>>>()?;
+
     // Prepare Metadata
-    let meta = VeghMetadata {
-        author: "CodeTease (PyVegh)".to_string(),
-        timestamp: Utc::now().timestamp(),
-        timestamp_human: Some(Utc::now().to_rfc3339()),
-        comment: comment.unwrap_or_default(),
-        tool_version: env!("CARGO_PKG_VERSION").to_string(),
-        format_version: SNAPSHOT_FORMAT_VERSION.to_string(),
+    let mut meta = new_metadata(comment);
+    if let Some(author) = author {
+        meta.author = author;
+    }
+    meta.app_version = app_version;
+    meta.codec = Some(codec.name().to_string());
+    meta.keep_until = keep_until;
+    meta.seekable = seekable;
+    meta.dictionary = dictionary.as_deref().map(dictionary_id);
+    if let Some(ts) = timestamp.or(reproducible.then_some(0)) {
+        meta.timestamp = ts;
+        meta.timestamp_human = DateTime::from_timestamp(ts, 0).map(|t| t.to_rfc3339());
+    }
+    // Path -> entry of the base, for incremental snapshots
+    let mut base_files: Option<HashMap<String, ManifestEntry>> = None;
+    // Whether the base looks reproducible, its modes reduced to 0o644/0o755
+    let mut base_modes_normalized = false;
+    if let Some(base_path) = base {
+        let hash = snapshot_hash(&base_path)
+            .with_context(|| format!("Base snapshot unreadable: {}", base_path.display()))?;
+        meta.base = Some(hex::encode(hash));
+        if incremental {
+            base_files = Some(base_entries(&base_path, dictionary.as_deref())?);
+            base_modes_normalized = base_files
+                .iter()
+                .flat_map(|files| files.values())
+                .all(|b| b.mode == normalized_mode(b.mode));
+            meta.features.push("incremental".to_string());
+            meta.min_tool_version = min_version_for(&meta.features);
+        }
+    } else if incremental {
+        bail!("An incremental snapshot needs a base snapshot");
+    }
+    let roots_abs = roots
+        .iter()
+        .map(|(root, _)| fs::canonicalize(root))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut prefixes: Vec<Option<String>> = roots.iter().map(|(_, p)| p.clone()).collect();
+    if prefixes.iter().any(Option::is_some) {
+        if keep_root_name {
+            bail!("keep_root_name cannot be combined with per-source prefixes");
+        }
+        meta.sources = roots
+            .iter()
+            .zip(&prefixes)
+            .map(|((root, _), prefix)| SourceRoot {
+                path: root.to_string_lossy().to_string(),
+                prefix: prefix.clone().unwrap_or_default(),
+            })
+            .collect();
+    } else if keep_root_name {
+        let [(source, _)] = roots else {
+            bail!("keep_root_name needs a single source");
+        };
+        let Some(name) = roots_abs[0].file_name() else {
+            bail!(
+                "keep_root_name: '{}' has no directory name",
+                source.display()
+            );
+        };
+        meta.root_name = Some(name.to_string_lossy().to_string());
+        prefixes[0] = meta.root_name.clone();
+    }
+    // Manifest paths only; the caches stay keyed by source-relative paths
+    let entry_path = |root: usize, rel: &str| match &prefixes[root] {
+        Some(prefix) => format!("{}/{}", prefix, rel),
+        None => rel.to_string(),
+    };
+    // An escaped relative name needs its root prefix escaped to match
+    let escaped_entry_path = |root: usize, rel: &str, escaped: bool| match &prefixes[root] {
+        Some(prefix) if escaped => format!("{}/{}", names::escape(prefix), rel),
+        _ => entry_path(root, rel),
     };
-    let meta_json = serde_json::to_string_pretty(&meta)?;
+    // Readers need to know about a binary manifest before they reach it
+    if manifest_format == ManifestFormat::MsgPack {
+        meta.features.push("manifest-msgpack".to_string());
+        meta.min_tool_version = min_version_for(&meta.features);
+    }
 
-    let mut encoder = zstd::stream::write::Encoder::new(file, level)?;
     let num_threads = std::thread::available_parallelism()
         .map(|n| n.get())
         .unwrap_or(1);
-    encoder.multithread(num_threads as u32)?;
+    let sink = SnapshotSink::new(Counted::new(sink), password.as_deref())?;
+    let zstd_threads = compression_threads.map_or(num_threads, |n| n as usize);
+    let encoder = CodecWriter::with_dictionary(sink, codec, level, zstd_threads, dictionary)?;
 
     let mut tar = tar::Builder::new(encoder);
+    if reproducible {
+        // Blob headers otherwise carry each source file's mtime, owner and mode
+        tar.mode(tar::HeaderMode::Deterministic);
+    }
 
     // Write Meta (Hidden Header)
-    let mut header = tar::Header::new_gnu();
-    header.set_path(".vegh.json")?;
-    header.set_size(meta_json.len() as u64);
-    header.set_mode(0o644);
-    header.set_cksum();
-    tar.append_data(&mut header, ".vegh.json", meta_json.as_bytes())?;
+    if interop {
+        // Compact: tar readers split PAX records on newlines
+        let meta_json = serde_json::to_string(&meta)?;
+        append_pax(
+            &mut tar,
+            tar::EntryType::XGlobalHeader,
+            ".vegh.json",
+            &[(PAX_METADATA_KEY, meta_json.as_bytes())],
+        )?;
+    } else if metadata_position == MetadataPosition::First {
+        let meta_json = serde_json::to_string_pretty(&meta)?;
+        append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+        after_entry(
+            &mut tar,
+            &mut offsets,
+            seekable,
+            ".vegh.json",
+            meta_json.len() as u64,
+        )?;
+    }
 
     // --- SETUP PROGRESS BAR (Only if verbose is true) ---
     // This runs completely in Rust, avoiding Python GIL overhead for thousands of updates.
@@ -127,163 +1072,212 @@ pub fn create_snap_logic(
     };
 
     // 1. Setup Channels
-    let (path_tx, path_rx) = bounded::<PathBuf>(1024);
+    let (path_tx, path_rx) = bounded::<(usize, PathBuf)>(1024);
     let (res_tx, res_rx) = bounded::<WorkerResult>(1024);
 
     // 2. Scanner Thread
-    let source_buf = source.to_path_buf();
-    let source_buf_for_scan = source_buf.clone();
     let path_tx_for_scan = path_tx.clone();
+    let res_tx_for_scan = res_tx.clone();
     let r_scan = running.clone();
 
-    // Reconstruct ignore logic
-    let mut override_builder = OverrideBuilder::new(&source_buf);
-    for pattern in include {
-        let _ = override_builder.add(&pattern);
-    }
-    for pattern in exclude {
-        let _ = override_builder.add(&format!("!{}", pattern));
+    // Reconstruct ignore logic, rooted at each source
+    let mut scan_roots = Vec::new();
+    for (root, _) in roots {
+        let overrides = override_builder(root, &include, &exclude)?.build()?;
+        scan_roots.push((root.clone(), overrides));
     }
 
-    // FIX: Override does not implement Default, so handle error manually
-    let overrides = override_builder
-        .build()
-        .unwrap_or_else(|_| OverrideBuilder::new(&source_buf).build().unwrap());
-
-    let scanner_handle = std::thread::spawn(move || {
-        let mut builder = WalkBuilder::new(&source_buf_for_scan);
-        for &f in PRESERVED_FILES {
-            builder.add_custom_ignore_filename(f);
-        }
-        // Exclude internal cache
-        builder.filter_entry(|entry| !entry.path().to_string_lossy().contains(CACHE_DIR));
-        builder.hidden(true).git_ignore(true).overrides(overrides);
+    let exclude_abs: Vec<PathBuf> = exclude_abs_paths
+        .into_iter()
+        .map(|p| fs::canonicalize(&p).unwrap_or(p))
+        .collect();
 
-        for result in builder.build() {
-            if !r_scan.load(Ordering::SeqCst) {
-                break;
+    pipeline.scanner = Some(std::thread::spawn(move || {
+        let mut outcome = WalkOutcome::default();
+        let walk_started = Instant::now();
+        // Time blocked handing paths to busy workers is not walk time
+        let mut blocked = Duration::ZERO;
+        // Every directory holding something archived, for preserve_empty_dirs
+        let mut occupied = HashSet::new();
+        let mut occupy = |path: &Path| {
+            if preserve_empty_dirs {
+                for dir in path.ancestors().skip(1) {
+                    if !occupied.insert(dir.to_path_buf()) {
+                        break;
+                    }
+                }
             }
-            if let Ok(entry) = result
-                && entry.file_type().map(|ft| ft.is_file()).unwrap_or(false)
-            {
-                // Check against output file recursion
-                if let Ok(abs) = fs::canonicalize(entry.path())
-                    && abs == output_abs
-                {
+        };
+        'roots: for (root, (root_path, overrides)) in scan_roots.into_iter().enumerate() {
+            let mut builder = WalkBuilder::new(&root_path);
+            walk.configure(&mut builder);
+            builder.follow_links(follow_symlinks).overrides(overrides);
+            let entries: Box<dyn Iterator<Item = _>> = match walk.threads {
+                Some(threads) if threads > 1 => {
+                    Box::new(ParallelWalk::start(&mut builder, threads))
+                }
+                _ => Box::new(builder.build()),
+            };
+            for result in entries {
+                if !r_scan.load(Ordering::SeqCst) {
+                    break 'roots;
+                }
+                let entry = match result {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        // Reported through the writer like unreadable files
+                        if res_tx_for_scan
+                            .send(WorkerResult::Error(e.to_string()))
+                            .is_err()
+                        {
+                            break 'roots;
+                        }
+                        continue;
+                    }
+                };
+                let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(true);
+                if is_dir && preserve_empty_dirs && entry.depth() > 0 {
+                    outcome.empty_dirs.push((root, entry.path().to_path_buf()));
+                }
+                if !is_dir && !exclude_abs.is_empty() && is_under_any(entry.path(), &exclude_abs) {
+                    outcome.abs_skipped += 1;
                     continue;
                 }
-                if path_tx_for_scan.send(entry.path().to_path_buf()).is_err() {
-                    break;
+                if entry.file_type().map(|ft| ft.is_symlink()).unwrap_or(false) {
+                    // Links are resolved by the writer once the walk is done
+                    occupy(entry.path());
+                    outcome.symlinks.push((root, entry.path().to_path_buf()));
+                    continue;
+                }
+                if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                    // Check against output file recursion
+                    if let Some(ref skip) = skip_path
+                        && let Ok(abs) = fs::canonicalize(entry.path())
+                        && is_output_or_volume(&abs, skip)
+                    {
+                        continue;
+                    }
+                    if walk.skips_snapshot(entry.path()) {
+                        continue;
+                    }
+                    if let Ok(meta) = entry.metadata()
+                        && !walk.size_allowed(meta.len())
+                    {
+                        let name = entry.path().strip_prefix(&root_path);
+                        let name = name.unwrap_or(entry.path()).to_string_lossy();
+                        outcome
+                            .size_skipped
+                            .push((root, name.to_string(), meta.len()));
+                        continue;
+                    }
+                    occupy(entry.path());
+                    let waited = Instant::now();
+                    let sent = path_tx_for_scan.send((root, entry.path().to_path_buf()));
+                    blocked += waited.elapsed();
+                    if sent.is_err() {
+                        break 'roots;
+                    }
                 }
             }
         }
-    });
+        outcome
+            .empty_dirs
+            .retain(|(_, dir)| !occupied.contains(dir));
+        outcome.empty_dirs.sort();
+        // Walk order is not fixed once several threads read the tree
+        outcome.symlinks.sort();
+        outcome.walk_time = walk_started.elapsed().saturating_sub(blocked);
+        outcome
+    }));
 
     // 3. Worker Threads
-    let mut worker_handles = Vec::new();
-    let cache_reader = cache_db.reader();
+    let cache_readers: Vec<_> = caches.iter().map(|c| c.reader()).collect();
+    let src_roots: Vec<PathBuf> = roots.iter().map(|(root, _)| root.clone()).collect();
     let written_blobs = Arc::new(dashmap::DashMap::new());
     let written_blobs_shared = written_blobs.clone();
+    let worker_nanos = Arc::new(AtomicU64::new(0));
 
     for _ in 0..num_threads {
+        let hash_nanos = worker_nanos.clone();
         let rx = path_rx.clone();
         let tx = res_tx.clone();
-        let reader = cache_reader.clone();
+        let readers = cache_readers.clone();
         let blobs = written_blobs_shared.clone();
-        let src_root = source_buf.clone();
+        let src_roots = src_roots.clone();
         let r_worker = running.clone();
         let no_cache_flag = no_cache;
 
-        worker_handles.push(std::thread::spawn(move || {
-            while let Ok(path) = rx.recv() {
-                if !r_worker.load(Ordering::SeqCst) {
-                    break;
-                }
+        pipeline.workers.push(std::thread::spawn(move || {
+            let timeout_roots = src_roots.clone();
+            let process = move |root: usize, path: PathBuf| -> Result<ProcessedMessage> {
+                let name = path.strip_prefix(&src_roots[root]).unwrap_or(&path);
+                let (name_str, escaped) = names::encode_path(name);
+                let metadata = path.metadata()?;
+                let size = metadata.len();
+                let modified = metadata
+                    .modified()
+                    .unwrap_or(SystemTime::UNIX_EPOCH)
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
 
-                let process_res = (|| -> Result<ProcessedMessage> {
-                    let name = path.strip_prefix(&src_root).unwrap_or(&path);
-                    let name_str = name.to_string_lossy().to_string();
-                    let metadata = path.metadata()?;
-                    let size = metadata.len();
-                    let modified = metadata
-                        .modified()
-                        .unwrap_or(SystemTime::UNIX_EPOCH)
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs();
-
-                    #[cfg(unix)]
-                    let (mode, inode, device_id, ctime_sec, ctime_nsec) = {
-                        use std::os::unix::fs::MetadataExt;
-                        (
-                            metadata.mode(),
-                            metadata.ino(),
-                            metadata.dev(),
-                            metadata.ctime(),
-                            metadata.ctime_nsec() as u32,
-                        )
-                    };
-                    #[cfg(not(unix))]
-                    let (mode, inode, device_id, ctime_sec, ctime_nsec) = (0o644, 0, 0, 0, 0);
+                #[cfg(unix)]
+                let (mode, inode, device_id, ctime_sec, ctime_nsec) = {
+                    use std::os::unix::fs::MetadataExt;
+                    (
+                        metadata.mode(),
+                        metadata.ino(),
+                        metadata.dev(),
+                        metadata.ctime(),
+                        metadata.ctime_nsec() as u32,
+                    )
+                };
+                #[cfg(not(unix))]
+                let (mode, inode, device_id, ctime_sec, ctime_nsec) = (0o644, 0, 0, 0, 0);
 
-                    let now_ts = SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    let use_cdc = size > CDC_THRESHOLD;
+                let now_ts = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let use_cdc = chunking == Chunking::Cdc && size > chunk_avg_size as u64;
 
-                    let cached_entry_opt = if no_cache_flag {
-                        None
-                    } else {
-                        reader.get(&name_str)?
-                    };
+                let cached_entry_opt = if no_cache_flag {
+                    None
+                } else {
+                    readers[root].get(&name_str)?
+                };
 
-                    let (hash, chunks_info, is_cached_hit) =
-                        if let Some(ref cached_entry) = cached_entry_opt {
-                            let is_hit = cached_entry.modified == modified
-                                && cached_entry.size == size
-                                && cached_entry.inode == inode
-                                && (cached_entry.device_id == 0
-                                    || device_id == 0
-                                    || cached_entry.device_id == device_id)
-                                && cached_entry.hash.is_some();
-
-                            if is_hit {
-                                if use_cdc {
-                                    if let Ok(Some(chunks)) = cached_entry.get_chunks() {
-                                        (cached_entry.hash.unwrap(), Some(chunks), true)
-                                    } else {
-                                        let (h, chunks) = compute_chunks(&path, CDC_AVG_SIZE)?;
-                                        let stored_chunks: Vec<StoredChunk> = chunks
-                                            .into_iter()
-                                            .map(|c| StoredChunk {
-                                                hash: c.hash,
-                                                offset: c.offset as u64,
-                                                length: c.length as u32,
-                                            })
-                                            .collect();
-                                        (h, Some(stored_chunks), false)
-                                    }
+                let (hash, chunks_info, is_cached_hit) =
+                    if let Some(ref cached_entry) = cached_entry_opt {
+                        let is_hit = cached_entry.modified == modified
+                            && cached_entry.size == size
+                            && cached_entry.inode == inode
+                            && (cached_entry.device_id == 0
+                                || device_id == 0
+                                || cached_entry.device_id == device_id)
+                            && cached_entry.hash.is_some();
+
+                        if is_hit {
+                            if use_cdc {
+                                if let Ok(Some(chunks)) = cached_entry.get_chunks() {
+                                    (cached_entry.hash.unwrap(), Some(chunks), true)
                                 } else {
-                                    (cached_entry.hash.unwrap(), None, true)
+                                    let (h, chunks) = compute_chunks(&path, chunk_avg_size)?;
+                                    let stored_chunks: Vec<StoredChunk> = chunks
+                                        .into_iter()
+                                        .map(|c| StoredChunk {
+                                            hash: c.hash,
+                                            offset: c.offset as u64,
+                                            length: c.length as u32,
+                                        })
+                                        .collect();
+                                    (h, Some(stored_chunks), false)
                                 }
-                            } else if use_cdc {
-                                let (h, chunks) = compute_chunks(&path, CDC_AVG_SIZE)?;
-                                let stored_chunks: Vec<StoredChunk> = chunks
-                                    .into_iter()
-                                    .map(|c| StoredChunk {
-                                        hash: c.hash,
-                                        offset: c.offset as u64,
-                                        length: c.length as u32,
-                                    })
-                                    .collect();
-                                (h, Some(stored_chunks), false)
                             } else {
-                                let h = compute_file_hash(&path)?;
-                                (h, None, false)
+                                (cached_entry.hash.unwrap(), None, true)
                             }
                         } else if use_cdc {
-                            let (h, chunks) = compute_chunks(&path, CDC_AVG_SIZE)?;
+                            let (h, chunks) = compute_chunks(&path, chunk_avg_size)?;
                             let stored_chunks: Vec<StoredChunk> = chunks
                                 .into_iter()
                                 .map(|c| StoredChunk {
@@ -296,70 +1290,136 @@ pub fn create_snap_logic(
                         } else {
                             let h = compute_file_hash(&path)?;
                             (h, None, false)
-                        };
-
-                    let mut data_action = DataAction::Cached;
-                    if let Some(chunks) = chunks_info.clone() {
-                        let mut chunks_to_write = Vec::new();
-                        for c in chunks {
-                            let hex_h = hex::encode(c.hash);
-                            if !blobs.contains_key(&hex_h) {
-                                chunks_to_write.push(c);
-                            }
-                        }
-                        if !chunks_to_write.is_empty() {
-                            data_action = DataAction::WriteChunks(chunks_to_write);
                         }
+                    } else if use_cdc {
+                        let (h, chunks) = compute_chunks(&path, chunk_avg_size)?;
+                        let stored_chunks: Vec<StoredChunk> = chunks
+                            .into_iter()
+                            .map(|c| StoredChunk {
+                                hash: c.hash,
+                                offset: c.offset as u64,
+                                length: c.length as u32,
+                            })
+                            .collect();
+                        (h, Some(stored_chunks), false)
                     } else {
-                        let hex_h = hex::encode(hash);
+                        let h = compute_file_hash(&path)?;
+                        (h, None, false)
+                    };
+
+                let mut data_action = DataAction::Cached;
+                if let Some(chunks) = chunks_info.clone() {
+                    let mut chunks_to_write = Vec::new();
+                    for c in chunks {
+                        let hex_h = hex::encode(c.hash);
                         if !blobs.contains_key(&hex_h) {
-                            data_action = DataAction::WriteFile(hash.to_vec());
+                            chunks_to_write.push(c);
                         }
                     }
-
-                    let mut entry = FileCacheEntry {
-                        size,
-                        modified,
-                        inode,
-                        device_id,
-                        ctime_sec,
-                        ctime_nsec,
-                        last_seen: now_ts,
-                        hash: Some(hash),
-                        chunks_compressed: None,
-                        sparse_hash: None,
-                    };
-
-                    if !is_cached_hit {
-                        entry.sparse_hash = compute_sparse_hash(&path, size).ok();
-                    } else if let Some(old) = cached_entry_opt.and_then(|e| e.sparse_hash) {
-                        entry.sparse_hash = Some(old);
+                    if !chunks_to_write.is_empty() {
+                        data_action = DataAction::WriteChunks(chunks_to_write);
                     }
-
-                    if let Some(chunks) = chunks_info {
-                        entry.set_chunks(chunks)?;
+                } else {
+                    let hex_h = hex::encode(hash);
+                    if !blobs.contains_key(&hex_h) {
+                        data_action = DataAction::WriteFile(hash.to_vec());
                     }
+                }
 
-                    Ok(ProcessedMessage {
-                        path_str: name_str,
-                        abs_path: path,
-                        metadata_info: MetadataInfo {
-                            size,
-                            modified,
-                            mode,
-                        },
-                        entry,
-                        data_action,
-                        is_cached_hit,
-                    })
-                })();
+                let mut entry = FileCacheEntry {
+                    size,
+                    modified,
+                    inode,
+                    device_id,
+                    ctime_sec,
+                    ctime_nsec,
+                    last_seen: now_ts,
+                    hash: Some(hash),
+                    chunks_compressed: None,
+                    sparse_hash: None,
+                };
 
-                match process_res {
-                    Ok(msg) => {
-                        let _ = tx.send(WorkerResult::Processed(Box::new(msg)));
-                    }
+                if !is_cached_hit {
+                    entry.sparse_hash = compute_sparse_hash(&path, size).ok();
+                } else if let Some(old) = cached_entry_opt.and_then(|e| e.sparse_hash) {
+                    entry.sparse_hash = Some(old);
+                }
+
+                if let Some(chunks) = chunks_info {
+                    entry.set_chunks(chunks)?;
+                }
+
+                Ok(ProcessedMessage {
+                    root,
+                    path_str: name_str,
+                    escaped,
+                    abs_path: path,
+                    metadata_info: MetadataInfo {
+                        size,
+                        modified,
+                        mode,
+                    },
+                    entry,
+                    data_action,
+                    is_cached_hit,
+                })
+            };
+            // With a timeout, files are read on a helper thread this worker keeps
+            // from file to file; it is only replaced after being given up on
+            let mut helper = None;
+            while let Ok((root, path)) = rx.recv() {
+                if !r_worker.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let name = path
+                    .strip_prefix(&timeout_roots[root])
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .to_string();
+                let processing = Instant::now();
+                let process_res = match per_file_timeout {
+                    None => process(root, path),
+                    Some(limit) => {
+                        let (job_tx, done_rx) = helper.get_or_insert_with(|| {
+                            let (job_tx, job_rx) = bounded::<(usize, PathBuf)>(1);
+                            let (done_tx, done_rx) = bounded(1);
+                            let job = process.clone();
+                            std::thread::spawn(move || {
+                                while let Ok((root, path)) = job_rx.recv() {
+                                    if done_tx.send(job(root, path)).is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                            (job_tx, done_rx)
+                        });
+                        let _ = job_tx.send((root, path));
+                        match done_rx.recv_timeout(limit) {
+                            Ok(res) => res,
+                            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                                helper = None;
+                                Err(anyhow::anyhow!("reader thread stopped"))
+                            }
+                            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                                // A read stuck in the kernel cannot be interrupted; the
+                                // helper is abandoned (it exits once the read returns)
+                                // and the file reported instead
+                                helper = None;
+                                let _ = tx.send(WorkerResult::TimedOut(name));
+                                continue;
+                            }
+                        }
+                    }
+                };
+                hash_nanos.fetch_add(processing.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+                match process_res {
+                    Ok(msg) => {
+                        let _ = tx.send(WorkerResult::Processed(Box::new(msg)));
+                    }
                     Err(e) => {
-                        let _ = tx.send(WorkerResult::Error(e.to_string()));
+                        let _ = tx.send(WorkerResult::Error(format!("{}: {}", name, e)));
                     }
                 }
             }
@@ -373,23 +1433,72 @@ pub fn create_snap_logic(
     let mut count = 0;
     let mut dedup_count = 0;
     let mut cache_hit_count = 0;
+    // Interop entries go out as they come, so duplicates are dropped here
+    let mut interop_paths = HashSet::new();
+    let mut dedup_saved_bytes = 0u64;
+    let mut bytes_done = 0u64;
+    let mut reported = 0;
+    let mut read_time = Duration::ZERO;
     let mut manifest = SnapshotManifest::default();
+    // Every path seen in the tree, stored or unchanged from the base
+    let mut present: HashSet<String> = HashSet::new();
     let mut batch_counter = 0;
+    let mut timed_out = Vec::new();
+    let mut warnings = Vec::new();
+
+    // Reproducible runs hold every result back and replay them in path order,
+    // so blobs land in the same place whatever order the workers finished in
+    let results: Box<dyn Iterator<Item = WorkerResult>> = if reproducible {
+        let mut all: Vec<WorkerResult> = res_rx.iter().collect();
+        all.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        Box::new(all.into_iter())
+    } else {
+        Box::new(res_rx.iter())
+    };
 
-    while let Ok(msg) = res_rx.recv() {
+    for msg in results {
         match msg {
+            WorkerResult::TimedOut(name) => {
+                match pb {
+                    Some(ref p) => p.println(format!("⚠️ Timed out reading: {}", name)),
+                    None => eprintln!("Timed out reading: {}", name),
+                }
+                timed_out.push(name);
+            }
             WorkerResult::Error(e) => {
+                if strict {
+                    bail!("Aborted (strict): {}", e);
+                }
                 if let Some(ref p) = pb {
                     p.println(format!("⚠️ Error: {}", e));
                 } else {
                     eprintln!("Error: {}", e);
                 }
+                warnings.push(e);
             }
             WorkerResult::Processed(pm_box) => {
                 let pm = *pm_box;
                 if pm.is_cached_hit {
                     cache_hit_count += 1;
                 }
+                if let Some(base_files) = &base_files {
+                    let name = escaped_entry_path(pm.root, &pm.path_str, pm.escaped);
+                    let hash = hex::encode(pm.entry.hash.unwrap_or_default());
+                    // Reproducible bases store normalized modes; compare like with like
+                    let mode = if base_modes_normalized {
+                        normalized_mode(pm.metadata_info.mode)
+                    } else {
+                        pm.metadata_info.mode
+                    };
+                    let unchanged = base_files
+                        .get(&name)
+                        .is_some_and(|b| b.symlink.is_none() && b.hash == hash && b.mode == mode);
+                    present.insert(name);
+                    if unchanged {
+                        caches[pm.root].insert(&pm.path_str, &pm.entry)?;
+                        continue;
+                    }
+                }
 
                 // Update Progress Bar UI
                 if let Some(ref p) = pb {
@@ -406,56 +1515,105 @@ pub fn create_snap_logic(
                     }
                 }
 
-                match pm.data_action {
-                    DataAction::Cached => {
-                        dedup_count += 1;
+                // Bytes this file actually added to the archive
+                let mut stored = 0u64;
+                if interop {
+                    // Every file in full under its own path; dedup needs blobs
+                    let path = escaped_entry_path(pm.root, &pm.path_str, pm.escaped);
+                    let header_path = names::decode_path(&path, pm.escaped);
+                    if interop_paths.insert(path) {
+                        let reading = Instant::now();
+                        let codec_before = tar.get_ref().busy();
+                        let mut f = File::open(&pm.abs_path)?;
+                        let size = f.metadata()?.len();
+                        let mode = pm.metadata_info.mode;
+                        let entry = UstarEntry {
+                            kind: tar::EntryType::Regular,
+                            path: &header_path,
+                            link: None,
+                            size,
+                            mode: if reproducible {
+                                normalized_mode(mode)
+                            } else {
+                                mode
+                            },
+                            mtime: pm.metadata_info.modified,
+                        };
+                        append_ustar(&mut tar, entry, (&mut f).take(size))?;
+                        let compressing = tar.get_ref().busy() - codec_before;
+                        read_time += reading.elapsed().saturating_sub(compressing);
+                        stored = size;
                     }
-                    DataAction::WriteFile(hash_bytes) => {
-                        let hash_hex = hex::encode(&hash_bytes);
-                        if !written_blobs.contains_key(&hash_hex) {
-                            let blob_path = format!("blobs/{}", hash_hex);
-                            let mut f = File::open(&pm.abs_path)?;
-                            tar.append_file(&blob_path, &mut f)?;
-                            written_blobs.insert(hash_hex, ());
-                        } else {
-                            // If it was already in written_blobs (from another file), count as dedup
+                } else {
+                    match pm.data_action {
+                        DataAction::Cached => {
                             dedup_count += 1;
-                            if let Some(ref p) = pb {
-                                p.set_message(format!("Dedup (Blob): {}", pm.path_str));
-                            }
                         }
-                    }
-                    DataAction::WriteChunks(chunks) => {
-                        let mut f = File::open(&pm.abs_path)?;
-                        let mut any_written = false;
-                        for chunk in chunks {
-                            let chunk_hex = hex::encode(chunk.hash);
-                            if !written_blobs.contains_key(&chunk_hex) {
-                                let blob_path = format!("blobs/{}", chunk_hex);
-                                f.seek(SeekFrom::Start(chunk.offset))?;
-                                let mut chunk_buf = vec![0u8; chunk.length as usize];
-                                f.read_exact(&mut chunk_buf)?;
-
-                                let mut header = tar::Header::new_gnu();
-                                header.set_path(&blob_path)?;
-                                header.set_size(chunk.length as u64);
-                                header.set_mode(0o644);
-                                header.set_cksum();
-                                tar.append_data(&mut header, &blob_path, &chunk_buf[..])?;
-                                written_blobs.insert(chunk_hex, ());
-                                any_written = true;
+                        DataAction::WriteFile(hash_bytes) => {
+                            let hash_hex = hex::encode(&hash_bytes);
+                            if !written_blobs.contains_key(&hash_hex) {
+                                let blob_path = format!("blobs/{}", hash_hex);
+                                let reading = Instant::now();
+                                let codec_before = tar.get_ref().busy();
+                                let mut f = File::open(&pm.abs_path)?;
+                                let size = f.metadata()?.len();
+                                tar.append_file(&blob_path, &mut f)?;
+                                // The file is read as it is compressed; keep the read part
+                                let compressing = tar.get_ref().busy() - codec_before;
+                                read_time += reading.elapsed().saturating_sub(compressing);
+                                after_entry(&mut tar, &mut offsets, seekable, &blob_path, size)?;
+                                stored = size;
+                                written_blobs.insert(hash_hex, ());
+                            } else {
+                                // If it was already in written_blobs (from another file), count as dedup
+                                dedup_count += 1;
+                                if let Some(ref p) = pb {
+                                    p.set_message(format!("Dedup (Blob): {}", pm.path_str));
+                                }
                             }
                         }
-                        if !any_written {
-                            dedup_count += 1;
-                            if let Some(ref p) = pb {
-                                p.set_message(format!("Dedup (Chunks): {}", pm.path_str));
+                        DataAction::WriteChunks(chunks) => {
+                            let mut f = File::open(&pm.abs_path)?;
+                            let mut any_written = false;
+                            for chunk in chunks {
+                                let chunk_hex = hex::encode(chunk.hash);
+                                if !written_blobs.contains_key(&chunk_hex) {
+                                    let blob_path = format!("blobs/{}", chunk_hex);
+                                    let reading = Instant::now();
+                                    f.seek(SeekFrom::Start(chunk.offset))?;
+                                    let mut chunk_buf = vec![0u8; chunk.length as usize];
+                                    f.read_exact(&mut chunk_buf)?;
+                                    read_time += reading.elapsed();
+
+                                    let mut header = tar::Header::new_gnu();
+                                    header.set_path(&blob_path)?;
+                                    header.set_size(chunk.length as u64);
+                                    header.set_mode(0o644);
+                                    header.set_cksum();
+                                    tar.append_data(&mut header, &blob_path, &chunk_buf[..])?;
+                                    after_entry(
+                                        &mut tar,
+                                        &mut offsets,
+                                        seekable,
+                                        &blob_path,
+                                        chunk.length as u64,
+                                    )?;
+                                    written_blobs.insert(chunk_hex, ());
+                                    stored += chunk.length as u64;
+                                    any_written = true;
+                                }
+                            }
+                            if !any_written {
+                                dedup_count += 1;
+                                if let Some(ref p) = pb {
+                                    p.set_message(format!("Dedup (Chunks): {}", pm.path_str));
+                                }
                             }
                         }
                     }
                 }
 
-                cache_db.insert(&pm.path_str, &pm.entry)?;
+                caches[pm.root].insert(&pm.path_str, &pm.entry)?;
 
                 let chunk_hashes_hex: Option<Vec<String>> = pm
                     .entry
@@ -465,126 +1623,3289 @@ pub fn create_snap_logic(
                     .map(|v| v.iter().map(|c| hex::encode(c.hash)).collect());
 
                 manifest.entries.push(ManifestEntry {
-                    path: pm.path_str,
+                    path: escaped_entry_path(pm.root, &pm.path_str, pm.escaped),
                     hash: hex::encode(pm.entry.hash.unwrap_or_default()),
                     size: pm.metadata_info.size,
                     modified: pm.metadata_info.modified,
                     mode: pm.metadata_info.mode,
                     chunks: chunk_hashes_hex,
+                    symlink: None,
+                    escaped: pm.escaped,
                 });
 
                 count += 1;
+                bytes_done += pm.metadata_info.size;
+                dedup_saved_bytes += pm.metadata_info.size.saturating_sub(stored);
+                if let Some(hook) = progress.as_mut()
+                    && count % progress_interval.max(1) == 0
+                {
+                    hook(SnapProgress {
+                        files: count,
+                        bytes: bytes_done,
+                        dedup_saved_bytes,
+                    })?;
+                    reported = count;
+                }
                 batch_counter += 1;
                 if batch_counter >= BATCH_COMMIT_SIZE {
-                    cache_db.commit_batch()?;
+                    for cache in caches.iter_mut() {
+                        cache.commit_batch()?;
+                    }
                     batch_counter = 0;
                 }
             }
         }
     }
 
-    let _ = scanner_handle.join();
-    for h in worker_handles {
-        let _ = h.join();
+    let WalkOutcome {
+        symlinks,
+        abs_skipped,
+        size_skipped,
+        empty_dirs,
+        walk_time,
+    } = pipeline.join();
+    for (root, name, size) in size_skipped {
+        warnings.push(format!(
+            "{}: skipped, {} bytes is outside the size limits",
+            entry_path(root, &name),
+            size
+        ));
+    }
+
+    // 5. Symlinks (stored as manifest-only entries, no blob)
+    let mut stripped_links = 0;
+    for (root, link) in symlinks {
+        let Some(target) = resolve_symlink_target(&roots_abs[root], &link, external_symlinks)?
+        else {
+            stripped_links += 1;
+            continue;
+        };
+        let name = link.strip_prefix(&roots[root].0).unwrap_or(&link);
+        let meta = fs::symlink_metadata(&link)?;
+        let modified = meta
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::MetadataExt;
+            meta.mode()
+        };
+        #[cfg(not(unix))]
+        let mode = 0o777;
+
+        let (name, escaped) = names::encode_path(name);
+        let path = escaped_entry_path(root, &name, escaped);
+        if let Some(base_files) = &base_files {
+            present.insert(path.clone());
+            if base_files
+                .get(&path)
+                .is_some_and(|b| b.symlink.as_deref() == Some(target.as_str()))
+            {
+                continue;
+            }
+        }
+        if interop && interop_paths.insert(path.clone()) {
+            let entry = UstarEntry {
+                kind: tar::EntryType::Symlink,
+                path: &names::decode_path(&path, escaped),
+                link: Some(&target),
+                size: 0,
+                mode,
+                mtime: modified,
+            };
+            append_ustar(&mut tar, entry, std::io::empty())?;
+        }
+        manifest.entries.push(ManifestEntry {
+            path,
+            modified,
+            mode,
+            symlink: Some(target),
+            escaped,
+            ..Default::default()
+        });
+        count += 1;
+    }
+    if stripped_links > 0
+        && let Some(ref p) = pb
+    {
+        p.println(format!(
+            "⚠️ Stripped {} symlink(s) pointing outside the source tree",
+            stripped_links
+        ));
+    }
+
+    // Empty directories: plain tar directory entries, which restores unpack
+    // like any other non-blob entry
+    for (root, dir) in empty_dirs {
+        let name = dir.strip_prefix(&roots[root].0).unwrap_or(&dir);
+        let (name, escaped) = names::encode_path(name);
+        let path = format!("{}/", escaped_entry_path(root, &name, escaped));
+        // Tar headers take the name's raw bytes
+        let header_path = names::decode_path(&path, escaped);
+        if interop {
+            let modified = fs::metadata(&dir)
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let entry = UstarEntry {
+                kind: tar::EntryType::Directory,
+                path: &header_path,
+                link: None,
+                size: 0,
+                mode: 0o755,
+                mtime: modified,
+            };
+            append_ustar(&mut tar, entry, std::io::empty())?;
+            continue;
+        }
+        tar.append_dir(&header_path, &dir)?;
+        after_entry(&mut tar, &mut offsets, seekable, &path, 0)?;
+    }
+
+    // 6. Every relative path appears exactly once. The first entry recorded wins:
+    // walked files before symlinks, in pipeline order. Later duplicates are dropped.
+    let mut seen_paths = HashSet::new();
+    let recorded = manifest.entries.len();
+    manifest
+        .entries
+        .retain(|e| seen_paths.insert(e.path.clone()));
+    count -= recorded - manifest.entries.len();
+    if reproducible {
+        manifest.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        for entry in &mut manifest.entries {
+            entry.mode = normalized_mode(entry.mode);
+        }
+    }
+
+    if abs_skipped > 0 {
+        let msg = format!(
+            "Skipped {} file(s) under excluded absolute paths",
+            abs_skipped
+        );
+        match pb {
+            Some(ref p) => p.println(format!("⚠️ {}", msg)),
+            None => eprintln!("{}", msg),
+        }
+        warnings.push(msg);
+    }
+
+    // The final tally is always reported, whatever the interval
+    if let Some(hook) = progress.as_mut()
+        && (reported != count || count == 0)
+    {
+        hook(SnapProgress {
+            files: count,
+            bytes: bytes_done,
+            dedup_saved_bytes,
+        })?;
     }
 
     if let Some(p) = pb {
         p.finish_with_message(format!(
-            "Packed {} files ({} cache hits, {} deduped).",
-            count, cache_hit_count, dedup_count
+            "Packed {} files ({} cache hits, {} deduped, {} saved).",
+            count,
+            cache_hit_count,
+            dedup_count,
+            indicatif::HumanBytes(dedup_saved_bytes)
         ));
     }
 
-    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    if manifest.entries.iter().any(|e| e.symlink.is_some()) {
+        manifest.features.push("symlinks".to_string());
+    }
+    if manifest.entries.iter().any(|e| e.escaped) {
+        manifest.features.push("escaped-names".to_string());
+    }
+    if let Some(base_files) = &base_files {
+        manifest.features.push("incremental".to_string());
+        manifest.deleted = base_files
+            .keys()
+            .filter(|path| !present.contains(*path))
+            .cloned()
+            .collect();
+        manifest.deleted.sort();
+    }
+    manifest.min_tool_version = min_version_for(&manifest.features);
+
+    // Interop entries are already where tar tools expect them
+    if !interop {
+        let manifest_bytes = manifest_format.encode(&manifest)?;
+        append_bytes(&mut tar, manifest_format.entry_name(), &manifest_bytes)?;
+        let manifest_len = manifest_bytes.len() as u64;
+        after_entry(
+            &mut tar,
+            &mut offsets,
+            seekable,
+            manifest_format.entry_name(),
+            manifest_len,
+        )?;
+    }
+
+    if metadata_position == MetadataPosition::Last {
+        meta.file_count = Some(count);
+        meta.features.extend(manifest.features.iter().cloned());
+        meta.min_tool_version = min_version_for(&meta.features);
+        let meta_json = serde_json::to_string_pretty(&meta)?;
+        append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+        after_entry(
+            &mut tar,
+            &mut offsets,
+            seekable,
+            ".vegh.json",
+            meta_json.len() as u64,
+        )?;
+    }
+
+    // The index is a regular entry; the trailer after the tar stream points at it
+    let index_at = match &offsets {
+        Some(index) => {
+            let index_bytes = serde_json::to_vec(index)?;
+            append_bytes(&mut tar, OFFSETS_ENTRY, &index_bytes)?;
+            let len = index_bytes.len() as u64;
+            let end = tar.get_ref().position();
+            Some((
+                end - len.div_ceil(512) * 512,
+                len,
+                index.frames[index.frames.len() - 1],
+            ))
+        }
+        None => None,
+    };
+
+    if !no_cache {
+        for mut cache in caches {
+            let _ = cache.garbage_collect(CACHE_RETENTION_SEC);
+            let _ = cache.commit();
+        }
+    }
+
+    let encoder = tar.into_inner()?;
+    let bytes_in = encoder.position();
+    let finishing = Instant::now();
+    let mut compress = encoder.busy();
+    let mut sink = encoder.finish()?;
+    compress += finishing.elapsed();
+    if let Some((offset, len, frame)) = index_at {
+        write_trailer(&mut sink, offset, len, frame)?;
+    }
+    let written = sink.finish()?;
+
+    Ok(SnapSummary {
+        count,
+        abs_skipped,
+        timed_out,
+        warnings,
+        digest: None,
+        profile: SnapProfile {
+            walk: walk_time,
+            hash: Duration::from_nanos(worker_nanos.load(Ordering::Relaxed)),
+            read: read_time,
+            compress,
+            total: started.elapsed(),
+            bytes_in,
+            bytes_out: written.count(),
+        },
+    })
+}
+
+/// Bookkeeping after each appended entry: records where it landed when an
+/// index is being built, and in seekable mode starts a new zstd frame once
+/// the current one holds `SEEKABLE_FRAME_SIZE` bytes.
+fn after_entry<W: Write>(
+    tar: &mut tar::Builder<CodecWriter<W>>,
+    offsets: &mut Option<OffsetIndex>,
+    seekable: bool,
+    path: &str,
+    size: u64,
+) -> Result<()> {
+    if let Some(index) = offsets.as_mut() {
+        index.record(path, size, tar.get_ref().position());
+    }
+    if seekable && tar.get_ref().frame_len() >= SEEKABLE_FRAME_SIZE {
+        let frame = tar.get_mut().end_frame()?;
+        if let Some(index) = offsets.as_mut() {
+            index.frames.push(frame);
+        }
+    }
+    Ok(())
+}
+
+/// Manifest entries of a full V3 snapshot, keyed by path, for comparing an
+/// incremental snapshot against it.
+/// A base compressed with a dictionary is expected to share the new one's.
+fn base_entries(base: &Path, dictionary: Option<&[u8]>) -> Result<HashMap<String, ManifestEntry>> {
+    let file = open_snapshot(base).with_context(|| format!("Open failed: {}", base.display()))?;
+    let mut archive = tar::Archive::new(open_decoder_using(file, None, dictionary)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            if manifest.features.iter().any(|f| f == "incremental") {
+                bail!(
+                    "Base snapshot {} is itself incremental; use a full snapshot as the base",
+                    base.display()
+                );
+            }
+            return Ok(manifest
+                .entries
+                .into_iter()
+                .map(|e| (e.path.clone(), e))
+                .collect());
+        }
+    }
+    bail!(
+        "Base snapshot {} has no manifest; run backfill_manifest on it first",
+        base.display()
+    )
+}
+
+/// Keeps the file type and whether anyone may execute it; drops the rest
+/// (umask, group/other bits) so the mode does not depend on the machine.
+fn normalized_mode(mode: u32) -> u32 {
+    let perms = if mode & 0o111 != 0 { 0o755 } else { 0o644 };
+    (mode & !0o7777) | perms
+}
+
+/// Appends an in-memory entry (metadata, manifest, blobs) with the standard header.
+fn append_bytes<W: Write>(tar: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<()> {
     let mut header = tar::Header::new_gnu();
-    header.set_path("manifest.json")?;
-    header.set_size(manifest_json.len() as u64);
+    header.set_size(data.len() as u64);
     header.set_mode(0o644);
     header.set_cksum();
-    tar.append_data(&mut header, "manifest.json", manifest_json.as_bytes())?;
+    tar.append_data(&mut header, path, data)?;
+    Ok(())
+}
 
-    if !no_cache {
-        let _ = cache_db.garbage_collect(CACHE_RETENTION_SEC);
-        let _ = cache_db.commit();
+/// PAX global header record that carries an interop snapshot's metadata.
+/// `comment` is standard and ignored by tar tools, so it extracts silently.
+const PAX_METADATA_KEY: &str = "comment";
+
+/// One `"<len> <key>=<value>\n"` PAX record; the length counts itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let body = key.len() + value.len() + 3;
+    let mut len = body + 1;
+    while len != body + len.to_string().len() {
+        len = body + len.to_string().len();
     }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
 
-    let zstd_encoder = tar.into_inner()?;
-    zstd_encoder.finish()?;
+/// Appends a PAX extended header: `XHeader` for the next entry,
+/// `XGlobalHeader` for the whole archive.
+fn append_pax<W: Write>(
+    tar: &mut tar::Builder<W>,
+    kind: tar::EntryType,
+    name: &str,
+    records: &[(&str, &[u8])],
+) -> Result<()> {
+    let data: Vec<u8> = records
+        .iter()
+        .flat_map(|(key, value)| pax_record(key, value))
+        .collect();
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(kind);
+    header.set_path(name)?;
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append(&header, &data[..])?;
+    Ok(())
+}
 
-    Ok(count)
+/// The end of `path` that fits ustar's 100-byte name field. PAX readers take
+/// the full path from the record written ahead of it.
+fn ustar_tail(path: &[u8]) -> &[u8] {
+    let mut start = path.len().saturating_sub(100);
+    // Skip UTF-8 continuation bytes rather than split a character
+    while path.get(start).is_some_and(|b| b & 0xC0 == 0x80) {
+        start += 1;
+    }
+    let tail = &path[start..];
+    &tail[tail.iter().take_while(|&&b| b == b'/').count()..]
 }
 
-pub fn restore_snap_logic(
-    input: &Path,
-    out_dir: &Path,
-    include: Option<Vec<String>>,
-    flatten: bool,
+/// Attributes of an entry in an interop snapshot.
+struct UstarEntry<'a> {
+    kind: tar::EntryType,
+    path: &'a Path,
+    link: Option<&'a str>,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+}
+
+/// Appends `entry` with a plain ustar header, preceded by a PAX header when
+/// its path or link target does not fit ustar's fields.
+fn append_ustar<W: Write>(
+    tar: &mut tar::Builder<W>,
+    entry: UstarEntry<'_>,
+    data: impl Read,
 ) -> Result<()> {
-    if !out_dir.exists() {
-        fs::create_dir_all(out_dir)?;
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(entry.kind);
+    header.set_size(entry.size);
+    header.set_mode(entry.mode & 0o7777);
+    header.set_mtime(entry.mtime);
+    let mut records: Vec<(&str, &[u8])> = Vec::new();
+    let path = names::path_bytes(entry.path);
+    if header.set_path(entry.path).is_err() {
+        records.push(("path", &path));
+        header.set_path(names::bytes_path(ustar_tail(&path).to_vec()))?;
+    }
+    if let Some(link) = entry.link
+        && header.set_link_name(link).is_err()
+    {
+        records.push(("linkpath", link.as_bytes()));
+        header.set_link_name(names::bytes_path(ustar_tail(link.as_bytes()).to_vec()))?;
+    }
+    if !records.is_empty() {
+        append_pax(tar, tar::EntryType::XHeader, "PaxHeader", &records)?;
     }
+    header.set_cksum();
+    tar.append(&header, data)?;
+    Ok(())
+}
 
-    let file = File::open(input).context("Open failed")?;
-    let decoder = zstd::stream::read::Decoder::new(file)?;
-    let mut archive = tar::Archive::new(decoder);
+/// Rejects archive paths that are empty, absolute or climb out with `..`.
+pub fn validate_relative_path(path: &str) -> Result<()> {
+    let p = Path::new(path);
+    let safe = !path.is_empty()
+        && !path.starts_with('/')
+        && !path.starts_with('\\')
+        && p.components().all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        bail!("Unsafe archive path '{}'", path);
+    }
+    Ok(())
+}
 
-    archive.unpack(out_dir)?;
+// --- Archive Editing ---
 
-    let manifest_path = out_dir.join("manifest.json");
-    if !manifest_path.exists() {
-        return Ok(());
+/// Repacks `input` into `output` with entries renamed per `renames`
+/// (old relative path -> new relative path). Returns how many were renamed.
+pub fn rename_entries_logic(
+    input: &Path,
+    output: &Path,
+    renames: &HashMap<String, String>,
+    level: i32,
+) -> Result<usize> {
+    for target in renames.values() {
+        validate_relative_path(target)?;
     }
 
-    let manifest_file = File::open(&manifest_path)?;
-    let manifest: SnapshotManifest = serde_json::from_reader(manifest_file)?;
-    let blobs_dir = out_dir.join("blobs");
+    let result = (|| -> Result<usize> {
+        let file = open_snapshot(input).context("Open failed")?;
+        let decoder = open_decoder(file)?;
+        let mut archive = tar::Archive::new(decoder);
 
-    for entry in manifest.entries {
-        if let Some(ref patterns) = include {
-            let mut matched = false;
-            for p in patterns {
-                if entry.path.starts_with(p) {
-                    matched = true;
-                    break;
+        let out = File::create(output).context("Output file creation failed")?;
+        let mut tar = tar::Builder::new(zstd::stream::write::Encoder::new(out, level)?);
+
+        let mut seen = HashSet::new();
+        let mut applied: HashSet<String> = HashSet::new();
+        let mut claim = |name: &str| -> Result<()> {
+            if !seen.insert(name.to_string()) {
+                bail!(
+                    "Rename collision: more than one entry would be named '{}'",
+                    name
+                );
+            }
+            Ok(())
+        };
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let mut header = entry.header().clone();
+
+            if let Some(format) = ManifestFormat::from_entry_path(&path) {
+                let mut manifest = format.decode(&mut entry)?;
+                check_requirements(manifest.min_tool_version.as_deref(), &manifest.features)?;
+                for m in manifest.entries.iter_mut() {
+                    if let Some(new_path) = renames.get(&m.path) {
+                        applied.insert(std::mem::replace(&mut m.path, new_path.clone()));
+                    }
+                    claim(&m.path)?;
                 }
+                append_bytes(&mut tar, &path, &format.encode(&manifest)?)?;
+            } else if path == ".vegh.json" || path.starts_with("blobs/") {
+                tar.append_data(&mut header, &path, &mut entry)?;
+            } else if path == OFFSETS_ENTRY {
+                // Offsets no longer hold once entries are rewritten
+                continue;
+            } else {
+                // V2 layout: the tar path is the file path
+                let name = match renames.get(&path) {
+                    Some(new_path) => {
+                        applied.insert(path);
+                        new_path.clone()
+                    }
+                    None => path,
+                };
+                claim(&name)?;
+                tar.append_data(&mut header, &name, &mut entry)?;
+            }
+        }
+
+        let mut missing: Vec<_> = renames.keys().filter(|k| !applied.contains(*k)).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            let names: Vec<_> = missing.into_iter().map(String::as_str).collect();
+            bail!("Entries not found in snapshot: {}", names.join(", "));
+        }
+
+        tar.into_inner()?.finish()?;
+        Ok(applied.len())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// Upgrades a V2 (manifest-less) snapshot to the V3 layout: every file is hashed
+/// into a blob and a manifest is written. Returns the number of files hashed.
+pub fn backfill_manifest_logic(input: &Path, output: &Path, level: i32) -> Result<usize> {
+    let result = (|| -> Result<usize> {
+        let file = open_snapshot(input).context("Open failed")?;
+        let decoder = open_decoder(file)?;
+        let mut archive = tar::Archive::new(decoder);
+
+        let out = File::create(output).context("Output file creation failed")?;
+        let mut tar = tar::Builder::new(zstd::stream::write::Encoder::new(out, level)?);
+
+        let mut written = HashSet::new();
+        let mut manifest = SnapshotManifest::default();
+        let mut hashed = 0;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+
+            if ManifestFormat::from_entry_path(&path).is_some() || path.starts_with("blobs/") {
+                bail!("'{}' already has a manifest", input.display());
             }
-            if !matched {
+            if path == ".vegh.json" {
+                // Keep unknown fields; only the layout version changes
+                let mut meta: serde_json::Value =
+                    serde_json::from_slice(&metadata_json(&mut entry)?)?;
+                meta["format_version"] = SNAPSHOT_FORMAT_VERSION.into();
+                append_bytes(&mut tar, ".vegh.json", &serde_json::to_vec_pretty(&meta)?)?;
                 continue;
             }
+
+            let header = entry.header().clone();
+            let modified = header.mtime().unwrap_or(0);
+            let mode = header.mode().unwrap_or(0o644);
+            match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mut data = Vec::with_capacity(entry.size() as usize);
+                    entry.read_to_end(&mut data)?;
+                    let hash = blake3::hash(&data).to_hex().to_string();
+                    if written.insert(hash.clone()) {
+                        append_bytes(&mut tar, &format!("blobs/{}", hash), &data)?;
+                    }
+                    manifest.entries.push(ManifestEntry {
+                        path,
+                        hash,
+                        size: data.len() as u64,
+                        modified,
+                        mode,
+                        ..Default::default()
+                    });
+                    hashed += 1;
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .map(|t| t.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    manifest.entries.push(ManifestEntry {
+                        path,
+                        modified,
+                        mode,
+                        symlink: Some(target),
+                        ..Default::default()
+                    });
+                }
+                // Directories are implied by file paths
+                _ => {}
+            }
         }
 
-        let dest_path = if flatten {
-            out_dir.join(Path::new(&entry.path).file_name().unwrap())
-        } else {
-            out_dir.join(&entry.path)
-        };
+        if manifest.entries.iter().any(|e| e.symlink.is_some()) {
+            manifest.features.push("symlinks".to_string());
+        }
+        manifest.min_tool_version = min_version_for(&manifest.features);
+        let manifest_bytes = ManifestFormat::Json.encode(&manifest)?;
+        append_bytes(&mut tar, ManifestFormat::Json.entry_name(), &manifest_bytes)?;
 
-        if let Some(parent) = dest_path.parent() {
-            fs::create_dir_all(parent)?;
+        tar.into_inner()?.finish()?;
+        Ok(hashed)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// Writes an in-memory `relative_path -> bytes` map as a regular V3 snapshot.
+/// Nothing is read from disk. Returns the number of files written.
+pub fn create_snap_from_map_logic(
+    files: &BTreeMap<String, Vec<u8>>,
+    output: &Path,
+    level: i32,
+    comment: Option<String>,
+) -> Result<usize> {
+    for path in files.keys() {
+        validate_relative_path(path)?;
+    }
+
+    let result = (|| -> Result<usize> {
+        let out = File::create(output).context("Output file creation failed")?;
+        let mut tar = tar::Builder::new(zstd::stream::write::Encoder::new(out, level)?);
+
+        let meta_json = serde_json::to_string_pretty(&new_metadata(comment))?;
+        append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+
+        let now = Utc::now().timestamp().max(0) as u64;
+        let mut written = HashSet::new();
+        let mut manifest = SnapshotManifest::default();
+        for (path, data) in files {
+            let hash = blake3::hash(data).to_hex().to_string();
+            if written.insert(hash.clone()) {
+                append_bytes(&mut tar, &format!("blobs/{}", hash), data)?;
+            }
+            manifest.entries.push(ManifestEntry {
+                path: path.clone(),
+                hash,
+                size: data.len() as u64,
+                modified: now,
+                mode: 0o100644,
+                ..Default::default()
+            });
+        }
+
+        let manifest_bytes = ManifestFormat::Json.encode(&manifest)?;
+        append_bytes(&mut tar, ManifestFormat::Json.entry_name(), &manifest_bytes)?;
+
+        tar.into_inner()?.finish()?;
+        Ok(manifest.entries.len())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// Appends the file at `path` as blobs the way `create_snap` stores files:
+/// CDC chunks averaging `chunk_avg_size` bytes, or one blob for the whole
+/// file without it. Blobs already in `written` are skipped. The file is
+/// streamed, never held whole. Returns its Blake3 hash, its chunk hashes
+/// when chunked, and the bytes it added to the archive.
+fn append_file_blobs<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: &Path,
+    chunk_avg_size: Option<usize>,
+    written: &mut HashSet<String>,
+) -> Result<(String, Option<Vec<String>>, u64)> {
+    let mut stored = 0;
+    let Some(avg_size) = chunk_avg_size else {
+        let hash = hex::encode(compute_file_hash(path)?);
+        if written.insert(hash.clone()) {
+            let mut f = File::open(path)?;
+            stored = f.metadata()?.len();
+            tar.append_file(format!("blobs/{}", hash), &mut f)?;
+        }
+        return Ok((hash, None, stored));
+    };
+    let (hash, chunks) = compute_chunks(path, avg_size)?;
+    let mut f = File::open(path)?;
+    let mut hashes = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let chunk_hex = hex::encode(chunk.hash);
+        if written.insert(chunk_hex.clone()) {
+            f.seek(SeekFrom::Start(chunk.offset as u64))?;
+            let mut chunk_buf = vec![0u8; chunk.length];
+            f.read_exact(&mut chunk_buf)?;
+            append_bytes(tar, &format!("blobs/{}", chunk_hex), &chunk_buf)?;
+            stored += chunk.length as u64;
         }
+        hashes.push(chunk_hex);
+    }
+    Ok((hex::encode(hash), Some(hashes), stored))
+}
 
-        let mut dest_file = File::create(&dest_path)?;
-        let chunk_hashes = entry.chunks.unwrap_or_else(|| vec![entry.hash.clone()]);
+/// A V3 snapshot built one entry at a time, for callers that produce files as
+/// they go. Blobs are staged as entries are added; `finish` then writes
+/// `.vegh.json` (with the file count, as `create_snap` does), the blobs and the
+/// manifest. Dropped unfinished, the partial output is deleted.
+pub struct SnapWriter {
+    output: PathBuf,
+    /// `None` once finished or aborted.
+    out: Option<File>,
+    level: i32,
+    meta: VeghMetadata,
+    /// The blobs so far; dropped, with its staging file, once finished.
+    body: Option<DeferredEntries>,
+    written: HashSet<String>,
+    manifest: SnapshotManifest,
+    paths: HashSet<String>,
+}
 
-        for chunk_hash in chunk_hashes {
-            let blob_path = blobs_dir.join(&chunk_hash);
-            if blob_path.exists() {
-                let mut blob_file = File::open(&blob_path)?;
-                std::io::copy(&mut blob_file, &mut dest_file)?;
+impl SnapWriter {
+    pub fn create(output: &Path, level: i32, comment: Option<String>) -> Result<Self> {
+        Codec::Zstd.check_level(level)?;
+        let out = File::create(output).context("Output file creation failed")?;
+        let body = match DeferredEntries::new(output) {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = fs::remove_file(output);
+                return Err(e);
             }
+        };
+        Ok(Self {
+            output: output.to_path_buf(),
+            out: Some(out),
+            level,
+            meta: new_metadata(comment),
+            body: Some(body),
+            written: HashSet::new(),
+            manifest: SnapshotManifest::default(),
+            paths: HashSet::new(),
+        })
+    }
+
+    /// Adds `data` as the file at `path`, stamped with the current time.
+    pub fn add_bytes(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.claim(path)?;
+        let hash = blake3::hash(data).to_hex().to_string();
+        if self.written.insert(hash.clone()) {
+            append_bytes(self.body()?, &format!("blobs/{}", hash), data)?;
         }
+        self.manifest.entries.push(ManifestEntry {
+            path: path.to_string(),
+            hash,
+            size: data.len() as u64,
+            modified: Utc::now().timestamp().max(0) as u64,
+            mode: 0o100644,
+            ..Default::default()
+        });
+        Ok(())
+    }
 
+    /// Adds the file at `fs_path` under `path`, keeping its mtime and mode.
+    /// The file is streamed in, chunked like `create_snap` when large.
+    pub fn add_file(&mut self, path: &str, fs_path: &Path) -> Result<()> {
+        let metadata =
+            fs::metadata(fs_path).with_context(|| format!("Read failed: {}", fs_path.display()))?;
+        if !metadata.is_file() {
+            bail!("'{}' is not a regular file", fs_path.display());
+        }
+        self.claim(path)?;
+        let modified = metadata
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
         #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let permissions = fs::Permissions::from_mode(entry.mode);
-            fs::set_permissions(&dest_path, permissions)?;
+        let mode = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.mode()
+        };
+        #[cfg(not(unix))]
+        let mode = 0o100644;
+        let chunking = (metadata.len() > CDC_AVG_SIZE as u64).then_some(CDC_AVG_SIZE);
+        let body = self
+            .body
+            .as_mut()
+            .context("Snapshot writer is already finished")?;
+        let (hash, chunks, _) = append_file_blobs(body.tar(), fs_path, chunking, &mut self.written)
+            .with_context(|| format!("Read failed: {}", fs_path.display()))
+            .inspect_err(|_| {
+                self.paths.remove(path);
+            })?;
+        self.manifest.entries.push(ManifestEntry {
+            path: path.to_string(),
+            hash,
+            size: metadata.len(),
+            modified,
+            mode,
+            chunks,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    fn body(&mut self) -> Result<&mut tar::Builder<std::io::BufWriter<File>>> {
+        Ok(self
+            .body
+            .as_mut()
+            .context("Snapshot writer is already finished")?
+            .tar())
+    }
+
+    /// Checks `path` can still be added and reserves it.
+    fn claim(&mut self, path: &str) -> Result<()> {
+        validate_relative_path(path)?;
+        if self.out.is_none() {
+            bail!("Snapshot writer is already finished");
         }
+        if !self.paths.insert(path.to_string()) {
+            bail!("'{}' was already added", path);
+        }
+        Ok(())
     }
 
-    let _ = fs::remove_file(manifest_path);
-    let _ = fs::remove_dir_all(blobs_dir);
-    let _ = fs::remove_file(out_dir.join(".vegh.json"));
+    /// Writes the metadata, the staged blobs and the manifest, and closes the
+    /// stream. Returns the number of files.
+    pub fn finish(&mut self) -> Result<usize> {
+        let out = self
+            .out
+            .take()
+            .context("Snapshot writer is already finished")?;
+        let mut body = self
+            .body
+            .take()
+            .context("Snapshot writer is already finished")?;
+        self.manifest.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.meta.file_count = Some(self.manifest.entries.len());
+        let result = (|| -> Result<()> {
+            let mut tar = tar::Builder::new(zstd::stream::write::Encoder::new(out, self.level)?);
+            let meta_json = serde_json::to_string_pretty(&self.meta)?;
+            append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+            body.splice_into(&mut tar)?;
+            let manifest_bytes = ManifestFormat::Json.encode(&self.manifest)?;
+            append_bytes(&mut tar, ManifestFormat::Json.entry_name(), &manifest_bytes)?;
+            tar.into_inner()?.finish()?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&self.output);
+        }
+        result.map(|()| self.manifest.entries.len())
+    }
 
-    Ok(())
+    pub fn is_finished(&self) -> bool {
+        self.out.is_none()
+    }
+
+    /// Stops without finishing and deletes the partial output.
+    pub fn abort(&mut self) {
+        self.body = None;
+        if self.out.take().is_some() {
+            let _ = fs::remove_file(&self.output);
+        }
+    }
+}
+
+impl Drop for SnapWriter {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Blob entries staged as a plain tar next to `output`, for writers that only
+/// know what `.vegh.json` says once every blob went by. `splice_into` copies
+/// them into the real archive after the metadata, so it still comes first and
+/// metadata reads stop at the first entry. The file is removed on drop.
+struct DeferredEntries {
+    path: PathBuf,
+    tar: Option<tar::Builder<std::io::BufWriter<File>>>,
+}
+
+impl DeferredEntries {
+    fn new(output: &Path) -> Result<Self> {
+        let path = PathBuf::from(format!("{}.entries", output.display()));
+        let file = File::create(&path).context("Staging file creation failed")?;
+        Ok(Self {
+            path,
+            tar: Some(tar::Builder::new(std::io::BufWriter::new(file))),
+        })
+    }
+
+    fn tar(&mut self) -> &mut tar::Builder<std::io::BufWriter<File>> {
+        self.tar.as_mut().expect("entries already spliced")
+    }
+
+    fn splice_into<W: Write>(&mut self, out: &mut tar::Builder<W>) -> Result<()> {
+        let staged = self.tar.take().expect("entries already spliced");
+        let file = staged
+            .into_inner()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        // Everything but the two zero blocks that end a tar archive
+        let len = file.metadata()?.len().saturating_sub(1024);
+        drop(file);
+        std::io::copy(&mut File::open(&self.path)?.take(len), out.get_mut())?;
+        Ok(())
+    }
+}
+
+impl Drop for DeferredEntries {
+    fn drop(&mut self) {
+        self.tar = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Combines several snapshots into one V3 snapshot at `output` without
+/// extracting them. Blobs are copied across (once each, so identical content
+/// is shared); V2 inputs have their files turned into blobs. With
+/// `prefix_with_source` every path is nested under its input's file stem
+/// (`api.vegh` -> `api/...`); otherwise a path present in two inputs is an
+/// error. Incremental inputs are refused, since their base's files would be
+/// missing from the result. `password` opens encrypted inputs and encrypts
+/// the output; `dictionary` opens inputs compressed with one. Returns the
+/// number of entries written.
+pub fn merge_snaps_logic(
+    inputs: &[PathBuf],
+    output: &Path,
+    prefix_with_source: bool,
+    level: i32,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<usize> {
+    if inputs.is_empty() {
+        bail!("merge_snaps needs at least one input");
+    }
+
+    let result = (|| -> Result<usize> {
+        let mut body = DeferredEntries::new(output)?;
+        let mut written = HashSet::new();
+        let mut manifest = SnapshotManifest::default();
+        // Merged path -> input it came from
+        let mut owners: HashMap<String, String> = HashMap::new();
+        // Directory entries already copied; several inputs may share one
+        let mut dirs = HashSet::new();
+
+        for input in inputs {
+            let source_name = input.display().to_string();
+            let prefix = if prefix_with_source {
+                let stem = input
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .with_context(|| format!("No file name to prefix with: {}", source_name))?;
+                Some(stem.to_string())
+            } else {
+                None
+            };
+            let prefixed = |path: &str, escaped: bool| match &prefix {
+                Some(prefix) if escaped => format!("{}/{}", names::escape(prefix), path),
+                Some(prefix) => format!("{}/{}", prefix, path),
+                None => path.to_string(),
+            };
+            let mut entries = Vec::new();
+
+            let file =
+                open_snapshot(input).with_context(|| format!("Open failed: {}", source_name))?;
+            let mut archive = tar::Archive::new(open_decoder_using(file, password, dictionary)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let (path, escaped) = names::encode(&entry.path_bytes());
+                let kind = entry.header().entry_type();
+
+                if path == ".vegh.json" {
+                    if let Ok(meta) =
+                        serde_json::from_slice::<VeghMetadata>(&metadata_json(&mut entry)?)
+                    {
+                        check_requirements(meta.min_tool_version.as_deref(), &meta.features)?;
+                    }
+                } else if let Some(format) = ManifestFormat::from_entry_path(&path) {
+                    let input_manifest = format.decode(&mut entry)?;
+                    check_requirements(
+                        input_manifest.min_tool_version.as_deref(),
+                        &input_manifest.features,
+                    )?;
+                    if input_manifest.features.iter().any(|f| f == "incremental") {
+                        bail!(
+                            "{} is incremental and lacks its base's files; merge a full snapshot instead",
+                            source_name
+                        );
+                    }
+                    entries.extend(input_manifest.entries);
+                } else if let Some(hash) = path.strip_prefix("blobs/") {
+                    if written.insert(hash.to_string()) {
+                        let mut header = entry.header().clone();
+                        body.tar().append_data(&mut header, &path, &mut entry)?;
+                    }
+                } else if path == OFFSETS_ENTRY {
+                    // Offsets describe the input's stream, not the merged one
+                } else if kind.is_dir() {
+                    // V2 directories and preserved empty directories
+                    let dir = prefixed(&path, escaped);
+                    if dirs.insert(dir.clone()) {
+                        let mut header = entry.header().clone();
+                        body.tar().append_data(
+                            &mut header,
+                            names::decode_path(&dir, escaped),
+                            std::io::empty(),
+                        )?;
+                    }
+                } else if kind == tar::EntryType::Symlink {
+                    // V2 symlink: a manifest entry in the merged snapshot
+                    let target = entry
+                        .link_name()?
+                        .map(|t| t.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    entries.push(ManifestEntry {
+                        path,
+                        modified: entry.header().mtime().unwrap_or(0),
+                        mode: entry.header().mode().unwrap_or(0o777),
+                        symlink: Some(target),
+                        escaped,
+                        ..Default::default()
+                    });
+                } else if kind.is_file() {
+                    // V2 layout: the file itself, which becomes a blob here
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    let hash = blake3::hash(&data).to_hex().to_string();
+                    if written.insert(hash.clone()) {
+                        append_bytes(body.tar(), &format!("blobs/{}", hash), &data)?;
+                    }
+                    entries.push(ManifestEntry {
+                        path,
+                        hash,
+                        size: data.len() as u64,
+                        modified: entry.header().mtime().unwrap_or(0),
+                        mode: entry.header().mode().unwrap_or(0o644),
+                        escaped,
+                        ..Default::default()
+                    });
+                }
+            }
+
+            for mut entry in entries {
+                entry.path = prefixed(&entry.path, entry.escaped);
+                if let Some(first) = owners.insert(entry.path.clone(), source_name.clone()) {
+                    bail!(
+                        "'{}' is in both {} and {}; pass prefix_with_source=True to keep both",
+                        entry.path,
+                        first,
+                        source_name
+                    );
+                }
+                manifest.entries.push(entry);
+            }
+        }
+
+        // Re-derived from the merged entries; the output is always JSON
+        if manifest.entries.iter().any(|e| e.symlink.is_some()) {
+            manifest.features.push("symlinks".to_string());
+        }
+        if manifest.entries.iter().any(|e| e.escaped) {
+            manifest.features.push("escaped-names".to_string());
+        }
+        manifest.min_tool_version = min_version_for(&manifest.features);
+
+        // The combined file count and features are only known now
+        let names: Vec<String> = inputs
+            .iter()
+            .map(|p| {
+                p.file_name()
+                    .unwrap_or(p.as_os_str())
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        let mut meta = new_metadata(Some(format!("Merged from {}", names.join(", "))));
+        meta.file_count = Some(manifest.entries.len());
+        meta.features = manifest.features.clone();
+        meta.min_tool_version = manifest.min_tool_version.clone();
+        let meta_json = serde_json::to_string_pretty(&meta)?;
+
+        let out = File::create(output).context("Output file creation failed")?;
+        let sink = SnapshotSink::new(out, password)?;
+        let mut tar = tar::Builder::new(CodecWriter::new(sink, Codec::Zstd, level, 0)?);
+        append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+        body.splice_into(&mut tar)?;
+        let manifest_bytes = ManifestFormat::Json.encode(&manifest)?;
+        append_bytes(&mut tar, ManifestFormat::Json.entry_name(), &manifest_bytes)?;
+
+        tar.into_inner()?.finish()?.finish()?;
+        Ok(manifest.entries.len())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// Rewrites `input` in place with the files at `changed` (relative paths)
+/// taken from `source` instead: changed files replace their old entries, new
+/// ones are added, and paths no longer in `source` are dropped. Everything
+/// else, blobs included, is streamed across without being unpacked; changed
+/// files are chunked and streamed as `create_snap` stores them. The new
+/// snapshot is V3 whatever `input` was, keeps its codec, and an encrypted
+/// one is sealed again with `password`. A snapshot that needs a zstd `dictionary` is
+/// rewritten with it. Returns the number of entries written.
+pub fn update_snap_logic(
+    input: &Path,
+    source: &Path,
+    changed: &[String],
+    level: i32,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<usize> {
+    for path in changed {
+        validate_relative_path(path)?;
+    }
+    if matches!(open_snapshot(input), Ok(SnapshotFile::Volumes(_))) {
+        bail!("Split snapshots cannot be updated in place");
+    }
+    let parent = hex::encode(
+        compute_file_hash(input)
+            .with_context(|| format!("Snapshot unreadable: {}", input.display()))?,
+    );
+    let changed_set: HashSet<&str> = changed.iter().map(String::as_str).collect();
+
+    // Pass 1: metadata and manifest, to know which blobs stay referenced
+    let mut old_meta = None;
+    let mut old_manifest = None;
+    let mut archive = tar::Archive::new(open_decoder_using(
+        open_snapshot(input)?,
+        password,
+        dictionary,
+    )?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if path == ".vegh.json" {
+            old_meta = serde_json::from_slice::<VeghMetadata>(&metadata_json(&mut entry)?).ok();
+        } else if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            check_requirements(manifest.min_tool_version.as_deref(), &manifest.features)?;
+            old_manifest = Some((format, manifest));
+        }
+    }
+    if let Some(meta) = &old_meta {
+        check_requirements(meta.min_tool_version.as_deref(), &meta.features)?;
+    }
+    // Snapshots from before `codec` was recorded are zstd
+    let codec = match old_meta.as_ref().and_then(|m| m.codec.as_deref()) {
+        Some(name) => Codec::parse(name)?,
+        None => Codec::Zstd,
+    };
+    codec.check_level(level)?;
+    // Only an encrypted input is sealed again
+    let mut magic = [0u8; ENCRYPTION_MAGIC.len()];
+    let encrypted =
+        open_snapshot(input)?.read_exact(&mut magic).is_ok() && &magic == ENCRYPTION_MAGIC;
+    let password = password.filter(|_| encrypted);
+    let (format, mut manifest) = old_manifest.unwrap_or((ManifestFormat::Json, Default::default()));
+    manifest
+        .entries
+        .retain(|e| !changed_set.contains(e.path.as_str()));
+    let mut kept_blobs: HashSet<String> = HashSet::new();
+    for entry in &manifest.entries {
+        match &entry.chunks {
+            Some(chunks) => kept_blobs.extend(chunks.iter().cloned()),
+            None => {
+                kept_blobs.insert(entry.hash.clone());
+            }
+        }
+    }
+
+    let tmp = PathBuf::from(format!("{}.updating", input.display()));
+    let result = (|| -> Result<usize> {
+        let mut body = DeferredEntries::new(&tmp)?;
+        let mut written = HashSet::new();
+
+        // Pass 2: copy what is kept; V2 files become blobs on the way
+        let mut archive = tar::Archive::new(open_decoder_using(
+            open_snapshot(input)?,
+            password,
+            dictionary,
+        )?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let (path, escaped) = names::encode(&entry.path_bytes());
+            let kind = entry.header().entry_type();
+            if let Some(hash) = path.strip_prefix("blobs/") {
+                if kept_blobs.contains(hash) && written.insert(hash.to_string()) {
+                    let mut header = entry.header().clone();
+                    body.tar().append_data(&mut header, &path, &mut entry)?;
+                }
+            } else if path == ".vegh.json"
+                || path == OFFSETS_ENTRY
+                || ManifestFormat::from_entry_path(&path).is_some()
+                || changed_set.contains(path.as_str())
+            {
+                continue;
+            } else if kind.is_dir() {
+                // V2 directories and preserved empty directories
+                let mut header = entry.header().clone();
+                body.tar().append_data(
+                    &mut header,
+                    names::decode_path(&path, escaped),
+                    std::io::empty(),
+                )?;
+            } else if kind.is_file() {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                let hash = blake3::hash(&data).to_hex().to_string();
+                if written.insert(hash.clone()) {
+                    append_bytes(body.tar(), &format!("blobs/{}", hash), &data)?;
+                }
+                manifest.entries.push(ManifestEntry {
+                    path,
+                    hash,
+                    size: data.len() as u64,
+                    modified: entry.header().mtime().unwrap_or(0),
+                    mode: entry.header().mode().unwrap_or(0o644),
+                    escaped,
+                    ..Default::default()
+                });
+            } else if kind == tar::EntryType::Symlink {
+                let target = entry
+                    .link_name()?
+                    .map(|t| t.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                manifest.entries.push(ManifestEntry {
+                    path,
+                    modified: entry.header().mtime().unwrap_or(0),
+                    mode: entry.header().mode().unwrap_or(0o777),
+                    symlink: Some(target),
+                    escaped,
+                    ..Default::default()
+                });
+            }
+        }
+
+        // Changed paths, read fresh from the source tree
+        let source_abs = fs::canonicalize(source)
+            .with_context(|| format!("Source unreadable: {}", source.display()))?;
+        for rel in changed {
+            let full = source.join(rel);
+            let Ok(metadata) = fs::symlink_metadata(&full) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                bail!("'{}' is a directory; list the files inside it instead", rel);
+            }
+            let modified = metadata
+                .modified()
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            #[cfg(unix)]
+            let mode = {
+                use std::os::unix::fs::MetadataExt;
+                metadata.mode()
+            };
+            #[cfg(not(unix))]
+            let mode = 0o644;
+
+            if metadata.file_type().is_symlink() {
+                // create_snap's default policy: links leaving the tree are stripped
+                let Some(target) =
+                    resolve_symlink_target(&source_abs, &full, ExternalSymlinks::Strip)?
+                else {
+                    continue;
+                };
+                manifest.entries.push(ManifestEntry {
+                    path: rel.clone(),
+                    modified,
+                    mode,
+                    symlink: Some(target),
+                    ..Default::default()
+                });
+                continue;
+            }
+            let (hash, chunks, _) =
+                append_file_blobs(body.tar(), &full, Some(CDC_AVG_SIZE), &mut written)
+                    .with_context(|| format!("Read failed: {}", rel))?;
+            manifest.entries.push(ManifestEntry {
+                path: rel.clone(),
+                hash,
+                size: metadata.len(),
+                modified,
+                mode,
+                chunks,
+                ..Default::default()
+            });
+        }
+
+        manifest.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        if manifest.entries.iter().any(|e| e.symlink.is_some())
+            && !manifest.features.iter().any(|f| f == "symlinks")
+        {
+            manifest.features.push("symlinks".to_string());
+        }
+        if manifest.entries.iter().any(|e| e.escaped)
+            && !manifest.features.iter().any(|f| f == "escaped-names")
+        {
+            manifest.features.push("escaped-names".to_string());
+        }
+        manifest.min_tool_version = min_version_for(&manifest.features);
+
+        // The new file count is only known now
+        let mut meta = old_meta.unwrap_or_else(|| new_metadata(None));
+        let fresh = new_metadata(Some(format!(
+            "Updated from {} ({} path(s) changed)",
+            parent,
+            changed.len()
+        )));
+        meta.timestamp = fresh.timestamp;
+        meta.timestamp_human = fresh.timestamp_human;
+        meta.comment = fresh.comment;
+        meta.tool_version = fresh.tool_version;
+        meta.format_version = fresh.format_version;
+        meta.codec = Some(codec.name().to_string());
+        meta.seekable = false;
+        meta.file_count = Some(manifest.entries.len());
+        meta.features = manifest.features.clone();
+        meta.min_tool_version = manifest.min_tool_version.clone();
+        meta.parent = Some(parent.clone());
+        let meta_json = serde_json::to_string_pretty(&meta)?;
+
+        let out = File::create(&tmp).context("Output file creation failed")?;
+        let sink = SnapshotSink::new(out, password)?;
+        let dictionary = meta
+            .dictionary
+            .is_some()
+            .then(|| dictionary.map(<[u8]>::to_vec))
+            .flatten();
+        let codec_writer = CodecWriter::with_dictionary(sink, codec, level, 0, dictionary)?;
+        let mut tar = tar::Builder::new(codec_writer);
+        append_bytes(&mut tar, ".vegh.json", meta_json.as_bytes())?;
+        body.splice_into(&mut tar)?;
+        append_bytes(&mut tar, format.entry_name(), &format.encode(&manifest)?)?;
+
+        tar.into_inner()?.finish()?.finish()?;
+        fs::rename(&tmp, input).context("Replacing the snapshot failed")?;
+        Ok(manifest.entries.len())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// --- Symlink Helpers ---
+
+/// Collapses `.` and `..` without touching the filesystem (targets may not exist).
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Relative path from directory `from` to `to` (both absolute and normalized).
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut rel = PathBuf::new();
+    for _ in common..from.len() {
+        rel.push("..");
+    }
+    for comp in &to[common..] {
+        rel.push(comp.as_os_str());
+    }
+    if rel.as_os_str().is_empty() {
+        rel.push(".");
+    }
+    rel
+}
+
+/// Returns the target to record for `link`, rewritten relative to the link when it
+/// points inside `root_abs`. `None` means the link was stripped by policy.
+fn resolve_symlink_target(
+    root_abs: &Path,
+    link: &Path,
+    policy: ExternalSymlinks,
+) -> Result<Option<String>> {
+    let target = fs::read_link(link)?;
+    let parent = link.parent().unwrap_or(Path::new("."));
+    let parent_abs = fs::canonicalize(parent)?;
+    let resolved = normalize_lexically(&parent_abs.join(&target));
+
+    if resolved.starts_with(root_abs) {
+        let rel = relative_path(&parent_abs, &resolved);
+        return Ok(Some(rel.to_string_lossy().replace('\\', "/")));
+    }
+
+    match policy {
+        ExternalSymlinks::Strip => Ok(None),
+        ExternalSymlinks::Keep => Ok(Some(target.to_string_lossy().to_string())),
+        ExternalSymlinks::Error => bail!(
+            "Symlink '{}' points outside the source tree ({})",
+            link.display(),
+            target.display()
+        ),
+    }
+}
+
+// --- Restore Logic ---
+
+/// Returned when a caller-supplied cancel hook asked us to stop.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Returned when an archive entry would be written outside the output directory.
+#[derive(Debug)]
+pub struct UnsafeEntry(pub String);
+
+impl std::fmt::Display for UnsafeEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Entry '{}' would be restored outside the output directory",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnsafeEntry {}
+
+/// Returned when two entries differ only in case and so would land on the same
+/// file on case-insensitive filesystems (macOS, Windows).
+#[derive(Debug)]
+pub struct CaseCollision(pub String, pub String);
+
+impl std::fmt::Display for CaseCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Entries '{}' and '{}' collide on case-insensitive filesystems",
+            self.0, self.1
+        )
+    }
+}
+
+impl std::error::Error for CaseCollision {}
+
+/// Restore destinations seen so far, keyed by their lowercased form.
+#[derive(Default)]
+struct CaseFolds(HashMap<String, String>);
+
+impl CaseFolds {
+    fn check(&mut self, path: &str, flatten: bool) -> Result<()> {
+        let dest = restore_dest(Path::new(""), path, false, flatten);
+        let key = dest.to_string_lossy().to_lowercase();
+        match self.0.get(&key) {
+            Some(seen) if seen != path => Err(CaseCollision(seen.clone(), path.to_string()).into()),
+            Some(_) => Ok(()),
+            None => {
+                self.0.insert(key, path.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returned by `Overwrite::Never` restores that would replace existing files.
+#[derive(Debug)]
+pub struct RestoreConflict(pub Vec<String>);
+
+impl std::fmt::Display for RestoreConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const SHOWN: usize = 20;
+        write!(
+            f,
+            "Refusing to overwrite {} existing file(s): {}",
+            self.0.len(),
+            self.0[..self.0.len().min(SHOWN)].join(", ")
+        )?;
+        if self.0.len() > SHOWN {
+            write!(f, " and {} more", self.0.len() - SHOWN)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RestoreConflict {}
+
+/// What a restore does about files already present in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    Always,
+    /// Fail with `RestoreConflict` before writing anything.
+    Never,
+    /// Leave existing files alone and count them as skipped.
+    Skip,
+}
+
+impl Overwrite {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "skip" => Ok(Self::Skip),
+            other => bail!(
+                "Invalid overwrite '{}' (expected 'always', 'never' or 'skip')",
+                other
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Files left as they were under `Overwrite::Skip`.
+    pub skipped: usize,
+    /// Incremental deletions, relative to `out_dir`, whether or not they were
+    /// there to remove.
+    pub deleted: Vec<PathBuf>,
+}
+
+/// Polled between entries and blob chunks; returning `Ok(true)` aborts cleanly.
+pub type CancelHook<'a> = &'a mut dyn FnMut() -> Result<bool>;
+
+/// Asked `(path, size)` for every file before it is written; `Ok(false)`
+/// skips it and an error aborts the restore.
+pub type EntryFilter<'a> = &'a mut dyn FnMut(&str, u64) -> Result<bool>;
+
+pub struct RestoreOptions {
+    /// Glob patterns (`*.py`, `src/**/*.rs`) or plain path prefixes (`config/`).
+    pub include: Option<Vec<String>>,
+    pub flatten: bool,
+    /// Re-hash every reconstructed file against its manifest hash.
+    pub verify: bool,
+    /// Give restored files their recorded mtime instead of the current time.
+    pub preserve_mtime: bool,
+    /// Needed for snapshots created with a password.
+    pub password: Option<String>,
+    pub overwrite: Overwrite,
+    /// Restore entries that differ only in case instead of failing with
+    /// `CaseCollision`; one of each pair then wins on case-insensitive systems.
+    pub allow_case_collisions: bool,
+    /// Files between progress hook calls.
+    pub progress_interval: usize,
+    /// Give restored files their recorded permission bits; otherwise every
+    /// file gets `0o644`. No effect on Windows.
+    pub preserve_permissions: bool,
+    /// `out_dir` already holds the base of an incremental snapshot; without
+    /// it restoring one fails with `MissingBase`.
+    pub base_restored: bool,
+    /// Leading path components dropped from every entry (tar's
+    /// `--strip-components`); entries with nothing left are skipped.
+    pub strip_components: usize,
+    /// Relative directory every entry is nested under, after stripping.
+    pub add_prefix: Option<String>,
+    /// Fail with `InsufficientSpace` before writing anything when the target
+    /// filesystem cannot hold the restore. Costs an extra pass over the headers.
+    pub check_space: bool,
+    /// The zstd dictionary the snapshot was compressed with, if any.
+    pub dictionary: Option<Vec<u8>>,
+    /// With `include`, read the manifest in an extra pass first and stage only
+    /// the blobs the selected files use. `open` must work more than once.
+    pub selective_staging: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            include: None,
+            flatten: false,
+            verify: false,
+            preserve_mtime: true,
+            password: None,
+            overwrite: Overwrite::Always,
+            allow_case_collisions: false,
+            progress_interval: 50,
+            preserve_permissions: true,
+            base_restored: false,
+            strip_components: 0,
+            add_prefix: None,
+            check_space: false,
+            dictionary: None,
+            selective_staging: false,
+        }
+    }
+}
+
+/// Returned when an incremental snapshot is restored on its own. Holds the
+/// base's integrity hash, if the metadata had one.
+#[derive(Debug)]
+pub struct MissingBase(pub Option<String>);
+
+impl std::fmt::Display for MissingBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Snapshot is incremental")?;
+        if let Some(hash) = &self.0 {
+            write!(f, " on base {}", hash)?;
+        }
+        write!(f, "; restore it with its base (base=... or restore_chain)")
+    }
+}
+
+impl std::error::Error for MissingBase {}
+
+/// The filesystem a restore targets has less free space than it needs
+/// (`needed` includes the safety margin).
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Not enough free space to restore: about {} needed, {} available",
+            indicatif::HumanBytes(self.needed),
+            indicatif::HumanBytes(self.available)
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// Base integrity hash of an incremental snapshot, `None` for full ones.
+pub fn incremental_base(meta: &VeghMetadata) -> Option<String> {
+    meta.features
+        .iter()
+        .any(|f| f == "incremental")
+        .then(|| meta.base.clone().unwrap_or_default())
+}
+
+/// Sets the permission bits of a restored file (Unix only).
+fn apply_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Replaces whatever is at `dest` with a link to `target`. Windows has separate
+/// file and directory links, so there the target is resolved next to `dest`.
+fn restore_symlink(target: &str, dest: &Path) -> Result<()> {
+    if fs::symlink_metadata(dest).is_ok() {
+        fs::remove_file(dest)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, dest)?;
+    #[cfg(windows)]
+    {
+        let resolved = dest.parent().unwrap_or(Path::new("")).join(target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, dest)?;
+        } else {
+            std::os::windows::fs::symlink_file(target, dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Files and bytes restored so far, reported to the hook every `interval`
+/// files and once more by `finish`.
+struct RestoreTally {
+    interval: usize,
+    done: SnapProgress,
+    reported: usize,
+}
+
+impl RestoreTally {
+    fn add(&mut self, bytes: u64, hook: &mut Option<ProgressHook>) -> Result<()> {
+        self.done.files += 1;
+        self.done.bytes += bytes;
+        if let Some(hook) = hook.as_mut()
+            && self.done.files.is_multiple_of(self.interval.max(1))
+        {
+            hook(self.done)?;
+            self.reported = self.done.files;
+        }
+        Ok(())
+    }
+
+    fn finish(&self, hook: &mut Option<ProgressHook>) -> Result<()> {
+        if let Some(hook) = hook.as_mut()
+            && (self.reported != self.done.files || self.done.files == 0)
+        {
+            hook(self.done)?;
+        }
+        Ok(())
+    }
+}
+
+/// Private directory for blobs while the archive streams by; removed on drop.
+struct StagingDir(PathBuf);
+
+impl StagingDir {
+    fn new(out_dir: &Path) -> Result<Self> {
+        let path = out_dir.join(format!(".vegh-restore-{}", std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    /// A fresh directory under the system temp dir, for reads that write
+    /// nothing else. Several may be open at once.
+    fn temp() -> Result<Self> {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            ".vegh-scan-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Hands out the raw (still encoded) snapshot bytes, once per pass over them.
+pub type SnapshotOpener<'a> = &'a mut dyn FnMut() -> Result<Box<dyn Read>>;
+
+/// Archive paths a restore with `include` would write, read from the headers
+/// (and manifest) only, each with whether it is escaped.
+fn restore_targets(
+    open: SnapshotOpener,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+    include: &Option<IncludeFilter>,
+) -> Result<Vec<(String, bool)>> {
+    let mut archive = tar::Archive::new(open_decoder_using(open()?, password, dictionary)?);
+    let mut legacy = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, escaped) = names::encode(&entry.path_bytes());
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            return Ok(manifest
+                .entries
+                .into_iter()
+                .map(|e| (e.path, e.escaped))
+                .filter(|(p, _)| matches_include(p, include))
+                .collect());
+        }
+        let special = path == ".vegh.json" || path == OFFSETS_ENTRY || path.starts_with("blobs/");
+        if !special && !entry.header().entry_type().is_dir() && matches_include(&path, include) {
+            legacy.push((path, escaped));
+        }
+    }
+    Ok(legacy)
+}
+
+/// Blobs a manifest entry is rebuilt from: its chunks, or its whole-file blob.
+fn blob_refs(entry: &ManifestEntry) -> Vec<&str> {
+    match &entry.chunks {
+        Some(chunks) => chunks.iter().map(String::as_str).collect(),
+        None => vec![entry.hash.as_str()],
+    }
+}
+
+/// The blobs the files matching `include` are rebuilt from, read from the
+/// manifest; `None` for snapshots without one.
+fn needed_blobs(
+    open: SnapshotOpener,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+    include: &Option<IncludeFilter>,
+) -> Result<Option<HashSet<String>>> {
+    let mut archive = tar::Archive::new(open_decoder_using(open()?, password, dictionary)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, _) = names::encode(&entry.path_bytes());
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            return Ok(Some(
+                manifest
+                    .entries
+                    .iter()
+                    .filter(|e| e.symlink.is_none() && matches_include(&e.path, include))
+                    .flat_map(blob_refs)
+                    .map(str::to_string)
+                    .collect(),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+// Headroom on top of a restore's own size: filesystem overhead, and whatever
+// else writes to the disk meanwhile
+const SPACE_MARGIN_PERCENT: u64 = 5;
+const SPACE_MARGIN_MIN: u64 = 16 * 1024 * 1024;
+
+/// Bytes a restore with `include` writes at its peak: the selected files plus,
+/// for V3 snapshots, the blobs staged until the files are rebuilt (only the
+/// ones they use when `selective`). Read from the headers and manifest only;
+/// entry bodies are skipped, never unpacked.
+fn restore_footprint(
+    open: SnapshotOpener,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+    include: &Option<IncludeFilter>,
+    selective: bool,
+) -> Result<u64> {
+    let mut archive = tar::Archive::new(open_decoder_using(open()?, password, dictionary)?);
+    let mut blobs = HashMap::new();
+    let mut legacy = 0u64;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, _) = names::encode(&entry.path_bytes());
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            let selected: Vec<_> = manifest
+                .entries
+                .iter()
+                .filter(|e| e.symlink.is_none() && matches_include(&e.path, include))
+                .collect();
+            let files = selected
+                .iter()
+                .fold(0u64, |sum, e| sum.saturating_add(e.size));
+            let staged = if selective {
+                let used: HashSet<&str> = selected.iter().flat_map(|e| blob_refs(e)).collect();
+                used.iter()
+                    .filter_map(|hash| blobs.get(*hash))
+                    .fold(0u64, |sum: u64, size| sum.saturating_add(*size))
+            } else {
+                blobs
+                    .values()
+                    .fold(0u64, |sum: u64, size| sum.saturating_add(*size))
+            };
+            return Ok(staged.saturating_add(files));
+        }
+        if let Some(hash) = path.strip_prefix("blobs/") {
+            blobs.insert(hash.to_string(), entry.size());
+        } else if path != ".vegh.json"
+            && path != OFFSETS_ENTRY
+            && entry.header().entry_type().is_file()
+            && matches_include(&path, include)
+        {
+            legacy = legacy.saturating_add(entry.size());
+        }
+    }
+    Ok(legacy)
+}
+
+/// Bytes an unprivileged process may still write on the filesystem holding
+/// `dir`, or its nearest existing ancestor. `None` where that is unknown.
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = dir
+        .ancestors()
+        .map(|d| {
+            if d.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                d
+            }
+        })
+        .find(|d| d.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs only writes into `stat`, and `c_path` is NUL-terminated
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // the field types differ between platforms
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Restore selection. Patterns with glob syntax match gitignore-style (so
+/// `*.py` hits at any depth); anything else selects by path prefix.
+struct IncludeFilter {
+    prefixes: Vec<String>,
+    globs: Option<Override>,
+}
+
+impl IncludeFilter {
+    fn new(include: Option<Vec<String>>) -> Result<Option<Self>> {
+        let Some(patterns) = include else {
+            return Ok(None);
+        };
+        let (globs, prefixes): (Vec<String>, Vec<String>) = patterns
+            .into_iter()
+            .partition(|p| p.contains(['*', '?', '[']));
+        let globs = if globs.is_empty() {
+            None
+        } else {
+            let mut builder = OverrideBuilder::new("");
+            for pattern in &globs {
+                builder
+                    .add(pattern)
+                    .with_context(|| format!("Invalid include pattern '{}'", pattern))?;
+            }
+            Some(builder.build()?)
+        };
+        Ok(Some(Self { prefixes, globs }))
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|p| path.starts_with(p.as_str()))
+            || self
+                .globs
+                .as_ref()
+                .is_some_and(|g| g.matched(path, false).is_whitelist())
+    }
+}
+
+fn matches_include(path: &str, include: &Option<IncludeFilter>) -> bool {
+    include.as_ref().is_none_or(|filter| filter.matches(path))
+}
+
+/// `strip_components` and `add_prefix` applied to archive paths.
+struct PathRewrite {
+    strip: usize,
+    prefix: Option<String>,
+}
+
+impl PathRewrite {
+    fn new(strip: usize, prefix: Option<String>) -> Result<Self> {
+        let prefix = prefix.map(|p| p.trim_end_matches('/').to_string());
+        if let Some(prefix) = &prefix {
+            validate_relative_path(prefix)
+                .with_context(|| format!("Invalid add_prefix '{}'", prefix))?;
+        }
+        Ok(Self { strip, prefix })
+    }
+
+    fn is_identity(&self) -> bool {
+        self.strip == 0 && self.prefix.is_none()
+    }
+
+    /// Where `path` goes relative to `out_dir`; `None` when stripping
+    /// consumes all of it. The original path is checked first, so stripping
+    /// cannot turn `../x` into a safe-looking `x`.
+    fn apply(&self, path: &str) -> Result<Option<String>> {
+        if self.is_identity() {
+            return Ok(Some(path.to_string()));
+        }
+        validate_relative_path(path).map_err(|_| UnsafeEntry(path.to_string()))?;
+        let rest: Vec<&str> = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .skip(self.strip)
+            .collect();
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        let rest = rest.join("/");
+        Ok(Some(match &self.prefix {
+            Some(prefix) => format!("{}/{}", prefix, rest),
+            None => rest,
+        }))
+    }
+}
+
+fn restore_dest(out_dir: &Path, path: &str, escaped: bool, flatten: bool) -> PathBuf {
+    let path = names::decode_path(path, escaped);
+    if flatten {
+        out_dir.join(path.file_name().unwrap_or_default())
+    } else {
+        out_dir.join(path)
+    }
+}
+
+/// Fails with `UnsafeEntry` unless `dest`, where archive entry `path` is about to
+/// be written, stays inside `root` (the canonical output directory), including
+/// through symlinks that earlier entries restored. A symlink sitting at `dest`
+/// itself is removed so the write cannot follow it.
+fn ensure_inside(root: &Path, dest: &Path, path: &str) -> Result<()> {
+    let escape = || anyhow::Error::new(UnsafeEntry(path.to_string()));
+    validate_relative_path(path).map_err(|_| escape())?;
+
+    // Components below the deepest existing directory are plain names by now
+    let existing = dest
+        .ancestors()
+        .skip(1)
+        .find_map(|dir| fs::canonicalize(dir).ok());
+    if !existing.is_some_and(|dir| dir.starts_with(root)) {
+        return Err(escape());
+    }
+    if fs::symlink_metadata(dest).is_ok_and(|m| m.file_type().is_symlink()) {
+        fs::remove_file(dest)?;
+    }
+    Ok(())
+}
+
+/// Concatenates the entry's blobs into `dest`, checking for cancellation per chunk.
+fn assemble_file(
+    entry: &ManifestEntry,
+    blobs_dir: &Path,
+    dest: &Path,
+    verify: bool,
+    check_cancel: &mut dyn FnMut() -> Result<()>,
+) -> Result<()> {
+    let mut dest_file = File::create(dest)?;
+    let mut hasher = blake3::Hasher::new();
+    let chunk_hashes = entry
+        .chunks
+        .clone()
+        .unwrap_or_else(|| vec![entry.hash.clone()]);
+
+    for chunk_hash in chunk_hashes {
+        check_cancel()?;
+        let blob_path = blobs_dir.join(&chunk_hash);
+        if !blob_path.exists() {
+            if verify {
+                bail!("Missing blob {} for '{}'", chunk_hash, entry.path);
+            }
+            continue;
+        }
+        let blob = fs::read(&blob_path)?;
+        if verify {
+            hasher.update(&blob);
+        }
+        dest_file.write_all(&blob)?;
+    }
+
+    if verify && hasher.finalize().to_hex().as_str() != entry.hash {
+        bail!("Integrity check failed for '{}'", entry.path);
+    }
+    Ok(())
+}
+
+pub fn restore_snap_logic(
+    input: &Path,
+    out_dir: &Path,
+    opts: RestoreOptions,
+    should_cancel: Option<CancelHook>,
+    progress: Option<ProgressHook>,
+) -> Result<RestoreSummary> {
+    let mut open =
+        || -> Result<Box<dyn Read>> { Ok(Box::new(open_snapshot(input).context("Open failed")?)) };
+    let opts = RestoreOptions {
+        selective_staging: true,
+        ..opts
+    };
+    restore_snap_from(&mut open, out_dir, opts, should_cancel, progress, None)
+}
+
+/// `restore_snap_logic` for snapshots that do not live in a file. `open` is
+/// called once, or twice with `Overwrite::Never` to check for conflicts first.
+/// `progress` counts files written or skipped, and the bytes written. `filter`
+/// sees every file that passed `include`; the ones it turns down are not
+/// counted anywhere.
+pub fn restore_snap_from(
+    open: SnapshotOpener,
+    out_dir: &Path,
+    opts: RestoreOptions,
+    mut should_cancel: Option<CancelHook>,
+    mut progress: Option<ProgressHook>,
+    mut filter: Option<EntryFilter>,
+) -> Result<RestoreSummary> {
+    let RestoreOptions {
+        include,
+        flatten,
+        verify,
+        preserve_mtime,
+        password,
+        overwrite,
+        allow_case_collisions,
+        progress_interval,
+        preserve_permissions,
+        base_restored,
+        strip_components,
+        add_prefix,
+        check_space,
+        dictionary,
+        selective_staging,
+    } = opts;
+    let dictionary = dictionary.as_deref();
+    let include = IncludeFilter::new(include)?;
+    let rewrite = PathRewrite::new(strip_components, add_prefix)?;
+    let mut restored = 0;
+    let mut skipped = 0;
+    let mut tally = RestoreTally {
+        interval: progress_interval,
+        done: SnapProgress::default(),
+        reported: 0,
+    };
+    let mut case_folds = CaseFolds::default();
+    let selective = selective_staging && include.is_some();
+
+    if check_space {
+        let footprint =
+            restore_footprint(open, password.as_deref(), dictionary, &include, selective)?;
+        // Sizes come from the snapshot itself, so nothing here may overflow
+        let margin = (footprint / 100 * SPACE_MARGIN_PERCENT).max(SPACE_MARGIN_MIN);
+        let needed = footprint.saturating_add(margin);
+        if let Some(available) = available_space(out_dir)
+            && available < needed
+        {
+            return Err(InsufficientSpace { needed, available }.into());
+        }
+    }
+    if overwrite == Overwrite::Never {
+        let mut conflicts = Vec::new();
+        for (path, escaped) in restore_targets(open, password.as_deref(), dictionary, &include)? {
+            if let Some(target) = rewrite.apply(&path)?
+                && fs::symlink_metadata(restore_dest(out_dir, &target, escaped, flatten)).is_ok()
+            {
+                conflicts.push(target);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(RestoreConflict(conflicts).into());
+        }
+    }
+    // None stages every blob: all are needed, or the manifest comes too late
+    let wanted_blobs = if selective {
+        needed_blobs(open, password.as_deref(), dictionary, &include)?
+    } else {
+        None
+    };
+    // Checked before ensure_inside, which clears a symlink sitting at the target
+    let keep_existing =
+        |dest: &Path| overwrite == Overwrite::Skip && fs::symlink_metadata(dest).is_ok();
+
+    if !out_dir.exists() {
+        fs::create_dir_all(out_dir)?;
+    }
+    let root = fs::canonicalize(out_dir)?;
+
+    let decoder = open_decoder_using(open()?, password.as_deref(), dictionary)?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_mtime(preserve_mtime);
+
+    let mut check_cancel = || -> Result<()> {
+        if let Some(cb) = should_cancel.as_mut()
+            && cb()?
+        {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    };
+
+    // Pass 1: stream the archive once. Blobs go to staging, V2 files straight out.
+    let staging = StagingDir::new(out_dir)?;
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+    let mut base_hash = None;
+
+    for entry in archive.entries()? {
+        check_cancel()?;
+        let mut entry = entry?;
+        let (path, escaped) = names::encode(&entry.path_bytes());
+
+        if path == ".vegh.json" {
+            if let Ok(meta) = serde_json::from_slice::<VeghMetadata>(&metadata_json(&mut entry)?) {
+                check_requirements(meta.min_tool_version.as_deref(), &meta.features)?;
+                // A raw content dictionary has no ID for zstd to check
+                if meta.dictionary.is_some() && meta.dictionary != dictionary.map(dictionary_id) {
+                    let kind = match dictionary {
+                        Some(_) => DictionaryError::Mismatch,
+                        None => DictionaryError::Required(0),
+                    };
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, kind).into());
+                }
+                base_hash = meta.base;
+            }
+            continue;
+        }
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            check_requirements(manifest.min_tool_version.as_deref(), &manifest.features)?;
+            manifest_opt = Some(manifest);
+            continue;
+        }
+        if let Some(hash) = path.strip_prefix("blobs/") {
+            if hash.is_empty() || hash.contains(['/', '\\']) || hash == ".." {
+                return Err(UnsafeEntry(path).into());
+            }
+            if wanted_blobs
+                .as_ref()
+                .is_none_or(|wanted| wanted.contains(hash))
+            {
+                entry.unpack(staging.0.join(hash))?;
+            }
+            continue;
+        }
+        if path == OFFSETS_ENTRY {
+            continue;
+        }
+
+        // V2 layout: plain files stored under their own path
+        if !matches_include(&path, &include) {
+            continue;
+        }
+        if !entry.header().entry_type().is_dir()
+            && let Some(filter) = filter.as_mut()
+            && !filter(&path, entry.size())?
+        {
+            continue;
+        }
+        let Some(target) = rewrite.apply(&path)? else {
+            continue;
+        };
+        // Files are written as they stream by, so V2 collisions are caught at
+        // the second entry of a pair, before it overwrites the first
+        if !allow_case_collisions && !entry.header().entry_type().is_dir() {
+            case_folds.check(&target, flatten)?;
+        }
+        let dest_path = restore_dest(out_dir, &target, escaped, flatten);
+        if !entry.header().entry_type().is_dir() && keep_existing(&dest_path) {
+            skipped += 1;
+            tally.add(0, &mut progress)?;
+            continue;
+        }
+        ensure_inside(&root, &dest_path, &target)?;
+        if flatten {
+            entry.unpack(&dest_path)?;
+        } else if !rewrite.is_identity() {
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entry.unpack(&dest_path)?;
+        } else {
+            entry.unpack_in(out_dir)?;
+        }
+        // tar applies the recorded mode (minus setuid/setgid) on its own
+        if !preserve_permissions && entry.header().entry_type().is_file() {
+            apply_mode(&dest_path, 0o644)?;
+        }
+        if !entry.header().entry_type().is_dir() {
+            restored += 1;
+            tally.add(entry.size(), &mut progress)?;
+        }
+    }
+
+    let Some(manifest) = manifest_opt else {
+        tally.finish(&mut progress)?;
+        return Ok(RestoreSummary {
+            restored,
+            skipped,
+            deleted: Vec::new(),
+        });
+    };
+    if !base_restored && manifest.features.iter().any(|f| f == "incremental") {
+        return Err(MissingBase(base_hash).into());
+    }
+
+    let mut selected = Vec::new();
+    for entry in manifest.entries {
+        if !matches_include(&entry.path, &include) {
+            continue;
+        }
+        if let Some(filter) = filter.as_mut()
+            && !filter(&entry.path, entry.size)?
+        {
+            continue;
+        }
+        if let Some(target) = rewrite.apply(&entry.path)? {
+            selected.push((target, entry));
+        }
+    }
+    if !allow_case_collisions {
+        for (target, _) in &selected {
+            case_folds.check(target, flatten)?;
+        }
+    }
+    // Links last, so a directory target already exists when Windows asks
+    selected.sort_by_key(|(_, entry)| entry.symlink.is_some());
+
+    // Pass 2: rebuild files from the staged blobs
+    for (target, entry) in selected {
+        check_cancel()?;
+
+        let dest_path = restore_dest(out_dir, &target, entry.escaped, flatten);
+        if keep_existing(&dest_path) {
+            skipped += 1;
+            tally.add(0, &mut progress)?;
+            continue;
+        }
+        ensure_inside(&root, &dest_path, &target)?;
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if let Some(ref target) = entry.symlink {
+            restore_symlink(target, &dest_path)?;
+            restored += 1;
+            tally.add(0, &mut progress)?;
+            continue;
+        }
+
+        if let Err(e) = assemble_file(&entry, &staging.0, &dest_path, verify, &mut check_cancel) {
+            // Never leave a half-written file behind
+            let _ = fs::remove_file(&dest_path);
+            return Err(e);
+        }
+
+        // Before permissions: a read-only mode would stop us opening it again
+        if preserve_mtime {
+            let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(entry.modified);
+            File::options()
+                .write(true)
+                .open(&dest_path)?
+                .set_modified(mtime)?;
+        }
+        apply_mode(
+            &dest_path,
+            if preserve_permissions {
+                entry.mode
+            } else {
+                0o644
+            },
+        )?;
+        restored += 1;
+        tally.add(entry.size, &mut progress)?;
+    }
+
+    // Incremental snapshots: drop what the base had and the tree no longer does
+    let mut deleted = Vec::new();
+    for path in &manifest.deleted {
+        if !matches_include(path, &include) {
+            continue;
+        }
+        let Some(target) = rewrite.apply(path)? else {
+            continue;
+        };
+        let dest_path = restore_dest(out_dir, &target, false, flatten);
+        ensure_inside(&root, &dest_path, &target)?;
+        if let Ok(rel) = dest_path.strip_prefix(out_dir) {
+            deleted.push(rel.to_path_buf());
+        }
+        if fs::symlink_metadata(&dest_path).is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(&dest_path)?;
+            // A deleted directory shows up as its files; drop it once empty
+            for dir in dest_path.ancestors().skip(1) {
+                if !dir.starts_with(out_dir) || dir == out_dir || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    tally.finish(&mut progress)?;
+    Ok(RestoreSummary {
+        restored,
+        skipped,
+        deleted,
+    })
+}
+
+// --- Atomic Restores ---
+
+/// A sibling of `out_dir` an atomic restore writes into first. Being in the
+/// same parent keeps the final move a rename on one filesystem; until
+/// `commit` it is deleted on drop, so a failed restore leaves `out_dir` alone.
+pub struct AtomicRestore {
+    staging: PathBuf,
+    out_dir: PathBuf,
+}
+
+impl AtomicRestore {
+    pub fn new(out_dir: &Path) -> Result<Self> {
+        let name = out_dir
+            .file_name()
+            .with_context(|| format!("'{}' has no directory name", out_dir.display()))?;
+        let parent = match out_dir.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        fs::create_dir_all(&parent)?;
+        let staging = parent.join(format!(
+            ".{}.vegh-atomic-{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir(&staging)?;
+        Ok(Self {
+            staging,
+            out_dir: out_dir.to_path_buf(),
+        })
+    }
+
+    /// Where the restore should write.
+    pub fn path(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Puts the staged tree in place of `out_dir`. A fresh `out_dir` is one
+    /// rename. An existing one is renamed aside first, whatever of it the
+    /// restore neither replaced nor listed in `deleted` (paths relative to
+    /// `out_dir`) is moved into the staged tree, and that tree is renamed into
+    /// place. On an error the moves are undone and `out_dir` is renamed back.
+    pub fn commit(self, deleted: &[PathBuf]) -> Result<()> {
+        if fs::symlink_metadata(&self.out_dir).is_err() {
+            return Ok(fs::rename(&self.staging, &self.out_dir)?);
+        }
+        let backup = self.staging.with_file_name(format!(
+            ".{}.vegh-old-{}",
+            self.out_dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            std::process::id()
+        ));
+        if backup.exists() {
+            fs::remove_dir_all(&backup)?;
+        }
+        fs::rename(&self.out_dir, &backup)
+            .with_context(|| format!("Moving '{}' aside failed", self.out_dir.display()))?;
+        let deleted: Vec<PathBuf> = deleted.iter().map(|p| backup.join(p)).collect();
+        let mut moved = Vec::new();
+        let result = merge_kept(&backup, &self.staging, &self.out_dir, &deleted, &mut moved)
+            .and_then(|()| Ok(fs::rename(&self.staging, &self.out_dir)?));
+        match result {
+            Ok(()) => {
+                let _ = fs::remove_dir_all(&backup);
+                Ok(())
+            }
+            Err(e) => {
+                for (now, was) in moved.iter().rev() {
+                    let _ = fs::rename(now, was);
+                }
+                fs::rename(&backup, &self.out_dir).with_context(|| {
+                    format!(
+                        "{e:#}; '{}' is left at '{}'",
+                        self.out_dir.display(),
+                        backup.display()
+                    )
+                })?;
+                Err(e)
+            }
+        }
+        // Drop clears the staging directory if it is still there
+    }
+}
+
+impl Drop for AtomicRestore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.staging);
+    }
+}
+
+/// Moves what `old` holds and `new` lacks into `new`, recording each move as
+/// `(new place, old place)`. Paths in `deleted` stay behind, and a directory
+/// holding one is merged entry by entry, then dropped if that empties it.
+/// `shown` is where `new` will end up, for error messages.
+fn merge_kept(
+    old: &Path,
+    new: &Path,
+    shown: &Path,
+    deleted: &[PathBuf],
+    moved: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    for entry in fs::read_dir(old)? {
+        let entry = entry?;
+        let src = entry.path();
+        let dest = new.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match fs::symlink_metadata(&dest) {
+            Ok(m) if m.is_dir() && is_dir => {
+                merge_kept(&src, &dest, &shown.join(entry.file_name()), deleted, moved)?
+            }
+            Ok(_) if is_dir => bail!(
+                "Cannot replace directory '{}' with a file",
+                shown.join(entry.file_name()).display()
+            ),
+            // The restored copy wins
+            Ok(_) => {}
+            Err(_) if deleted.contains(&src) => {}
+            Err(_) if is_dir && deleted.iter().any(|d| d.starts_with(&src)) => {
+                fs::create_dir(&dest)?;
+                merge_kept(&src, &dest, &shown.join(entry.file_name()), deleted, moved)?;
+                let _ = fs::remove_dir(&dest);
+            }
+            Err(_) => {
+                fs::rename(&src, &dest)?;
+                moved.push((dest, src));
+            }
+        }
+    }
+    Ok(())
+}
+
+// --- Recovery ---
+
+/// Counts the decoded bytes handed to the tar reader.
+struct ReadCounter<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for ReadCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Outcome of `recover_snap_logic`.
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Files and symlinks written under their archive path.
+    pub recovered: usize,
+    /// Blobs saved to `blobs/` because no manifest said whose they are.
+    pub orphaned_blobs: usize,
+    /// Manifest files left out because some of their blobs were lost.
+    pub lost: Vec<PathBuf>,
+    /// How far into the decoded tar stream reading got before it failed;
+    /// `None` when the stream ended cleanly.
+    pub stopped_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Salvages what an interrupted or damaged snapshot still holds. The stream is
+/// read until it stops decoding; every entry that came through whole is kept
+/// and a half-read one is discarded. V3 files are rebuilt (and checked against
+/// their digest) when the manifest survived along with all of their blobs.
+/// Without a manifest the blobs themselves go to `out_dir/blobs/`.
+pub fn recover_snap_logic(
+    input: &Path,
+    out_dir: &Path,
+    password: Option<&str>,
+) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+    fs::create_dir_all(out_dir)?;
+    let root = fs::canonicalize(out_dir)?;
+    let staging = StagingDir::new(out_dir)?;
+    let mut blobs = HashSet::new();
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+
+    let count = Rc::new(Cell::new(0));
+    let decoder = open_decoder_with(open_snapshot(input).context("Open failed")?, password)?;
+    let mut archive = tar::Archive::new(ReadCounter {
+        inner: decoder,
+        count: count.clone(),
+    });
+    let scan = (|| -> Result<()> {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            if path == ".vegh.json" || path == OFFSETS_ENTRY {
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+            } else if let Some(format) = ManifestFormat::from_entry_path(&path) {
+                manifest_opt = Some(format.decode(&mut entry)?);
+            } else if let Some(hash) = path.strip_prefix("blobs/") {
+                if hash.is_empty() || hash.contains(['/', '\\']) || hash == ".." {
+                    continue;
+                }
+                let dest = staging.0.join(hash);
+                if let Err(e) = entry.unpack(&dest) {
+                    let _ = fs::remove_file(&dest);
+                    return Err(e.into());
+                }
+                blobs.insert(hash.to_string());
+            } else {
+                // V2 file: written where it belongs, unless it points outside
+                let dest = out_dir.join(names::bytes_path(entry.path_bytes().into_owned()));
+                if ensure_inside(&root, &dest, &path).is_err() {
+                    continue;
+                }
+                let is_dir = entry.header().entry_type().is_dir();
+                if let Err(e) = entry.unpack_in(out_dir) {
+                    if !is_dir {
+                        let _ = fs::remove_file(&dest);
+                    }
+                    return Err(e.into());
+                }
+                if !is_dir {
+                    report.recovered += 1;
+                }
+            }
+        }
+        // tar stops at its end-of-archive blocks; the codec must still end cleanly
+        std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+        Ok(())
+    })();
+    if let Err(e) = scan {
+        report.stopped_at = Some(count.get());
+        report.error = Some(format!("{:#}", e));
+    }
+
+    let Some(manifest) = manifest_opt else {
+        if !blobs.is_empty() {
+            let kept = out_dir.join("blobs");
+            fs::create_dir_all(&kept)?;
+            for hash in &blobs {
+                fs::rename(staging.0.join(hash), kept.join(hash))?;
+            }
+            report.orphaned_blobs = blobs.len();
+        }
+        return Ok(report);
+    };
+    let mut entries = manifest.entries;
+    // Links last, so a directory target already exists when Windows asks
+    entries.sort_by_key(|entry| entry.symlink.is_some());
+    for entry in entries {
+        let dest = out_dir.join(names::decode_path(&entry.path, entry.escaped));
+        if ensure_inside(&root, &dest, &entry.path).is_err() {
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Some(ref target) = entry.symlink {
+            restore_symlink(target, &dest)?;
+            report.recovered += 1;
+            continue;
+        }
+        let complete = match &entry.chunks {
+            Some(chunks) => chunks.iter().all(|c| blobs.contains(c)),
+            None => blobs.contains(&entry.hash),
+        };
+        if !complete {
+            report
+                .lost
+                .push(names::decode_path(&entry.path, entry.escaped));
+            continue;
+        }
+        if assemble_file(&entry, &staging.0, &dest, true, &mut || Ok(())).is_err() {
+            let _ = fs::remove_file(&dest);
+            report
+                .lost
+                .push(names::decode_path(&entry.path, entry.escaped));
+            continue;
+        }
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(entry.modified);
+        File::options()
+            .write(true)
+            .open(&dest)?
+            .set_modified(mtime)?;
+        apply_mode(&dest, entry.mode)?;
+        report.recovered += 1;
+    }
+    Ok(report)
+}
+
+// --- Incremental Chains ---
+
+/// Snapshots directly inside `dir` (not recursive): `.vegh` files and the
+/// first volume (`.vegh.001`) of split ones.
+fn snapshots_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let first_volume = volume_base(&path)
+            .is_some_and(|base| base.extension().is_some_and(|ext| ext == "vegh"));
+        if path.is_file() && (path.extension().is_some_and(|ext| ext == "vegh") || first_volume) {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Deletes a snapshot found by `snapshots_in`, with all its volumes.
+fn delete_snapshot(path: &Path) -> Result<()> {
+    fs::remove_file(path).with_context(|| format!("Delete failed: {}", path.display()))?;
+    if let Some(base) = volume_base(path) {
+        for index in 2.. {
+            if fs::remove_file(volume_path(&base, index)).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The JSON held by a `.vegh.json` entry: the file itself, or the `comment`
+/// record when it is the PAX global header of an interop snapshot.
+pub fn metadata_json<R: Read>(entry: &mut tar::Entry<'_, R>) -> std::io::Result<Vec<u8>> {
+    if let Some(records) = entry.pax_extensions()? {
+        for record in records {
+            let record = record?;
+            if record.key_bytes() == PAX_METADATA_KEY.as_bytes() {
+                return Ok(record.value_bytes().to_vec());
+            }
+        }
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "PAX header carries no snapshot metadata",
+        ));
+    }
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads `.vegh.json` wherever it sits in the stream.
+pub fn read_metadata(input: &Path) -> Result<VeghMetadata> {
+    read_metadata_with(input, None)
+}
+
+/// `read_metadata` for snapshots that may be password-encrypted.
+pub fn read_metadata_with(input: &Path, password: Option<&str>) -> Result<VeghMetadata> {
+    let file = open_snapshot(input).with_context(|| format!("Open failed: {}", input.display()))?;
+    metadata_in(
+        open_decoder_with(file, password)?,
+        &input.display().to_string(),
+    )
+}
+
+/// `read_metadata_with` over the raw snapshot bytes from any reader, loading
+/// `dictionary` if the snapshot needs one; `name` identifies the snapshot in
+/// error messages.
+pub fn read_metadata_from<R: Read + 'static>(
+    input: R,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+    name: &str,
+) -> Result<VeghMetadata> {
+    metadata_in(open_decoder_using(input, password, dictionary)?, name)
+}
+
+fn metadata_in(decoder: Box<dyn Read>, name: &str) -> Result<VeghMetadata> {
+    let mut archive = tar::Archive::new(decoder);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy() == ".vegh.json" {
+            return serde_json::from_slice(&metadata_json(&mut entry)?)
+                .with_context(|| format!("Unreadable metadata in {}", name));
+        }
+    }
+    bail!("Metadata not found in {}", name)
+}
+
+/// Follows `base` references from `input`, looking bases up by integrity hash
+/// among the `.vegh` files directly inside `search_dirs`. Returns the chain
+/// oldest first, ending with `input`; every member's metadata must be readable.
+pub fn resolve_chain_logic(input: &Path, search_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    for dir in search_dirs {
+        // Missing search directories are not an error; the base may be elsewhere
+        candidates.extend(snapshots_in(dir).unwrap_or_default());
+    }
+    candidates.sort();
+    // Candidates are hashed lazily, at most once each
+    let mut hashes: HashMap<PathBuf, String> = HashMap::new();
+
+    let mut chain = vec![input.to_path_buf()];
+    let mut seen = HashSet::new();
+    let mut meta = read_metadata(input)?;
+    while let Some(base_hash) = meta.base.clone() {
+        if !seen.insert(base_hash.clone()) {
+            bail!("Snapshot chain loops back to base {}", base_hash);
+        }
+        let mut found = None;
+        for candidate in &candidates {
+            if !hashes.contains_key(candidate) {
+                let hash = hex::encode(snapshot_hash(candidate)?);
+                hashes.insert(candidate.clone(), hash);
+            }
+            if hashes[candidate] == base_hash {
+                found = Some(candidate.clone());
+                break;
+            }
+        }
+        let Some(base_path) = found else {
+            bail!(
+                "Base snapshot {} of {} not found in search directories",
+                base_hash,
+                chain
+                    .last()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default()
+            );
+        };
+        meta = read_metadata(&base_path)?;
+        chain.push(base_path);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Restores every snapshot of `input`'s chain into `out_dir`, oldest first,
+/// so newer snapshots overwrite what their bases laid down.
+pub fn restore_chain_logic(
+    input: &Path,
+    out_dir: &Path,
+    search_dirs: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    let chain = resolve_chain_logic(input, search_dirs)?;
+    for snapshot in &chain {
+        let opts = RestoreOptions {
+            base_restored: true,
+            ..Default::default()
+        };
+        restore_snap_logic(snapshot, out_dir, opts, None, None)
+            .with_context(|| format!("Restoring {} failed", snapshot.display()))?;
+    }
+    Ok(chain)
+}
+
+/// Writes `.vegh.json` and the manifest entry (if any) into `out_dir` as-is,
+/// skipping blobs and file data. Returns the sidecar names written.
+pub fn extract_metadata_logic(input: &Path, out_dir: &Path) -> Result<Vec<String>> {
+    let file = open_snapshot(input).context("Open failed")?;
+    let decoder = open_decoder(file)?;
+    let mut archive = tar::Archive::new(decoder);
+    fs::create_dir_all(out_dir)?;
+
+    let mut written = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if path == ".vegh.json" {
+            fs::write(out_dir.join(&path), metadata_json(&mut entry)?)?;
+            written.push(path);
+        } else if ManifestFormat::from_entry_path(&path).is_some() {
+            let mut out = File::create(out_dir.join(&path))?;
+            std::io::copy(&mut entry, &mut out)?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+// --- Retention ---
+
+/// Snapshots directly inside `dir` whose `keep_until` is before `now`, sorted.
+/// Unreadable files and snapshots without a retention are left alone.
+pub fn prune_expired_logic(dir: &Path, now: i64, delete: bool) -> Result<Vec<PathBuf>> {
+    let mut expired = Vec::new();
+    for path in snapshots_in(dir)? {
+        if let Ok(meta) = read_metadata(&path)
+            && meta.keep_until.is_some_and(|until| until < now)
+        {
+            expired.push(path);
+        }
+    }
+    expired.sort();
+
+    if delete {
+        for path in &expired {
+            delete_snapshot(path)?;
+        }
+    }
+    Ok(expired)
+}
+
+/// A snapshot considered by `prune_by_policy_logic`.
+struct PruneCandidate {
+    path: PathBuf,
+    /// Metadata timestamp, or the file's mtime when that is 0 (reproducible
+    /// snapshots) or unreadable.
+    time: i64,
+    /// `None` when the metadata could not be read, so the base is unknown.
+    base: Option<Option<String>>,
+}
+
+/// Snapshots directly inside `dir` that fall outside a retention policy,
+/// sorted. The `keep_last` newest are always kept; of the rest, those older
+/// than `keep_days` go, or all of them without `keep_days`. A snapshot that
+/// a kept one builds on (its `base`, transitively) is kept too. Every kept
+/// snapshot's metadata must be readable, or its base could not be protected;
+/// encrypted ones need `password`.
+pub fn prune_by_policy_logic(
+    dir: &Path,
+    keep_last: Option<usize>,
+    keep_days: Option<u64>,
+    now: i64,
+    delete: bool,
+    password: Option<&str>,
+) -> Result<Vec<PathBuf>> {
+    if keep_last.is_none() && keep_days.is_none() {
+        bail!("Pass keep_last and/or keep_days; an empty policy would delete every snapshot");
+    }
+    let mut candidates: Vec<PruneCandidate> = Vec::new();
+    for path in snapshots_in(dir)? {
+        let meta = read_metadata_with(&path, password).ok();
+        let time = match meta.as_ref().map(|m| m.timestamp) {
+            Some(ts) if ts != 0 => ts,
+            _ => fs::metadata(&path)?
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as i64),
+        };
+        candidates.push(PruneCandidate {
+            path,
+            time,
+            base: meta.map(|m| m.base),
+        });
+    }
+    // Newest first; the path breaks ties so the outcome does not depend on read_dir
+    candidates.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.path.cmp(&b.path)));
+
+    let cutoff = keep_days.map(|days| now.saturating_sub((days as i64).saturating_mul(86_400)));
+    let (mut kept, mut doomed): (Vec<PruneCandidate>, Vec<PruneCandidate>) =
+        (Vec::new(), Vec::new());
+    for (rank, candidate) in candidates.into_iter().enumerate() {
+        let expired = cutoff.is_none_or(|cutoff| candidate.time < cutoff);
+        if rank < keep_last.unwrap_or(0) || !expired {
+            kept.push(candidate);
+        } else {
+            doomed.push(candidate);
+        }
+    }
+
+    // Rescue bases of kept snapshots until no kept snapshot points at a doomed one
+    let mut hashes: HashMap<PathBuf, String> = HashMap::new();
+    let mut checked = 0;
+    while checked < kept.len() {
+        let candidate = &kept[checked];
+        checked += 1;
+        let Some(base) = &candidate.base else {
+            bail!(
+                "Cannot read the metadata of kept snapshot {}, so the snapshot it builds on is unknown (encrypted snapshots need a password)",
+                candidate.path.display()
+            );
+        };
+        let Some(base) = base.clone() else {
+            continue;
+        };
+        let mut rescued = None;
+        for (i, other) in doomed.iter().enumerate() {
+            if !hashes.contains_key(&other.path) {
+                hashes.insert(other.path.clone(), hex::encode(snapshot_hash(&other.path)?));
+            }
+            if hashes[&other.path] == base {
+                rescued = Some(i);
+                break;
+            }
+        }
+        if let Some(i) = rescued {
+            kept.push(doomed.remove(i));
+        }
+    }
+
+    let mut doomed: Vec<PathBuf> = doomed.into_iter().map(|c| c.path).collect();
+    doomed.sort();
+    if delete {
+        for path in &doomed {
+            delete_snapshot(path)?;
+        }
+    }
+    Ok(doomed)
+}
+
+// --- Staged Reads ---
+
+/// Reads a staged file's blobs back to back, opening one at a time.
+struct StagedFile<'a> {
+    dir: &'a Path,
+    chunks: std::slice::Iter<'a, &'a str>,
+    current: Option<File>,
+}
+
+impl<'a> StagedFile<'a> {
+    fn new(dir: &'a Path, chunks: &'a [&'a str]) -> Self {
+        Self {
+            dir,
+            chunks: chunks.iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for StagedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = Some(File::open(self.dir.join(chunk))?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Hands every regular file `wanted` selects to `visit` as a stream: V2 files
+/// as they go by, V3 files in manifest order once the archive is read, from
+/// blobs staged in a temporary directory rather than memory. Returns
+/// `(path, blob)` for the V3 files whose blobs are missing.
+pub fn visit_files(
+    input: Box<dyn Read>,
+    password: Option<&str>,
+    wanted: &dyn Fn(&str) -> bool,
+    visit: &mut dyn FnMut(&str, &mut dyn Read) -> Result<()>,
+) -> Result<Vec<(String, String)>> {
+    let staging = StagingDir::temp()?;
+    let mut staged = HashSet::new();
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+    let mut archive = tar::Archive::new(open_decoder_with(input, password)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let (path, _) = names::encode(&entry.path_bytes());
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format.decode(&mut entry)?;
+            check_requirements(manifest.min_tool_version.as_deref(), &manifest.features)?;
+            manifest_opt = Some(manifest);
+        } else if let Some(hash) = path.strip_prefix("blobs/") {
+            if hash.is_empty() || hash.contains(['/', '\\']) || hash == ".." {
+                return Err(UnsafeEntry(path).into());
+            }
+            std::io::copy(&mut entry, &mut File::create(staging.0.join(hash))?)?;
+            staged.insert(hash.to_string());
+        } else if path != ".vegh.json"
+            && path != OFFSETS_ENTRY
+            && entry.header().entry_type().is_file()
+            && wanted(&path)
+        {
+            visit(&path, &mut entry)?;
+        }
+    }
+
+    let mut missing = Vec::new();
+    for entry in manifest_opt.map(|m| m.entries).unwrap_or_default() {
+        if entry.symlink.is_some() || !wanted(&entry.path) {
+            continue;
+        }
+        let chunks = blob_refs(&entry);
+        match chunks.iter().find(|c| !staged.contains(**c)) {
+            Some(absent) => missing.push((entry.path.clone(), absent.to_string())),
+            None => visit(&entry.path, &mut StagedFile::new(&staging.0, &chunks))?,
+        }
+    }
+    Ok(missing)
+}
+
+// --- Verification ---
+
+/// What one read of a snapshot found; the verify functions report from it.
+#[derive(Default)]
+struct ContentScan {
+    /// Every file with whether it checked out: manifest files for V3, tar
+    /// files for V2.
+    files: Vec<(String, bool)>,
+    /// Problems found along the way, in order.
+    errors: Vec<String>,
+    has_manifest: bool,
+    /// Features this build cannot read.
+    unsupported: Option<anyhow::Error>,
+    /// Why the stream could not be read to its end.
+    stream_error: Option<anyhow::Error>,
+}
+
+/// Reads the whole snapshot once without extracting it. Blobs are re-hashed
+/// as they stream by and the good ones staged in a temporary directory, so
+/// chunked files can be reassembled from disk and checked against their
+/// recorded digest; nothing is held in memory.
+fn scan_contents(
+    input: &Path,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<ContentScan> {
+    let mut scan = ContentScan::default();
+    let staging = StagingDir::temp()?;
+    let mut good_blobs = HashSet::new();
+    let mut legacy = Vec::new();
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+
+    let file = open_snapshot(input).context("Open failed")?;
+    let mut archive = tar::Archive::new(open_decoder_using(file, password, dictionary)?);
+    let note_requirements = |scan: &mut ContentScan, result: Result<()>| {
+        if let Err(e) = result
+            && scan.unsupported.is_none()
+        {
+            scan.unsupported = Some(e);
+        }
+    };
+    let read = (|| -> Result<()> {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let (path, _) = names::encode(&entry.path_bytes());
+            if path == ".vegh.json" {
+                let parsed = metadata_json(&mut entry)
+                    .map_err(serde_json::Error::io)
+                    .and_then(|json| serde_json::from_slice::<VeghMetadata>(&json));
+                match parsed {
+                    Ok(meta) => note_requirements(
+                        &mut scan,
+                        check_requirements(meta.min_tool_version.as_deref(), &meta.features),
+                    ),
+                    Err(e) => scan.errors.push(format!("Unreadable .vegh.json: {}", e)),
+                }
+            } else if let Some(format) = ManifestFormat::from_entry_path(&path) {
+                match format.decode(&mut entry) {
+                    Ok(manifest) => {
+                        note_requirements(
+                            &mut scan,
+                            check_requirements(
+                                manifest.min_tool_version.as_deref(),
+                                &manifest.features,
+                            ),
+                        );
+                        manifest_opt = Some(manifest);
+                    }
+                    Err(e) => scan.errors.push(format!("Unreadable manifest: {:#}", e)),
+                }
+            } else if let Some(hash) = path.strip_prefix("blobs/") {
+                if hash.is_empty() || hash.contains(['/', '\\']) || hash == ".." {
+                    return Err(UnsafeEntry(path).into());
+                }
+                let staged = staging.0.join(hash);
+                let mut sink = HashingWriter::new(File::create(&staged)?);
+                std::io::copy(&mut entry, &mut sink)?;
+                if hex::encode(sink.digest()) == hash {
+                    good_blobs.insert(hash.to_string());
+                } else {
+                    drop(sink);
+                    fs::remove_file(&staged)?;
+                    scan.errors
+                        .push(format!("Blob {} does not match its hash", hash));
+                }
+            } else if path == OFFSETS_ENTRY || entry.header().entry_type().is_dir() {
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+            } else {
+                // V2 file: decoding it end to end is the only check available
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+                legacy.push((path, true));
+            }
+        }
+        // tar stops at its end-of-archive blocks; the codec must still end cleanly
+        std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+        Ok(())
+    })();
+    if let Err(e) = read {
+        // The stream cannot be followed past a broken header or frame
+        scan.errors.push(format!("Stream unreadable: {:#}", e));
+        scan.stream_error = Some(e);
+    }
+
+    let Some(manifest) = manifest_opt else {
+        scan.files = legacy;
+        return Ok(scan);
+    };
+    scan.has_manifest = true;
+    for entry in manifest.entries.into_iter().filter(|e| e.symlink.is_none()) {
+        let ok = match blob_refs(&entry)
+            .into_iter()
+            .find(|c| !good_blobs.contains(*c))
+        {
+            Some(bad) => {
+                scan.errors.push(format!(
+                    "{}: blob {} is missing or corrupt",
+                    entry.path, bad
+                ));
+                false
+            }
+            // A single blob is named after its content, already checked above
+            None if entry.chunks.is_none() => true,
+            // Chunks are checked one by one, so only their order can be wrong
+            None => {
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(
+                    &mut StagedFile::new(&staging.0, &blob_refs(&entry)),
+                    &mut hasher,
+                )?;
+                let matches = hasher.finalize().to_hex().as_str() == entry.hash;
+                if !matches {
+                    scan.errors.push(format!(
+                        "{}: reassembled content does not match its hash",
+                        entry.path
+                    ));
+                }
+                matches
+            }
+        };
+        scan.files.push((entry.path, ok));
+    }
+    Ok(scan)
+}
+
+/// Checks a snapshot without extracting it: the stream must decode, every blob
+/// must match its content hash and every manifest file must reassemble to its
+/// recorded digest. Fails with the first problem found.
+pub fn verify_snapshot_logic(
+    input: &Path,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<()> {
+    let mut scan = scan_contents(input, password, dictionary)?;
+    if let Some(e) = scan.unsupported.take().or(scan.stream_error.take()) {
+        return Err(e);
+    }
+    match scan.errors.first() {
+        Some(problem) => bail!("{}", problem),
+        None => Ok(()),
+    }
+}
+
+/// Reassembles every file and compares its Blake3 digest with the one the
+/// manifest recorded at pack time. Files with missing blobs count as mismatches.
+pub fn verify_contents_logic(
+    input: &Path,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<Vec<(String, bool)>> {
+    let mut scan = scan_contents(input, password, dictionary)?;
+    if let Some(e) = scan.unsupported.take() {
+        return Err(e);
+    }
+    if !scan.has_manifest {
+        if let Some(e) = scan.stream_error.take() {
+            return Err(e);
+        }
+        bail!(
+            "'{}' has no manifest, so no recorded digests",
+            input.display()
+        );
+    }
+    Ok(scan.files)
+}
+
+/// Outcome of `verify_snap_logic`. Every problem found is listed, not just the first.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub entries_ok: usize,
+    pub entries_total: usize,
+    pub errors: Vec<String>,
+}
+
+/// Reads the whole snapshot without writing anything but its staged blobs:
+/// tar headers must pass their checksums, the compressed stream must end
+/// cleanly, every blob must match its hash and every manifest file must
+/// reassemble to its recorded digest. Files count as entries (manifest files
+/// for V3, tar files for V2).
+pub fn verify_snap_logic(
+    input: &Path,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> Result<VerifyReport> {
+    let mut scan = scan_contents(input, password, dictionary)?;
+    if let Some(e) = scan.unsupported.take() {
+        scan.errors.insert(0, format!("{:#}", e));
+    }
+    Ok(VerifyReport {
+        entries_ok: scan.files.iter().filter(|(_, ok)| *ok).count(),
+        entries_total: scan.files.len(),
+        errors: scan.errors,
+    })
 }
@@ -0,0 +1,313 @@
+use anyhow::{Result, bail};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key};
+use std::io::{self, BufRead, Read, Write};
+
+// --- Password Encryption ---
+
+// Everything after the header is the codec stream, sealed with
+// ChaCha20-Poly1305 (STREAM construction) under an Argon2id key.
+pub const ENCRYPTION_MAGIC: &[u8; 8] = b"VEGHENC\x01";
+const KDF_ARGON2ID: u8 = 1;
+const SALT_LEN: usize = 16;
+// 12-byte nonce minus the 5 bytes STREAM uses for the counter and last flag
+const NONCE_PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = ENCRYPTION_MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_PREFIX_LEN;
+/// Plaintext bytes per sealed segment; each one carries a 16-byte tag.
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// Headers are untrusted, so costs above this many times the defaults are
+/// refused rather than spent on a crafted file.
+const MAX_COST_FACTOR: u32 = 4;
+
+/// The snapshot is encrypted and no password was given, or the password
+/// did not open it.
+#[derive(Debug)]
+pub enum CryptError {
+    PasswordRequired,
+    WrongPassword,
+}
+
+impl std::fmt::Display for CryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PasswordRequired => write!(f, "Snapshot is encrypted; a password is required"),
+            Self::WrongPassword => write!(f, "Wrong password or corrupted encrypted snapshot"),
+        }
+    }
+}
+
+impl std::error::Error for CryptError {}
+
+fn crypt_error(e: CryptError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Whether `e` (possibly wrapped) is a missing or wrong password.
+pub fn is_crypt_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<CryptError>())
+}
+
+/// Argon2id cost and salt, stored in the clear so readers can derive the key.
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; SALT_LEN],
+}
+
+impl KdfParams {
+    fn derive(&self, password: &str) -> io::Result<Key> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+fn encode_header(kdf: &KdfParams, nonce: &[u8; NONCE_PREFIX_LEN]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(ENCRYPTION_MAGIC);
+    header.push(KDF_ARGON2ID);
+    for v in [kdf.m_cost, kdf.t_cost, kdf.p_cost] {
+        header.extend_from_slice(&v.to_le_bytes());
+    }
+    header.extend_from_slice(&kdf.salt);
+    header.extend_from_slice(nonce);
+    header
+}
+
+fn decode_header(header: &[u8; HEADER_LEN]) -> io::Result<(KdfParams, [u8; NONCE_PREFIX_LEN])> {
+    let magic = ENCRYPTION_MAGIC.len();
+    if header[magic] != KDF_ARGON2ID {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unknown key derivation {} in encrypted snapshot",
+                header[magic]
+            ),
+        ));
+    }
+    let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let salt_at = magic + 1 + 12;
+    let nonce_at = salt_at + SALT_LEN;
+    let kdf = KdfParams {
+        m_cost: word(magic + 1),
+        t_cost: word(magic + 5),
+        p_cost: word(magic + 9),
+        salt: header[salt_at..nonce_at].try_into().unwrap(),
+    };
+    if kdf.m_cost > Params::DEFAULT_M_COST * MAX_COST_FACTOR
+        || kdf.t_cost > Params::DEFAULT_T_COST * MAX_COST_FACTOR
+        || kdf.p_cost > Params::DEFAULT_P_COST * MAX_COST_FACTOR
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Encrypted snapshot asks for excessive key derivation cost (m={}, t={}, p={})",
+                kdf.m_cost, kdf.t_cost, kdf.p_cost
+            ),
+        ));
+    }
+    Ok((kdf, header[nonce_at..].try_into().unwrap()))
+}
+
+/// Seals everything written to it; `finish` writes the final segment.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    header: Vec<u8>,
+    encryptor: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, password: &str) -> Result<Self> {
+        if password.is_empty() {
+            bail!("password must not be empty");
+        }
+        let defaults = Params::default();
+        let mut kdf = KdfParams {
+            m_cost: defaults.m_cost(),
+            t_cost: defaults.t_cost(),
+            p_cost: defaults.p_cost(),
+            salt: [0; SALT_LEN],
+        };
+        let mut nonce = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut kdf.salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let key = kdf.derive(password)?;
+        let header = encode_header(&kdf, &nonce);
+        inner.write_all(&header)?;
+        let encryptor = EncryptorBE32::from_aead(ChaCha20Poly1305::new(&key), (&nonce).into());
+        Ok(Self {
+            inner,
+            header,
+            encryptor: Some(encryptor),
+            buffer: Vec::with_capacity(SEGMENT),
+        })
+    }
+
+    /// Seals the last (possibly empty) segment and hands back the sink.
+    pub fn finish(mut self) -> Result<W> {
+        let Some(encryptor) = self.encryptor.take() else {
+            bail!("Encrypted stream was already finished");
+        };
+        let sealed = encryptor
+            .encrypt_last(Payload {
+                msg: &self.buffer,
+                aad: &self.header,
+            })
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full buffer is only sealed once more data shows it is not the last
+        if self.buffer.len() == SEGMENT {
+            let encryptor = self
+                .encryptor
+                .as_mut()
+                .ok_or_else(|| io::Error::other("Encrypted stream is closed"))?;
+            let sealed = encryptor
+                .encrypt_next(Payload {
+                    msg: &self.buffer,
+                    aad: &self.header,
+                })
+                .map_err(|_| io::Error::other("Encryption failed"))?;
+            self.inner.write_all(&sealed)?;
+            self.buffer.clear();
+        }
+        let n = buf.len().min(SEGMENT - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where a snapshot's bytes go: straight to the sink, or sealed first.
+pub enum SnapshotSink<W: Write> {
+    Plain(W),
+    Encrypted(EncryptWriter<W>),
+}
+
+impl<W: Write> SnapshotSink<W> {
+    pub fn new(sink: W, password: Option<&str>) -> Result<Self> {
+        Ok(match password {
+            Some(password) => Self::Encrypted(EncryptWriter::new(sink, password)?),
+            None => Self::Plain(sink),
+        })
+    }
+
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Self::Plain(mut sink) => {
+                sink.flush()?;
+                Ok(sink)
+            }
+            Self::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for SnapshotSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Encrypted(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+/// Opens segments sealed by `EncryptWriter`, failing on the first one that
+/// does not authenticate.
+pub struct DecryptReader<R: BufRead> {
+    inner: R,
+    header: [u8; HEADER_LEN],
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> DecryptReader<R> {
+    /// Reads the header from `inner` and derives the key. The first segment
+    /// is opened right away, so a wrong password fails here.
+    pub fn new(mut inner: R, password: Option<&str>) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        let Some(password) = password else {
+            return Err(crypt_error(CryptError::PasswordRequired));
+        };
+        let (kdf, nonce) = decode_header(&header)?;
+        let key = kdf.derive(password)?;
+        let decryptor = DecryptorBE32::from_aead(ChaCha20Poly1305::new(&key), (&nonce).into());
+        let mut reader = Self {
+            inner,
+            header,
+            decryptor: Some(decryptor),
+            plain: Vec::new(),
+            pos: 0,
+        };
+        reader.next_segment()?;
+        Ok(reader)
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            return Ok(());
+        };
+        let mut sealed = Vec::with_capacity(SEGMENT + TAG_LEN);
+        (&mut self.inner)
+            .take((SEGMENT + TAG_LEN) as u64)
+            .read_to_end(&mut sealed)?;
+        let is_last = self.inner.fill_buf()?.is_empty();
+        let payload = Payload {
+            msg: &sealed,
+            aad: &self.header,
+        };
+        let opened = if is_last {
+            let decryptor = self.decryptor.take().unwrap();
+            decryptor.decrypt_last(payload)
+        } else {
+            decryptor.decrypt_next(payload)
+        };
+        self.plain = opened.map_err(|_| crypt_error(CryptError::WrongPassword))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
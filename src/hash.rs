@@ -1,8 +1,9 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use blake3::Hasher;
 use memmap2::MmapOptions;
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, copy};
+use std::io::{Read, Seek, SeekFrom, Write, copy};
 use std::path::Path;
 
 // --- Copied & Adapted from Vegh 0.4.0 src/hash.rs ---
@@ -106,3 +107,96 @@ pub fn compute_chunks(path: &Path, avg_size: usize) -> Result<([u8; 32], Vec<Chu
         Ok((hash, chunks))
     }
 }
+
+// BLAKE3 of everything written through it, so a snapshot's integrity hash
+// is known once it is written, without reading the file back
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Digest choices for whole-file hashes handed to users (`check_integrity`,
+// `hash_file`). Content addressing inside snapshots always uses BLAKE3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "blake3" => Ok(Self::Blake3),
+            "sha256" => Ok(Self::Sha256),
+            other => bail!(
+                "Invalid algorithm '{}' (expected 'blake3' or 'sha256')",
+                other
+            ),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+        }
+    }
+}
+
+// Hex digest of everything `reader` yields
+pub fn digest_reader(algorithm: Algorithm, reader: &mut impl Read) -> std::io::Result<String> {
+    match algorithm {
+        Algorithm::Blake3 => {
+            let mut hasher = Hasher::new();
+            copy(reader, &mut hasher)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        Algorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            copy(reader, &mut hasher)?;
+            Ok(hex::encode(hasher.finalize()))
+        }
+    }
+}
+
+// Hex digest of a file, memory-mapped when possible; BLAKE3 then hashes
+// across all cores
+pub fn digest_file(algorithm: Algorithm, path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let Ok(mmap) = (unsafe { MmapOptions::new().map(&file) }) else {
+        return digest_reader(algorithm, &mut file);
+    };
+    Ok(match algorithm {
+        Algorithm::Blake3 => {
+            let mut hasher = Hasher::new();
+            hasher.update_rayon(&mmap);
+            hasher.finalize().to_hex().to_string()
+        }
+        Algorithm::Sha256 => hex::encode(Sha256::digest(&mmap)),
+    })
+}
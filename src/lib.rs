@@ -1,31 +1,97 @@
+use anyhow::Context;
 use pyo3::exceptions::{PyIOError, PyValueError};
 use pyo3::prelude::*;
-use std::collections::HashMap;
+use pyo3::types::{PyBool, PyBytes};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufRead, Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
 
 // Declare modules so they are available to the crate
+pub mod codec;
 pub mod core;
+pub mod crypt;
 pub mod hash;
+pub mod names;
+pub mod offsets;
+pub mod sign;
 pub mod storage;
+pub mod volume;
 
-use crate::core::{create_snap_logic, restore_snap_logic};
-use crate::storage::{CACHE_DIR, SnapshotManifest};
-use ignore::{WalkBuilder, overrides::OverrideBuilder};
+use crate::codec::{
+    Codec, dictionary_unsupported, is_dictionary_error, is_unrecognized, open_decoder,
+    open_decoder_using,
+};
+use crate::core::{
+    AtomicRestore, CDC_AVG_SIZE, CancelHook, Cancelled, CaseCollision, Chunking, ExternalSymlinks,
+    FormatError, InvalidPatterns, MetadataPosition, MissingBase, Overwrite, ProgressHook,
+    RestoreConflict, RestoreOptions, SnapOptions, SnapProgress, SnapWriter, UnsafeEntry,
+    VeghMetadata, WalkSettings, backfill_manifest_logic, check_format_version, check_requirements,
+    create_multi_snap_logic, create_snap_from_map_logic, create_snap_logic,
+    create_snap_to_writer_logic, extract_metadata_logic, incremental_base, merge_snaps_logic,
+    metadata_json, new_metadata, override_builder, prune_by_policy_logic, prune_expired_logic,
+    read_metadata_from, recover_snap_logic, rename_entries_logic, resolve_chain_logic,
+    restore_chain_logic, restore_snap_from, restore_snap_logic, update_snap_logic,
+    validate_relative_path, verify_contents_logic, verify_snap_logic, verify_snapshot_logic,
+    visit_files,
+};
+use crate::crypt::is_crypt_error;
+use crate::hash::{Algorithm, compute_file_hash, digest_file, digest_reader};
+use crate::offsets::{IndexedReader, OFFSETS_ENTRY};
+use crate::sign::{sign_snapshot, verify_snapshot_signature};
+use crate::storage::{CACHE_DIR, ManifestEntry, ManifestFormat, SnapshotManifest};
+use crate::volume::{SnapshotFile, open_snapshot, remove_volumes, snapshot_hash, volume_path};
+use ignore::WalkBuilder;
+use pyo3::create_exception;
 
 // Constants synced with core/storage
 const PRESERVED_FILES: &[&str] = &[".veghignore", ".gitignore", ".npmignore", ".dockerignore"];
 
+create_exception!(vegh._core, VeghFormatError, PyValueError);
+
 // --- Helper Functions (Internal) ---
 
+/// Surfaces a manifest's version requirement through the io::Error-based helpers.
+fn manifest_requirements(manifest: &SnapshotManifest) -> Result<(), std::io::Error> {
+    check_requirements(manifest.min_tool_version.as_deref(), &manifest.features).map_err(
+        |e| match e.downcast::<FormatError>() {
+            Ok(fe) => std::io::Error::other(fe),
+            Err(e) => std::io::Error::other(e.to_string()),
+        },
+    )
+}
+
+/// Maps snapshot read errors, keeping `VeghFormatError` distinct from plain IO failures.
+fn read_err(e: std::io::Error) -> PyErr {
+    if is_unrecognized(&e) || is_crypt_error(&e) || is_dictionary_error(&e) {
+        return PyValueError::new_err(e.to_string());
+    }
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<FormatError>())
+    {
+        Some(fe) => VeghFormatError::new_err(fe.to_string()),
+        None => PyIOError::new_err(format!("Failed to read snapshot: {}", e)),
+    }
+}
+
 /// Helper to load snapshot content (works for both V2 legacy and V3 blobs).
 fn load_snapshot_data(
     file_path: &Path,
     filter_fn: impl Fn(&str) -> bool,
 ) -> Result<Vec<(String, Vec<u8>)>, std::io::Error> {
-    let file = File::open(file_path)?;
-    let decoder = zstd::stream::read::Decoder::new(file)?;
+    load_snapshot_data_using(file_path, None, filter_fn).map_err(dictionary_unsupported)
+}
+
+/// `load_snapshot_data` for callers that take a `dictionary`.
+fn load_snapshot_data_using(
+    file_path: &Path,
+    dictionary: Option<&[u8]>,
+    filter_fn: impl Fn(&str) -> bool,
+) -> Result<Vec<(String, Vec<u8>)>, std::io::Error> {
+    let file = open_snapshot(file_path)?;
+    let decoder = open_decoder_using(file, None, dictionary)?;
     let mut archive = tar::Archive::new(decoder);
 
     let mut blobs: HashMap<String, Vec<u8>> = HashMap::new();
@@ -37,9 +103,10 @@ fn load_snapshot_data(
         let mut entry = entry?;
         let path = entry.path()?.to_string_lossy().to_string();
 
-        if path == "manifest.json" {
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
             // Found the treasure map!
-            if let Ok(manifest) = serde_json::from_reader::<_, SnapshotManifest>(&mut entry) {
+            if let Ok(manifest) = format.decode(&mut entry) {
+                manifest_requirements(&manifest)?;
                 manifest_opt = Some(manifest);
             }
         } else if path.starts_with("blobs/") {
@@ -62,7 +129,7 @@ fn load_snapshot_data(
     if let Some(manifest) = manifest_opt {
         let mut reconstructed = Vec::new();
         for entry in manifest.entries {
-            if !filter_fn(&entry.path) {
+            if entry.symlink.is_some() || !filter_fn(&entry.path) {
                 continue;
             }
 
@@ -92,14 +159,147 @@ fn load_snapshot_data(
     }
 }
 
+/// A manifest entry's name as Python sees it: the bytes it had on disk,
+/// decoded like `os.fsdecode` would.
+fn entry_name(entry: &ManifestEntry) -> OsString {
+    names::decode_path(&entry.path, entry.escaped).into_os_string()
+}
+
+/// Substring include/exclude matching used by the snapshot-reading utilities.
+fn substring_filter(
+    path: &str,
+    include: &Option<Vec<String>>,
+    exclude: &Option<Vec<String>>,
+) -> bool {
+    if let Some(incs) = include
+        && !incs.iter().any(|inc| path.contains(inc.as_str()))
+    {
+        return false;
+    }
+    if let Some(excs) = exclude
+        && excs.iter().any(|exc| path.contains(exc.as_str()))
+    {
+        return false;
+    }
+    true
+}
+
+/// Header-level view of a snapshot: the manifest plus blob sizes, no bodies kept.
+struct SnapshotIndex {
+    metadata: Option<VeghMetadata>,
+    manifest: Option<SnapshotManifest>,
+    blob_sizes: HashMap<String, u64>,
+    /// V2 entries built from the tar headers (no content hash).
+    legacy: Vec<ManifestEntry>,
+}
+
+impl SnapshotIndex {
+    /// Manifest entries for V3, header-derived entries for V2.
+    fn into_entries(self) -> Vec<ManifestEntry> {
+        match self.manifest {
+            Some(manifest) => manifest.entries,
+            None => self.legacy,
+        }
+    }
+}
+
+fn read_snapshot_index(file_path: &Path) -> Result<SnapshotIndex, std::io::Error> {
+    let file = open_snapshot(file_path)?;
+    let decoder = open_decoder(file)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut index = SnapshotIndex {
+        metadata: None,
+        manifest: None,
+        blob_sizes: HashMap::new(),
+        legacy: Vec::new(),
+    };
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let size = entry.size();
+
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            index.manifest = format.decode(&mut entry).ok();
+            if let Some(manifest) = &index.manifest {
+                manifest_requirements(manifest)?;
+            }
+        } else if let Some(hash) = path.strip_prefix("blobs/") {
+            index.blob_sizes.insert(hash.to_string(), size);
+        } else if path == ".vegh.json" {
+            index.metadata = metadata_json(&mut entry)
+                .ok()
+                .and_then(|json| serde_json::from_slice(&json).ok());
+        } else if path != OFFSETS_ENTRY {
+            let header = entry.header();
+            index.legacy.push(ManifestEntry {
+                path,
+                size,
+                modified: header.mtime().unwrap_or(0),
+                mode: header.mode().unwrap_or(0),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(index)
+}
+
+// --- Portability Rules ---
+
+const WINDOWS_INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+// Legacy MAX_PATH, minus room for a short destination prefix
+const WINDOWS_MAX_PATH: usize = 260;
+const WINDOWS_MAX_COMPONENT: usize = 255;
+
+fn portability_problems(path: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if path.chars().count() > WINDOWS_MAX_PATH {
+        problems.push(format!("path longer than {} characters", WINDOWS_MAX_PATH));
+    }
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if let Some(c) = component
+            .chars()
+            .find(|c| WINDOWS_INVALID_CHARS.contains(c) || c.is_control())
+        {
+            problems.push(format!(
+                "'{}' contains invalid character {:?}",
+                component, c
+            ));
+        }
+        // Reserved names are reserved with any extension ("nul.txt")
+        let stem = component.split('.').next().unwrap_or(component);
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+        {
+            problems.push(format!("'{}' is a reserved device name", component));
+        }
+        if component.ends_with('.') || component.ends_with(' ') {
+            problems.push(format!("'{}' ends with a dot or space", component));
+        }
+        if component.chars().count() > WINDOWS_MAX_COMPONENT {
+            problems.push(format!(
+                "'{}...' longer than {} characters",
+                component.chars().take(16).collect::<String>(),
+                WINDOWS_MAX_COMPONENT
+            ));
+        }
+    }
+    problems
+}
+
 // --- FIX 1: Add helper to read snapshot files as text for SLOC analysis ---
 #[pyfunction]
 fn read_snapshot_text(file_path: String) -> PyResult<Vec<(String, String)>> {
     let path = Path::new(&file_path);
 
     // Reuse existing load_snapshot_data logic (Handles both V2 and V3/Blobs)
-    let files = load_snapshot_data(path, |_| true)
-        .map_err(|e| PyIOError::new_err(format!("Failed to read snapshot: {}", e)))?;
+    let files = load_snapshot_data(path, |_| true).map_err(read_err)?;
 
     let mut results = Vec::new();
     for (name, content) in files {
@@ -115,10 +315,309 @@ fn read_snapshot_text(file_path: String) -> PyResult<Vec<(String, String)>> {
     Ok(results)
 }
 
+/// Adapter that lets the Rust pipeline write into any Python object exposing `.write(bytes)`.
+struct PyWriter {
+    inner: Py<PyAny>,
+}
+
+impl Write for PyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Python::attach(|py| {
+            let written = self
+                .inner
+                .bind(py)
+                .call_method1("write", (PyBytes::new(py, buf),))
+                .map_err(std::io::Error::other)?;
+            // Some writers return None instead of a byte count; treat that as "all of it".
+            // A short count is a partial write and `write_all` resends the rest.
+            Ok(written
+                .extract::<usize>()
+                .map_or(buf.len(), |n| n.min(buf.len())))
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Python::attach(|py| {
+            let obj = self.inner.bind(py);
+            if obj.hasattr("flush").unwrap_or(false) {
+                obj.call_method0("flush").map_err(std::io::Error::other)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Adapter that reads from any Python object exposing `.read(n)`.
+struct PyReader {
+    inner: Py<PyAny>,
+}
+
+impl Read for PyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Python::attach(|py| {
+            let chunk = self
+                .inner
+                .bind(py)
+                .call_method1("read", (buf.len(),))
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            let chunk = chunk.cast::<PyBytes>().map_err(|_| {
+                std::io::Error::other("read() on the snapshot stream must return bytes")
+            })?;
+            let data = chunk.as_bytes();
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        })
+    }
+}
+
+/// Each Python call fetches this much, so decoders never pay per-byte calls.
+const PY_READ_BUFFER: usize = 1024 * 1024;
+
+/// Where a read function takes its snapshot from: a path, an in-memory
+/// `bytes` object, or a file-like object with `.read()`.
+enum SnapshotSource {
+    Path(PathBuf),
+    Bytes(std::sync::Arc<[u8]>),
+    Stream {
+        inner: Py<PyAny>,
+        opened: std::sync::atomic::AtomicBool,
+    },
+}
+
+impl SnapshotSource {
+    fn new(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if let Ok(bytes) = obj.cast::<PyBytes>() {
+            return Ok(Self::Bytes(bytes.as_bytes().into()));
+        }
+        if obj.hasattr("read")? {
+            return Ok(Self::Stream {
+                inner: obj.clone().unbind(),
+                opened: Default::default(),
+            });
+        }
+        obj.extract::<PathBuf>().map(Self::Path).map_err(|_| {
+            pyo3::exceptions::PyTypeError::new_err(
+                "expected a path, bytes or a file-like object with .read()",
+            )
+        })
+    }
+
+    /// Whether `open` may be called more than once. Streams are rewound with
+    /// `seek(0)`, so only seekable ones qualify.
+    fn can_reopen(&self) -> bool {
+        match self {
+            Self::Path(_) | Self::Bytes(_) => true,
+            Self::Stream { inner, .. } => Python::attach(|py| {
+                inner
+                    .bind(py)
+                    .call_method0("seekable")
+                    .and_then(|s| s.is_truthy())
+                    .unwrap_or(false)
+            }),
+        }
+    }
+
+    /// The raw snapshot bytes from the start.
+    fn open(&self) -> std::io::Result<Box<dyn Read>> {
+        match self {
+            Self::Path(path) => Ok(Box::new(open_snapshot(path)?)),
+            Self::Bytes(data) => Ok(Box::new(std::io::Cursor::new(data.clone()))),
+            Self::Stream { inner, opened } => {
+                if opened.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    if !self.can_reopen() {
+                        return Err(std::io::Error::other(
+                            "This stream can only be read once; pass bytes or a seekable file object",
+                        ));
+                    }
+                    Python::attach(|py| inner.bind(py).call_method1("seek", (0,)).map(drop))
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                }
+                let reader = Python::attach(|py| PyReader {
+                    inner: inner.clone_ref(py),
+                });
+                Ok(Box::new(std::io::BufReader::with_capacity(
+                    PY_READ_BUFFER,
+                    reader,
+                )))
+            }
+        }
+    }
+
+    /// How the snapshot is named in error messages.
+    fn name(&self) -> String {
+        match self {
+            Self::Path(path) => path.display().to_string(),
+            Self::Bytes(_) => "<bytes>".to_string(),
+            Self::Stream { .. } => "<stream>".to_string(),
+        }
+    }
+}
+
 // --- PyFunctions Wrappers ---
 
+/// Packs `source` into `output`.
+///
+/// `include` and `exclude` take gitignore-style globs. Once any `include` is
+/// given, only files matching one are packed. A file matching both is kept:
+/// includes win, so `exclude=["*.log"], include=["important.log", "*.py"]`
+/// keeps `important.log` and drops every other `.log`. A bad glob raises
+/// `ValueError`.
+///
+/// Patterns anchor like `.gitignore` rules: a leading `/` (or `./`) or a
+/// `/` inside ties one to the source root, so `exclude=["/node_modules"]`
+/// drops only the top-level directory while `"node_modules"` drops one at any
+/// depth. A pattern naming a directory covers everything in it, for
+/// `include` as well as `exclude`.
+///
+/// `verifier`, if given, is called as `verifier(output_path, integrity_hash)` once the
+/// archive is fully written, with the same hash `check_integrity` would return. A falsy
+/// return value vetoes the snapshot: the output is deleted and `ValueError` is raised.
+/// Exceptions raised by the verifier also delete the output and propagate unchanged.
+///
+/// `base` names the snapshot this one builds on; see `resolve_chain`.
+///
+/// `chunking="cdc"` (the default) cuts every file larger than `chunk_avg_size`
+/// (bytes, 1 KiB to 2 MiB, default 1 MiB) into content-defined chunks of roughly
+/// that size, ranging from half to twice it. Identical chunks are stored once, so
+/// a small edit to a large file only adds the chunks around it. The manifest
+/// lists each file's chunk hashes in order and restore concatenates them back.
+/// `chunking="file"` stores every file as a single blob.
+///
+/// Either way, files with identical content share one stored blob; restore still
+/// writes each of them out as an independent copy. `snapshot_stats` reports how
+/// many bytes this saved as `dedup_saved_bytes`.
+///
+/// `build_offset_index=True` also records where every entry sits in the
+/// decompressed stream, so `read_file_indexed` can fetch one file without
+/// reading the archive front to back. Other readers ignore the index.
+/// `seekable=True` additionally restarts the zstd stream every 4 MiB, so those
+/// reads only decompress from the nearest frame instead of from the start,
+/// at a small cost in ratio.
+///
+/// `progress`, if given, is called as `progress(files_done, bytes_done)` every
+/// `callback_interval` files (default 50) and once more at the end with the
+/// final totals. With `dedup_within=True` a third argument, `dedup_saved_bytes`,
+/// carries the running total of bytes skipped because their content was
+/// already stored. Returning `False` cancels the snapshot with `ValueError`;
+/// exceptions raised by it abort the snapshot and propagate unchanged. Either
+/// way the partial output is removed.
+///
+/// `codec` picks the compression around the tar stream: `"zstd"` (default,
+/// `level` -7 to 22; negative levels trade ratio for speed), `"gzip"`
+/// (`level` 1-9, readable by standard tar tooling) or `"none"` (plain tar,
+/// `level` ignored). Readers detect it from the file itself. A `level` outside
+/// the codec's range raises `ValueError` before anything is written.
+///
+/// `author` and `app_version` stamp who produced the snapshot (e.g. a user or
+/// CI job) into its metadata. `tool_version` stays the PyVegh version and
+/// `format_version` the snapshot layout version.
+///
+/// `max_depth` caps how deep the walk descends: `1` takes only the files
+/// directly in `source`, `2` also those one directory down, and so on.
+/// `respect_gitignore=False` archives gitignored files too and
+/// `include_hidden=True` takes dotfiles such as `.env`; `.veghignore` rules
+/// still apply either way.
+///
+/// `ignore_files` adds ignore file names read in every directory to the
+/// defaults (`.veghignore`, `.gitignore`, `.npmignore`, `.dockerignore`), e.g.
+/// a project-specific `.buildignore`. As with `.gitignore`, each file's
+/// rules cover its own directory and everything below it, never its parents:
+/// `src/.veghignore` only filters inside `src/`. `dry_run_snap` applies the
+/// same rules.
+///
+/// `min_size` and `max_size` (bytes, inclusive) leave out files outside that
+/// range, e.g. `max_size=50_000_000` to skip large media. Each one skipped is
+/// listed in the `return_warnings` messages.
+///
+/// `exclude_abs_paths` leaves out everything under those absolute paths; how
+/// many files that skipped is printed and also added to the `return_warnings`
+/// messages.
+///
+/// Other snapshots in the tree (`*.vegh` files and their `.001`-style volumes)
+/// are left out so archives do not nest; `exclude_snapshots=False` keeps them.
+///
+/// `split_bytes` writes the snapshot as numbered volumes `<output>.001`,
+/// `<output>.002`, ... of at most that many bytes each, cut from the
+/// compressed stream. Readers take the first volume's path and pick up the rest
+/// (it cannot be combined with `build_offset_index` or `seekable`).
+///
+/// `threads` sets the number of zstd compression threads (default: one per
+/// CPU; `1` or `0` for single-threaded). It relies on libzstd's multithread
+/// support, which the bundled build has; without it a warning is printed and
+/// compression runs on one thread.
+///
+/// `walk_threads` reads the source tree with that many threads instead of
+/// one, which helps on fast disks with many small files; hashing already runs
+/// on a worker per CPU. Files then reach the archive in no fixed order, unless
+/// `reproducible=True` puts them back in path order. Callbacks still run on
+/// the calling thread only.
+///
+/// `password` encrypts the whole snapshot (ChaCha20-Poly1305 under an Argon2id
+/// key); reading it back then needs the same password. It cannot be combined
+/// with `build_offset_index` or `seekable`.
+///
+/// Symlinks are stored as links and recreated by `restore_snap`;
+/// `follow_symlinks=True` archives their targets as regular files instead.
+///
+/// Directories are implied by the files inside them, so ones left with no
+/// files are not recorded. `preserve_empty_dirs=True` stores them as
+/// directory entries and `restore_snap` recreates them (e.g. a `logs/` the
+/// application expects to exist).
+///
+/// `interop=True` writes a strictly standard ustar/PAX archive, compressed
+/// with `codec`, that plain `tar` extracts into a clean tree (for zstd,
+/// `tar -I zstd -xf`). Files are stored in full under their own paths and
+/// `.vegh.json` travels in a PAX global header rather than as a file; every
+/// PyVegh reader, `restore_snap` included, still finds it. Dropped in this
+/// mode: blob dedup and chunking (each file is stored whole), the manifest and
+/// what needs it (hash checks on restore, `verify`, incremental snapshots),
+/// offset indexes and seekable frames, encryption, `metadata_position="last"`
+/// and `manifest_format="msgpack"`; combining any of them with it fails before
+/// anything is written.
+///
+/// Files that cannot be walked or read are skipped with a warning on stderr;
+/// `return_warnings=True` also hands those messages back, appended to the
+/// return value as a list. `strict=True` instead aborts with `IOError` on the
+/// first one.
+///
+/// `per_file_timeout_secs` gives up on a file whose first read (the one that
+/// hashes it) takes longer than that, and keeps going without it; the return
+/// value then also lists those files. A read stuck in the kernel cannot be
+/// cancelled, so its thread is left behind until the read returns. The second
+/// read that copies a changed file into the snapshot is not limited; it
+/// normally comes straight from the page cache.
+///
+/// `return_digest=True` returns `{count, digest, timed_out, warnings}`
+/// instead, where `digest` is the snapshot's integrity hash (what
+/// `check_integrity` reports), computed while the output was written rather
+/// than by reading it back. `verifier` gets the same hash.
+///
+/// `profile=True` returns that dict with a timing breakdown added, to tell a
+/// walk-, I/O- or CPU-bound snapshot apart: `walk_secs` (the directory walk),
+/// `hash_secs` (stat, read and hash on the workers, summed over them),
+/// `read_secs` (reading file contents into the archive), `compress_secs`
+/// (the codec, writing its output included) and `total_secs`, plus
+/// `bytes_in` (uncompressed tar bytes) and `bytes_out` (bytes written). The
+/// phases run concurrently, so they can add up to more than `total_secs`.
+///
+/// `dictionary` compresses with a zstd dictionary from `train_dictionary`,
+/// which pays off for many small, similar snapshots. Its hash is recorded in
+/// `.vegh.json` and every reader then needs the same bytes as `dictionary`:
+/// `restore_snap`, its filtered and subtree variants, `merge_snaps`,
+/// `update_snap`, the `verify_*` checks, `get_metadata`, `list_files`,
+/// `list_files_detailed`, `read_file_from_snap`, `cat_file`, `count_locs`,
+/// `snapshot_stats` and `diff_snaps` take it. Readers without a `dictionary`
+/// argument fail with `ValueError`.
+/// It needs the zstd codec and cannot be combined with `build_offset_index`,
+/// `seekable` or `interop`.
+///
+/// `reproducible=True` makes the output byte-identical for an unchanged tree:
+/// entries are written in path order, modes and tar headers are normalized and
+/// the metadata timestamp is `timestamp` (or `0` if not given). Files are
+/// hashed in parallel as usual but written only once the whole tree is read.
 #[pyfunction]
-#[pyo3(signature = (source, output, level=3, comment=None, include=None, exclude=None, no_cache=false, verbose=true))]
+#[pyo3(signature = (source, output, level=3, comment=None, include=None, exclude=None, no_cache=false, verbose=true, external_symlinks="strip", exclude_abs_paths=None, metadata_position="first", manifest_format="json", per_file_timeout_secs=None, verifier=None, keep_root_name=false, base=None, keep_until=None, chunking="cdc", chunk_avg_size=CDC_AVG_SIZE, build_offset_index=false, seekable=false, progress=None, dedup_within=false, author=None, app_version=None, callback_interval=None, codec="zstd", reproducible=None, timestamp=None, strict=None, return_warnings=false, follow_symlinks=None, max_depth=None, respect_gitignore=None, include_hidden=None, ignore_files=None, password=None, threads=None, min_size=None, max_size=None, split_bytes=None, exclude_snapshots=None, preserve_empty_dirs=None, return_digest=false, interop=None, walk_threads=None, profile=None, dictionary=None))]
 #[allow(clippy::too_many_arguments)]
 fn create_snap(
     source: String,
@@ -129,156 +628,2940 @@ fn create_snap(
     exclude: Option<Vec<String>>,
     no_cache: bool,
     verbose: bool, // Added flag to control UI output from Python
-) -> PyResult<usize> {
+    external_symlinks: &str,
+    exclude_abs_paths: Option<Vec<String>>,
+    metadata_position: &str,
+    manifest_format: &str,
+    per_file_timeout_secs: Option<f64>,
+    verifier: Option<Py<PyAny>>,
+    keep_root_name: bool,
+    base: Option<String>,
+    keep_until: Option<i64>,
+    chunking: &str,
+    chunk_avg_size: usize,
+    build_offset_index: bool,
+    seekable: bool,
+    progress: Option<Py<PyAny>>,
+    dedup_within: bool,
+    author: Option<String>,
+    app_version: Option<String>,
+    callback_interval: Option<usize>,
+    codec: &str,
+    reproducible: Option<bool>,
+    timestamp: Option<i64>,
+    strict: Option<bool>,
+    return_warnings: bool,
+    follow_symlinks: Option<bool>,
+    max_depth: Option<usize>,
+    respect_gitignore: Option<bool>,
+    include_hidden: Option<bool>,
+    ignore_files: Option<Vec<String>>,
+    password: Option<String>,
+    threads: Option<u32>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    split_bytes: Option<u64>,
+    exclude_snapshots: Option<bool>,
+    preserve_empty_dirs: Option<bool>,
+    return_digest: bool,
+    interop: Option<bool>,
+    walk_threads: Option<usize>,
+    profile: Option<bool>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Py<PyAny>> {
     let source_path = Path::new(&source);
     let output_path = Path::new(&output);
+    let external_symlinks = ExternalSymlinks::parse(external_symlinks)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let metadata_position = MetadataPosition::parse(metadata_position)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let manifest_format =
+        ManifestFormat::parse(manifest_format).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let chunking = Chunking::parse(chunking).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Chunking::check_avg_size(chunk_avg_size).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let codec = Codec::parse(codec).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    codec
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let mut walk = walk_settings(
+        max_depth,
+        respect_gitignore,
+        include_hidden,
+        ignore_files,
+        min_size,
+        max_size,
+        exclude_snapshots,
+    )?;
+    walk.threads = walk_threads;
+    walk.check()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    if let Some(pw) = &password {
+        if pw.is_empty() {
+            return Err(PyValueError::new_err("password must not be empty"));
+        }
+        if build_offset_index || seekable {
+            return Err(PyValueError::new_err(
+                "build_offset_index and seekable cannot be combined with a password",
+            ));
+        }
+    }
+    if callback_interval == Some(0) {
+        return Err(PyValueError::new_err(
+            "callback_interval must be at least 1",
+        ));
+    }
+    if let Some(limit) = split_bytes {
+        if limit == 0 {
+            return Err(PyValueError::new_err("split_bytes must be at least 1"));
+        }
+        if build_offset_index || seekable {
+            return Err(PyValueError::new_err(
+                "build_offset_index and seekable cannot be combined with split_bytes",
+            ));
+        }
+    }
+    if dictionary.is_some() {
+        if codec != Codec::Zstd {
+            return Err(PyValueError::new_err("dictionary needs codec=\"zstd\""));
+        }
+        if build_offset_index || seekable {
+            return Err(PyValueError::new_err(
+                "build_offset_index and seekable cannot be combined with a dictionary",
+            ));
+        }
+    }
+    let per_file_timeout = per_file_timeout_secs
+        .map(std::time::Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(format!("Invalid per_file_timeout_secs: {}", e)))?;
+
+    let opts = SnapOptions {
+        level,
+        comment,
+        author,
+        app_version,
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+        no_cache,
+        verbose,
+        external_symlinks,
+        exclude_abs_paths: exclude_abs_paths
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+        metadata_position,
+        manifest_format,
+        per_file_timeout,
+        keep_root_name,
+        base: base.map(PathBuf::from),
+        incremental: false,
+        keep_until,
+        chunking,
+        chunk_avg_size,
+        build_offset_index,
+        seekable,
+        progress_interval: callback_interval.unwrap_or(SnapOptions::default().progress_interval),
+        codec,
+        reproducible: reproducible.unwrap_or(false),
+        timestamp,
+        strict: strict.unwrap_or(false),
+        follow_symlinks: follow_symlinks.unwrap_or(false),
+        walk,
+        password,
+        compression_threads: threads,
+        split_bytes,
+        preserve_empty_dirs: preserve_empty_dirs.unwrap_or(false),
+        interop: interop.unwrap_or(false),
+        dictionary,
+    };
+
+    let mut progress_hook = progress.map(|cb| {
+        move |p: SnapProgress| -> anyhow::Result<()> {
+            Python::attach(|py| {
+                let verdict = if dedup_within {
+                    cb.call1(py, (p.files, p.bytes, p.dedup_saved_bytes))?
+                } else {
+                    cb.call1(py, (p.files, p.bytes))?
+                };
+                // Only an explicit False cancels; callbacks usually return None
+                if verdict.bind(py).is(PyBool::new(py, false)) {
+                    return Err(Cancelled.into());
+                }
+                Ok(())
+            })
+        }
+    });
+
+    let summary = create_snap_logic(
+        source_path,
+        output_path,
+        opts,
+        progress_hook.as_mut().map(|h| h as ProgressHook),
+    )
+    .map_err(core_err)?;
+
+    if let Some(cb) = verifier {
+        // Split snapshots are verified (and read back) through their first volume
+        let written = match split_bytes {
+            Some(_) => volume_path(output_path, 1).to_string_lossy().to_string(),
+            None => output.clone(),
+        };
+        let discard = || match split_bytes {
+            Some(_) => remove_volumes(output_path),
+            None => {
+                let _ = std::fs::remove_file(output_path);
+            }
+        };
+        let hash = summary.digest.map(hex::encode).unwrap_or_default();
+        let verdict = Python::attach(|py| cb.call1(py, (written.as_str(), hash))?.is_truthy(py));
+        match verdict {
+            Ok(true) => {}
+            Ok(false) => {
+                discard();
+                return Err(PyValueError::new_err(format!(
+                    "Snapshot '{}' rejected by verifier",
+                    output
+                )));
+            }
+            Err(e) => {
+                discard();
+                return Err(e);
+            }
+        }
+    }
+
+    // One report dict; profile adds its timings to it
+    let profile = profile.unwrap_or(false);
+    if return_digest || profile {
+        return Python::attach(|py| {
+            let report = SnapReport {
+                count: summary.count,
+                digest: summary.digest.map(hex::encode).unwrap_or_default(),
+                timed_out: summary.timed_out,
+                warnings: summary.warnings,
+            }
+            .into_pyobject(py)?;
+            if profile {
+                let timings = &summary.profile;
+                for (key, phase) in [
+                    ("walk_secs", timings.walk),
+                    ("hash_secs", timings.hash),
+                    ("read_secs", timings.read),
+                    ("compress_secs", timings.compress),
+                    ("total_secs", timings.total),
+                ] {
+                    report.set_item(key, phase.as_secs_f64())?;
+                }
+                report.set_item("bytes_in", timings.bytes_in)?;
+                report.set_item("bytes_out", timings.bytes_out)?;
+            }
+            Ok(report.into_any().unbind())
+        });
+    }
+
+    // With a timeout the caller also gets the files that were given up on,
+    // and with return_warnings the ones that could not be read
+    Python::attach(|py| {
+        let result = match (per_file_timeout.is_some(), return_warnings) {
+            (true, true) => (summary.count, summary.timed_out, summary.warnings)
+                .into_pyobject(py)?
+                .into_any(),
+            (true, false) => (summary.count, summary.timed_out)
+                .into_pyobject(py)?
+                .into_any(),
+            (false, true) => (summary.count, summary.warnings)
+                .into_pyobject(py)?
+                .into_any(),
+            (false, false) => summary.count.into_pyobject(py)?.into_any(),
+        };
+        Ok(result.unbind())
+    })
+}
+
+#[derive(IntoPyObject)]
+struct SnapReport {
+    count: usize,
+    digest: String,
+    timed_out: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// Packs only what changed in `source` since `base_snap`, a full snapshot:
+/// files whose content hash or mode differs from its manifest, new files, and
+/// a list of the base paths that are gone. The metadata's `base` holds the
+/// base's integrity hash; `restore_snap(..., base=base_snap)` or
+/// `restore_chain` rebuild the full tree. Returns the number of files stored.
+#[pyfunction]
+#[pyo3(signature = (source, output, base_snap, level=3, comment=None, include=None, exclude=None, no_cache=false, verbose=true))]
+#[allow(clippy::too_many_arguments)]
+fn create_incremental_snap(
+    py: Python<'_>,
+    source: String,
+    output: String,
+    base_snap: String,
+    level: i32,
+    comment: Option<String>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    no_cache: bool,
+    verbose: bool,
+) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let opts = SnapOptions {
+        level,
+        comment,
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+        no_cache,
+        verbose,
+        base: Some(PathBuf::from(base_snap)),
+        incremental: true,
+        ..Default::default()
+    };
+    py.detach(|| create_snap_logic(Path::new(&source), Path::new(&output), opts, None))
+        .map(|summary| summary.count)
+        .map_err(|e| {
+            if e.is::<FormatError>() || e.downcast_ref::<std::io::Error>().is_some() {
+                core_err(e)
+            } else {
+                PyValueError::new_err(format!("{:#}", e))
+            }
+        })
+}
+
+/// Packs several directories into one snapshot. `sources` is a list of
+/// `(source_path, archive_prefix)` pairs; each tree's files are stored under
+/// its prefix (`[("./src", "src"), ("/etc/app", "etc")]` gives `src/...` and
+/// `etc/...`), so prefixes must not repeat or nest. Ignore files and
+/// `include`/`exclude` patterns apply within each tree as they would for
+/// `create_snap`. The metadata lists the roots and prefixes under `sources`.
+/// Returns the number of files packed.
+#[pyfunction]
+#[pyo3(signature = (sources, output, level=3, comment=None, include=None, exclude=None, no_cache=false, verbose=true))]
+#[allow(clippy::too_many_arguments)]
+fn create_multi_snap(
+    py: Python<'_>,
+    sources: Vec<(String, String)>,
+    output: String,
+    level: i32,
+    comment: Option<String>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    no_cache: bool,
+    verbose: bool,
+) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let sources: Vec<(PathBuf, String)> = sources
+        .into_iter()
+        .map(|(source, prefix)| (PathBuf::from(source), prefix))
+        .collect();
+    let opts = SnapOptions {
+        level,
+        comment,
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+        no_cache,
+        verbose,
+        ..Default::default()
+    };
+    py.detach(|| create_multi_snap_logic(&sources, Path::new(&output), opts, None))
+        .map(|summary| summary.count)
+        .map_err(|e| {
+            if e.downcast_ref::<std::io::Error>().is_some() {
+                core_err(e)
+            } else {
+                PyValueError::new_err(format!("{:#}", e))
+            }
+        })
+}
+
+/// Per-file content check: `(path, still_matches)` for every file, comparing
+/// its reassembled content with the Blake3 digest recorded when it was packed.
+/// Blobs are staged in a temporary directory while chunked files are checked,
+/// so memory use stays flat. Encrypted snapshots need their `password`, ones
+/// compressed with a dictionary that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn verify_contents(
+    py: Python<'_>,
+    file_path: String,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Vec<(String, bool)>> {
+    py.detach(|| {
+        verify_contents_logic(
+            Path::new(&file_path),
+            password.as_deref(),
+            dictionary.as_deref(),
+        )
+    })
+    .map_err(core_err)
+}
+
+/// `IOError` for filesystem problems, `ValueError` for bad keys.
+fn sign_err(e: anyhow::Error) -> PyErr {
+    if e.downcast_ref::<std::io::Error>().is_some() {
+        PyIOError::new_err(format!("{:#}", e))
+    } else {
+        PyValueError::new_err(format!("{:#}", e))
+    }
+}
+
+/// Signs the snapshot's full compressed bytes with an Ed25519 private key
+/// (PKCS#8 PEM, e.g. from `openssl genpkey -algorithm ed25519`) and writes the
+/// detached signature to `<file_path>.sig`. Returns the sidecar path. Unlike
+/// `check_integrity`, this proves who produced the snapshot.
+#[pyfunction]
+fn sign_snap(py: Python<'_>, file_path: String, private_key_pem: String) -> PyResult<String> {
+    py.detach(|| sign_snapshot(Path::new(&file_path), &private_key_pem))
+        .map(|sidecar| sidecar.to_string_lossy().to_string())
+        .map_err(sign_err)
+}
+
+/// Whether `<file_path>.sig` is a valid signature of the snapshot by the
+/// holder of `public_key_pem` (a PEM Ed25519 public key). Any change to the
+/// snapshot makes this `False`; a missing sidecar raises `IOError`.
+#[pyfunction]
+fn verify_signature(py: Python<'_>, file_path: String, public_key_pem: String) -> PyResult<bool> {
+    py.detach(|| verify_snapshot_signature(Path::new(&file_path), &public_key_pem))
+        .map_err(sign_err)
+}
+
+/// Checks a snapshot's blobs and manifest without extracting it, the same
+/// scan as `verify_snap`. Raises `ValueError` describing the first problem
+/// found. `password` and `dictionary` are as for `verify_contents`.
+#[pyfunction]
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn verify_snapshot(
+    py: Python<'_>,
+    file_path: String,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<()> {
+    py.detach(|| {
+        verify_snapshot_logic(
+            Path::new(&file_path),
+            password.as_deref(),
+            dictionary.as_deref(),
+        )
+    })
+    .map_err(|e| match e.downcast::<FormatError>() {
+        Ok(fe) => VeghFormatError::new_err(fe.to_string()),
+        Err(e) => PyValueError::new_err(format!("{:#}", e)),
+    })
+}
+
+#[derive(IntoPyObject)]
+struct SnapVerification {
+    entries_ok: usize,
+    entries_total: usize,
+    errors: Vec<String>,
+}
+
+/// Dry restore: decodes the whole snapshot and re-hashes its contents without
+/// writing anything, returning `{entries_ok, entries_total, errors}`. Unlike
+/// `verify_snapshot` it keeps going after a problem and lists them all; a
+/// broken stream ends the scan with an error for it. `password` and
+/// `dictionary` are as for `verify_contents`.
+#[pyfunction]
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn verify_snap(
+    py: Python<'_>,
+    file_path: String,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<SnapVerification> {
+    let report = py
+        .detach(|| {
+            verify_snap_logic(
+                Path::new(&file_path),
+                password.as_deref(),
+                dictionary.as_deref(),
+            )
+        })
+        .map_err(core_err)?;
+    Ok(SnapVerification {
+        entries_ok: report.entries_ok,
+        entries_total: report.entries_total,
+        errors: report.errors,
+    })
+}
+
+#[derive(IntoPyObject)]
+struct SnapRecovery {
+    recovered: usize,
+    orphaned_blobs: usize,
+    lost: Vec<OsString>,
+    stopped_at: Option<u64>,
+    error: Option<String>,
+}
+
+/// Salvages an interrupted or damaged snapshot into `out_dir` instead of
+/// failing outright, returning `{recovered, orphaned_blobs, lost,
+/// stopped_at, error}`.
+///
+/// **Default (V3) snapshots cannot be recovered by name.** Their file names
+/// live in the manifest, which is written last, so a snapshot cut off
+/// anywhere before its end gives back content only: the surviving blobs,
+/// without paths. Only snapshots that store files under their own paths
+/// (`interop=True`, or ones from before the V3 layout) come back file by file.
+///
+/// The stream is decoded as far as it goes and every entry that came through
+/// whole is written; `recovered` counts the files. `stopped_at` is how far
+/// into the decoded stream reading got and `error` why it stopped, both
+/// `None` for an intact snapshot. Files whose blobs were cut off are listed
+/// in `lost`. When the manifest itself was lost, the surviving blobs are
+/// saved as `out_dir/blobs/<hash>` and counted in `orphaned_blobs`.
+#[pyfunction]
+#[pyo3(signature = (file_path, out_dir, password=None))]
+fn recover_snap(
+    py: Python<'_>,
+    file_path: String,
+    out_dir: String,
+    password: Option<String>,
+) -> PyResult<SnapRecovery> {
+    let report = py
+        .detach(|| {
+            recover_snap_logic(
+                Path::new(&file_path),
+                Path::new(&out_dir),
+                password.as_deref(),
+            )
+        })
+        .map_err(core_err)?;
+    Ok(SnapRecovery {
+        recovered: report.recovered,
+        orphaned_blobs: report.orphaned_blobs,
+        lost: report
+            .lost
+            .into_iter()
+            .map(PathBuf::into_os_string)
+            .collect(),
+        stopped_at: report.stopped_at,
+        error: report.error,
+    })
+}
+
+fn find_snapshots(dir: &Path, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_snapshots(&path, found)?;
+        } else if path.extension().is_some_and(|ext| ext == "vegh") {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Verifies every `.vegh` file under `dir` on a pool of `threads` workers
+/// (defaults to the CPU count) with the GIL released. Returns
+/// `(path, ok, error)` per snapshot, sorted by path.
+#[pyfunction]
+#[pyo3(signature = (dir, threads=None))]
+fn verify_all(
+    py: Python<'_>,
+    dir: String,
+    threads: Option<usize>,
+) -> PyResult<Vec<(String, bool, Option<String>)>> {
+    let mut snapshots = Vec::new();
+    find_snapshots(Path::new(&dir), &mut snapshots)
+        .map_err(|e| PyIOError::new_err(format!("Failed to scan '{}': {}", dir, e)))?;
+    snapshots.sort();
+
+    let threads = threads
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+        .clamp(1, snapshots.len().max(1));
+
+    let results = py.detach(|| {
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let slots: Vec<std::sync::Mutex<Option<Result<(), String>>>> = snapshots
+            .iter()
+            .map(|_| std::sync::Mutex::new(None))
+            .collect();
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    loop {
+                        let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some(path) = snapshots.get(i) else { break };
+                        let res =
+                            verify_snapshot_logic(path, None, None).map_err(|e| format!("{:#}", e));
+                        *slots[i].lock().unwrap() = Some(res);
+                    }
+                });
+            }
+        });
+        slots
+            .into_iter()
+            .map(|slot| slot.into_inner().unwrap().unwrap_or(Ok(())))
+            .collect::<Vec<_>>()
+    });
+
+    Ok(snapshots
+        .into_iter()
+        .zip(results)
+        .map(|(path, res)| {
+            let path = path.to_string_lossy().to_string();
+            match res {
+                Ok(()) => (path, true, None),
+                Err(e) => (path, false, Some(e)),
+            }
+        })
+        .collect())
+}
+
+/// Search directories for chain bases; defaults to the snapshot's own directory.
+fn chain_search_dirs(file_path: &str, search_dirs: Option<Vec<String>>) -> Vec<PathBuf> {
+    match search_dirs {
+        Some(dirs) => dirs.into_iter().map(PathBuf::from).collect(),
+        None => {
+            let parent = Path::new(file_path).parent().unwrap_or(Path::new(""));
+            vec![if parent.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                parent.to_path_buf()
+            }]
+        }
+    }
+}
+
+/// Snapshots needed to rebuild `file_path`, oldest base first.
+#[pyfunction]
+#[pyo3(signature = (file_path, search_dirs=None))]
+fn resolve_chain(file_path: String, search_dirs: Option<Vec<String>>) -> PyResult<Vec<String>> {
+    let dirs = chain_search_dirs(&file_path, search_dirs);
+    resolve_chain_logic(Path::new(&file_path), &dirs)
+        .map(|chain| {
+            chain
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        })
+        .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+}
+
+/// Restores `file_path` and all its bases into `out_dir`, oldest first.
+/// Returns the applied chain.
+#[pyfunction]
+#[pyo3(signature = (file_path, out_dir, search_dirs=None))]
+fn restore_chain(
+    file_path: String,
+    out_dir: String,
+    search_dirs: Option<Vec<String>>,
+) -> PyResult<Vec<String>> {
+    let dirs = chain_search_dirs(&file_path, search_dirs);
+    restore_chain_logic(Path::new(&file_path), Path::new(&out_dir), &dirs)
+        .map(|chain| {
+            chain
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        })
+        .map_err(core_err)
+}
+
+/// Adds a manifest to a legacy V2 snapshot so hash-based tools work on it.
+/// Returns the number of files hashed.
+#[pyfunction]
+#[pyo3(signature = (input_path, output_path, level=3))]
+fn backfill_manifest(input_path: String, output_path: String, level: i32) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    backfill_manifest_logic(Path::new(&input_path), Path::new(&output_path), level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Builds a snapshot purely from memory: `files` maps safe relative paths to bytes.
+#[pyfunction]
+#[pyo3(signature = (files, output, level=3, comment=None))]
+fn create_snap_from_map(
+    files: BTreeMap<String, Vec<u8>>,
+    output: String,
+    level: i32,
+    comment: Option<String>,
+) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    create_snap_from_map_logic(&files, Path::new(&output), level, comment)
+        .map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn writer_err(e: anyhow::Error) -> PyErr {
+    if e.downcast_ref::<std::io::Error>().is_some() {
+        core_err(e)
+    } else {
+        PyValueError::new_err(format!("{:#}", e))
+    }
+}
+
+/// Builds a snapshot at `output` entry by entry, for files generated on the
+/// fly. The result is a regular snapshot, readable and restorable like one
+/// from `create_snap`:
+///
+/// ```python
+/// with SnapshotWriter("out.vegh", comment="generated") as snap:
+///     snap.add_bytes("config/app.json", b"{}")
+///     snap.add_file("assets/logo.png", "/tmp/render/logo.png")
+/// ```
+///
+/// Leaving the `with` block calls `finish()`, unless it raised, in which case
+/// the partial file is deleted. Without `with`, call `finish()` yourself;
+/// a writer garbage-collected unfinished also deletes its output. Paths must
+/// be safe relative paths, each added once.
+#[pyclass(name = "SnapshotWriter")]
+struct SnapshotWriter {
+    inner: SnapWriter,
+}
+
+#[pymethods]
+impl SnapshotWriter {
+    #[new]
+    #[pyo3(signature = (output, level=3, comment=None))]
+    fn new(output: String, level: i32, comment: Option<String>) -> PyResult<Self> {
+        let inner = SnapWriter::create(Path::new(&output), level, comment).map_err(writer_err)?;
+        Ok(Self { inner })
+    }
+
+    /// Adds `data` as the file at `arc_path`, stamped with the current time.
+    fn add_bytes(&mut self, arc_path: &str, data: &[u8]) -> PyResult<()> {
+        self.inner.add_bytes(arc_path, data).map_err(writer_err)
+    }
+
+    /// Adds the file at `fs_path` as `arc_path`, keeping its mtime and mode.
+    /// The file is streamed in, not loaded, with the GIL released.
+    fn add_file(&mut self, py: Python<'_>, arc_path: &str, fs_path: PathBuf) -> PyResult<()> {
+        py.detach(|| self.inner.add_file(arc_path, &fs_path))
+            .map_err(writer_err)
+    }
+
+    /// Writes the manifest and closes the snapshot. Returns the number of files.
+    fn finish(&mut self) -> PyResult<usize> {
+        self.inner.finish().map_err(writer_err)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (exc_type, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        if exc_type.is_some_and(|t| !t.is_none()) {
+            self.inner.abort();
+        } else if !self.inner.is_finished() {
+            self.inner.finish().map_err(writer_err)?;
+        }
+        Ok(false)
+    }
+}
+
+/// Same entry set and metadata as `create_snap`, but emitted as a plain
+/// (uncompressed) tar stream into a Python writer, e.g. `sys.stdout.buffer`:
+/// `create_snap_to_writer` with `codec="none"`.
+#[pyfunction]
+#[pyo3(signature = (source, writer, comment=None, include=None, exclude=None, no_cache=false, verbose=false))]
+fn create_tar_to_writer(
+    source: String,
+    writer: Py<PyAny>,
+    comment: Option<String>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    no_cache: bool,
+    verbose: bool,
+) -> PyResult<usize> {
+    create_snap_to_writer(
+        source, writer, 3, comment, include, exclude, no_cache, verbose, "none",
+    )
+}
+
+/// `create_snap` into a Python writer (anything with `.write(bytes)`, such as
+/// a socket file or `io.BytesIO`) instead of a file, so nothing touches disk.
+/// Returns the number of files packed. An exception raised by the writer
+/// aborts the snapshot and propagates as-is; other failures raise `IOError`.
+#[pyfunction]
+#[pyo3(signature = (source, writer, level=3, comment=None, include=None, exclude=None, no_cache=false, verbose=false, codec="zstd"))]
+#[allow(clippy::too_many_arguments)]
+fn create_snap_to_writer(
+    source: String,
+    writer: Py<PyAny>,
+    level: i32,
+    comment: Option<String>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    no_cache: bool,
+    verbose: bool,
+    codec: &str,
+) -> PyResult<usize> {
+    let source_path = Path::new(&source);
+    let codec = Codec::parse(codec).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    codec
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+    let opts = SnapOptions {
+        level,
+        comment,
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+        no_cache,
+        verbose,
+        codec,
+        ..Default::default()
+    };
+
+    create_snap_to_writer_logic(source_path, PyWriter { inner: writer }, None, opts, None)
+        .map(|summary| summary.count)
+        .map_err(|e| writer_exception(&e).unwrap_or_else(|| PyIOError::new_err(e.to_string())))
+}
+
+/// The exception a `PyWriter` failed with, if that is what stopped `e`.
+fn writer_exception(e: &anyhow::Error) -> Option<PyErr> {
+    let py_err = e.chain().find_map(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()?
+            .get_ref()?
+            .downcast_ref::<PyErr>()
+    })?;
+    Some(Python::attach(|py| py_err.clone_ref(py)))
+}
+
+/// Restores `file_path` into `out_dir` and returns how many files were written.
+///
+/// `include` limits the restore to matching entries: glob patterns (`*.py`,
+/// `src/**/*.rs`) match anywhere in the tree, plain paths select by prefix.
+///
+/// Snapshots written by a newer pyvegh with an incompatible format version are
+/// refused unless `force=True`. So are snapshots holding paths that differ
+/// only in case (`README.md` and `readme.md`), which would overwrite each
+/// other on macOS and Windows. Files get their recorded mtime back unless
+/// `preserve_mtime=False`, and their recorded Unix permissions (so scripts stay
+/// executable) unless `preserve_permissions=False`, which makes every file
+/// `0o644`. Encrypted snapshots need their `password`.
+///
+/// `overwrite` decides what happens to files already in `out_dir`: `"always"`
+/// replaces them, `"never"` raises `ValueError` listing the conflicts before
+/// anything is written, and `"skip"` leaves them untouched. The count returned
+/// is of files written, so skipped ones are left out of it (`callback` still
+/// counts them as done).
+///
+/// `file_path` may also be the snapshot as `bytes` or a file-like object with
+/// `.read()`. Streams that cannot seek are read in a single pass, so the
+/// format version check is skipped and `overwrite="never"` is unavailable.
+///
+/// `callback`, if given, is called as `callback(files_done, bytes_done)` every
+/// `callback_interval` files (default 50) and once more at the end, like
+/// `create_snap`'s `progress`. Skipped files count as done; `bytes_done` is
+/// what was written. Returning `False` cancels the restore with `ValueError`.
+/// The GIL is only held while a callback runs.
+///
+/// `atomic=True` restores into a temporary directory next to `out_dir` and
+/// renames it into place only once every entry was written; on any error the
+/// temporary directory is deleted and `out_dir` is left as it was. An existing
+/// `out_dir` is renamed aside for the swap, and the files the snapshot does
+/// not replace or delete are moved over into the new tree first, so `out_dir`
+/// must be a directory that can be renamed (not a mount point) and other
+/// processes briefly see it missing. `overwrite` must stay `"always"` with
+/// it.
+///
+/// `strip_components` drops that many leading directories from every entry
+/// path and `add_prefix` then nests the result under a relative directory,
+/// like tar's `--strip-components` and `--transform`. `include` still matches
+/// the paths as archived; entries stripped to nothing are skipped. An entry
+/// whose archived path is unsafe raises `ValueError` even if stripping would
+/// hide it.
+///
+/// `check_space=True` first sums the sizes the restore will write from the
+/// entry headers and manifest (bodies are skipped, not extracted), and raises
+/// `IOError` before writing anything if the target filesystem has less free
+/// space than that plus a margin (5%, at least 16 MiB). V3 snapshots count
+/// their blobs too, as they sit in a staging directory until the files are
+/// rebuilt; with `include`, only the blobs the selected files use are staged
+/// (and counted) unless the snapshot is a stream that cannot seek. Like
+/// `overwrite="never"` it needs a second pass, so it is unavailable for
+/// streams that cannot seek. The check is skipped where the platform does not
+/// report free space.
+///
+/// Snapshots created with a `dictionary` need the same one here; without it,
+/// or with another one, `ValueError` is raised. An incremental snapshot's
+/// `base` is read with it as well.
+///
+/// Incremental snapshots (see `create_incremental_snap`) need `base`, the path
+/// of the snapshot they were made against; its integrity hash must match the
+/// one recorded, or `ValueError` is raised. The base is restored first, then
+/// the changes and deletions on top, and the count covers both. `callback`
+/// then reports each of the two passes from zero. `overwrite` must stay
+/// `"always"` for them.
+#[pyfunction]
+#[pyo3(signature = (file_path, out_dir, include=None, flatten=false, should_cancel=None, verify=false, force=None, preserve_mtime=None, password=None, overwrite=None, callback=None, callback_interval=None, preserve_permissions=None, base=None, atomic=None, strip_components=None, add_prefix=None, check_space=None, dictionary=None))]
+#[allow(clippy::too_many_arguments)]
+fn restore_snap(
+    py: Python<'_>,
+    file_path: Bound<'_, PyAny>,
+    out_dir: String,
+    include: Option<Vec<String>>,
+    flatten: bool,
+    should_cancel: Option<Py<PyAny>>,
+    verify: bool,
+    force: Option<bool>,
+    preserve_mtime: Option<bool>,
+    password: Option<String>,
+    overwrite: Option<String>,
+    callback: Option<Py<PyAny>>,
+    callback_interval: Option<usize>,
+    preserve_permissions: Option<bool>,
+    base: Option<String>,
+    atomic: Option<bool>,
+    strip_components: Option<usize>,
+    add_prefix: Option<String>,
+    check_space: Option<bool>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<usize> {
+    if callback_interval == Some(0) {
+        return Err(PyValueError::new_err(
+            "callback_interval must be at least 1",
+        ));
+    }
+    let source = SnapshotSource::new(&file_path)?;
+    let output_path = Path::new(&out_dir);
+    let overwrite_mode = overwrite
+        .as_deref()
+        .map(Overwrite::parse)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    if let Some(prefix) = &add_prefix {
+        validate_relative_path(prefix.trim_end_matches('/'))
+            .map_err(|e| PyValueError::new_err(format!("Invalid add_prefix: {}", e)))?;
+    }
+    let atomic = atomic.unwrap_or(false);
+    if atomic && overwrite_mode.is_some_and(|mode| mode != Overwrite::Always) {
+        return Err(PyValueError::new_err(
+            "atomic restores need overwrite=\"always\"",
+        ));
+    }
+    ensure_readable_version(
+        &source,
+        force.unwrap_or(false),
+        password.as_deref(),
+        dictionary.as_deref(),
+    )?;
+
+    // Streams that cannot be rewound are checked by the restore itself
+    let needs_base = if source.can_reopen() {
+        source
+            .open()
+            .map_err(anyhow::Error::from)
+            .and_then(|raw| {
+                read_metadata_from(
+                    raw,
+                    password.as_deref(),
+                    dictionary.as_deref(),
+                    &source.name(),
+                )
+            })
+            .ok()
+            .and_then(|meta| incremental_base(&meta))
+    } else {
+        None
+    };
+    let base = match (needs_base, base) {
+        (Some(expected), Some(base)) => {
+            let actual = snapshot_hash(Path::new(&base))
+                .map(hex::encode)
+                .map_err(|e| PyIOError::new_err(format!("Base snapshot unreadable: {}", e)))?;
+            if actual != expected {
+                return Err(PyValueError::new_err(format!(
+                    "Base '{}' does not match: the snapshot was made against {}, this is {}",
+                    base, expected, actual
+                )));
+            }
+            if overwrite_mode.is_some_and(|mode| mode != Overwrite::Always) {
+                return Err(PyValueError::new_err(
+                    "overwrite must be \"always\" for incremental snapshots",
+                ));
+            }
+            Some(base)
+        }
+        (Some(expected), None) => {
+            return Err(PyValueError::new_err(
+                MissingBase((!expected.is_empty()).then_some(expected)).to_string(),
+            ));
+        }
+        (None, Some(base)) => {
+            return Err(PyValueError::new_err(format!(
+                "base='{}' was given but the snapshot is not incremental",
+                base
+            )));
+        }
+        (None, None) => None,
+    };
+
+    let make_opts = |base_restored: bool| RestoreOptions {
+        include: include.clone(),
+        flatten,
+        verify,
+        preserve_mtime: preserve_mtime.unwrap_or(true),
+        password: password.clone(),
+        overwrite: overwrite_mode.unwrap_or(Overwrite::Always),
+        allow_case_collisions: force.unwrap_or(false),
+        progress_interval: callback_interval.unwrap_or(RestoreOptions::default().progress_interval),
+        preserve_permissions: preserve_permissions.unwrap_or(true),
+        base_restored,
+        strip_components: strip_components.unwrap_or(0),
+        add_prefix: add_prefix.clone(),
+        check_space: check_space.unwrap_or(false),
+        dictionary: dictionary.clone(),
+        selective_staging: source.can_reopen(),
+    };
+
+    let mut cancel_hook = should_cancel.map(|cb| {
+        move || -> anyhow::Result<bool> { Python::attach(|py| Ok(cb.call0(py)?.is_truthy(py)?)) }
+    });
+    let mut progress_hook = callback.map(|cb| {
+        move |p: SnapProgress| -> anyhow::Result<()> {
+            Python::attach(|py| {
+                let verdict = cb.call1(py, (p.files, p.bytes))?;
+                if verdict.bind(py).is(PyBool::new(py, false)) {
+                    return Err(Cancelled.into());
+                }
+                Ok(())
+            })
+        }
+    });
+
+    let staged = if atomic {
+        Some(AtomicRestore::new(output_path).map_err(core_err)?)
+    } else {
+        None
+    };
+    let target = staged
+        .as_ref()
+        .map_or(output_path.to_path_buf(), |s| s.path().to_path_buf());
+
+    let mut open = || source.open().context("Open failed");
+    let summary = py
+        .detach(|| {
+            let mut restored = 0;
+            if let Some(base) = &base {
+                restored += restore_snap_logic(
+                    Path::new(base),
+                    &target,
+                    make_opts(false),
+                    cancel_hook.as_mut().map(|h| h as CancelHook),
+                    progress_hook.as_mut().map(|h| h as ProgressHook),
+                )
+                .with_context(|| format!("Restoring base {} failed", base))?
+                .restored;
+            }
+            let mut summary = restore_snap_from(
+                &mut open,
+                &target,
+                make_opts(base.is_some()),
+                cancel_hook.as_mut().map(|h| h as CancelHook),
+                progress_hook.as_mut().map(|h| h as ProgressHook),
+                None,
+            )?;
+            summary.restored += restored;
+            if let Some(staged) = staged {
+                staged.commit(&summary.deleted)?;
+            }
+            anyhow::Ok(summary)
+        })
+        .map_err(core_err)?;
+    Ok(summary.restored)
+}
+
+/// Restores `file_path` into `out_dir`, asking `filter_cb(path, size)` about
+/// each file before it is unpacked, and returns how many files were written.
+///
+/// `filter_cb` returns `"write"` to restore the file or `"skip"` to leave it
+/// out; anything else raises `ValueError`. An exception raised by `filter_cb`
+/// aborts the restore and propagates as-is. Files already written stay on
+/// disk. Directories are created as needed and never asked about.
+///
+/// The GIL is only held while `filter_cb` runs. Encrypted snapshots need
+/// their `password`, ones compressed with a dictionary that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, out_dir, filter_cb, password=None, dictionary=None))]
+fn restore_snap_filtered(
+    py: Python<'_>,
+    file_path: Bound<'_, PyAny>,
+    out_dir: String,
+    filter_cb: Py<PyAny>,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<usize> {
+    let source = SnapshotSource::new(&file_path)?;
+    ensure_readable_version(&source, false, password.as_deref(), dictionary.as_deref())?;
+    let opts = RestoreOptions {
+        password,
+        dictionary,
+        ..Default::default()
+    };
+
+    let mut filter = move |path: &str, size: u64| -> anyhow::Result<bool> {
+        Python::attach(|py| {
+            let verdict = filter_cb.call1(py, (path, size))?;
+            match verdict.extract::<String>(py).ok().as_deref() {
+                Some("write") => Ok(true),
+                Some("skip") => Ok(false),
+                _ => Err(PyValueError::new_err(format!(
+                    "filter_cb must return \"write\" or \"skip\", got {}",
+                    verdict.bind(py).repr()?
+                ))
+                .into()),
+            }
+        })
+    };
+    let mut open = || source.open().context("Open failed");
+    py.detach(|| {
+        restore_snap_from(
+            &mut open,
+            Path::new(&out_dir),
+            opts,
+            None,
+            None,
+            Some(&mut filter),
+        )
+    })
+    .map(|summary| summary.restored)
+    .map_err(core_err)
+}
+
+/// Restores only what sits under `subtree` (e.g. `"dist"`), with that prefix
+/// removed, so `dist/app.js` lands at `out_dir/app.js`. Returns how many files
+/// were written; `ValueError` if none are under `subtree`.
+///
+/// Files elsewhere in legacy (V2) snapshots are skipped without decoding
+/// their contents. V3 blobs are shared between paths, so only those the
+/// subtree's files use are staged, unless the snapshot is a stream that
+/// cannot seek and every blob is.
+/// Encrypted snapshots need their `password`, ones compressed with a
+/// dictionary that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, subtree, out_dir, password=None, dictionary=None))]
+fn restore_subtree(
+    py: Python<'_>,
+    file_path: Bound<'_, PyAny>,
+    subtree: String,
+    out_dir: String,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<usize> {
+    let subtree = subtree.trim_matches('/');
+    validate_relative_path(subtree)
+        .map_err(|e| PyValueError::new_err(format!("Invalid subtree: {}", e)))?;
+    let source = SnapshotSource::new(&file_path)?;
+    ensure_readable_version(&source, false, password.as_deref(), dictionary.as_deref())?;
+    let opts = RestoreOptions {
+        include: Some(vec![format!("{}/", subtree)]),
+        strip_components: subtree.split('/').count(),
+        password,
+        dictionary,
+        selective_staging: source.can_reopen(),
+        ..Default::default()
+    };
+
+    let output_path = Path::new(&out_dir);
+    let existed = output_path.exists();
+    let mut open = || source.open().context("Open failed");
+    let restored = py
+        .detach(|| restore_snap_from(&mut open, output_path, opts, None, None, None))
+        .map_err(core_err)?
+        .restored;
+    if restored == 0 {
+        if !existed {
+            // Only succeeds while nothing was written into it
+            let _ = std::fs::remove_dir(output_path);
+        }
+        return Err(PyValueError::new_err(format!(
+            "Nothing in the snapshot is under '{}/'",
+            subtree
+        )));
+    }
+    Ok(restored)
+}
+
+/// Raises `ValueError` when `input` was written with a format version newer
+/// than this build reads. Snapshots without readable metadata predate format
+/// versions and are let through; the reader reports anything else wrong.
+/// Streams that cannot be rewound are not checked, since that would use up
+/// the one pass the caller needs.
+fn ensure_readable_version(
+    input: &SnapshotSource,
+    force: bool,
+    password: Option<&str>,
+    dictionary: Option<&[u8]>,
+) -> PyResult<()> {
+    if force || !input.can_reopen() {
+        return Ok(());
+    }
+    let meta = input
+        .open()
+        .map_err(anyhow::Error::from)
+        .and_then(|raw| read_metadata_from(raw, password, dictionary, &input.name()));
+    match meta {
+        Ok(meta) => check_format_version(&meta.format_version)
+            .map_err(|e| PyValueError::new_err(format!("{}; pass force=True to try anyway", e))),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Maps core errors to Python: callback exceptions are re-raised as-is,
+/// cancellation, entries escaping the output directory, refused overwrites,
+/// case-insensitive path collisions,
+/// files that are not snapshots at all and missing or wrong passwords or
+/// dictionaries become a `ValueError`, unreadable snapshot features a `VeghFormatError`,
+/// everything else an `IOError`.
+fn core_err(e: anyhow::Error) -> PyErr {
+    if e.is::<FormatError>() {
+        return VeghFormatError::new_err(e.to_string());
+    }
+    if e.is::<Cancelled>()
+        || e.is::<UnsafeEntry>()
+        || e.is::<RestoreConflict>()
+        || e.is::<CaseCollision>()
+        || e.is::<InvalidPatterns>()
+        || e.is::<MissingBase>()
+    {
+        return PyValueError::new_err(e.to_string());
+    }
+    if e.downcast_ref::<std::io::Error>()
+        .is_some_and(|io| is_unrecognized(io) || is_crypt_error(io) || is_dictionary_error(io))
+    {
+        return PyValueError::new_err(e.to_string());
+    }
+    match e.downcast::<PyErr>() {
+        Ok(py_err) => py_err,
+        Err(e) => PyIOError::new_err(e.to_string()),
+    }
+}
+
+/// Combines `inputs` into one snapshot at `output` without extracting them,
+/// with a fresh `.vegh.json` for the result. A path found in two inputs raises
+/// `ValueError`, unless `prefix_with_source=True` nests every input's entries
+/// under its file stem (`api.vegh` -> `api/...`). Returns the entry count.
+///
+/// Incremental inputs raise `ValueError`: they hold only what changed since
+/// their base. `password` opens encrypted inputs and encrypts the result;
+/// `dictionary` opens inputs compressed with one (the result does not use it).
+#[pyfunction]
+#[pyo3(signature = (inputs, output, prefix_with_source=None, level=3, password=None, dictionary=None))]
+fn merge_snaps(
+    py: Python<'_>,
+    inputs: Vec<String>,
+    output: String,
+    prefix_with_source: Option<bool>,
+    level: i32,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    if password.as_deref() == Some("") {
+        return Err(PyValueError::new_err("password must not be empty"));
+    }
+    let inputs: Vec<PathBuf> = inputs.into_iter().map(PathBuf::from).collect();
+    py.detach(|| {
+        merge_snaps_logic(
+            &inputs,
+            Path::new(&output),
+            prefix_with_source.unwrap_or(false),
+            level,
+            password.as_deref(),
+            dictionary.as_deref(),
+        )
+    })
+    .map_err(|e| {
+        if e.is::<FormatError>() || e.downcast_ref::<std::io::Error>().is_some() {
+            core_err(e)
+        } else {
+            PyValueError::new_err(format!("{:#}", e))
+        }
+    })
+}
+
+/// Rewrites the snapshot at `file_path` in place with `changed_paths` (relative
+/// to `source`) swapped in: changed files replace their entries, new files are
+/// added and paths missing from `source` are removed. Unchanged entries are
+/// copied across as stored, so only the changed files are read and hashed.
+/// The metadata comment and `parent` field record the previous snapshot's
+/// integrity hash. Returns the number of entries in the updated snapshot.
+///
+/// The snapshot keeps its codec (`level` is on that codec's scale) and its
+/// changed files are chunked as `create_snap` does. Changed symlinks follow
+/// `create_snap`'s default `external_symlinks="strip"`: a link pointing
+/// outside `source` is dropped, not recorded. An encrypted snapshot
+/// needs its `password` and stays encrypted; one compressed with a zstd
+/// `dictionary` needs it too.
+#[pyfunction]
+#[pyo3(signature = (file_path, source, changed_paths, level=3, password=None, dictionary=None))]
+fn update_snap(
+    py: Python<'_>,
+    file_path: String,
+    source: String,
+    changed_paths: Vec<String>,
+    level: i32,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<usize> {
+    py.detach(|| {
+        update_snap_logic(
+            Path::new(&file_path),
+            Path::new(&source),
+            &changed_paths,
+            level,
+            password.as_deref(),
+            dictionary.as_deref(),
+        )
+    })
+    .map_err(|e| {
+        if e.is::<FormatError>() || e.downcast_ref::<std::io::Error>().is_some() {
+            core_err(e)
+        } else {
+            PyValueError::new_err(format!("{:#}", e))
+        }
+    })
+}
+
+/// Repacks a snapshot with arbitrary per-file renames (`old -> new` relative paths).
+#[pyfunction]
+#[pyo3(signature = (input_path, output_path, renames, level=3))]
+fn rename_entries(
+    input_path: String,
+    output_path: String,
+    renames: HashMap<String, String>,
+    level: i32,
+) -> PyResult<usize> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    rename_entries_logic(
+        Path::new(&input_path),
+        Path::new(&output_path),
+        &renames,
+        level,
+    )
+    .map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+// --- Utility Functions ---
+
+#[derive(IntoPyObject)]
+struct PortabilityIssue {
+    path: String,
+    reason: String,
+}
+
+/// Header-only scan for entry paths that would fail to extract on Windows.
+#[pyfunction]
+fn check_portability(file_path: String) -> PyResult<Vec<PortabilityIssue>> {
+    let index = read_snapshot_index(Path::new(&file_path)).map_err(read_err)?;
+
+    let mut issues = Vec::new();
+    for entry in index.into_entries() {
+        for reason in portability_problems(&entry.path) {
+            issues.push(PortabilityIssue {
+                path: entry.path.clone(),
+                reason,
+            });
+        }
+    }
+    Ok(issues)
+}
+
+/// Entries whose mtime is later than the snapshot's recorded creation time,
+/// as `(path, mtime)`. Hints at clock skew or files changing mid-capture.
+/// Blake3 over the sorted entry paths, blind to content, sizes and times.
+fn structure_digest(file_path: &str) -> PyResult<String> {
+    let index = read_snapshot_index(Path::new(file_path)).map_err(read_err)?;
+    let mut paths: Vec<String> = index.into_entries().into_iter().map(|e| e.path).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut hasher = blake3::Hasher::new();
+    for path in &paths {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Digest of the snapshot's tree shape. Snapshots with the same paths share it
+/// even when file contents differ.
+#[pyfunction]
+fn structure_fingerprint(file_path: String) -> PyResult<String> {
+    structure_digest(&file_path)
+}
+
+#[pyfunction]
+fn structure_equal(a: String, b: String) -> PyResult<bool> {
+    Ok(structure_digest(&a)? == structure_digest(&b)?)
+}
+
+#[pyfunction]
+fn entries_after_creation(file_path: String) -> PyResult<Vec<(String, u64)>> {
+    let index = read_snapshot_index(Path::new(&file_path)).map_err(read_err)?;
+    let created = index
+        .metadata
+        .as_ref()
+        .map(|m| m.timestamp)
+        .ok_or_else(|| PyValueError::new_err("Metadata not found in snapshot"))?;
+
+    Ok(index
+        .into_entries()
+        .into_iter()
+        .filter(|e| e.modified as i64 > created)
+        .map(|e| (e.path, e.modified))
+        .collect())
+}
+
+/// Files present in both snapshots whose preserved mode differs, as
+/// `(path, old_mode, new_mode)`. Content changes are not considered.
+#[pyfunction]
+fn permission_diff(old_path: String, new_path: String) -> PyResult<Vec<(String, u32, u32)>> {
+    let old_modes: HashMap<String, u32> = read_snapshot_index(Path::new(&old_path))
+        .map_err(read_err)?
+        .into_entries()
+        .into_iter()
+        .map(|e| (e.path, e.mode))
+        .collect();
+    let new_entries = read_snapshot_index(Path::new(&new_path))
+        .map_err(read_err)?
+        .into_entries();
+
+    let mut changes: Vec<(String, u32, u32)> = new_entries
+        .into_iter()
+        .filter_map(|e| {
+            let old_mode = *old_modes.get(&e.path)?;
+            // Compare permission bits only; file-type bits differ between V2 headers and manifests
+            (old_mode & 0o7777 != e.mode & 0o7777).then_some((e.path, old_mode, e.mode))
+        })
+        .collect();
+    changes.sort();
+    Ok(changes)
+}
+
+/// What an entry holds, for telling whether it changed between snapshots.
+#[derive(PartialEq)]
+struct EntryFingerprint {
+    size: u64,
+    hash: String,
+    symlink: Option<String>,
+}
+
+/// One streaming pass over `file_path`: V3 entries come from the manifest,
+/// V2 entries are hashed with Blake3 as their bodies go by. Keyed by the
+/// names' raw bytes, so escaped and plain spellings of a name agree.
+fn entry_fingerprints(
+    file_path: &Path,
+    dictionary: Option<&[u8]>,
+) -> std::io::Result<HashMap<Vec<u8>, EntryFingerprint>> {
+    let file = open_snapshot(file_path)?;
+    let decoder = open_decoder_using(file, None, dictionary)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut legacy = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format
+                .decode(&mut entry)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+            manifest_requirements(&manifest)?;
+            return Ok(manifest
+                .entries
+                .into_iter()
+                .map(|e| {
+                    let name = names::decode(&e.path, e.escaped);
+                    let print = EntryFingerprint {
+                        size: e.size,
+                        hash: e.hash,
+                        symlink: e.symlink,
+                    };
+                    (name, print)
+                })
+                .collect());
+        }
+        if path.starts_with("blobs/") || path == ".vegh.json" || path == OFFSETS_ENTRY {
+            continue;
+        }
+        let symlink = entry.link_name()?.map(|l| l.to_string_lossy().to_string());
+        let mut hasher = blake3::Hasher::new();
+        let size = std::io::copy(&mut entry, &mut hasher)?;
+        let print = EntryFingerprint {
+            size,
+            hash: hasher.finalize().to_hex().to_string(),
+            symlink,
+        };
+        legacy.insert(entry.path_bytes().into_owned(), print);
+    }
+    Ok(legacy)
+}
+
+#[derive(IntoPyObject)]
+struct SnapDiff {
+    added: Vec<OsString>,
+    removed: Vec<OsString>,
+    modified: Vec<OsString>,
+}
+
+impl SnapDiff {
+    /// Sorts raw names and decodes them for Python.
+    fn from_names(added: Vec<Vec<u8>>, removed: Vec<Vec<u8>>, modified: Vec<Vec<u8>>) -> Self {
+        let decode = |mut raw: Vec<Vec<u8>>| {
+            raw.sort();
+            raw.into_iter()
+                .map(|name| names::bytes_path(name).into_os_string())
+                .collect()
+        };
+        SnapDiff {
+            added: decode(added),
+            removed: decode(removed),
+            modified: decode(modified),
+        }
+    }
+}
+
+/// Paths added, removed or modified going from `old_path` to `new_path`.
+/// Contents are compared by Blake3 digest (from the manifest when there is
+/// one), so neither snapshot is extracted. Snapshots compressed with a
+/// dictionary need that `dictionary`; both are read with it.
+#[pyfunction]
+#[pyo3(signature = (old_path, new_path, dictionary=None))]
+fn diff_snaps(
+    py: Python<'_>,
+    old_path: String,
+    new_path: String,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<SnapDiff> {
+    let dictionary = dictionary.as_deref();
+    let (old, new) = py
+        .detach(|| {
+            Ok::<_, std::io::Error>((
+                entry_fingerprints(Path::new(&old_path), dictionary)?,
+                entry_fingerprints(Path::new(&new_path), dictionary)?,
+            ))
+        })
+        .map_err(read_err)?;
+
+    let (mut added, mut modified) = (Vec::new(), Vec::new());
+    for (path, print) in &new {
+        match old.get(path) {
+            None => added.push(path.clone()),
+            Some(before) if before != print => modified.push(path.clone()),
+            Some(_) => {}
+        }
+    }
+    let removed = old.into_keys().filter(|p| !new.contains_key(p)).collect();
+    Ok(SnapDiff::from_names(added, removed, modified))
+}
+
+/// Paths added, removed or modified in the live `source` tree since the
+/// snapshot `file_path` was taken, i.e. what `diff_snaps` would report
+/// against a fresh snapshot. The walk applies `include`/`exclude` and the
+/// ignore files as `create_snap` does with its other walk options at their
+/// defaults. Files are only hashed (Blake3) when their size still matches.
+///
+/// The snapshot is expected to hold `source` itself at its root: one made with
+/// `keep_root_name=True`, from several sources, with `follow_symlinks=True`,
+/// `max_depth` or another non-default walk option shows those differences as
+/// changes too.
+#[pyfunction]
+#[pyo3(signature = (file_path, source, include=None, exclude=None))]
+fn diff_snap_against_dir(
+    py: Python<'_>,
+    file_path: String,
+    source: String,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+) -> PyResult<SnapDiff> {
+    let source_path = Path::new(&source);
+    py.detach(|| {
+        let snap = entry_fingerprints(Path::new(&file_path), None)
+            .map_err(dictionary_unsupported)
+            .map_err(read_err)?;
+        let live = walk_source(
+            source_path,
+            include,
+            exclude,
+            &WalkSettings::default(),
+            false,
+        )?;
+
+        let (mut added, mut modified) = (Vec::new(), Vec::new());
+        let mut seen = HashSet::new();
+        for (rel, size) in live {
+            let full = source_path.join(&rel);
+            let path = names::path_bytes(&rel).into_owned();
+            let is_link = std::fs::symlink_metadata(&full).is_ok_and(|m| m.is_symlink());
+            match snap.get(&path) {
+                None => added.push(path.clone()),
+                Some(print) => {
+                    let changed = match &print.symlink {
+                        Some(target) => std::fs::read_link(&full)
+                            .map_or(true, |t| t.to_string_lossy() != target.as_str()),
+                        None => {
+                            is_link
+                                || print.size != size
+                                || compute_file_hash(&full)
+                                    .map_or(true, |h| hex::encode(h) != print.hash)
+                        }
+                    };
+                    if changed {
+                        modified.push(path.clone());
+                    }
+                }
+            }
+            seen.insert(path);
+        }
+        // The walk does not list symlinks to directories; check those directly
+        let mut removed = Vec::new();
+        for (path, print) in snap {
+            if seen.contains(&path) {
+                continue;
+            }
+            let live = std::fs::read_link(source_path.join(names::bytes_path(path.clone())));
+            match (&print.symlink, live) {
+                (Some(target), Ok(live)) => {
+                    if live.to_string_lossy() != target.as_str() {
+                        modified.push(path);
+                    }
+                }
+                _ => removed.push(path),
+            }
+        }
+        Ok(SnapDiff::from_names(added, removed, modified))
+    })
+}
+
+// Enough to judge entropy without recompressing whole files
+const COMPRESSIBILITY_SAMPLE: u64 = 64 * 1024;
+// Below this, container overhead dominates and the ratio says nothing
+const COMPRESSIBILITY_MIN_SIZE: u64 = 1024;
+
+/// Compressed/original size of the first bytes of `reader` at a fast zstd level.
+fn sample_ratio<R: Read>(reader: R) -> std::io::Result<Option<f64>> {
+    let mut sample = Vec::new();
+    reader
+        .take(COMPRESSIBILITY_SAMPLE)
+        .read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(None);
+    }
+    let compressed = zstd::bulk::compress(&sample, 1)?;
+    Ok(Some(compressed.len() as f64 / sample.len() as f64))
+}
+
+/// Files whose sampled compression ratio (compressed/original) exceeds
+/// `threshold`, i.e. content that is already compressed. Sorted worst first.
+#[pyfunction]
+#[pyo3(signature = (file_path, threshold=0.9))]
+fn incompressible_files(file_path: String, threshold: f64) -> PyResult<Vec<(String, f64)>> {
+    let io = |e: std::io::Error| PyIOError::new_err(format!("Failed to read snapshot: {}", e));
+    let file = open_snapshot(&file_path).map_err(io)?;
+    let decoder = open_decoder(file).map_err(read_err)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut blob_ratios: HashMap<String, f64> = HashMap::new();
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+    let mut results = Vec::new();
+
+    for entry in archive.entries().map_err(io)? {
+        let mut entry = entry.map_err(io)?;
+        let path = entry.path().map_err(io)?.to_string_lossy().to_string();
+
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            let manifest = format
+                .decode(&mut entry)
+                .map_err(|e| PyValueError::new_err(format!("Invalid manifest: {}", e)))?;
+            manifest_requirements(&manifest).map_err(read_err)?;
+            manifest_opt = Some(manifest);
+        } else if let Some(hash) = path.strip_prefix("blobs/") {
+            if let Some(ratio) = sample_ratio(&mut entry).map_err(io)? {
+                blob_ratios.insert(hash.to_string(), ratio);
+            }
+        } else if path != ".vegh.json"
+            && path != OFFSETS_ENTRY
+            && entry.size() >= COMPRESSIBILITY_MIN_SIZE
+        {
+            // V2: the entry itself is the file
+            if let Some(ratio) = sample_ratio(&mut entry).map_err(io)? {
+                results.push((path, ratio));
+            }
+        }
+    }
+
+    if let Some(manifest) = manifest_opt {
+        results.clear();
+        for entry in manifest.entries {
+            if entry.symlink.is_some() || entry.size < COMPRESSIBILITY_MIN_SIZE {
+                continue;
+            }
+            // Chunked files: average the per-chunk samples
+            let chunks = entry.chunks.unwrap_or_else(|| vec![entry.hash.clone()]);
+            let ratios: Vec<f64> = chunks
+                .iter()
+                .filter_map(|c| blob_ratios.get(c))
+                .copied()
+                .collect();
+            if !ratios.is_empty() {
+                results.push((entry.path, ratios.iter().sum::<f64>() / ratios.len() as f64));
+            }
+        }
+    }
+
+    results.retain(|(_, ratio)| *ratio > threshold);
+    results.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(results)
+}
+
+#[derive(IntoPyObject)]
+struct OverlapReport {
+    total: usize,
+    present: usize,
+    identical: usize,
+    ratio: f64,
+}
+
+/// Fraction of a snapshot's files already on disk under `target_dir`.
+/// V3 entries are compared by content hash; V2 entries by path and size only.
+#[pyfunction]
+#[pyo3(signature = (file_path, target_dir, include=None, exclude=None))]
+fn overlap_ratio(
+    file_path: String,
+    target_dir: String,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+) -> PyResult<OverlapReport> {
+    let index = read_snapshot_index(Path::new(&file_path)).map_err(read_err)?;
+    let target = Path::new(&target_dir);
+    let mut report = OverlapReport {
+        total: 0,
+        present: 0,
+        identical: 0,
+        ratio: 1.0,
+    };
+
+    if let Some(manifest) = index.manifest {
+        for entry in manifest.entries {
+            if !substring_filter(&entry.path, &include, &exclude) {
+                continue;
+            }
+            report.total += 1;
+            let on_disk = target.join(&entry.path);
+            let Ok(meta) = std::fs::symlink_metadata(&on_disk) else {
+                continue;
+            };
+            report.present += 1;
+
+            let same = match &entry.symlink {
+                Some(link) => std::fs::read_link(&on_disk)
+                    .map(|t| t.to_string_lossy() == link.as_str())
+                    .unwrap_or(false),
+                None => {
+                    meta.is_file()
+                        && meta.len() == entry.size
+                        && compute_file_hash(&on_disk)
+                            .map(|h| hex::encode(h) == entry.hash)
+                            .unwrap_or(false)
+                }
+            };
+            if same {
+                report.identical += 1;
+            }
+        }
+    } else {
+        for entry in index.legacy {
+            if !substring_filter(&entry.path, &include, &exclude) {
+                continue;
+            }
+            report.total += 1;
+            if let Ok(meta) = std::fs::metadata(target.join(&entry.path)) {
+                report.present += 1;
+                if meta.is_file() && meta.len() == entry.size {
+                    report.identical += 1;
+                }
+            }
+        }
+    }
+
+    if report.total > 0 {
+        report.ratio = report.identical as f64 / report.total as f64;
+    }
+    Ok(report)
+}
+
+#[derive(IntoPyObject)]
+struct SnapshotDelta {
+    path: String,
+    file_count: usize,
+    full_bytes: u64,
+    new_bytes: u64,
+}
+
+#[derive(IntoPyObject)]
+struct DedupReport {
+    full_bytes: u64,
+    stored_bytes: u64,
+    savings_ratio: f64,
+    snapshots: Vec<SnapshotDelta>,
+}
+
+/// "What-if" for a series of snapshots (oldest first): bytes stored as full
+/// snapshots vs. bytes an incremental, deduplicated chain would have needed.
+#[pyfunction]
+fn analyze_dedup_potential(snapshot_paths: Vec<String>) -> PyResult<DedupReport> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut report = DedupReport {
+        full_bytes: 0,
+        stored_bytes: 0,
+        savings_ratio: 0.0,
+        snapshots: Vec::new(),
+    };
+
+    for snap in snapshot_paths {
+        let index = read_snapshot_index(Path::new(&snap)).map_err(read_err)?;
+
+        let mut delta = SnapshotDelta {
+            path: snap,
+            file_count: 0,
+            full_bytes: 0,
+            new_bytes: 0,
+        };
+
+        if let Some(manifest) = index.manifest {
+            for entry in manifest.entries.iter().filter(|e| e.symlink.is_none()) {
+                delta.file_count += 1;
+                delta.full_bytes += entry.size;
+                let chunks = entry
+                    .chunks
+                    .clone()
+                    .unwrap_or_else(|| vec![entry.hash.clone()]);
+                for chunk in chunks {
+                    let size = index.blob_sizes.get(&chunk).copied().unwrap_or(entry.size);
+                    if seen.insert(chunk) {
+                        delta.new_bytes += size;
+                    }
+                }
+            }
+        } else {
+            // V2 has no content hashes; unchanged path+size+mtime is the best proxy
+            for e in index.legacy {
+                delta.file_count += 1;
+                delta.full_bytes += e.size;
+                if seen.insert(format!("{}\0{}\0{}", e.path, e.size, e.modified)) {
+                    delta.new_bytes += e.size;
+                }
+            }
+        }
+
+        report.full_bytes += delta.full_bytes;
+        report.stored_bytes += delta.new_bytes;
+        report.snapshots.push(delta);
+    }
+
+    if report.full_bytes > 0 {
+        report.savings_ratio = 1.0 - report.stored_bytes as f64 / report.full_bytes as f64;
+    }
+    Ok(report)
+}
+
+/// Paths of the files in a snapshot. `file_path` may also be the snapshot as
+/// `bytes` or a file-like object with `.read()`.
+///
+/// Only project files are listed: the snapshot's own bookkeeping entries
+/// (`.vegh.json`, the manifest, the offset index and content blobs) are left
+/// out, while archived `.gitignore`/`.veghignore` files are listed like any
+/// other. `include_internal=True` returns `(path, internal)` pairs instead,
+/// with the bookkeeping entries appended after the files and flagged `True`.
+///
+/// Names that were not valid UTF-8 on disk come back the way `os.fsdecode`
+/// spells them, so `os.fsencode(path)` gives the exact bytes. Encrypted
+/// snapshots need their `password`, ones compressed with a dictionary that
+/// `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, force=None, password=None, include_internal=None, dictionary=None))]
+fn list_files(
+    py: Python<'_>,
+    file_path: Bound<'_, PyAny>,
+    force: Option<bool>,
+    password: Option<String>,
+    include_internal: Option<bool>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Py<PyAny>> {
+    let source = SnapshotSource::new(&file_path)?;
+    let password = password.as_deref();
+    let dictionary = dictionary.as_deref();
+    ensure_readable_version(&source, force.unwrap_or(false), password, dictionary)?;
+    let file = source
+        .open()
+        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let decoder = open_decoder_using(file, password, dictionary).map_err(read_err)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut files = Vec::new();
+    let mut internal = Vec::new();
+
+    // We can't use load_snapshot_data here efficiently because we just want names.
+    // So we iterate. If we find manifest at the end, we use it.
+    if let Ok(entries) = archive.entries() {
+        for mut e in entries.flatten() {
+            let path = e.path().unwrap().into_owned();
+            let path_str = path.to_string_lossy().to_string();
+
+            if let Some(format) = ManifestFormat::from_entry_path(&path_str) {
+                internal.push(path.into_os_string());
+                if let Ok(manifest) = format.decode(&mut e) {
+                    manifest_requirements(&manifest).map_err(read_err)?;
+                    files = manifest
+                        .entries
+                        .into_iter()
+                        .map(|entry| entry_name(&entry))
+                        .collect();
+                }
+            } else if path_str.starts_with("blobs/")
+                || path_str == ".vegh.json"
+                || path_str == OFFSETS_ENTRY
+            {
+                internal.push(path.into_os_string());
+            } else {
+                // Header names are raw bytes already
+                files.push(path.into_os_string());
+            }
+        }
+    }
+    // If manifest was found (V3), 'files' was overwritten with manifest entries.
+    // If not (V2), 'files' contains the tar entries.
+    if !include_internal.unwrap_or(false) {
+        return Ok(files.into_pyobject(py)?.into_any().unbind());
+    }
+    let flagged: Vec<(OsString, bool)> = files
+        .into_iter()
+        .map(|p| (p, false))
+        .chain(internal.into_iter().map(|p| (p, true)))
+        .collect();
+    Ok(flagged.into_pyobject(py)?.into_any().unbind())
+}
+
+/// Raw `.vegh.json` text. `file_path` may also be the snapshot as `bytes` or
+/// a file-like object with `.read()`. Every writer puts `.vegh.json` first
+/// (unless `create_snap` was given `metadata_position="last"`), so only the
+/// start of the stream is decompressed, whatever the snapshot's size.
+/// Encrypted snapshots need their `password`, ones compressed with a
+/// dictionary that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn get_metadata(
+    file_path: Bound<'_, PyAny>,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<String> {
+    let file = SnapshotSource::new(&file_path)?
+        .open()
+        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+    metadata_text(
+        open_decoder_using(file, password.as_deref(), dictionary.as_deref()).map_err(read_err)?,
+    )
+}
+
+/// Decoder for readers that take neither a password nor a dictionary.
+fn open_plain(path: &Path) -> PyResult<Box<dyn Read>> {
+    let file = open_snapshot(path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    open_decoder(file).map_err(read_err)
+}
+
+/// `.vegh.json` from an opened decoder, so each caller picks how it is opened.
+fn metadata_text(decoder: Box<dyn Read>) -> PyResult<String> {
+    let mut archive = tar::Archive::new(decoder);
+
+    if let Ok(entries) = archive.entries() {
+        for entry in entries {
+            if let Ok(mut e) = entry
+                && let Ok(p) = e.path()
+                && p.to_string_lossy() == ".vegh.json"
+            {
+                let content = String::from_utf8_lossy(
+                    &metadata_json(&mut e).map_err(|e| PyIOError::new_err(e.to_string()))?,
+                )
+                .into_owned();
+                if let Ok(meta) = serde_json::from_str::<VeghMetadata>(&content) {
+                    check_requirements(meta.min_tool_version.as_deref(), &meta.features)
+                        .map_err(core_err)?;
+                }
+                return Ok(content);
+            }
+        }
+    }
+    Err(PyValueError::new_err("Metadata not found in snapshot"))
+}
+
+/// Writes the snapshot's sidecars (`.vegh.json`, manifest) into `out_dir`
+/// without extracting any file data. Returns the names written.
+#[pyfunction]
+fn extract_metadata(file_path: String, out_dir: String) -> PyResult<Vec<String>> {
+    extract_metadata_logic(Path::new(&file_path), Path::new(&out_dir))
+        .map_err(|e| PyIOError::new_err(format!("{:#}", e)))
+}
+
+/// `.vegh.json` as a Python dict instead of raw JSON text, with `created` added
+/// as a UTC `datetime` for the `timestamp`. Raises `ValueError` when required
+/// fields are missing or the format version is one this build cannot read.
+#[pyfunction]
+fn get_metadata_dict(py: Python<'_>, file_path: String) -> PyResult<Py<PyAny>> {
+    let raw = metadata_text(open_plain(Path::new(&file_path))?)?;
+    let invalid = |e: serde_json::Error| PyValueError::new_err(format!("Invalid metadata: {}", e));
+    let meta: VeghMetadata = serde_json::from_str(&raw).map_err(invalid)?;
+    check_format_version(&meta.format_version).map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+    // Render from the raw JSON so fields unknown to this build survive
+    let value: serde_json::Value = serde_json::from_str(&raw).map_err(invalid)?;
+    let dict = json_to_py(py, &value)?;
+    let datetime = py.import("datetime")?;
+    let utc = datetime.getattr("timezone")?.getattr("utc")?;
+    let created = datetime
+        .getattr("datetime")?
+        .call_method1("fromtimestamp", (meta.timestamp, utc))?;
+    dict.bind(py).set_item("created", created)?;
+    Ok(dict)
+}
+
+/// Snapshots in `dir` past their `keep_until` (against `now`, default the
+/// current time). They are only deleted when `delete=True`.
+#[pyfunction]
+#[pyo3(signature = (dir, now=None, delete=false))]
+fn prune_expired(dir: String, now: Option<i64>, delete: bool) -> PyResult<Vec<String>> {
+    let now = now.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    prune_expired_logic(Path::new(&dir), now, delete)
+        .map(|paths| {
+            paths
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        })
+        .map_err(|e| PyIOError::new_err(format!("{:#}", e)))
+}
+
+/// Applies a retention policy to the snapshots in `dir`: the `keep_last` most
+/// recent (by their `.vegh.json` timestamp) always stay, and of the others
+/// those older than `keep_days` (all of them without `keep_days`) are deleted.
+/// Returns the paths deleted, or with `dry_run=True` the ones that would be.
+/// `now` (default the current time) is what `keep_days` counts back from.
+///
+/// Snapshots without a usable timestamp (reproducible ones record 0) are
+/// dated by their file's modification time. A snapshot that a kept one was
+/// made against (`base`) is never deleted, and split snapshots go with all
+/// their volumes. Encrypted snapshots need `password`; if a kept snapshot's
+/// metadata cannot be read, nothing is deleted and an IOError is raised.
+#[pyfunction]
+#[pyo3(signature = (dir, keep_last=None, keep_days=None, dry_run=false, now=None, password=None))]
+fn prune_snaps(
+    dir: String,
+    keep_last: Option<usize>,
+    keep_days: Option<u64>,
+    dry_run: bool,
+    now: Option<i64>,
+    password: Option<String>,
+) -> PyResult<Vec<String>> {
+    if keep_last.is_none() && keep_days.is_none() {
+        return Err(PyValueError::new_err(
+            "Pass keep_last and/or keep_days; an empty policy would delete every snapshot",
+        ));
+    }
+    let now = now.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    prune_by_policy_logic(
+        Path::new(&dir),
+        keep_last,
+        keep_days,
+        now,
+        !dry_run,
+        password.as_deref(),
+    )
+    .map(|paths| {
+        paths
+            .into_iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect()
+    })
+    .map_err(|e| PyIOError::new_err(format!("{:#}", e)))
+}
+
+/// Converts a JSON value into the equivalent Python object.
+fn json_to_py(py: Python<'_>, value: &serde_json::Value) -> PyResult<Py<PyAny>> {
+    use serde_json::Value;
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_pyobject(py)?.to_owned().into_any().unbind(),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => i.into_pyobject(py)?.into_any().unbind(),
+            (None, Some(f)) => f.into_pyobject(py)?.into_any().unbind(),
+            _ => n.to_string().into_pyobject(py)?.into_any().unbind(),
+        },
+        Value::String(s) => s.into_pyobject(py)?.into_any().unbind(),
+        Value::Array(items) => {
+            let list = pyo3::types::PyList::empty(py);
+            for item in items {
+                list.append(json_to_py(py, item)?)?;
+            }
+            list.into_any().unbind()
+        }
+        Value::Object(map) => {
+            let dict = pyo3::types::PyDict::new(py);
+            for (k, v) in map {
+                dict.set_item(k, json_to_py(py, v)?)?;
+            }
+            dict.into_any().unbind()
+        }
+    })
+}
+
+/// Fields that differ between two snapshots' `.vegh.json`, as
+/// `{field: {"before": ..., "after": ...}}`. Missing fields are `None`.
+#[pyfunction]
+fn metadata_diff(py: Python<'_>, old_path: String, new_path: String) -> PyResult<Py<PyAny>> {
+    let parse = |path: String| -> PyResult<serde_json::Map<String, serde_json::Value>> {
+        let raw = metadata_text(open_plain(Path::new(&path))?)?;
+        serde_json::from_str(&raw)
+            .map_err(|e| PyValueError::new_err(format!("Invalid metadata: {}", e)))
+    };
+    let old = parse(old_path)?;
+    let new = parse(new_path)?;
+
+    let fields: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let null = serde_json::Value::Null;
+    let diff = pyo3::types::PyDict::new(py);
+    for field in fields {
+        let before = old.get(field).unwrap_or(&null);
+        let after = new.get(field).unwrap_or(&null);
+        if before != after {
+            let change = pyo3::types::PyDict::new(py);
+            change.set_item("before", json_to_py(py, before)?)?;
+            change.set_item("after", json_to_py(py, after)?)?;
+            diff.set_item(field, change)?;
+        }
+    }
+    Ok(diff.into_any().unbind())
+}
+
+#[derive(IntoPyObject)]
+struct SnapshotStats {
+    /// Files in the snapshot (manifest entries for V3, tar entries for V2).
+    entry_count: usize,
+    /// Sum of every tar entry's size: the stream before compression.
+    uncompressed_bytes: u64,
+    /// Size of the snapshot file on disk.
+    compressed_bytes: u64,
+    /// `compressed_bytes / uncompressed_bytes`.
+    compression_ratio: f64,
+    timestamp: Option<i64>,
+    /// File bytes not stored because identical content (a whole file or a
+    /// chunk) already was: total file size minus total blob size. Always 0
+    /// for V2 snapshots, which store every file in full.
+    dedup_saved_bytes: u64,
+}
+
+/// Dashboard numbers for a snapshot, gathered in a single pass over it.
+/// Encrypted snapshots need their `password`, ones compressed with a
+/// dictionary that `dictionary`; `compressed_bytes` is still the file's size.
+#[pyfunction]
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn snapshot_stats(
+    py: Python<'_>,
+    file_path: String,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<SnapshotStats> {
+    let path = Path::new(&file_path);
+    py.detach(|| -> std::io::Result<SnapshotStats> {
+        let file = open_snapshot(path)?;
+        let compressed_bytes = file.byte_len()?;
+        let mut archive = tar::Archive::new(open_decoder_using(
+            file,
+            password.as_deref(),
+            dictionary.as_deref(),
+        )?);
+
+        let mut uncompressed_bytes = 0u64;
+        let mut legacy_count = 0usize;
+        let mut manifest_count = None;
+        let mut timestamp = None;
+        let mut blob_bytes = 0u64;
+        let mut file_bytes = 0u64;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            uncompressed_bytes += entry.size();
+            let name = entry.path()?.to_string_lossy().to_string();
+            if name.starts_with("blobs/") {
+                blob_bytes += entry.size();
+            }
+            if let Some(format) = ManifestFormat::from_entry_path(&name) {
+                if let Ok(manifest) = format.decode(&mut entry) {
+                    manifest_requirements(&manifest)?;
+                    manifest_count = Some(manifest.entries.len());
+                    file_bytes = manifest
+                        .entries
+                        .iter()
+                        .filter(|e| e.symlink.is_none())
+                        .map(|e| e.size)
+                        .sum();
+                }
+            } else if name == ".vegh.json" {
+                timestamp = metadata_json(&mut entry)
+                    .ok()
+                    .and_then(|json| serde_json::from_slice::<VeghMetadata>(&json).ok())
+                    .map(|m| m.timestamp);
+            } else if !name.starts_with("blobs/")
+                && name != OFFSETS_ENTRY
+                && !entry.header().entry_type().is_dir()
+            {
+                legacy_count += 1;
+            }
+        }
+
+        let compression_ratio = if uncompressed_bytes > 0 {
+            compressed_bytes as f64 / uncompressed_bytes as f64
+        } else {
+            1.0
+        };
+        Ok(SnapshotStats {
+            entry_count: manifest_count.unwrap_or(legacy_count),
+            uncompressed_bytes,
+            compressed_bytes,
+            compression_ratio,
+            timestamp,
+            dedup_saved_bytes: file_bytes.saturating_sub(blob_bytes),
+        })
+    })
+    .map_err(read_err)
+}
+
+/// `algorithm` as given to `check_integrity`/`hash_file`, and whether the
+/// digest should carry an `"<algorithm>:"` tag (only when one was asked for).
+fn digest_algorithm(algorithm: Option<&str>) -> PyResult<(Algorithm, bool)> {
+    match algorithm {
+        None => Ok((Algorithm::Blake3, false)),
+        Some(name) => Algorithm::parse(name)
+            .map(|alg| (alg, true))
+            .map_err(|e| PyValueError::new_err(e.to_string())),
+    }
+}
+
+fn tag_digest(algorithm: Algorithm, tagged: bool, digest: String) -> String {
+    if tagged {
+        format!("{}:{}", algorithm.name(), digest)
+    } else {
+        digest
+    }
+}
+
+/// Hex BLAKE3 of the snapshot file as stored, the hash `base`, verifiers and
+/// `return_digest` use. `algorithm` picks `"blake3"` or `"sha256"` instead
+/// and tags the result (`"sha256:9f86..."`) so the two cannot be mixed up.
+#[pyfunction]
+#[pyo3(signature = (file_path, algorithm=None))]
+fn check_integrity(
+    py: Python<'_>,
+    file_path: String,
+    algorithm: Option<String>,
+) -> PyResult<String> {
+    let (alg, tagged) = digest_algorithm(algorithm.as_deref())?;
+    let io = |e: std::io::Error| PyIOError::new_err(e.to_string());
+    let digest = py.detach(|| match open_snapshot(Path::new(&file_path))? {
+        SnapshotFile::Single(_) => digest_file(alg, Path::new(&file_path)),
+        // Split snapshots hash as the stream they were cut from
+        SnapshotFile::Volumes(mut volumes) => digest_reader(alg, &mut volumes),
+    });
+    Ok(tag_digest(alg, tagged, digest.map_err(io)?))
+}
+
+/// Content sniffed for NUL bytes before a file is treated as binary.
+const BINARY_SNIFF: u64 = 8 * 1024;
+
+/// The binary rule every LOC report shares: a NUL byte in the first
+/// `BINARY_SNIFF` bytes of the file.
+fn looks_binary(head: &[u8]) -> bool {
+    head[..head.len().min(BINARY_SNIFF as usize)].contains(&0)
+}
+
+/// Line statistics of one stream, gathered without holding it in memory.
+#[derive(Default, Clone, Copy)]
+struct LineStats {
+    len: u64,
+    newlines: usize,
+    ends_with_newline: bool,
+    /// Offset of the first NUL byte, if it falls within `BINARY_SNIFF`.
+    first_nul: Option<u64>,
+}
+
+impl LineStats {
+    fn read<R: Read>(reader: R) -> std::io::Result<Self> {
+        let mut reader = std::io::BufReader::new(reader);
+        let mut stats = Self::default();
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            if stats.first_nul.is_none() && stats.len < BINARY_SNIFF {
+                let head = &buf[..buf.len().min((BINARY_SNIFF - stats.len) as usize)];
+                if looks_binary(head) {
+                    stats.first_nul = head
+                        .iter()
+                        .position(|&b| b == 0)
+                        .map(|p| stats.len + p as u64);
+                }
+            }
+            stats.newlines += buf.iter().filter(|&&b| b == b'\n').count();
+            stats.ends_with_newline = buf[buf.len() - 1] == b'\n';
+            stats.len += buf.len() as u64;
+            let n = buf.len();
+            reader.consume(n);
+        }
+        Ok(stats)
+    }
+
+    /// Stats of `self` followed by `next` (consecutive chunks of one file).
+    fn then(self, next: &Self) -> Self {
+        Self {
+            len: self.len + next.len,
+            newlines: self.newlines + next.newlines,
+            ends_with_newline: if next.len > 0 {
+                next.ends_with_newline
+            } else {
+                self.ends_with_newline
+            },
+            first_nul: self.first_nul.or(next
+                .first_nul
+                .map(|p| self.len + p)
+                .filter(|&p| p < BINARY_SNIFF)),
+        }
+    }
+
+    /// Line count as `str::lines` would report it; `None` for binary content
+    /// (see `looks_binary`).
+    fn lines(&self) -> Option<usize> {
+        if self.first_nul.is_some() {
+            return None;
+        }
+        Some(self.newlines + usize::from(self.len > 0 && !self.ends_with_newline))
+    }
+}
+
+/// Lines per file, streamed entry by entry so large files never sit in memory.
+/// Binary files (a NUL byte in the first 8 KB) count as 0 unless `strict`,
+/// which leaves them out and returns `(results, skipped)` with a
+/// `(path, reason)` for each one. `file_path` may also be the snapshot as
+/// `bytes` or a file-like object with `.read()`. Snapshots compressed with a
+/// dictionary need that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, strict=false, dictionary=None))]
+fn count_locs(
+    py: Python<'_>,
+    file_path: Bound<'_, PyAny>,
+    strict: bool,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Py<PyAny>> {
+    let source = SnapshotSource::new(&file_path)?;
+    let LocScan { counted, missing } = py
+        .detach(|| stream_line_stats(source.open()?, dictionary.as_deref()))
+        .map_err(read_err)?;
+
+    let mut results = Vec::new();
+    let mut skipped = Vec::new();
+    for (name, stats) in counted {
+        match stats.lines() {
+            Some(lines) => results.push((name, lines)),
+            None if strict => {
+                skipped.push((name, "binary (NUL byte in the first 8 KB)".to_string()))
+            }
+            None => results.push((name, 0)),
+        }
+    }
+    if !strict {
+        return Ok(results.into_pyobject(py)?.into_any().unbind());
+    }
+    skipped.extend(
+        missing
+            .into_iter()
+            .map(|(name, hash)| (name, format!("missing blob {}", hash))),
+    );
+    Ok((results, skipped).into_pyobject(py)?.into_any().unbind())
+}
+
+struct LocScan {
+    counted: Vec<(String, LineStats)>,
+    /// `(path, blob)` for V3 files whose blobs are missing.
+    missing: Vec<(String, String)>,
+}
+
+/// Per-file `LineStats` for every regular file in the snapshot.
+fn stream_line_stats(input: Box<dyn Read>, dictionary: Option<&[u8]>) -> std::io::Result<LocScan> {
+    let mut archive = tar::Archive::new(open_decoder_using(input, None, dictionary)?);
+
+    let mut blobs: HashMap<String, LineStats> = HashMap::new();
+    let mut manifest_opt: Option<SnapshotManifest> = None;
+    let mut legacy = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+
+        if let Some(format) = ManifestFormat::from_entry_path(&path) {
+            if let Ok(manifest) = format.decode(&mut entry) {
+                manifest_requirements(&manifest)?;
+                manifest_opt = Some(manifest);
+            }
+        } else if let Some(hash) = path.strip_prefix("blobs/") {
+            blobs.insert(hash.to_string(), LineStats::read(&mut entry)?);
+        } else if path != ".vegh.json" && path != OFFSETS_ENTRY {
+            legacy.push((path, LineStats::read(&mut entry)?));
+        }
+    }
 
-    create_snap_logic(
-        source_path,
-        output_path,
-        level,
-        comment,
-        include.unwrap_or_default(),
-        exclude.unwrap_or_default(),
-        no_cache,
-        verbose,
-    )
-    .map_err(|e| PyIOError::new_err(e.to_string()))
+    let Some(manifest) = manifest_opt else {
+        return Ok(LocScan {
+            counted: legacy,
+            missing: Vec::new(),
+        });
+    };
+    let mut counted = Vec::new();
+    let mut missing = Vec::new();
+    for entry in manifest.entries {
+        if entry.symlink.is_some() {
+            continue;
+        }
+        let chunks = entry.chunks.unwrap_or_else(|| vec![entry.hash.clone()]);
+        let mut stats = LineStats::default();
+        let mut absent = None;
+        for hash in chunks {
+            match blobs.get(&hash) {
+                Some(chunk) => stats = stats.then(chunk),
+                None => {
+                    absent = Some(hash);
+                    break;
+                }
+            }
+        }
+        match absent {
+            Some(hash) => missing.push((entry.path, hash)),
+            None => counted.push((entry.path, stats)),
+        }
+    }
+    Ok(LocScan { counted, missing })
 }
 
-#[pyfunction]
-#[pyo3(signature = (file_path, out_dir, include=None, flatten=false))]
-fn restore_snap(
-    file_path: String,
-    out_dir: String,
-    include: Option<Vec<String>>,
-    flatten: bool,
-) -> PyResult<()> {
-    let input_path = Path::new(&file_path);
-    let output_path = Path::new(&out_dir);
-
-    restore_snap_logic(input_path, output_path, include, flatten)
-        .map_err(|e| PyIOError::new_err(e.to_string()))
+/// Comment syntax of a language `count_locs_by_language` recognizes.
+struct Language {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
 }
 
-// --- Utility Functions ---
+const C_BLOCK: Option<(&str, &str)> = Some(("/*", "*/"));
 
-#[pyfunction]
-fn list_files(file_path: String) -> PyResult<Vec<String>> {
-    let file = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-    let decoder = zstd::stream::read::Decoder::new(file).unwrap();
-    let mut archive = tar::Archive::new(decoder);
+const LANGUAGES: &[Language] = &[
+    Language {
+        name: "Python",
+        extensions: &["py", "pyi", "pyw"],
+        line_comments: &["#"],
+        block_comment: None,
+    },
+    Language {
+        name: "Rust",
+        extensions: &["rs"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "JavaScript",
+        extensions: &["js", "mjs", "cjs", "jsx"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "TypeScript",
+        extensions: &["ts", "mts", "cts", "tsx"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "C",
+        extensions: &["c", "h"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "C++",
+        extensions: &["cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "Go",
+        extensions: &["go"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "Java",
+        extensions: &["java"],
+        line_comments: &["//"],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "CSS",
+        extensions: &["css"],
+        line_comments: &[],
+        block_comment: C_BLOCK,
+    },
+    Language {
+        name: "Shell",
+        extensions: &["sh", "bash", "zsh"],
+        line_comments: &["#"],
+        block_comment: None,
+    },
+    Language {
+        name: "Ruby",
+        extensions: &["rb"],
+        line_comments: &["#"],
+        block_comment: None,
+    },
+    Language {
+        name: "TOML",
+        extensions: &["toml"],
+        line_comments: &["#"],
+        block_comment: None,
+    },
+    Language {
+        name: "YAML",
+        extensions: &["yml", "yaml"],
+        line_comments: &["#"],
+        block_comment: None,
+    },
+];
 
-    let mut files = Vec::new();
+fn language_of(path: &str) -> Option<&'static Language> {
+    let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|l| l.extensions.contains(&ext.as_str()))
+}
 
-    // We can't use load_snapshot_data here efficiently because we just want names.
-    // So we iterate. If we find manifest at the end, we use it.
-    if let Ok(entries) = archive.entries() {
-        for mut e in entries.flatten() {
-            let path = e.path().unwrap().into_owned();
-            let path_str = path.to_string_lossy().to_string();
+#[derive(IntoPyObject, Default)]
+struct LanguageLocs {
+    files: usize,
+    code: usize,
+    blank: usize,
+    comment: usize,
+}
 
-            if path_str == "manifest.json" {
-                let mut content = String::new();
-                if e.read_to_string(&mut content).is_ok()
-                    && let Ok(manifest) = serde_json::from_str::<SnapshotManifest>(&content)
+impl LanguageLocs {
+    /// Classifies each line read from `text`. Lines are comments when they
+    /// start with a line comment or sit inside a block comment; string literals
+    /// are not parsed, so a comment marker inside one can be miscounted.
+    fn add(&mut self, mut text: impl BufRead, lang: &Language) -> std::io::Result<()> {
+        self.files += 1;
+        let mut in_block = false;
+        let mut raw = Vec::new();
+        loop {
+            raw.clear();
+            if text.read_until(b'\n', &mut raw)? == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&raw);
+            let line = line.trim();
+            if in_block {
+                self.comment += 1;
+                in_block = !lang
+                    .block_comment
+                    .is_some_and(|(_, end)| line.contains(end));
+                continue;
+            }
+            if line.is_empty() {
+                self.blank += 1;
+                continue;
+            }
+            let opens_block = |at: usize| {
+                lang.block_comment
+                    .is_some_and(|(start, end)| !line[at + start.len()..].contains(end))
+            };
+            if lang.line_comments.iter().any(|c| line.starts_with(c)) {
+                self.comment += 1;
+            } else if let Some((start, _)) = lang.block_comment
+                && line.starts_with(start)
+            {
+                self.comment += 1;
+                in_block = opens_block(0);
+            } else {
+                self.code += 1;
+                // Code followed by an unterminated `/*` still opens a block
+                if let Some((start, _)) = lang.block_comment
+                    && let Some(at) = line.rfind(start)
                 {
-                    files = manifest
-                        .entries
-                        .into_iter()
-                        .map(|entry| entry.path)
-                        .collect();
+                    in_block = opens_block(at);
                 }
-            } else if !path_str.starts_with("blobs/") && path_str != ".vegh.json" {
-                files.push(path_str);
             }
         }
+        Ok(())
     }
-    // If manifest was found (V3), 'files' was overwritten with manifest entries.
-    // If not (V2), 'files' contains the tar entries.
-    Ok(files)
 }
 
+/// cloc-style totals per language: `{language: {files, code, blank, comment}}`.
+/// Languages come from the file extension (Python, Rust, JavaScript,
+/// TypeScript, C, C++, Go, Java, CSS, Shell, Ruby, TOML, YAML); other files
+/// and binary ones (a NUL byte in the first 8 KB, as for `count_locs`) are
+/// left out. Files are streamed, so large ones never sit in memory.
 #[pyfunction]
-fn get_metadata(file_path: String) -> PyResult<String> {
-    let file = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-    let decoder = zstd::stream::read::Decoder::new(file).unwrap();
-    let mut archive = tar::Archive::new(decoder);
+fn count_locs_by_language(
+    py: Python<'_>,
+    file_path: String,
+) -> PyResult<BTreeMap<&'static str, LanguageLocs>> {
+    let input = open_snapshot(Path::new(&file_path)).map_err(read_err)?;
+    py.detach(|| {
+        let mut report: BTreeMap<&'static str, LanguageLocs> = BTreeMap::new();
+        let wanted = |path: &str| language_of(path).is_some();
+        visit_files(Box::new(input), None, &wanted, &mut |name, content| {
+            let Some(lang) = language_of(name) else {
+                return Ok(());
+            };
+            let mut head = Vec::new();
+            (&mut *content).take(BINARY_SNIFF).read_to_end(&mut head)?;
+            if looks_binary(&head) {
+                return Ok(());
+            }
+            let text = std::io::BufReader::new(head.as_slice().chain(content));
+            Ok(report.entry(lang.name).or_default().add(text, lang)?)
+        })?;
+        Ok(report)
+    })
+    .map_err(core_err)
+}
 
-    if let Ok(entries) = archive.entries() {
-        for entry in entries {
-            if let Ok(mut e) = entry
-                && let Ok(p) = e.path()
-                && p.to_string_lossy() == ".vegh.json"
-            {
-                let mut content = String::new();
-                e.read_to_string(&mut content)
-                    .map_err(|e| PyIOError::new_err(e.to_string()))?;
-                return Ok(content);
+#[derive(Default)]
+struct LocNode {
+    loc: usize,
+    file_count: usize,
+    is_file: bool,
+    children: BTreeMap<String, LocNode>,
+}
+
+impl LocNode {
+    fn insert(&mut self, path: &str, loc: usize) {
+        self.loc += loc;
+        self.file_count += 1;
+        match path.split_once('/') {
+            Some((dir, rest)) => self
+                .children
+                .entry(dir.to_string())
+                .or_default()
+                .insert(rest, loc),
+            None => {
+                let leaf = self.children.entry(path.to_string()).or_default();
+                leaf.loc = loc;
+                leaf.is_file = true;
             }
         }
     }
-    Err(PyValueError::new_err("Metadata not found in snapshot"))
+
+    fn to_json(&self, name: &str) -> serde_json::Value {
+        if self.is_file {
+            return serde_json::json!({ "name": name, "type": "file", "loc": self.loc });
+        }
+        let children: Vec<_> = self.children.iter().map(|(n, c)| c.to_json(n)).collect();
+        serde_json::json!({
+            "name": name,
+            "type": "dir",
+            "loc": self.loc,
+            "file_count": self.file_count,
+            "children": children,
+        })
+    }
 }
 
+/// Nested JSON "code map" of a snapshot: directories carry aggregate `loc` and
+/// `file_count`, files carry their own `loc`. Lines are counted as by
+/// `count_locs`, binary files as 0.
 #[pyfunction]
-fn check_integrity(file_path: String) -> PyResult<String> {
-    let file = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-    let mut hasher = blake3::Hasher::new();
+fn loc_tree(py: Python<'_>, file_path: String) -> PyResult<String> {
+    let input = open_snapshot(Path::new(&file_path)).map_err(read_err)?;
+    let LocScan { counted, .. } = py
+        .detach(|| stream_line_stats(Box::new(input), None))
+        .map_err(dictionary_unsupported)
+        .map_err(read_err)?;
 
-    if let Ok(mmap) = unsafe { memmap2::MmapOptions::new().map(&file) } {
-        hasher.update_rayon(&mmap);
-    } else {
-        let mut f = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-        std::io::copy(&mut f, &mut hasher).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let mut root = LocNode::default();
+    for (name, stats) in counted {
+        root.insert(&name, stats.lines().unwrap_or(0));
     }
 
-    Ok(hasher.finalize().to_hex().to_string())
+    serde_json::to_string(&root.to_json("."))
+        .map_err(|e| PyValueError::new_err(format!("Failed to encode tree: {}", e)))
 }
 
-#[pyfunction]
-fn count_locs(file_path: String) -> PyResult<Vec<(String, usize)>> {
-    let path = Path::new(&file_path);
+// --- Directory Scanners & Hybrid Logic ---
 
-    // Load all files (no filter)
-    let files = load_snapshot_data(path, |_| true)
-        .map_err(|e| PyIOError::new_err(format!("Failed to read snapshot: {}", e)))?;
+#[derive(IntoPyObject)]
+struct MetadataPreview {
+    author: String,
+    comment: String,
+    format_version: String,
+}
 
-    let mut results = Vec::new();
-    for (name, content) in files {
-        if let Ok(text) = String::from_utf8(content) {
-            results.push((name, text.lines().count()));
-        } else {
-            results.push((name, 0));
-        }
+#[derive(IntoPyObject)]
+struct DryRunReport {
+    files: Vec<(OsString, u64, bool)>,
+    total_bytes: u64,
+    metadata: MetadataPreview,
+}
+
+/// Files `create_snap` would pick up, with their sizes. Entries the walk cannot
+/// read are reported on stderr, or raise `IOError` with `strict=True`.
+/// `include`, `exclude`, `max_depth`, `respect_gitignore`, `include_hidden`,
+/// `ignore_files`, `min_size`, `max_size` and `exclude_snapshots` shape the
+/// walk the same way they do for `create_snap`.
+///
+/// `details=True` returns a dict instead: `files` as `(path, size,
+/// is_preserved)` tuples, where `is_preserved` marks the ignore files
+/// (`.gitignore`, `.veghignore`, ...) that get archived along with the tree
+/// (being dotfiles, only with `include_hidden=True`),
+/// `total_bytes`, and `metadata` with the `author`, `comment` and
+/// `format_version` that `create_snap` would write for the same `comment`
+/// and `author`.
+#[pyfunction]
+#[pyo3(signature = (source, include=None, exclude=None, strict=None, max_depth=None, respect_gitignore=None, include_hidden=None, ignore_files=None, min_size=None, max_size=None, exclude_snapshots=None, details=None, comment=None, author=None))]
+#[allow(clippy::too_many_arguments)]
+fn dry_run_snap(
+    py: Python<'_>,
+    source: String,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    strict: Option<bool>,
+    max_depth: Option<usize>,
+    respect_gitignore: Option<bool>,
+    include_hidden: Option<bool>,
+    ignore_files: Option<Vec<String>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    exclude_snapshots: Option<bool>,
+    details: Option<bool>,
+    comment: Option<String>,
+    author: Option<String>,
+) -> PyResult<Py<PyAny>> {
+    let walk = walk_settings(
+        max_depth,
+        respect_gitignore,
+        include_hidden,
+        ignore_files,
+        min_size,
+        max_size,
+        exclude_snapshots,
+    )?;
+    let files = walk_source(
+        Path::new(&source),
+        include,
+        exclude,
+        &walk,
+        strict.unwrap_or(false),
+    )?;
+    if !details.unwrap_or(false) {
+        let files: Vec<(OsString, u64)> = files
+            .into_iter()
+            .map(|(path, size)| (path.into_os_string(), size))
+            .collect();
+        return Ok(files.into_pyobject(py)?.into_any().unbind());
     }
 
-    Ok(results)
+    // Same starting point as the writer, so the preview cannot drift from it
+    let mut meta = new_metadata(comment);
+    if let Some(author) = author {
+        meta.author = author;
+    }
+    let report = DryRunReport {
+        total_bytes: files.iter().map(|(_, size)| size).sum(),
+        files: files
+            .into_iter()
+            .map(|(path, size)| {
+                let name = path.file_name().and_then(|n| n.to_str());
+                let preserved = name.is_some_and(|n| walk.ignore_files.iter().any(|f| f == n));
+                (path.into_os_string(), size, preserved)
+            })
+            .collect(),
+        metadata: MetadataPreview {
+            author: meta.author,
+            comment: meta.comment,
+            format_version: meta.format_version,
+        },
+    };
+    Ok(report.into_pyobject(py)?.into_any().unbind())
 }
 
-// --- Directory Scanners & Hybrid Logic ---
+#[derive(IntoPyObject)]
+struct SnapEstimate {
+    file_count: usize,
+    total_bytes: u64,
+    estimated_compressed_bytes: u64,
+}
+
+/// Files sampled by `estimate_snap`, spread evenly over the walk.
+const ESTIMATE_SAMPLE_FILES: usize = 256;
 
+/// Walks `source` like `dry_run_snap` and estimates the snapshot size by
+/// compressing the first 64 KiB of a sample of files at `level`.
 #[pyfunction]
-#[pyo3(signature = (source, include=None, exclude=None))]
-fn dry_run_snap(
+#[pyo3(signature = (source, level=3, include=None, exclude=None))]
+fn estimate_snap(
+    py: Python<'_>,
     source: String,
+    level: i32,
     include: Option<Vec<String>>,
     exclude: Option<Vec<String>>,
-) -> PyResult<Vec<(String, u64)>> {
+) -> PyResult<SnapEstimate> {
+    Codec::Zstd
+        .check_level(level)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
     let source_path = Path::new(&source);
-    let mut results = Vec::new();
+    let files = walk_source(
+        source_path,
+        include,
+        exclude,
+        &WalkSettings::default(),
+        false,
+    )?;
+    let total_bytes: u64 = files.iter().map(|(_, size)| size).sum();
 
-    let mut override_builder = OverrideBuilder::new(source_path);
-    if let Some(incs) = include {
-        for pattern in incs {
-            let _ = override_builder.add(&pattern);
+    let step = files.len().div_ceil(ESTIMATE_SAMPLE_FILES).max(1);
+    let (sampled, compressed) = py.detach(|| {
+        let mut sampled = 0u64;
+        let mut compressed = 0u64;
+        for (name, _) in files.iter().step_by(step) {
+            let Ok(file) = File::open(source_path.join(name)) else {
+                continue;
+            };
+            let mut sample = Vec::new();
+            if file
+                .take(COMPRESSIBILITY_SAMPLE)
+                .read_to_end(&mut sample)
+                .is_err()
+            {
+                continue;
+            }
+            if let Ok(out) = zstd::bulk::compress(&sample, level) {
+                sampled += sample.len() as u64;
+                compressed += out.len() as u64;
+            }
         }
+        (sampled, compressed)
+    });
+
+    let ratio = if sampled > 0 {
+        compressed as f64 / sampled as f64
+    } else {
+        1.0
+    };
+    Ok(SnapEstimate {
+        file_count: files.len(),
+        total_bytes,
+        estimated_compressed_bytes: (total_bytes as f64 * ratio).round() as u64,
+    })
+}
+
+/// Trains a zstd dictionary of at most `dict_size` bytes on the contents of
+/// `sample_files`, for `create_snap(dictionary=...)`. Samples should look like
+/// what the snapshots will hold (typically files from a few of the projects);
+/// too few or too small ones raise `ValueError`. A few hundred samples and
+/// ~100 KiB are a reasonable start.
+#[pyfunction]
+fn train_dictionary(
+    py: Python<'_>,
+    sample_files: Vec<String>,
+    dict_size: usize,
+) -> PyResult<Vec<u8>> {
+    if dict_size == 0 {
+        return Err(PyValueError::new_err("dict_size must be at least 1"));
     }
-    if let Some(excs) = exclude {
-        for pattern in excs {
-            let _ = override_builder.add(&format!("!{}", pattern));
+    py.detach(|| {
+        let samples = sample_files
+            .iter()
+            .map(|path| {
+                std::fs::read(path)
+                    .map_err(|e| PyIOError::new_err(format!("Cannot read sample {}: {}", path, e)))
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        zstd::dict::from_samples(&samples, dict_size)
+            .map_err(|e| PyValueError::new_err(format!("Dictionary training failed: {}", e)))
+    })
+}
+
+/// Walk options shared by `create_snap` and `dry_run_snap`, unset ones left at
+/// their defaults.
+fn walk_settings(
+    max_depth: Option<usize>,
+    respect_gitignore: Option<bool>,
+    include_hidden: Option<bool>,
+    ignore_files: Option<Vec<String>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    exclude_snapshots: Option<bool>,
+) -> PyResult<WalkSettings> {
+    let defaults = WalkSettings::default();
+    // Extra names add to the defaults, so `.veghignore` always applies
+    let mut all_ignore_files = defaults.ignore_files;
+    for name in ignore_files.unwrap_or_default() {
+        if !all_ignore_files.contains(&name) {
+            all_ignore_files.push(name);
         }
     }
+    let walk = WalkSettings {
+        max_depth,
+        min_size,
+        max_size,
+        exclude_snapshots: exclude_snapshots.unwrap_or(defaults.exclude_snapshots),
+        respect_gitignore: respect_gitignore.unwrap_or(defaults.respect_gitignore),
+        include_hidden: include_hidden.unwrap_or(defaults.include_hidden),
+        ignore_files: all_ignore_files,
+        threads: None,
+    };
+    walk.check()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(walk)
+}
+
+/// Files a snapshot of `source` would pick up, with their sizes. Walk errors
+/// are printed and skipped, or returned as `IOError` when `strict`.
+fn walk_source(
+    source_path: &Path,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    walk: &WalkSettings,
+    strict: bool,
+) -> PyResult<Vec<(PathBuf, u64)>> {
+    let mut results = Vec::new();
+
+    let mut override_builder = override_builder(
+        source_path,
+        &include.unwrap_or_default(),
+        &exclude.unwrap_or_default(),
+    )
+    .map_err(|e| PyValueError::new_err(e.to_string()))?;
     let _ = override_builder.add(&format!("!{}", CACHE_DIR));
 
     let overrides = override_builder
@@ -286,25 +3569,120 @@ fn dry_run_snap(
         .map_err(|e| PyIOError::new_err(format!("Override build fail: {}", e)))?;
 
     let mut builder = WalkBuilder::new(source_path);
-    for &f in PRESERVED_FILES {
-        builder.add_custom_ignore_filename(f);
-    }
-    builder.hidden(true).git_ignore(true).overrides(overrides);
-    builder.filter_entry(|entry| !entry.path().to_string_lossy().contains(CACHE_DIR));
+    walk.configure(&mut builder);
+    builder.overrides(overrides);
 
-    for entry in builder.build().flatten() {
+    for result in builder.build() {
+        let entry = match result {
+            Ok(entry) => entry,
+            Err(e) if strict => return Err(PyIOError::new_err(e.to_string())),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
         let path = entry.path();
         if path.is_file() {
             let name = path.strip_prefix(source_path).unwrap_or(path);
-            let name_str = name.to_string_lossy().to_string();
             let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
-            results.push((name_str, size));
+            if !walk.size_allowed(size) || walk.skips_snapshot(path) {
+                continue;
+            }
+            results.push((name.to_path_buf(), size));
         }
     }
 
     Ok(results)
 }
 
+/// Which rule excluded (or re-included) a path, as reported by `trace_exclusion`.
+#[derive(IntoPyObject)]
+struct ExclusionTrace {
+    source: String,
+    pattern: String,
+    line: Option<usize>,
+    excluded: bool,
+}
+
+/// Pinpoints the ignore file, pattern and line responsible for excluding `path`
+/// (relative to `source`). Deeper ignore files win over shallower ones, like the walker.
+#[pyfunction]
+fn trace_exclusion(source: String, path: String) -> PyResult<Option<ExclusionTrace>> {
+    let root = Path::new(&source);
+    let rel = Path::new(&path);
+    let full = root.join(rel);
+    let is_dir = full.is_dir();
+
+    if rel.components().any(|c| c.as_os_str() == CACHE_DIR) {
+        return Ok(Some(ExclusionTrace {
+            source: "<internal>".to_string(),
+            pattern: CACHE_DIR.to_string(),
+            line: None,
+            excluded: true,
+        }));
+    }
+
+    // Walk from the deepest directory up to the root
+    let mut dirs: Vec<&Path> = rel.ancestors().skip(1).collect();
+    if dirs.is_empty() {
+        dirs.push(Path::new(""));
+    }
+    for dir_rel in dirs {
+        let dir = root.join(dir_rel);
+        let mut gi_builder = ignore::gitignore::GitignoreBuilder::new(&dir);
+        let mut any = false;
+        for &f in PRESERVED_FILES {
+            let candidate = dir.join(f);
+            if candidate.is_file() && gi_builder.add(&candidate).is_none() {
+                any = true;
+            }
+        }
+        if !any {
+            continue;
+        }
+        let matcher = gi_builder
+            .build()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        let glob = match matcher.matched_path_or_any_parents(&full, is_dir) {
+            ignore::Match::Ignore(g) | ignore::Match::Whitelist(g) => g,
+            ignore::Match::None => continue,
+        };
+
+        let from = glob.from().map(Path::to_path_buf).unwrap_or_default();
+        // Globs don't carry line numbers; the last matching line is the one that applied.
+        let line = std::fs::read_to_string(&from).ok().and_then(|text| {
+            text.lines()
+                .enumerate()
+                .filter(|(_, l)| l.trim() == glob.original())
+                .map(|(i, _)| i + 1)
+                .last()
+        });
+        let shown = from.strip_prefix(root).unwrap_or(&from);
+        return Ok(Some(ExclusionTrace {
+            source: shown.to_string_lossy().to_string(),
+            pattern: glob.original().to_string(),
+            line,
+            excluded: !glob.is_whitelist(),
+        }));
+    }
+
+    // Nothing in the ignore files; the walker also hides dotfiles
+    if rel
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+    {
+        return Ok(Some(ExclusionTrace {
+            source: "<hidden>".to_string(),
+            pattern: ".*".to_string(),
+            line: None,
+            excluded: true,
+        }));
+    }
+
+    Ok(None)
+}
+
 #[pyfunction]
 #[pyo3(signature = (source, include=None, exclude=None))]
 fn get_context_xml(
@@ -318,28 +3696,9 @@ fn get_context_xml(
     // Case 1: Source is a Snapshot File
     if source_path.is_file() {
         let files = load_snapshot_data(source_path, |path| {
-            if let Some(incs) = &include {
-                let mut matched = false;
-                for inc in incs {
-                    if path.contains(inc) {
-                        matched = true;
-                        break;
-                    }
-                }
-                if !matched {
-                    return false;
-                }
-            }
-            if let Some(excs) = &exclude {
-                for exc in excs {
-                    if path.contains(exc) {
-                        return false;
-                    }
-                }
-            }
-            true
+            substring_filter(path, &include, &exclude)
         })
-        .map_err(|e| PyIOError::new_err(format!("Failed to read snapshot: {}", e)))?;
+        .map_err(read_err)?;
 
         for (name, content) in files {
             if content.contains(&0) {
@@ -355,17 +3714,12 @@ fn get_context_xml(
 
     // Case 2: Source is a Directory
     } else {
-        let mut override_builder = OverrideBuilder::new(source_path);
-        if let Some(incs) = include {
-            for pattern in incs {
-                let _ = override_builder.add(&pattern);
-            }
-        }
-        if let Some(excs) = exclude {
-            for pattern in excs {
-                let _ = override_builder.add(&format!("!{}", pattern));
-            }
-        }
+        let mut override_builder = override_builder(
+            source_path,
+            &include.unwrap_or_default(),
+            &exclude.unwrap_or_default(),
+        )
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
         let _ = override_builder.add(&format!("!{}", CACHE_DIR));
         let overrides = override_builder
             .build()
@@ -421,8 +3775,7 @@ fn search_snap(
     let path = Path::new(&file_path);
     let prefix_str = prefix.unwrap_or_default();
 
-    let files = load_snapshot_data(path, |p| p.starts_with(&prefix_str))
-        .map_err(|e| PyIOError::new_err(format!("Failed to read snapshot: {}", e)))?;
+    let files = load_snapshot_data(path, |p| p.starts_with(&prefix_str)).map_err(read_err)?;
 
     let mut results = Vec::new();
     let query_lower = if !case_sensitive {
@@ -457,12 +3810,19 @@ fn search_snap(
     Ok(results)
 }
 
+/// One file's bytes, read by loading the snapshot. Snapshots compressed with
+/// a dictionary need that `dictionary`.
 #[pyfunction]
-fn cat_file(file_path: String, target_file: String) -> PyResult<Vec<u8>> {
+#[pyo3(signature = (file_path, target_file, dictionary=None))]
+fn cat_file(
+    file_path: String,
+    target_file: String,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Vec<u8>> {
     let path = Path::new(&file_path);
 
-    let files = load_snapshot_data(path, |p| p == target_file)
-        .map_err(|e| PyIOError::new_err(format!("Failed to read snapshot: {}", e)))?;
+    let files = load_snapshot_data_using(path, dictionary.as_deref(), |p| p == target_file)
+        .map_err(read_err)?;
 
     if let Some((_, content)) = files.into_iter().next() {
         Ok(content)
@@ -474,6 +3834,178 @@ fn cat_file(file_path: String, target_file: String) -> PyResult<Vec<u8>> {
     }
 }
 
+/// `cat_file` for snapshots built with `build_offset_index=True`: seeks
+/// straight to the manifest and the file's blobs instead of scanning.
+#[pyfunction]
+fn read_file_indexed(file_path: String, inner_path: String) -> PyResult<Vec<u8>> {
+    let mut reader = IndexedReader::open(Path::new(&file_path)).map_err(core_err)?;
+
+    let mut manifest = None;
+    for format in [ManifestFormat::Json, ManifestFormat::MsgPack] {
+        if let Some(raw) = reader.read_entry(format.entry_name()).map_err(core_err)? {
+            manifest = Some(format.decode(&raw[..]).map_err(core_err)?);
+            break;
+        }
+    }
+    let Some(manifest) = manifest else {
+        return Err(PyValueError::new_err("Offset index lists no manifest"));
+    };
+    manifest_requirements(&manifest).map_err(read_err)?;
+
+    let Some(entry) = manifest
+        .entries
+        .into_iter()
+        .find(|e| e.path == inner_path && e.symlink.is_none())
+    else {
+        return Err(PyValueError::new_err(format!(
+            "File '{}' not found in snapshot",
+            inner_path
+        )));
+    };
+
+    let mut content = Vec::with_capacity(entry.size as usize);
+    for hash in entry.chunks.unwrap_or_else(|| vec![entry.hash.clone()]) {
+        let blob = format!("blobs/{}", hash);
+        match reader.read_entry(&blob).map_err(core_err)? {
+            Some(data) => content.extend_from_slice(&data),
+            None => {
+                return Err(PyIOError::new_err(format!(
+                    "Blob {} missing for '{}'",
+                    hash, inner_path
+                )));
+            }
+        }
+    }
+    Ok(content)
+}
+
+/// One file's bytes without extracting anything. Snapshots with an offset
+/// index are read by seeking; otherwise the archive is streamed only as far
+/// as needed: a V2 file ends the scan when it is reached, a V3 file costs one
+/// pass up to the manifest and a second that stops after its last blob.
+/// Snapshots compressed with a dictionary need that `dictionary`.
+#[pyfunction]
+#[pyo3(signature = (file_path, entry_path, dictionary=None))]
+fn read_file_from_snap(
+    py: Python<'_>,
+    file_path: String,
+    entry_path: String,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Vec<u8>> {
+    let path = Path::new(&file_path);
+    if IndexedReader::open(path).is_ok() {
+        return read_file_indexed(file_path, entry_path);
+    }
+    match py.detach(|| stream_single_file(path, &entry_path, dictionary.as_deref())) {
+        Ok(Some(content)) => Ok(content),
+        Ok(None) => Err(PyValueError::new_err(format!(
+            "File '{}' not found in snapshot",
+            entry_path
+        ))),
+        Err(e) => Err(read_err(e)),
+    }
+}
+
+fn stream_single_file(
+    path: &Path,
+    entry_path: &str,
+    dictionary: Option<&[u8]>,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let open = || -> std::io::Result<tar::Archive<Box<dyn Read>>> {
+        Ok(tar::Archive::new(open_decoder_using(
+            open_snapshot(path)?,
+            None,
+            dictionary,
+        )?))
+    };
+
+    // Pass 1: the V2 file itself, or the manifest that says which blobs to fetch
+    let mut manifest = None;
+    let mut archive = open()?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if let Some(format) = ManifestFormat::from_entry_path(&name) {
+            manifest = format.decode(&mut entry).ok();
+            break;
+        }
+        if name == entry_path {
+            let mut content = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut content)?;
+            return Ok(Some(content));
+        }
+    }
+    let Some(manifest) = manifest else {
+        return Ok(None);
+    };
+    manifest_requirements(&manifest)?;
+    let Some(target) = manifest
+        .entries
+        .into_iter()
+        .find(|e| e.path == entry_path && e.symlink.is_none())
+    else {
+        return Ok(None);
+    };
+
+    // Pass 2: collect just those blobs, stopping once the last one is in
+    let chunks = target.chunks.unwrap_or_else(|| vec![target.hash.clone()]);
+    let mut pending: HashSet<&str> = chunks.iter().map(String::as_str).collect();
+    let mut blobs: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut archive = open()?;
+    for entry in archive.entries()? {
+        if pending.is_empty() {
+            break;
+        }
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if let Some(hash) = name.strip_prefix("blobs/")
+            && pending.remove(hash)
+        {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            blobs.insert(hash.to_string(), data);
+        }
+    }
+
+    let mut content = Vec::with_capacity(target.size as usize);
+    for hash in &chunks {
+        let Some(data) = blobs.get(hash) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Blob {} missing for '{}'", hash, entry_path),
+            ));
+        };
+        content.extend_from_slice(data);
+    }
+    Ok(Some(content))
+}
+
+#[derive(IntoPyObject)]
+struct ReadFilesResult {
+    files: BTreeMap<String, Vec<u8>>,
+    missing: Vec<String>,
+}
+
+/// Batch counterpart to `cat_file`: one forward pass for every requested path.
+#[pyfunction]
+fn read_files(file_path: String, inner_paths: Vec<String>) -> PyResult<ReadFilesResult> {
+    let path = Path::new(&file_path);
+    let wanted: HashSet<&str> = inner_paths.iter().map(String::as_str).collect();
+
+    let files: BTreeMap<String, Vec<u8>> = load_snapshot_data(path, |p| wanted.contains(p))
+        .map_err(read_err)?
+        .into_iter()
+        .collect();
+
+    let missing = inner_paths
+        .iter()
+        .filter(|p| !files.contains_key(p.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(ReadFilesResult { files, missing })
+}
+
 // --- FIX 2: Add signature attribute to scan_locs_dir ---
 #[pyfunction]
 #[pyo3(signature = (source, exclude=None))]
@@ -481,12 +4013,8 @@ fn scan_locs_dir(source: String, exclude: Option<Vec<String>>) -> PyResult<Vec<(
     let source_path = Path::new(&source);
     let mut results = Vec::new();
 
-    let mut override_builder = OverrideBuilder::new(source_path);
-    if let Some(excs) = exclude {
-        for pattern in excs {
-            let _ = override_builder.add(&format!("!{}", pattern));
-        }
-    }
+    let mut override_builder = override_builder(source_path, &[], &exclude.unwrap_or_default())
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
     let _ = override_builder.add(&format!("!{}", CACHE_DIR));
     let overrides = override_builder
         .build()
@@ -508,30 +4036,25 @@ fn scan_locs_dir(source: String, exclude: Option<Vec<String>>) -> PyResult<Vec<(
                 continue;
             }
 
-            let count = if let Ok(mut file) = File::open(path) {
-                let mut buffer = [0; 1024];
-                let chunk_size = file.read(&mut buffer).unwrap_or(0);
-                if buffer[..chunk_size].contains(&0) {
-                    0
-                } else if file.seek(SeekFrom::Start(0)).is_ok() {
-                    let reader = std::io::BufReader::new(file);
-                    reader.lines().count()
-                } else {
-                    0
-                }
-            } else {
-                0
-            };
+            // Same counter and binary rule as `count_locs`
+            let count = File::open(path)
+                .and_then(LineStats::read)
+                .ok()
+                .and_then(|stats| stats.lines())
+                .unwrap_or(0);
             results.push((name_str, count));
         }
     }
     Ok(results)
 }
 
+/// `(path, size, hash)` for every file. The third field is the Blake3 content
+/// hash from the manifest for V3 snapshots and an empty string for V2 ones,
+/// which record none. `list_files_detailed` puts the entry type there instead.
 #[pyfunction]
-fn list_files_details(file_path: String) -> PyResult<Vec<(String, u64, String)>> {
-    let file = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-    let decoder = zstd::stream::read::Decoder::new(file).unwrap();
+fn list_files_details(file_path: String) -> PyResult<Vec<(OsString, u64, String)>> {
+    let file = open_snapshot(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let decoder = open_decoder(file).map_err(read_err)?;
     let mut archive = tar::Archive::new(decoder);
     let mut results = Vec::new();
 
@@ -541,60 +4064,167 @@ fn list_files_details(file_path: String) -> PyResult<Vec<(String, u64, String)>>
             let path = e.path().unwrap().into_owned();
             let path_str = path.to_string_lossy().to_string();
 
-            if path_str == "manifest.json" {
-                let mut content = String::new();
-                if e.read_to_string(&mut content).is_ok()
-                    && let Ok(manifest) = serde_json::from_str::<SnapshotManifest>(&content)
-                {
-                    return Ok(manifest
-                        .entries
-                        .into_iter()
-                        .map(|en| (en.path, en.size, en.hash))
-                        .collect());
-                }
+            if let Some(format) = ManifestFormat::from_entry_path(&path_str)
+                && let Ok(manifest) = format.decode(&mut e)
+            {
+                manifest_requirements(&manifest).map_err(read_err)?;
+                return Ok(manifest
+                    .entries
+                    .into_iter()
+                    .map(|en| (entry_name(&en), en.size, en.hash))
+                    .collect());
             }
 
             if !path_str.starts_with("blobs/")
                 && path_str != ".vegh.json"
-                && path_str != "manifest.json"
+                && path_str != OFFSETS_ENTRY
+                && ManifestFormat::from_entry_path(&path_str).is_none()
             {
-                results.push((path_str, size, String::new()));
+                results.push((path.into_os_string(), size, String::new()));
             }
         }
     }
     Ok(results)
 }
 
+/// How a tar header's entry type is reported by `list_files_detailed`.
+fn entry_type_name(kind: tar::EntryType) -> &'static str {
+    match kind {
+        tar::EntryType::Regular | tar::EntryType::Continuous => "file",
+        tar::EntryType::Directory => "dir",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        _ => "other",
+    }
+}
+
+/// `(path, size, entry_type)` for every entry, in one pass over the snapshot.
+/// `entry_type` is `"file"`, `"dir"`, `"symlink"`, `"hardlink"` or `"other"`;
+/// V3 snapshots hold files, symlinks and the empty directories kept by
+/// `preserve_empty_dirs`. Unlike `list_files_details`, whose third field is
+/// the content hash, this says what each entry is. Internal entries such as
+/// `.vegh.json` are left out. Takes the same inputs as `list_files`, with a
+/// `password` and `dictionary` where the snapshot needs them.
 #[pyfunction]
-fn hash_file(file_path: String) -> PyResult<String> {
-    let file = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-    let mut hasher = blake3::Hasher::new();
+#[pyo3(signature = (file_path, password=None, dictionary=None))]
+fn list_files_detailed(
+    file_path: Bound<'_, PyAny>,
+    password: Option<String>,
+    dictionary: Option<Vec<u8>>,
+) -> PyResult<Vec<(OsString, u64, String)>> {
+    let source = SnapshotSource::new(&file_path)?;
+    let file = source
+        .open()
+        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let decoder =
+        open_decoder_using(file, password.as_deref(), dictionary.as_deref()).map_err(read_err)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut results = Vec::new();
 
-    if let Ok(mmap) = unsafe { memmap2::MmapOptions::new().map(&file) } {
-        hasher.update_rayon(&mmap);
-    } else {
-        let mut f = File::open(&file_path).map_err(|e| PyIOError::new_err(e.to_string()))?;
-        std::io::copy(&mut f, &mut hasher).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    for entry in archive.entries().map_err(read_err)? {
+        let mut e = entry.map_err(read_err)?;
+        let path = e.path().map_err(read_err)?.into_owned();
+        let path_str = path.to_string_lossy().to_string();
+
+        if let Some(format) = ManifestFormat::from_entry_path(&path_str) {
+            let manifest = format
+                .decode(&mut e)
+                .map_err(|e| PyValueError::new_err(format!("Unreadable manifest: {}", e)))?;
+            manifest_requirements(&manifest).map_err(read_err)?;
+            // Only directories sit beside the manifest as entries of their own
+            results.retain(|(_, _, kind)| kind == "dir");
+            results.extend(manifest.entries.into_iter().map(|en| {
+                let kind = if en.symlink.is_some() {
+                    "symlink"
+                } else {
+                    "file"
+                };
+                (entry_name(&en), en.size, kind.to_string())
+            }));
+            return Ok(results);
+        }
+        if !path_str.starts_with("blobs/") && path_str != ".vegh.json" && path_str != OFFSETS_ENTRY
+        {
+            let kind = entry_type_name(e.header().entry_type());
+            results.push((path.into_os_string(), e.size(), kind.to_string()));
+        }
     }
+    Ok(results)
+}
 
-    Ok(hasher.finalize().to_hex().to_string())
+/// Hex BLAKE3 of any file; `algorithm` works as for `check_integrity`.
+#[pyfunction]
+#[pyo3(signature = (file_path, algorithm=None))]
+fn hash_file(py: Python<'_>, file_path: String, algorithm: Option<String>) -> PyResult<String> {
+    let (alg, tagged) = digest_algorithm(algorithm.as_deref())?;
+    let digest = py
+        .detach(|| digest_file(alg, Path::new(&file_path)))
+        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+    Ok(tag_digest(alg, tagged, digest))
 }
 
 #[pymodule]
 #[pyo3(name = "_core")]
 fn pyvegh_core(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(create_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(create_tar_to_writer, m)?)?;
+    m.add_function(wrap_pyfunction!(create_snap_to_writer, m)?)?;
     m.add_function(wrap_pyfunction!(dry_run_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(train_dictionary, m)?)?;
+    m.add_function(wrap_pyfunction!(trace_exclusion, m)?)?;
     m.add_function(wrap_pyfunction!(restore_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(restore_snap_filtered, m)?)?;
+    m.add_function(wrap_pyfunction!(restore_subtree, m)?)?;
+    m.add_function(wrap_pyfunction!(recover_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(create_multi_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_snap_against_dir, m)?)?;
+    m.add_function(wrap_pyfunction!(rename_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_snaps, m)?)?;
+    m.add_function(wrap_pyfunction!(update_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(create_incremental_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(sign_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_signature, m)?)?;
     m.add_function(wrap_pyfunction!(list_files, m)?)?;
     m.add_function(wrap_pyfunction!(check_integrity, m)?)?;
+    m.add_function(wrap_pyfunction!(analyze_dedup_potential, m)?)?;
+    m.add_function(wrap_pyfunction!(check_portability, m)?)?;
+    m.add_function(wrap_pyfunction!(read_files, m)?)?;
+    m.add_function(wrap_pyfunction!(read_file_indexed, m)?)?;
+    m.add_function(wrap_pyfunction!(metadata_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(overlap_ratio, m)?)?;
+    m.add_function(wrap_pyfunction!(entries_after_creation, m)?)?;
+    m.add_function(wrap_pyfunction!(structure_fingerprint, m)?)?;
+    m.add_function(wrap_pyfunction!(structure_equal, m)?)?;
+    m.add_function(wrap_pyfunction!(permission_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_snaps, m)?)?;
+    m.add_function(wrap_pyfunction!(read_file_from_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(snapshot_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(create_snap_from_map, m)?)?;
+    m.add_function(wrap_pyfunction!(backfill_manifest, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_snapshot, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_snap, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_contents, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_all, m)?)?;
+    m.add_function(wrap_pyfunction!(resolve_chain, m)?)?;
+    m.add_function(wrap_pyfunction!(restore_chain, m)?)?;
+    m.add_function(wrap_pyfunction!(get_metadata_dict, m)?)?;
+    m.add_function(wrap_pyfunction!(prune_expired, m)?)?;
+    m.add_function(wrap_pyfunction!(prune_snaps, m)?)?;
+    m.add_function(wrap_pyfunction!(incompressible_files, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_metadata, m)?)?;
+    m.add_class::<SnapshotWriter>()?;
+    m.add("VeghFormatError", m.py().get_type::<VeghFormatError>())?;
     m.add_function(wrap_pyfunction!(get_metadata, m)?)?;
     m.add_function(wrap_pyfunction!(scan_locs_dir, m)?)?;
     m.add_function(wrap_pyfunction!(cat_file, m)?)?;
     m.add_function(wrap_pyfunction!(list_files_details, m)?)?;
+    m.add_function(wrap_pyfunction!(list_files_detailed, m)?)?;
     m.add_function(wrap_pyfunction!(get_context_xml, m)?)?;
     m.add_function(wrap_pyfunction!(search_snap, m)?)?;
     m.add_function(wrap_pyfunction!(count_locs, m)?)?;
+    m.add_function(wrap_pyfunction!(count_locs_by_language, m)?)?;
+    m.add_function(wrap_pyfunction!(loc_tree, m)?)?;
     m.add_function(wrap_pyfunction!(read_snapshot_text, m)?)?;
     m.add_function(wrap_pyfunction!(hash_file, m)?)?;
     Ok(())
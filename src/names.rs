@@ -0,0 +1,103 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+// --- Non-UTF-8 Names ---
+
+// Tar headers carry names as raw bytes, but the manifest stores them as
+// strings. A name that is not valid UTF-8 is escaped there instead: each
+// undecodable byte becomes `\xNN` and a literal `\` becomes `\\`. Its entry
+// is flagged so readers know to undo it; every other name is stored as is.
+
+/// The raw bytes of `path`, as a tar header stores them.
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// The path named by raw header bytes.
+pub fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Manifest form of a name, and whether it had to be escaped.
+pub fn encode(bytes: &[u8]) -> (String, bool) {
+    if let Ok(name) = std::str::from_utf8(bytes) {
+        return (name.to_string(), false);
+    }
+    let mut name = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        name.push_str(&escape(chunk.valid()));
+        for byte in chunk.invalid() {
+            name.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    (name, true)
+}
+
+/// `encode` for a path on disk.
+pub fn encode_path(path: &Path) -> (String, bool) {
+    encode(&path_bytes(path))
+}
+
+/// `text` as it appears inside an escaped name.
+pub fn escape(text: &str) -> Cow<'_, str> {
+    if text.contains('\\') {
+        Cow::Owned(text.replace('\\', "\\\\"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// The raw bytes of a manifest name. Malformed escapes are kept verbatim.
+pub fn decode(name: &str, escaped: bool) -> Vec<u8> {
+    if !escaped {
+        return name.as_bytes().to_vec();
+    }
+    let bytes = name.as_bytes();
+    let mut raw = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) == Some(&b'\\') {
+                raw.push(b'\\');
+                i += 2;
+                continue;
+            }
+            if bytes.get(i + 1) == Some(&b'x')
+                && let Some(hex) = name.get(i + 2..i + 4)
+                && hex.bytes().all(|b| b.is_ascii_hexdigit())
+                && let Ok(byte) = u8::from_str_radix(hex, 16)
+            {
+                raw.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        raw.push(bytes[i]);
+        i += 1;
+    }
+    raw
+}
+
+/// The path a manifest name stands for, relative to wherever it is restored.
+pub fn decode_path(name: &str, escaped: bool) -> PathBuf {
+    if escaped {
+        bytes_path(decode(name, true))
+    } else {
+        PathBuf::from(name)
+    }
+}
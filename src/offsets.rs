@@ -0,0 +1,142 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// --- Offset Index (random access) ---
+
+pub const OFFSETS_ENTRY: &str = ".vegh.offsets";
+// zstd skippable frame: decoders step over it, so plain readers are unaffected
+const TRAILER_MAGIC: u32 = 0x184D_2A5E;
+const TRAILER_PAYLOAD: u32 = 32;
+const TRAILER_LEN: u64 = 8 + TRAILER_PAYLOAD as u64;
+const TAR_BLOCK: u64 = 512;
+
+/// Where every tar entry's data sits, so readers can jump straight to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OffsetIndex {
+    /// Tar entry name -> (data offset, size) in the uncompressed stream.
+    pub entries: BTreeMap<String, (u64, u64)>,
+    /// Points a decoder can start from, as (uncompressed offset, compressed offset).
+    pub frames: Vec<(u64, u64)>,
+}
+
+impl Default for OffsetIndex {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            frames: vec![(0, 0)],
+        }
+    }
+}
+
+impl OffsetIndex {
+    /// Records the entry just appended; `end` is the stream position after it.
+    pub fn record(&mut self, path: &str, size: u64, end: u64) {
+        let padded = size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        self.entries.insert(path.to_string(), (end - padded, size));
+    }
+
+    /// Closest frame start at or before `offset`.
+    fn frame_for(&self, offset: u64) -> (u64, u64) {
+        self.frames
+            .iter()
+            .rev()
+            .find(|(u, _)| *u <= offset)
+            .copied()
+            .unwrap_or((0, 0))
+    }
+}
+
+/// Appends the trailer that lets readers find the index without a scan.
+pub fn write_trailer<W: Write>(
+    sink: &mut W,
+    index_offset: u64,
+    index_size: u64,
+    frame: (u64, u64),
+) -> io::Result<()> {
+    sink.write_all(&TRAILER_MAGIC.to_le_bytes())?;
+    sink.write_all(&TRAILER_PAYLOAD.to_le_bytes())?;
+    for v in [index_offset, index_size, frame.0, frame.1] {
+        sink.write_all(&v.to_le_bytes())?;
+    }
+    sink.flush()
+}
+
+/// Random-access reader over a snapshot written with an offset index.
+pub struct IndexedReader {
+    file: File,
+    len: u64,
+    index: OffsetIndex,
+}
+
+impl IndexedReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).context("Open failed")?;
+        let len = file.metadata()?.len();
+        if len < TRAILER_LEN {
+            bail!("Snapshot has no offset index");
+        }
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+
+        let word = |i: usize| u32::from_le_bytes(trailer[i..i + 4].try_into().unwrap());
+        let quad = |i: usize| u64::from_le_bytes(trailer[i..i + 8].try_into().unwrap());
+        if word(0) != TRAILER_MAGIC || word(4) != TRAILER_PAYLOAD {
+            bail!("Snapshot has no offset index (create it with build_offset_index=True)");
+        }
+        let (offset, size, frame) = (quad(8), quad(16), (quad(24), quad(32)));
+
+        let raw = Self::read_at(&mut file, len, frame, offset, size)?;
+        let index = serde_json::from_slice(&raw).context("Unreadable offset index")?;
+        Ok(Self { file, len, index })
+    }
+
+    /// `size` bytes at `offset` of the uncompressed stream, decoding from
+    /// `frame`. The positions come from the file itself, so they are checked
+    /// rather than trusted: a damaged index fails instead of over-allocating.
+    fn read_at(
+        file: &mut File,
+        len: u64,
+        frame: (u64, u64),
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>> {
+        let Some(skip) = offset.checked_sub(frame.0) else {
+            bail!(
+                "Corrupt offset index: entry at {} precedes its frame",
+                offset
+            );
+        };
+        if frame.1 >= len {
+            bail!(
+                "Corrupt offset index: frame at {} is past the end of the file",
+                frame.1
+            );
+        }
+        file.seek(SeekFrom::Start(frame.1))?;
+        let mut decoder = zstd::stream::read::Decoder::new(&mut *file)?;
+        io::copy(&mut (&mut decoder).take(skip), &mut io::sink())?;
+        let mut data = Vec::new();
+        (&mut decoder).take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            bail!(
+                "Corrupt offset index: entry at {} runs past the stream",
+                offset
+            );
+        }
+        Ok(data)
+    }
+
+    /// Data of the tar entry named `name`, if the snapshot has one.
+    pub fn read_entry(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(&(offset, size)) = self.index.entries.get(name) else {
+            return Ok(None);
+        };
+        let frame = self.index.frame_for(offset);
+        Self::read_at(&mut self.file, self.len, frame, offset, size).map(Some)
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::volume::snapshot_hash;
+
+// --- Detached Signatures ---
+
+// Ed25519 over a BLAKE3 digest of every compressed byte, so any change to the
+// archive (or to one of its volumes) invalidates the signature.
+const SIGNATURE_CONTEXT: &[u8] = b"vegh-snapshot-signature-v1\0";
+pub const SIGNATURE_EXT: &str = "sig";
+
+/// Where the detached signature of `snapshot` lives: `<snapshot>.sig`.
+pub fn signature_path(snapshot: &Path) -> PathBuf {
+    let mut name = snapshot.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIGNATURE_EXT);
+    PathBuf::from(name)
+}
+
+fn signed_message(digest: &[u8; 32]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, digest.as_slice()].concat()
+}
+
+/// Signs `snapshot` with a PKCS#8 PEM Ed25519 private key and writes the
+/// hex signature next to it. Returns the sidecar path.
+pub fn sign_snapshot(snapshot: &Path, private_key_pem: &str) -> Result<PathBuf> {
+    let key = SigningKey::from_pkcs8_pem(private_key_pem)
+        .map_err(|e| anyhow::anyhow!("Invalid Ed25519 private key: {}", e))?;
+    let digest = snapshot_hash(snapshot)?;
+    let signature = key.sign(&signed_message(&digest));
+    let sidecar = signature_path(snapshot);
+    fs::write(&sidecar, format!("{}\n", hex::encode(signature.to_bytes())))
+        .with_context(|| format!("Writing {} failed", sidecar.display()))?;
+    Ok(sidecar)
+}
+
+/// Checks the sidecar signature of `snapshot` against a PEM (SubjectPublicKeyInfo)
+/// Ed25519 public key. A malformed signature counts as a failed check.
+pub fn verify_snapshot_signature(snapshot: &Path, public_key_pem: &str) -> Result<bool> {
+    let key = VerifyingKey::from_public_key_pem(public_key_pem)
+        .map_err(|e| anyhow::anyhow!("Invalid Ed25519 public key: {}", e))?;
+    let sidecar = signature_path(snapshot);
+    let text = fs::read_to_string(&sidecar)
+        .with_context(|| format!("Signature unreadable: {}", sidecar.display()))?;
+    let Some(signature) = hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return Ok(false);
+    };
+    let digest = snapshot_hash(snapshot)?;
+    // Strict: non-canonical signatures and weak keys are rejected too
+    Ok(key
+        .verify_strict(&signed_message(&digest), &signature)
+        .is_ok())
+}
@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition, WriteTransaction};
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
@@ -33,11 +33,80 @@ pub struct ManifestEntry {
     pub modified: u64,
     pub mode: u32,
     pub chunks: Option<Vec<String>>,
+    /// Link target for symlink entries (relative when it points inside the tree).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// `path` was not valid UTF-8 on disk and is escaped; see `names::decode`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub escaped: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SnapshotManifest {
     pub entries: Vec<ManifestEntry>,
+    /// Reader-visible features in use; see `core::check_requirements`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tool_version: Option<String>,
+    /// Incremental snapshots only: paths of the base that no longer exist.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
+}
+
+pub const MANIFEST_JSON: &str = "manifest.json";
+pub const MANIFEST_MSGPACK: &str = "manifest.msgpack";
+
+/// On-disk encoding of the manifest. The entry name tells readers which one it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Json,
+    /// Compact binary form for trees with very large manifests.
+    MsgPack,
+}
+
+impl ManifestFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MsgPack),
+            other => bail!(
+                "Invalid manifest_format '{}' (expected 'json' or 'msgpack')",
+                other
+            ),
+        }
+    }
+
+    /// Detects a manifest entry by its archive path.
+    pub fn from_entry_path(path: &str) -> Option<Self> {
+        match path {
+            MANIFEST_JSON => Some(Self::Json),
+            MANIFEST_MSGPACK => Some(Self::MsgPack),
+            _ => None,
+        }
+    }
+
+    pub fn entry_name(self) -> &'static str {
+        match self {
+            Self::Json => MANIFEST_JSON,
+            Self::MsgPack => MANIFEST_MSGPACK,
+        }
+    }
+
+    pub fn encode(self, manifest: &SnapshotManifest) -> Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec_pretty(manifest)?),
+            // Named fields so serde defaults keep working across versions
+            Self::MsgPack => Ok(rmp_serde::to_vec_named(manifest)?),
+        }
+    }
+
+    pub fn decode<R: std::io::Read>(self, reader: R) -> Result<SnapshotManifest> {
+        match self {
+            Self::Json => Ok(serde_json::from_reader(reader)?),
+            Self::MsgPack => Ok(rmp_serde::from_read(reader)?),
+        }
+    }
 }
 // ----------------------------------
 
@@ -288,3 +357,13 @@ impl CacheDB {
         Ok(())
     }
 }
+
+impl Drop for CacheDB {
+    fn drop(&mut self) {
+        // Abort an unfinished batch before the database handle goes away,
+        // otherwise redb blocks forever waiting on the live write transaction.
+        if let Some(txn) = self.txn.take() {
+            let _ = txn.abort();
+        }
+    }
+}
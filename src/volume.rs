@@ -0,0 +1,205 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hash::compute_file_hash;
+
+// --- Split Volumes ---
+
+// A split snapshot is the compressed stream cut into `<output>.001`,
+// `<output>.002`, ... and read back by concatenating them in order.
+
+/// `index`-th volume (1-based) of a snapshot split at `base`.
+pub fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// The base path when `path` names a first volume (`snap.vegh.001`).
+pub fn volume_base(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(".001")?;
+    (!stem.is_empty()).then(|| path.with_file_name(stem))
+}
+
+/// Whether `path` is `output` itself or one of its volumes.
+pub fn is_output_or_volume(path: &Path, output: &Path) -> bool {
+    if path == output {
+        return true;
+    }
+    let (Some(name), Some(out_name)) = (path.file_name(), output.file_name()) else {
+        return false;
+    };
+    path.parent() == output.parent()
+        && name
+            .to_str()
+            .zip(out_name.to_str())
+            .and_then(|(name, out_name)| name.strip_prefix(out_name)?.strip_prefix('.'))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Whether a file name looks like a snapshot: `*.vegh` or one of its
+/// numbered volumes (`*.vegh.001`).
+pub fn is_snapshot_name(name: &str) -> bool {
+    let stem = match name.rsplit_once('.') {
+        Some((stem, index)) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) => {
+            stem
+        }
+        _ => name,
+    };
+    stem.len() > ".vegh".len() && stem.ends_with(".vegh")
+}
+
+/// Deletes every volume of `base`, stopping at the first missing one.
+pub fn remove_volumes(base: &Path) {
+    for index in 1.. {
+        if fs::remove_file(volume_path(base, index)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes at most `limit` bytes per volume, starting the next one as needed.
+pub struct VolumeWriter {
+    base: PathBuf,
+    limit: u64,
+    index: usize,
+    current: File,
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// Creates the first volume right away, so even an empty stream has one.
+    /// Volumes left by an earlier, longer run at `base` are deleted first;
+    /// readers would otherwise append them to this stream.
+    pub fn new(base: &Path, limit: u64) -> io::Result<Self> {
+        if limit == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "split_bytes must be at least 1",
+            ));
+        }
+        remove_volumes(base);
+        Ok(Self {
+            current: File::create(volume_path(base, 1))?,
+            base: base.to_path_buf(),
+            limit,
+            index: 1,
+            written: 0,
+        })
+    }
+
+    pub fn volume_count(&self) -> usize {
+        self.index
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.limit {
+            self.current.flush()?;
+            self.index += 1;
+            self.current = File::create(volume_path(&self.base, self.index))?;
+            self.written = 0;
+        }
+        let room = (self.limit - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.flush()
+    }
+}
+
+/// Reads the volumes of a split snapshot back as one stream.
+pub struct VolumeReader {
+    paths: Vec<PathBuf>,
+    next: usize,
+    current: File,
+    total_len: u64,
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() || self.next == self.paths.len() {
+                return Ok(n);
+            }
+            self.current = File::open(&self.paths[self.next])?;
+            self.next += 1;
+        }
+    }
+}
+
+/// A snapshot on disk: one file, or the volumes of a split one.
+pub enum SnapshotFile {
+    Single(File),
+    Volumes(VolumeReader),
+}
+
+impl SnapshotFile {
+    /// Size on disk, summed over all volumes.
+    pub fn byte_len(&self) -> io::Result<u64> {
+        match self {
+            Self::Single(f) => Ok(f.metadata()?.len()),
+            Self::Volumes(v) => Ok(v.total_len),
+        }
+    }
+}
+
+impl Read for SnapshotFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Single(f) => f.read(buf),
+            Self::Volumes(v) => v.read(buf),
+        }
+    }
+}
+
+/// BLAKE3 of the snapshot as stored, the same value `check_integrity` reports
+/// and `base` references record. Split snapshots hash as the stream they were
+/// cut from.
+pub fn snapshot_hash(path: &Path) -> anyhow::Result<[u8; 32]> {
+    match open_snapshot(path)? {
+        SnapshotFile::Single(_) => compute_file_hash(path),
+        SnapshotFile::Volumes(mut volumes) => {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(&mut volumes, &mut hasher)?;
+            Ok(*hasher.finalize().as_bytes())
+        }
+    }
+}
+
+/// Opens `path` for reading. A first volume (`*.001`) brings along the
+/// volumes numbered after it, up to the first one missing.
+pub fn open_snapshot(path: impl AsRef<Path>) -> io::Result<SnapshotFile> {
+    let path = path.as_ref();
+    let first = File::open(path)?;
+    let Some(base) = volume_base(path) else {
+        return Ok(SnapshotFile::Single(first));
+    };
+    let paths: Vec<PathBuf> = (2..)
+        .map(|index| volume_path(&base, index))
+        .take_while(|p| p.is_file())
+        .collect();
+    if paths.is_empty() {
+        return Ok(SnapshotFile::Single(first));
+    }
+    let mut total_len = first.metadata()?.len();
+    for p in &paths {
+        total_len += fs::metadata(p)?.len();
+    }
+    Ok(SnapshotFile::Volumes(VolumeReader {
+        paths,
+        next: 0,
+        current: first,
+        total_len,
+    }))
+}
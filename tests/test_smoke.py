@@ -1,4 +1,5 @@
 from vegh import create_snap, check_integrity, get_metadata
+from vegh._core import list_files
 
 # This is a Smoke Test to ensure Rust bindings load correctly into Python.
 # Actual compression/decompression logic is heavily tested in the Shell script.
@@ -31,3 +32,2142 @@ def test_basic_flow(tmp_path):
     # 4. Check Integrity
     checksum = check_integrity(str(snap_file))
     assert len(checksum) == 64  # SHA256 length
+
+
+def test_included_preserved_file_is_not_duplicated(tmp_path):
+    """Explicitly including a preserved file must still yield a single entry."""
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / ".gitignore").write_text("*.log\n")
+    (source / "main.py").write_text("print('hi')")
+
+    snap_file = tmp_path / "dup.vegh"
+    create_snap(str(source), str(snap_file), include=[".gitignore", "main.py"], verbose=False)
+
+    files = list_files(str(snap_file))
+    assert files.count(".gitignore") == 1
+    assert len(files) == len(set(files))
+
+
+def _zstd_raw(data):
+    """Wrap bytes in a zstd frame of uncompressed blocks (no zstd module needed)."""
+    block = 128 * 1024
+    out = bytearray(b"\x28\xb5\x2f\xfd\x00\x38")  # magic, no flags, 128 KiB window
+    chunks = [data[i : i + block] for i in range(0, len(data), block)] or [b""]
+    for i, chunk in enumerate(chunks):
+        last = 1 if i == len(chunks) - 1 else 0
+        out += ((len(chunk) << 3) | last).to_bytes(3, "little") + chunk
+    return bytes(out)
+
+
+def test_restore_refuses_path_traversal(tmp_path):
+    """A manifest entry climbing out with '..' must not be written anywhere."""
+    import io
+    import json
+    import tarfile
+
+    import pytest
+    from vegh import restore_snap
+
+    payload = b"pwned"
+    manifest = {
+        "entries": [
+            {"path": "../evil.txt", "hash": "h", "size": len(payload), "modified": 0, "mode": 0o644, "chunks": None}
+        ]
+    }
+    raw = io.BytesIO()
+    with tarfile.open(fileobj=raw, mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name, data in [("blobs/h", payload), ("manifest.json", json.dumps(manifest).encode())]:
+            info = tarfile.TarInfo(name)
+            info.size = len(data)
+            tar.addfile(info, io.BytesIO(data))
+
+    snap_file = tmp_path / "evil.vegh"
+    snap_file.write_bytes(_zstd_raw(raw.getvalue()))
+    out = tmp_path / "out"
+
+    with pytest.raises(ValueError, match=r"\.\./evil\.txt"):
+        restore_snap(str(snap_file), str(out))
+    assert not (tmp_path / "evil.txt").exists()
+
+
+def test_restore_include_glob(tmp_path):
+    """Restoring with include=['*.py'] brings back only the Python files."""
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    (source / "pkg").mkdir(parents=True)
+    (source / "main.py").write_text("print('hi')")
+    (source / "pkg" / "util.py").write_text("X = 1")
+    (source / "README.md").write_text("# readme")
+    (source / "pkg" / "data.json").write_text("{}")
+
+    snap_file = tmp_path / "mixed.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    restored = restore_snap(str(snap_file), str(out), include=["*.py"])
+
+    assert restored == 2
+    files = sorted(p.relative_to(out).as_posix() for p in out.rglob("*") if p.is_file())
+    assert files == ["main.py", "pkg/util.py"]
+
+
+def test_non_snapshot_input_raises_cleanly(tmp_path):
+    """A file that is not a snapshot must raise ValueError, not abort the interpreter."""
+    import pytest
+
+    bogus = tmp_path / "notes.vegh"
+    bogus.write_text("just some text, definitely not an archive\n")
+
+    with pytest.raises(ValueError, match="Not a snapshot"):
+        list_files(str(bogus))
+
+
+def test_restore_preserves_mtime(tmp_path):
+    """Restored files keep the modification time they were snapshotted with."""
+    import os
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    target = source / "input.c"
+    target.write_text("int main(void) { return 0; }\n")
+    os.utime(target, (1_600_000_000, 1_600_000_000))
+
+    snap_file = tmp_path / "mtime.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    assert abs((out / "input.c").stat().st_mtime - 1_600_000_000) < 1
+
+    fresh = tmp_path / "fresh"
+    restore_snap(str(snap_file), str(fresh), preserve_mtime=False)
+    assert abs((fresh / "input.c").stat().st_mtime - 1_600_000_000) > 1
+
+
+def test_reproducible_snapshots_are_byte_identical(tmp_path):
+    """Two reproducible snapshots of the same tree hash identically."""
+    import hashlib
+
+    source = tmp_path / "src"
+    (source / "pkg").mkdir(parents=True)
+    for i in range(40):
+        (source / "pkg" / f"mod_{i}.py").write_text(f"VALUE = {i}\n" * (i + 1))
+    (source / "README.md").write_text("# readme\n")
+
+    digests = []
+    for run in ("first", "second"):
+        snap_file = tmp_path / f"{run}.vegh"
+        create_snap(str(source), str(snap_file), verbose=False, reproducible=True)
+        digests.append(hashlib.sha256(snap_file.read_bytes()).hexdigest())
+
+    assert digests[0] == digests[1]
+
+
+def test_relative_symlink_survives_restore(tmp_path):
+    """A relative symlink is archived as a link and restored as one."""
+    import os
+
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    (source / "conf").mkdir(parents=True)
+    (source / "conf" / "base.toml").write_text("debug = false\n")
+    try:
+        os.symlink("conf/base.toml", source / "active.toml")
+    except (OSError, NotImplementedError):
+        pytest.skip("symlinks not supported here")
+
+    snap_file = tmp_path / "links.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    link = out / "active.toml"
+    assert link.is_symlink()
+    assert os.readlink(link) == "conf/base.toml"
+    assert link.read_text() == "debug = false\n"
+
+
+def test_directory_symlink_survives_restore(tmp_path):
+    """A link to a directory is restored as a link that still lists its target."""
+    import os
+
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    (source / "zz_conf").mkdir(parents=True)
+    (source / "zz_conf" / "base.toml").write_text("debug = false\n")
+    try:
+        os.symlink("zz_conf", source / "a_conf", target_is_directory=True)
+    except (OSError, NotImplementedError):
+        pytest.skip("symlinks not supported here")
+
+    snap_file = tmp_path / "dirlink.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    link = out / "a_conf"
+    assert link.is_symlink()
+    assert link.is_dir()
+    assert (link / "base.toml").read_text() == "debug = false\n"
+
+
+def test_new_features_require_the_release_that_reads_them(tmp_path):
+    """A msgpack manifest names the first release that reads it, not the old baseline."""
+    import json
+
+    import vegh
+    from vegh import VeghFormatError
+
+    assert vegh.__version__ == "0.10.0"
+    assert VeghFormatError.__module__ == "vegh._core"
+    assert issubclass(VeghFormatError, ValueError)
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("x")
+
+    snap_file = tmp_path / "packed.vegh"
+    create_snap(
+        str(source), str(snap_file), verbose=False, no_cache=True, manifest_format="msgpack"
+    )
+    meta = json.loads(get_metadata(str(snap_file)))
+    assert "manifest-msgpack" in meta["features"]
+    assert meta["min_tool_version"] == "0.10.0"
+    assert check_integrity(str(snap_file))
+
+
+def test_max_depth_limits_snapshot_and_dry_run(tmp_path):
+    """max_depth=2 keeps top-level files and one directory down, nothing deeper."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "top.txt").write_text("0")
+    deep = source
+    for level in range(1, 5):
+        deep = deep / f"d{level}"
+        deep.mkdir()
+        (deep / f"f{level}.txt").write_text(str(level))
+
+    snap_file = tmp_path / "shallow.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True, max_depth=2)
+
+    expected = ["d1/f1.txt", "top.txt"]
+    assert sorted(list_files(str(snap_file))) == expected
+    assert sorted(name for name, _ in dry_run_snap(str(source), max_depth=2)) == expected
+
+
+def test_respect_gitignore_false_keeps_ignored_files(tmp_path):
+    """With respect_gitignore=False, gitignored files end up in the snapshot."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / ".gitignore").write_text("build.log\n")
+    (source / "main.py").write_text("print('hi')")
+    (source / "build.log").write_text("lots of output")
+
+    default_snap = tmp_path / "default.vegh"
+    create_snap(str(source), str(default_snap), verbose=False, no_cache=True)
+    assert "build.log" not in list_files(str(default_snap))
+
+    full_snap = tmp_path / "full.vegh"
+    create_snap(
+        str(source), str(full_snap), verbose=False, no_cache=True, respect_gitignore=False
+    )
+    assert "build.log" in list_files(str(full_snap))
+    names = [name for name, _ in dry_run_snap(str(source), respect_gitignore=False)]
+    assert "build.log" in names
+
+
+def test_long_paths_round_trip(tmp_path):
+    """Paths well past tar's 100-byte name field keep their exact name."""
+    from vegh import restore_snap
+
+    rel = "/".join(f"level_{i:02d}_abcdefghij" for i in range(10)) + "/final_file.txt"
+    assert len(rel) >= 200
+
+    source = tmp_path / "src"
+    target = source / rel
+    target.parent.mkdir(parents=True)
+    target.write_text("deep")
+
+    snap_file = tmp_path / "deep.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    assert list_files(str(snap_file)) == [rel]
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    assert (out / rel).read_text() == "deep"
+
+
+def test_multithreaded_compression_restores(tmp_path):
+    """A larger source compressed with threads=4 restores byte for byte."""
+    import os
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    payloads = {
+        "random.bin": os.urandom(4 * 1024 * 1024),
+        "text.log": b"".join(b"request %d served\n" % i for i in range(400_000)),
+    }
+    for name, data in payloads.items():
+        (source / name).write_bytes(data)
+
+    snap_file = tmp_path / "mt.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True, threads=4)
+    # The log still compresses when the work is split across threads
+    assert snap_file.stat().st_size < sum(len(data) for data in payloads.values()) // 2
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    for name, data in payloads.items():
+        assert (out / name).read_bytes() == data
+
+
+def test_max_size_skips_large_files(tmp_path):
+    """max_size=1_000_000 leaves a 10 MB file out and keeps the small ones."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "video.bin").write_bytes(b"\0" * (10 * 1024 * 1024))
+    (source / "notes.txt").write_text("small")
+    (source / "main.py").write_text("print('hi')")
+
+    snap_file = tmp_path / "small.vegh"
+    count, warnings = create_snap(
+        str(source),
+        str(snap_file),
+        verbose=False,
+        no_cache=True,
+        max_size=1_000_000,
+        return_warnings=True,
+    )
+
+    assert count == 2
+    assert sorted(list_files(str(snap_file))) == ["main.py", "notes.txt"]
+    assert any("video.bin" in w for w in warnings)
+    names = sorted(name for name, _ in dry_run_snap(str(source), max_size=1_000_000))
+    assert names == ["main.py", "notes.txt"]
+
+
+def test_per_file_timeout_keeps_files_that_read_in_time(tmp_path):
+    """With a generous per_file_timeout_secs every file is stored and none reported."""
+    source = tmp_path / "src"
+    source.mkdir()
+    for i in range(200):
+        (source / f"f{i}.txt").write_text(f"file {i}")
+    (source / "big.bin").write_bytes(bytes(range(256)) * 4096)
+
+    snap_file = tmp_path / "timeout.vegh"
+    count, timed_out = create_snap(
+        str(source), str(snap_file), verbose=False, no_cache=True, per_file_timeout_secs=30
+    )
+    assert count == 201
+    assert timed_out == []
+    assert len(list_files(str(snap_file))) == 201
+
+
+def test_exclude_abs_paths_reports_the_skipped_files(tmp_path):
+    """Files under exclude_abs_paths are left out and counted in the returned warnings."""
+    source = tmp_path / "src"
+    (source / "secret").mkdir(parents=True)
+    (source / "keep.txt").write_text("keep")
+    (source / "secret" / "a.txt").write_text("a")
+    (source / "secret" / "b.txt").write_text("b")
+
+    snap_file = tmp_path / "abs.vegh"
+    count, warnings = create_snap(
+        str(source),
+        str(snap_file),
+        verbose=False,
+        no_cache=True,
+        exclude_abs_paths=[str((source / "secret").resolve())],
+        return_warnings=True,
+    )
+    assert count == 1
+    assert list_files(str(snap_file)) == ["keep.txt"]
+    assert "Skipped 2 file(s) under excluded absolute paths" in warnings
+
+
+def test_restore_reports_case_collisions(tmp_path):
+    """README.md and readme.md in one snapshot are refused unless force=True."""
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "README.md").write_text("upper")
+    (source / "readme.md").write_text("lower")
+    if len(list(source.iterdir())) < 2:
+        pytest.skip("filesystem is case-insensitive")
+
+    snap_file = tmp_path / "case.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    with pytest.raises(ValueError, match=r"(?=.*README\.md)(?=.*readme\.md)"):
+        restore_snap(str(snap_file), str(out))
+    assert not (out / "README.md").exists() and not (out / "readme.md").exists()
+
+    assert restore_snap(str(snap_file), str(out), force=True) == 2
+
+
+def test_split_volumes_round_trip(tmp_path):
+    """split_bytes=1 MB writes numbered volumes that restore to identical files."""
+    import os
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    payloads = {"noise.bin": os.urandom(3 * 1024 * 1024), "notes.txt": b"kept whole\n"}
+    for name, data in payloads.items():
+        (source / name).write_bytes(data)
+
+    snap_file = tmp_path / "split.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True, split_bytes=1_000_000)
+
+    volumes = sorted(tmp_path.glob("split.vegh.*"))
+    assert len(volumes) >= 3
+    assert all(v.stat().st_size <= 1_000_000 for v in volumes)
+    assert not snap_file.exists()
+
+    first = str(tmp_path / "split.vegh.001")
+    assert sorted(list_files(first)) == sorted(payloads)
+    out = tmp_path / "out"
+    restore_snap(first, str(out))
+    for name, data in payloads.items():
+        assert (out / name).read_bytes() == data
+
+
+def test_invalid_glob_patterns_raise(tmp_path):
+    """A glob that does not parse is an error, not a silently ignored filter."""
+    import pytest
+
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "keep.txt").write_text("kept\n")
+
+    snap_file = tmp_path / "bad.vegh"
+    with pytest.raises(ValueError, match=r"\[unterminated"):
+        create_snap(str(source), str(snap_file), verbose=False, exclude=["[unterminated"])
+    assert not snap_file.exists()
+
+    with pytest.raises(ValueError, match=r"\[unterminated"):
+        dry_run_snap(str(source), include=["[unterminated"])
+
+
+def test_include_wins_over_overlapping_exclude(tmp_path):
+    """A file matched by both an include and an exclude is kept."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    for name in ("important.log", "debug.log", "main.py", "notes.txt"):
+        (source / name).write_text(name)
+
+    filters = {"include": ["important.log", "*.py"], "exclude": ["*.log"]}
+    expected = ["important.log", "main.py"]
+    assert sorted(name for name, _ in dry_run_snap(str(source), **filters)) == expected
+
+    snap_file = tmp_path / "filtered.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True, **filters)
+    assert sorted(list_files(str(snap_file))) == expected
+
+
+def test_restore_keeps_executable_bit(tmp_path):
+    """A 0o755 script comes back executable unless preserve_permissions=False."""
+    import os
+    import stat
+
+    import pytest
+
+    from vegh import restore_snap
+
+    if os.name != "posix":
+        pytest.skip("Unix permissions only")
+
+    source = tmp_path / "src"
+    source.mkdir()
+    script = source / "run.sh"
+    script.write_text("#!/bin/sh\necho hi\n")
+    script.chmod(0o755)
+
+    snap_file = tmp_path / "exec.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    assert stat.S_IMODE((out / "run.sh").stat().st_mode) == 0o755
+
+    plain = tmp_path / "plain"
+    restore_snap(str(snap_file), str(plain), preserve_permissions=False)
+    assert stat.S_IMODE((plain / "run.sh").stat().st_mode) == 0o644
+
+
+def test_nested_veghignore_applies_to_its_subtree(tmp_path):
+    """src/.veghignore filters src/ and below, not the directories above it."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src_root"
+    (source / "src" / "inner").mkdir(parents=True)
+    for name in ("secret.txt", "src/secret.txt", "src/main.py", "src/inner/secret.txt"):
+        (source / name).write_text(name)
+    (source / "src" / ".veghignore").write_text("secret.txt\n")
+
+    expected = ["secret.txt", "src/main.py"]
+    assert sorted(name for name, _ in dry_run_snap(str(source))) == expected
+
+    snap_file = tmp_path / "nested.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    assert sorted(list_files(str(snap_file))) == expected
+
+
+def test_existing_snapshots_are_not_archived(tmp_path):
+    """A .vegh file already in the source is skipped unless asked for."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "main.py").write_text("print('hi')\n")
+    create_snap(str(source), str(tmp_path / "old.vegh"), verbose=False, no_cache=True)
+    (tmp_path / "old.vegh").rename(source / "old.vegh")
+
+    snap_file = tmp_path / "new.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    assert list_files(str(snap_file)) == ["main.py"]
+    assert [name for name, _ in dry_run_snap(str(source))] == ["main.py"]
+
+    kept = dry_run_snap(str(source), exclude_snapshots=False)
+    assert sorted(name for name, _ in kept) == ["main.py", "old.vegh"]
+
+
+def test_restore_snap_filtered_skips_and_aborts(tmp_path):
+    """filter_cb picks files by path and size; its exceptions propagate."""
+    import pytest
+
+    from vegh import restore_snap_filtered
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "keep.txt").write_text("keep")
+    (source / "big.bin").write_bytes(b"x" * 4096)
+
+    snap_file = tmp_path / "filtered.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    seen = []
+
+    def only_small(path, size):
+        seen.append(path)
+        return "skip" if size > 1024 else "write"
+
+    out = tmp_path / "out"
+    assert restore_snap_filtered(str(snap_file), str(out), only_small) == 1
+    assert (out / "keep.txt").read_text() == "keep"
+    assert not (out / "big.bin").exists()
+    assert sorted(seen) == ["big.bin", "keep.txt"]
+
+    def refuse(path, size):
+        raise KeyError(path)
+
+    with pytest.raises(KeyError):
+        restore_snap_filtered(str(snap_file), str(tmp_path / "aborted"), refuse)
+
+
+def test_recover_snap_salvages_truncated_snapshot(tmp_path):
+    """A snapshot cut in half still gives back its complete entries."""
+    import os
+
+    from vegh import recover_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    for i in range(20):
+        (source / f"f{i}.bin").write_bytes(os.urandom(20000))
+
+    snap_file = tmp_path / "whole.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    intact = recover_snap(str(snap_file), str(tmp_path / "intact"))
+    assert intact["recovered"] == 20
+    assert intact["stopped_at"] is None and intact["error"] is None
+
+    data = snap_file.read_bytes()
+    cut = tmp_path / "cut.vegh"
+    cut.write_bytes(data[: len(data) // 2])
+
+    report = recover_snap(str(cut), str(tmp_path / "salvage"))
+    assert report["stopped_at"] is not None
+    assert report["error"]
+    # The manifest sits at the end, so only the blobs survive
+    assert report["orphaned_blobs"] > 0
+    assert len(os.listdir(tmp_path / "salvage" / "blobs")) == report["orphaned_blobs"]
+
+    # Files stored under their own paths come back by name
+    named = tmp_path / "named.vegh"
+    create_snap(str(source), str(named), verbose=False, no_cache=True, interop=True)
+    data = named.read_bytes()
+    named.write_bytes(data[: len(data) // 2])
+    report = recover_snap(str(named), str(tmp_path / "by_name"))
+    assert report["recovered"] > 0
+    restored = sorted(os.listdir(tmp_path / "by_name"))
+    assert len(restored) == report["recovered"]
+    for name in restored:
+        assert (tmp_path / "by_name" / name).read_bytes() == (source / name).read_bytes()
+
+
+def test_preserve_empty_dirs_round_trip(tmp_path):
+    """Empty directories only come back when preserve_empty_dirs=True."""
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    (source / "logs").mkdir(parents=True)
+    (source / "cache" / "deep").mkdir(parents=True)
+    (source / "app.py").write_text("print('hi')\n")
+
+    kept = tmp_path / "kept.vegh"
+    create_snap(
+        str(source), str(kept), verbose=False, no_cache=True, preserve_empty_dirs=True
+    )
+    out = tmp_path / "out"
+    assert restore_snap(str(kept), str(out)) == 1
+    assert (out / "logs").is_dir()
+    assert (out / "cache" / "deep").is_dir()
+    assert (out / "app.py").read_text() == "print('hi')\n"
+
+    plain = tmp_path / "plain.vegh"
+    create_snap(str(source), str(plain), verbose=False, no_cache=True)
+    bare = tmp_path / "bare"
+    restore_snap(str(plain), str(bare))
+    assert not (bare / "logs").exists()
+
+
+def test_create_multi_snap_nests_each_root(tmp_path):
+    """Each source tree lands under its prefix, with its own ignore rules."""
+    import json
+
+    import pytest
+
+    from vegh import create_multi_snap, get_metadata, restore_snap
+
+    app = tmp_path / "app"
+    app.mkdir()
+    (app / "main.py").write_text("app")
+    (app / ".veghignore").write_text("*.log\n")
+    (app / "debug.log").write_text("noise")
+    conf = tmp_path / "conf"
+    conf.mkdir()
+    (conf / "main.py").write_text("conf")
+    (conf / "keep.log").write_text("kept")
+
+    snap_file = tmp_path / "multi.vegh"
+    count = create_multi_snap(
+        [(str(app), "app"), (str(conf), "etc/app")],
+        str(snap_file),
+        verbose=False,
+        no_cache=True,
+    )
+    assert count == 3
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    assert (out / "app" / "main.py").read_text() == "app"
+    assert not (out / "app" / "debug.log").exists()
+    assert (out / "etc" / "app" / "main.py").read_text() == "conf"
+    assert (out / "etc" / "app" / "keep.log").read_text() == "kept"
+
+    meta = json.loads(get_metadata(str(snap_file)))
+    assert [s["prefix"] for s in meta["sources"]] == ["app", "etc/app"]
+
+    with pytest.raises(ValueError):
+        create_multi_snap(
+            [(str(app), "x"), (str(conf), "x/y")],
+            str(tmp_path / "clash.vegh"),
+            verbose=False,
+        )
+
+
+def test_out_of_range_level_is_rejected(tmp_path):
+    """Bad zstd levels fail up front; fast negative levels are accepted."""
+    import pytest
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("a" * 1000)
+
+    with pytest.raises(ValueError, match=r"compression level 50 out of range \(-7..=22\)"):
+        create_snap(str(source), str(tmp_path / "bad.vegh"), level=50, verbose=False)
+    assert not (tmp_path / "bad.vegh").exists()
+
+    fast = tmp_path / "fast.vegh"
+    assert create_snap(str(source), str(fast), level=-5, verbose=False, no_cache=True) == 1
+
+
+def test_create_snap_returns_digest(tmp_path):
+    """return_digest hands back the same hash check_integrity computes."""
+    from vegh import check_integrity
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("alpha")
+    (source / "b.txt").write_text("beta")
+
+    snap_file = tmp_path / "digest.vegh"
+    report = create_snap(
+        str(source), str(snap_file), verbose=False, no_cache=True, return_digest=True
+    )
+    assert report["count"] == 2
+    assert report["digest"] == check_integrity(str(snap_file))
+
+    split = tmp_path / "split.vegh"
+    report = create_snap(
+        str(source),
+        str(split),
+        verbose=False,
+        no_cache=True,
+        split_bytes=100,
+        return_digest=True,
+    )
+    assert report["digest"] == check_integrity(str(split) + ".001")
+
+
+def test_check_integrity_algorithms(tmp_path):
+    """An explicit algorithm tags the digest; the default stays bare BLAKE3."""
+    import hashlib
+
+    import pytest
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("alpha")
+    snap_file = tmp_path / "alg.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    plain = check_integrity(str(snap_file))
+    assert check_integrity(str(snap_file), algorithm="blake3") == "blake3:" + plain
+    sha = hashlib.sha256(snap_file.read_bytes()).hexdigest()
+    assert check_integrity(str(snap_file), algorithm="sha256") == "sha256:" + sha
+
+    with pytest.raises(ValueError):
+        check_integrity(str(snap_file), algorithm="md5")
+
+
+def test_diff_snap_against_dir_reports_drift(tmp_path):
+    """Edits, additions and deletions in the live tree show up; ignored files do not."""
+    from vegh import diff_snap_against_dir
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "same.txt").write_text("same")
+    (source / "edit.txt").write_text("before")
+    (source / "gone.txt").write_text("gone")
+    (source / ".veghignore").write_text("*.tmp\n")
+
+    snap_file = tmp_path / "drift.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    clean = diff_snap_against_dir(str(snap_file), str(source))
+    assert clean == {"added": [], "removed": [], "modified": []}
+
+    (source / "edit.txt").write_text("after!")
+    (source / "gone.txt").unlink()
+    (source / "new.txt").write_text("new")
+    (source / "scratch.tmp").write_text("ignored")
+
+    drift = diff_snap_against_dir(str(snap_file), str(source))
+    assert drift == {"added": ["new.txt"], "removed": ["gone.txt"], "modified": ["edit.txt"]}
+
+
+def test_diff_snap_against_dir_checks_directory_links(tmp_path):
+    """A symlink to a directory is compared as a link, not reported as removed."""
+    import os
+
+    import pytest
+
+    from vegh import diff_snap_against_dir
+
+    source = tmp_path / "src"
+    (source / "real").mkdir(parents=True)
+    (source / "other").mkdir()
+    (source / "real" / "a.txt").write_text("a")
+    try:
+        os.symlink("real", source / "link")
+    except OSError:
+        pytest.skip("symlinks unavailable")
+
+    snap_file = tmp_path / "links.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    assert diff_snap_against_dir(str(snap_file), str(source)) == {"added": [], "removed": [], "modified": []}
+
+    (source / "link").unlink()
+    os.symlink("other", source / "link")
+    assert diff_snap_against_dir(str(snap_file), str(source))["modified"] == ["link"]
+
+
+def test_atomic_restore_leaves_out_dir_untouched_on_failure(tmp_path):
+    """A restore that fails midway with atomic=True writes nothing to out_dir."""
+    import os
+
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    for i in range(5):
+        (source / f"f{i}.txt").write_text(f"new {i}")
+
+    snap_file = tmp_path / "atomic.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    out.mkdir()
+    (out / "f0.txt").write_text("old")
+
+    def fail_midway(files, _bytes):
+        if files >= 2:
+            raise RuntimeError("injected failure")
+
+    with pytest.raises(RuntimeError):
+        restore_snap(
+            str(snap_file),
+            str(out),
+            atomic=True,
+            callback=fail_midway,
+            callback_interval=1,
+        )
+    assert os.listdir(out) == ["f0.txt"]
+    assert (out / "f0.txt").read_text() == "old"
+    assert sorted(os.listdir(tmp_path)) == ["atomic.vegh", "out", "src"]
+
+    assert restore_snap(str(snap_file), str(out), atomic=True) == 5
+    assert (out / "f0.txt").read_text() == "new 0"
+
+    fresh = tmp_path / "fresh"
+    assert restore_snap(str(snap_file), str(fresh), atomic=True) == 5
+    assert sorted(os.listdir(fresh)) == [f"f{i}.txt" for i in range(5)]
+
+
+def test_atomic_restore_swaps_existing_out_dir(tmp_path):
+    """An atomic restore over an existing out_dir keeps unrelated files and applies deletions."""
+    import os
+
+    import pytest
+
+    from vegh import create_incremental_snap, restore_snap
+
+    source = tmp_path / "src"
+    (source / "gone").mkdir(parents=True)
+    (source / "keep.txt").write_text("keep")
+    (source / "drop.txt").write_text("drop")
+    (source / "gone" / "inner.txt").write_text("inner")
+    base = tmp_path / "base.vegh"
+    create_snap(str(source), str(base), no_cache=True)
+    (source / "drop.txt").unlink()
+    (source / "gone" / "inner.txt").unlink()
+    (source / "gone").rmdir()
+    (source / "new.txt").write_text("new")
+    inc = tmp_path / "inc.vegh"
+    create_incremental_snap(str(source), str(inc), str(base), no_cache=True)
+
+    out = tmp_path / "out"
+    restore_snap(str(base), str(out))
+    (out / "mine.txt").write_text("mine")
+    restore_snap(str(inc), str(out), base=str(base), atomic=True)
+    assert sorted(os.listdir(out)) == ["keep.txt", "mine.txt", "new.txt"]
+    assert (out / "mine.txt").read_text() == "mine"
+    assert sorted(os.listdir(tmp_path)) == ["base.vegh", "inc.vegh", "out", "src"]
+
+    # A file that would replace a directory rolls the whole swap back
+    (out / "new.txt").unlink()
+    (out / "new.txt").mkdir()
+    (out / "new.txt" / "x").write_text("x")
+    with pytest.raises(OSError, match="Cannot replace directory '.*out.new.txt'"):
+        restore_snap(str(inc), str(out), base=str(base), atomic=True)
+    assert sorted(os.listdir(out)) == ["keep.txt", "mine.txt", "new.txt"]
+    assert (out / "new.txt" / "x").read_text() == "x"
+    assert sorted(os.listdir(tmp_path)) == ["base.vegh", "inc.vegh", "out", "src"]
+
+
+def test_list_files_hides_internal_entries(tmp_path):
+    """Bookkeeping entries only appear, flagged, with include_internal=True."""
+    from vegh._core import list_files
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "app.py").write_text("print('hi')\n")
+    (source / "lib.py").write_text("x = 1\n")
+
+    snap_file = tmp_path / "internal.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    files = list_files(str(snap_file))
+    assert sorted(files) == ["app.py", "lib.py"]
+
+    flagged = list_files(str(snap_file), include_internal=True)
+    assert sorted(p for p, internal in flagged if not internal) == sorted(files)
+    internal = [p for p, is_internal in flagged if is_internal]
+    assert ".vegh.json" in internal
+    assert any(p.startswith("blobs/") for p in internal)
+
+
+def test_restore_strip_components_and_add_prefix(tmp_path):
+    """Entries are re-rooted on restore; add_prefix cannot leave out_dir."""
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    (source / "pkg" / "sub").mkdir(parents=True)
+    (source / "pkg" / "a.txt").write_text("a")
+    (source / "pkg" / "sub" / "b.txt").write_text("b")
+    (source / "top.txt").write_text("top")
+
+    snap_file = tmp_path / "strip.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    count = restore_snap(
+        str(snap_file), str(out), strip_components=1, add_prefix="vendor/lib"
+    )
+    assert count == 2
+    assert (out / "vendor" / "lib" / "a.txt").read_text() == "a"
+    assert (out / "vendor" / "lib" / "sub" / "b.txt").read_text() == "b"
+    assert not (out / "top.txt").exists()
+
+    with pytest.raises(ValueError):
+        restore_snap(str(snap_file), str(tmp_path / "bad"), add_prefix="../escape")
+    assert not (tmp_path / "escape").exists()
+
+
+def test_metadata_reads_stop_at_the_header(tmp_path):
+    """get_metadata only decodes the start of the stream, merged snapshots included."""
+    import json
+    import os
+
+    from vegh import merge_snaps, update_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "big.bin").write_bytes(os.urandom(16 * 1024 * 1024))
+
+    snap_file = tmp_path / "big.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    merged = tmp_path / "merged.vegh"
+    merge_snaps([str(snap_file)], str(merged))
+    (source / "small.txt").write_text("new")
+    update_snap(str(snap_file), str(source), ["small.txt"])
+
+    # The first 256 KiB of a 16 MiB snapshot: a full scan would hit the cut
+    for snap in (snap_file, merged):
+        head = snap.read_bytes()[: 256 * 1024]
+        assert json.loads(get_metadata(head))["format_version"]
+    assert json.loads(get_metadata(snap_file.read_bytes()[: 256 * 1024]))["file_count"] == 2
+
+
+def test_snapshot_writer_builds_restorable_snapshot(tmp_path):
+    """SnapshotWriter output matches create_snap's layout; failures leave nothing."""
+    import json
+
+    import pytest
+
+    from vegh import SnapshotWriter, restore_snap
+
+    import os
+
+    on_disk = tmp_path / "logo.bin"
+    on_disk.write_bytes(b"\x89PNG")
+    large = tmp_path / "large.bin"
+    large.write_bytes(os.urandom(3 * 1024 * 1024))
+    snap_file = tmp_path / "built.vegh"
+    with SnapshotWriter(str(snap_file), comment="generated") as snap:
+        snap.add_bytes("config/app.json", b"{}")
+        snap.add_file("assets/logo.bin", str(on_disk))
+        snap.add_file("assets/large.bin", str(large))
+        with pytest.raises(ValueError):
+            snap.add_bytes("config/app.json", b"again")
+        with pytest.raises(ValueError):
+            snap.add_bytes("../escape.txt", b"x")
+
+    meta = json.loads(get_metadata(str(snap_file)))
+    assert meta["comment"] == "generated"
+    assert meta["file_count"] == 3
+    assert sorted(list_files(str(snap_file))) == ["assets/large.bin", "assets/logo.bin", "config/app.json"]
+    out = tmp_path / "out"
+    assert restore_snap(str(snap_file), str(out), verify=True) == 3
+    assert (out / "assets" / "logo.bin").read_bytes() == b"\x89PNG"
+    assert (out / "assets" / "large.bin").read_bytes() == large.read_bytes()
+    assert not (tmp_path / "built.vegh.entries").exists()
+
+    broken = tmp_path / "broken.vegh"
+    with pytest.raises(RuntimeError):
+        with SnapshotWriter(str(broken)) as snap:
+            snap.add_bytes("a.txt", b"a")
+            raise RuntimeError("generator failed")
+    assert not broken.exists()
+    assert not (tmp_path / "broken.vegh.entries").exists()
+
+    snap = SnapshotWriter(str(tmp_path / "manual.vegh"))
+    snap.add_bytes("a.txt", b"a")
+    assert snap.finish() == 1
+    with pytest.raises(ValueError):
+        snap.add_bytes("b.txt", b"b")
+
+
+def test_interop_snapshot_is_a_plain_tar(tmp_path):
+    """interop=True yields a ustar/PAX archive with the metadata in a global header."""
+    import json
+    import tarfile
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    nested = source / "deep" / ("d" * 120)
+    nested.mkdir(parents=True)
+    (nested / "f.txt").write_text("f")
+    (source / "a.txt").write_text("a")
+
+    snap_file = tmp_path / "interop.vegh"
+    count = create_snap(
+        str(source),
+        str(snap_file),
+        comment="plain",
+        verbose=False,
+        no_cache=True,
+        interop=True,
+        codec="gzip",
+    )
+    assert count == 2
+
+    with tarfile.open(snap_file) as tf:
+        assert sorted(tf.getnames()) == ["a.txt", f"deep/{'d' * 120}/f.txt"]
+        assert json.loads(tf.pax_headers["comment"])["comment"] == "plain"
+
+    assert json.loads(get_metadata(str(snap_file)))["comment"] == "plain"
+    out = tmp_path / "out"
+    assert restore_snap(str(snap_file), str(out)) == 2
+    assert (out / "deep" / ("d" * 120) / "f.txt").read_text() == "f"
+
+
+def test_parallel_walk_matches_serial_walk(tmp_path):
+    """walk_threads finds the same files; reproducible output stays byte-identical."""
+    import pytest
+
+    source = tmp_path / "src"
+    for d in range(20):
+        sub = source / f"d{d}" / "sub"
+        sub.mkdir(parents=True)
+        for i in range(50):
+            (sub / f"f{i}.txt").write_text(f"{d}/{i}")
+
+    serial = tmp_path / "serial.vegh"
+    parallel = tmp_path / "parallel.vegh"
+    create_snap(str(source), str(serial), verbose=False, no_cache=True, reproducible=True)
+    count = create_snap(
+        str(source),
+        str(parallel),
+        verbose=False,
+        no_cache=True,
+        reproducible=True,
+        walk_threads=4,
+    )
+
+    assert count == 1000
+    assert parallel.read_bytes() == serial.read_bytes()
+    with pytest.raises(ValueError):
+        create_snap(str(source), str(tmp_path / "bad.vegh"), verbose=False, walk_threads=0)
+
+
+def test_restore_subtree_strips_the_prefix(tmp_path):
+    """Only dist/ is restored, directly into out_dir; a missing subtree raises."""
+    import pytest
+
+    from vegh import restore_subtree
+
+    source = tmp_path / "src"
+    (source / "dist" / "js").mkdir(parents=True)
+    (source / "dist" / "index.html").write_text("<html>")
+    (source / "dist" / "js" / "app.js").write_text("app")
+    (source / "distro.txt").write_text("not in dist")
+    (source / "main.py").write_text("print()")
+
+    snap_file = tmp_path / "site.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "deploy"
+    assert restore_subtree(str(snap_file), "dist/", str(out)) == 2
+    assert sorted(p.relative_to(out).as_posix() for p in out.rglob("*") if p.is_file()) == [
+        "index.html",
+        "js/app.js",
+    ]
+
+    with pytest.raises(ValueError):
+        restore_subtree(str(snap_file), "missing", str(tmp_path / "none"))
+    assert not (tmp_path / "none").exists()
+
+
+def test_create_snap_profile_reports_phases(tmp_path):
+    """profile=True adds per-phase timings and byte counts to the report."""
+    import os
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "random.bin").write_bytes(os.urandom(256 * 1024))
+    (source / "text.txt").write_text("hello\n" * 10_000)
+
+    snap_file = tmp_path / "profiled.vegh"
+    report = create_snap(str(source), str(snap_file), verbose=False, no_cache=True, profile=True)
+    # The return_digest report, extended
+    digest_report = create_snap(
+        str(source), str(tmp_path / "digest.vegh"), verbose=False, no_cache=True, return_digest=True
+    )
+    assert set(digest_report) < set(report)
+    assert report["count"] == 2
+    for phase in ("walk_secs", "hash_secs", "read_secs", "compress_secs", "total_secs"):
+        assert report[phase] >= 0.0
+    assert report["compress_secs"] <= report["total_secs"]
+    assert report["bytes_out"] == snap_file.stat().st_size
+    assert report["bytes_in"] > 256 * 1024
+
+
+def test_non_utf8_filenames_round_trip(tmp_path):
+    """A name that is not valid UTF-8 is archived, listed and restored byte for byte."""
+    import os
+    import sys
+
+    import pytest
+
+    from vegh._core import restore_snap
+
+    if sys.platform == "win32":
+        pytest.skip("Windows file names are not byte strings")
+
+    source = tmp_path / "src"
+    (source / "sub").mkdir(parents=True)
+    raw_names = [b"caf\xe9.txt", b"sub/\xff\xfe back\\slash", b"plain.txt"]
+    try:
+        for name in raw_names:
+            with open(os.path.join(os.fsencode(source), name), "wb") as f:
+                f.write(b"data for " + name)
+    except OSError:
+        pytest.skip("filesystem rejects non-UTF-8 names")
+
+    snap_file = tmp_path / "names.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    listed = sorted(os.fsencode(name) for name in list_files(str(snap_file)))
+    assert listed == sorted(raw_names)
+
+    for strip, prefix, out_name in [(0, None, "out"), (1, "moved", "out_rewritten")]:
+        out_dir = tmp_path / out_name
+        restore_snap(str(snap_file), str(out_dir), strip_components=strip, add_prefix=prefix)
+        for name in raw_names:
+            if strip and b"/" not in name:
+                continue
+            target = name.split(b"/", strip)[-1]
+            if prefix:
+                target = prefix.encode() + b"/" + target
+            with open(os.path.join(os.fsencode(out_dir), target), "rb") as f:
+                assert f.read() == b"data for " + name
+
+
+def test_restore_check_space_refuses_before_writing(tmp_path):
+    """check_space=True raises IOError up front when the files cannot fit."""
+    import io
+    import json
+    import tarfile
+
+    import pytest
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "small.txt").write_text("fits anywhere")
+    snap_file = tmp_path / "small.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    assert restore_snap(str(snap_file), str(tmp_path / "ok"), check_space=True) == 1
+
+    # The manifest claims an exabyte; no filesystem here has that free
+    manifest = {
+        "entries": [
+            {"path": "huge.bin", "hash": "h", "size": 2**60, "modified": 0, "mode": 0o644, "chunks": None}
+        ]
+    }
+    raw = io.BytesIO()
+    with tarfile.open(fileobj=raw, mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name, data in [("blobs/h", b"tiny"), ("manifest.json", json.dumps(manifest).encode())]:
+            info = tarfile.TarInfo(name)
+            info.size = len(data)
+            tar.addfile(info, io.BytesIO(data))
+    huge_file = tmp_path / "huge.vegh"
+    huge_file.write_bytes(_zstd_raw(raw.getvalue()))
+
+    out = tmp_path / "out"
+    with pytest.raises(IOError, match="Not enough free space"):
+        restore_snap(str(huge_file), str(out), check_space=True)
+    assert not out.exists()
+
+
+def test_selective_restore_stages_only_the_blobs_it_needs(tmp_path):
+    """include= and restore_subtree only stage the selected files' blobs."""
+    from vegh import restore_snap, restore_subtree
+
+    source = tmp_path / "src"
+    (source / "keep").mkdir(parents=True)
+    (source / "other").mkdir()
+    (source / "keep" / "a.txt").write_text("wanted")
+    for i in range(4):
+        (source / "other" / f"{i}.txt").write_text(f"unwanted {i}")
+    snap_file = tmp_path / "snap.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    out = tmp_path / "out"
+    staged = []
+
+    def peek(_files, _bytes):
+        for staging in out.glob(".vegh-restore-*"):
+            staged.append(len(list(staging.iterdir())))
+
+    assert restore_snap(str(snap_file), str(out), include=["keep/"], callback=peek, callback_interval=1) == 1
+    assert staged and max(staged) == 1
+    assert (out / "keep" / "a.txt").read_text() == "wanted"
+
+    staged.clear()
+    restore_snap(snap_file.read_bytes(), str(out), include=["keep/"], callback=peek, callback_interval=1)
+    assert staged and max(staged) == 1
+
+    assert restore_subtree(str(snap_file), "keep", str(tmp_path / "sub")) == 1
+    assert (tmp_path / "sub" / "a.txt").read_text() == "wanted"
+
+
+def test_dry_run_details_preview_the_snapshot(tmp_path):
+    """details=True flags ignore files and previews the metadata create_snap writes."""
+    import json
+
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    (source / "pkg").mkdir(parents=True)
+    (source / ".gitignore").write_text("*.log\n")
+    (source / "pkg" / ".veghignore").write_text("tmp/\n")
+    (source / "main.py").write_text("print('hi')\n")
+    (source / "debug.log").write_text("ignored")
+
+    plain = dry_run_snap(str(source), include_hidden=True)
+    report = dry_run_snap(str(source), include_hidden=True, details=True, comment="nightly", author="ci-bot")
+    assert sorted((path, size) for path, size, _ in report["files"]) == sorted(plain)
+    flags = {path: preserved for path, _, preserved in report["files"]}
+    assert flags == {".gitignore": True, "pkg/.veghignore": True, "main.py": False}
+    assert report["total_bytes"] == sum(size for _, size in plain)
+
+    snap_file = tmp_path / "preview.vegh"
+    create_snap(
+        str(source),
+        str(snap_file),
+        comment="nightly",
+        author="ci-bot",
+        include_hidden=True,
+        verbose=False,
+        no_cache=True,
+    )
+    assert sorted(list_files(str(snap_file))) == sorted(flags)
+    meta = json.loads(get_metadata(str(snap_file)))
+    assert report["metadata"] == {key: meta[key] for key in ("author", "comment", "format_version")}
+
+
+def test_anchored_patterns_match_from_the_root(tmp_path):
+    """'/node_modules' only drops the top-level directory; 'node_modules' drops all."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    for folder in ["node_modules", "web/node_modules", "build", "web/build"]:
+        (source / folder).mkdir(parents=True)
+        (source / folder / "index.js").write_text("x")
+    (source / "main.js").write_text("m")
+
+    def walked(**kwargs):
+        return sorted(name for name, _ in dry_run_snap(str(source), **kwargs))
+
+    assert walked(exclude=["node_modules"]) == ["build/index.js", "main.js", "web/build/index.js"]
+    anchored = ["build/index.js", "main.js", "web/build/index.js", "web/node_modules/index.js"]
+    assert walked(exclude=["/node_modules"]) == anchored
+    assert walked(exclude=["./node_modules"]) == anchored
+    assert walked(include=["/build"]) == ["build/index.js"]
+    assert walked(include=["build"]) == ["build/index.js", "web/build/index.js"]
+
+    snap_file = tmp_path / "anchored.vegh"
+    create_snap(str(source), str(snap_file), exclude=["/node_modules"], verbose=False, no_cache=True)
+    assert sorted(list_files(str(snap_file))) == anchored
+
+
+def test_zstd_dictionary_round_trip(tmp_path):
+    """A trained dictionary compresses snapshots that restore only with it."""
+    import json
+
+    import pytest
+    from vegh import restore_snap, train_dictionary
+
+    samples = []
+    for i in range(200):
+        sample = tmp_path / "samples" / f"s{i}.py"
+        sample.parent.mkdir(exist_ok=True)
+        sample.write_text(f"import os\n\ndef handler_{i}(event):\n    return {{'id': {i}, 'ok': True}}\n" * 3)
+        samples.append(str(sample))
+    dictionary = train_dictionary(samples, 4096)
+    assert isinstance(dictionary, bytes) and 0 < len(dictionary) <= 4096
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "app.py").write_text("import os\n\ndef handler_7(event):\n    return {'id': 7, 'ok': True}\n")
+    snap_file = tmp_path / "dict.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True, dictionary=dictionary)
+
+    with pytest.raises(ValueError, match="dictionary"):
+        restore_snap(str(snap_file), str(tmp_path / "without"))
+    other = train_dictionary(samples[:100], 2048)
+    with pytest.raises(ValueError, match="dictionary"):
+        restore_snap(str(snap_file), str(tmp_path / "other"), dictionary=other)
+
+    out = tmp_path / "out"
+    assert restore_snap(str(snap_file), str(out), dictionary=dictionary) == 1
+    assert (out / "app.py").read_text() == (source / "app.py").read_text()
+
+    # Read-only functions take the dictionary too
+    from vegh import (
+        count_locs,
+        diff_snaps,
+        get_metadata_dict,
+        read_file_from_snap,
+        restore_snap_filtered,
+        restore_subtree,
+        snapshot_stats,
+    )
+    from vegh._core import cat_file, list_files_detailed
+
+    content = (source / "app.py").read_bytes()
+    with pytest.raises(ValueError, match="pass it as dictionary="):
+        get_metadata(str(snap_file))
+    meta = json.loads(get_metadata(str(snap_file), dictionary=dictionary))
+    assert meta["dictionary"]
+    assert list_files(str(snap_file), dictionary=dictionary) == ["app.py"]
+    assert [e[0] for e in list_files_detailed(str(snap_file), dictionary=dictionary)] == ["app.py"]
+    assert cat_file(str(snap_file), "app.py", dictionary=dictionary) == content
+    assert read_file_from_snap(str(snap_file), "app.py", dictionary=dictionary) == content
+    assert dict(count_locs(str(snap_file), dictionary=dictionary)) == {"app.py": 4}
+    assert snapshot_stats(str(snap_file), dictionary=dictionary)["entry_count"] == 1
+    assert diff_snaps(str(snap_file), str(snap_file), dictionary=dictionary)["modified"] == []
+    filtered = tmp_path / "filtered"
+    assert restore_snap_filtered(
+        str(snap_file), str(filtered), lambda path, size: "write", dictionary=dictionary
+    ) == 1
+    (source / "pkg").mkdir()
+    (source / "pkg" / "mod.py").write_text("X = 1\n")
+    nested = tmp_path / "nested.vegh"
+    create_snap(str(source), str(nested), verbose=False, no_cache=True, dictionary=dictionary)
+    sub = tmp_path / "sub"
+    assert restore_subtree(str(nested), "pkg", str(sub), dictionary=dictionary) == 1
+    assert (sub / "mod.py").read_text() == "X = 1\n"
+
+    # Functions without a dictionary argument say where to read it instead
+    with pytest.raises(ValueError, match="cannot load") as raised:
+        get_metadata_dict(str(snap_file))
+    assert "pass it as" not in str(raised.value)
+
+    with pytest.raises(ValueError):
+        create_snap(str(source), str(tmp_path / "gz.vegh"), codec="gzip", dictionary=dictionary)
+    with pytest.raises(ValueError):
+        train_dictionary(samples, 0)
+
+
+def test_password_encryption_round_trip(tmp_path):
+    """Encrypted snapshots open only with the right password and intact bytes."""
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "secret.txt").write_text("top secret " * 5000)
+    snap = tmp_path / "enc.vegh"
+    create_snap(str(source), str(snap), password="hunter2")
+    assert b"top secret" not in snap.read_bytes()
+
+    out = tmp_path / "out"
+    restore_snap(str(snap), str(out), password="hunter2")
+    assert (out / "secret.txt").read_text() == "top secret " * 5000
+
+    with pytest.raises(ValueError, match="[Ww]rong password"):
+        restore_snap(str(snap), str(tmp_path / "bad"), password="hunter3")
+    with pytest.raises(ValueError, match="password is required"):
+        restore_snap(str(snap), str(tmp_path / "none"))
+
+    truncated = tmp_path / "truncated.vegh"
+    truncated.write_bytes(snap.read_bytes()[:-64])
+    with pytest.raises(ValueError):
+        restore_snap(str(truncated), str(tmp_path / "cut"), password="hunter2")
+
+    # Key derivation cost is read from the header; absurd values are refused
+    crafted = bytearray(snap.read_bytes())
+    crafted[9:13] = (0xFFFFFFFF).to_bytes(4, "little")
+    (tmp_path / "crafted.vegh").write_bytes(bytes(crafted))
+    with pytest.raises(OSError, match="key derivation cost"):
+        restore_snap(
+            str(tmp_path / "crafted.vegh"), str(tmp_path / "x"), password="hunter2"
+        )
+
+
+def test_split_rewrite_drops_stale_volumes(tmp_path):
+    """A shorter split run over a longer one leaves no old volumes behind."""
+    import os
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "noise.bin").write_bytes(os.urandom(3 * 1024 * 1024))
+    snap_file = tmp_path / "split.vegh"
+    create_snap(str(source), str(snap_file), no_cache=True, split_bytes=500_000)
+    assert len(list(tmp_path.glob("split.vegh.*"))) >= 6
+
+    (source / "noise.bin").write_bytes(os.urandom(600_000))
+    report = create_snap(
+        str(source), str(snap_file), no_cache=True, split_bytes=500_000, return_digest=True
+    )
+    assert len(list(tmp_path.glob("split.vegh.*"))) == 2
+    assert report["digest"] == check_integrity(str(tmp_path / "split.vegh.001"))
+
+
+def test_prune_snaps_keeps_newest_and_their_bases(tmp_path):
+    """Retention ranks by timestamp or mtime and never orphans a kept snapshot."""
+    import os
+
+    import pytest
+
+    from vegh import prune_snaps
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("alpha")
+    snaps = tmp_path / "snaps"
+    snaps.mkdir()
+
+    create_snap(str(source), str(snaps / "old.vegh"), timestamp=1_000)
+    create_snap(str(source), str(snaps / "repro.vegh"), reproducible=True)
+    # Reproducible snapshots record timestamp 0; their mtime dates them
+    assert prune_snaps(str(snaps), keep_last=1, dry_run=True) == [str(snaps / "old.vegh")]
+    os.remove(snaps / "repro.vegh")
+
+    create_snap(str(source), str(snaps / "other.vegh"), timestamp=2_000)
+    create_snap(str(source), str(snaps / "inc.vegh"), timestamp=3_000, base=str(snaps / "old.vegh"))
+    (source / "b.bin").write_bytes(os.urandom(300_000))
+    create_snap(str(source), str(snaps / "split.vegh"), timestamp=500, split_bytes=100_000)
+    assert len(list(snaps.glob("split.vegh.*"))) > 1
+
+    # inc.vegh is kept, so its base old.vegh is too
+    doomed = prune_snaps(str(snaps), keep_last=1)
+    assert doomed == [str(snaps / "other.vegh"), str(snaps / "split.vegh.001")]
+    assert sorted(p.name for p in snaps.iterdir()) == ["inc.vegh", "old.vegh"]
+
+    create_snap(str(source), str(snaps / "enc.vegh"), password="pw")
+    with pytest.raises(OSError, match="password"):
+        prune_snaps(str(snaps), keep_last=1, dry_run=True)
+    assert prune_snaps(str(snaps), keep_last=1, dry_run=True, password="pw") == [
+        str(snaps / "inc.vegh"),
+        str(snaps / "old.vegh"),
+    ]
+
+
+def test_merge_snaps_keeps_links_dirs_and_features(tmp_path):
+    """Merging carries symlinks, empty dirs and encrypted inputs; incremental ones are refused."""
+    import json
+    import os
+    import sys
+
+    import pytest
+
+    from vegh import create_incremental_snap, merge_snaps, restore_snap
+
+    api = tmp_path / "api"
+    (api / "empty").mkdir(parents=True)
+    (api / "main.py").write_text("print('api')")
+    web = tmp_path / "web"
+    web.mkdir()
+    (web / "index.html").write_text("<p>web</p>")
+    if sys.platform != "win32":
+        os.symlink("index.html", web / "home.html")
+
+    create_snap(str(api), str(tmp_path / "api.vegh"), preserve_empty_dirs=True)
+    create_snap(str(web), str(tmp_path / "web.vegh"), password="pw")
+
+    merged = tmp_path / "merged.vegh"
+    inputs = [str(tmp_path / "api.vegh"), str(tmp_path / "web.vegh")]
+    with pytest.raises(ValueError, match="password"):
+        merge_snaps(inputs, str(merged), prefix_with_source=True)
+    merge_snaps(inputs, str(merged), prefix_with_source=True, password="pw")
+
+    out = tmp_path / "out"
+    restore_snap(str(merged), str(out), password="pw")
+    assert (out / "api" / "main.py").read_text() == "print('api')"
+    assert (out / "api" / "empty").is_dir()
+    assert (out / "web" / "index.html").read_text() == "<p>web</p>"
+    if sys.platform != "win32":
+        assert os.readlink(out / "web" / "home.html") == "index.html"
+        meta = json.loads(get_metadata(str(merged), password="pw"))
+        assert "symlinks" in meta["features"]
+
+    (api / "new.py").write_text("print('new')")
+    create_incremental_snap(str(api), str(tmp_path / "inc.vegh"), str(tmp_path / "api.vegh"))
+    with pytest.raises(ValueError, match="incremental"):
+        merge_snaps([str(tmp_path / "inc.vegh")], str(tmp_path / "bad.vegh"))
+    assert not (tmp_path / "bad.vegh").exists()
+
+
+def test_custom_ignore_files_extend_the_defaults(tmp_path):
+    """Extra ignore file names apply alongside .veghignore, not instead of it."""
+    from vegh import dry_run_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / ".veghignore").write_text("secret.txt\n")
+    (source / ".buildignore").write_text("*.log\n")
+    for name in ["secret.txt", "build.log", "main.py"]:
+        (source / name).write_text(name)
+
+    files = [path for path, _ in dry_run_snap(str(source), ignore_files=[".buildignore"])]
+    assert files == ["main.py"]
+    files = [path for path, _ in dry_run_snap(str(source))]
+    assert sorted(files) == ["build.log", "main.py"]
+
+
+def test_non_utf8_names_in_listings_diffs_and_recovery(tmp_path):
+    """Readers other than restore report and write non-UTF-8 names as they were on disk."""
+    import os
+    import sys
+
+    import pytest
+
+    from vegh import diff_snap_against_dir, dry_run_snap, recover_snap
+    from vegh._core import list_files_detailed
+
+    if sys.platform == "win32":
+        pytest.skip("Windows file names are not byte strings")
+
+    source = tmp_path / "src"
+    source.mkdir()
+    raw = b"caf\xe9.txt"
+    try:
+        with open(os.path.join(os.fsencode(source), raw), "wb") as f:
+            f.write(b"latte")
+    except OSError:
+        pytest.skip("filesystem rejects non-UTF-8 names")
+    (source / "plain.txt").write_text("plain")
+    name = os.fsdecode(raw)
+
+    snap_file = tmp_path / "names.vegh"
+    create_snap(str(source), str(snap_file), no_cache=True)
+
+    assert sorted(path for path, _ in dry_run_snap(str(source))) == sorted([name, "plain.txt"])
+    assert name in [path for path, _, _ in list_files_detailed(str(snap_file))]
+    assert diff_snap_against_dir(str(snap_file), str(source)) == {
+        "added": [],
+        "removed": [],
+        "modified": [],
+    }
+
+    out = tmp_path / "recovered"
+    report = recover_snap(str(snap_file), str(out))
+    assert report["recovered"] == 2
+    with open(os.path.join(os.fsencode(out), raw), "rb") as f:
+        assert f.read() == b"latte"
+
+
+def test_update_snap_keeps_codec_and_encryption(tmp_path):
+    """update_snap swaps in changed files without changing how the snapshot is stored."""
+    import json
+    import os
+
+    import pytest
+
+    from vegh import restore_snap, update_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "keep.txt").write_text("unchanged")
+    (source / "big.bin").write_bytes(os.urandom(3 * 1024 * 1024))
+    (source / "gone.txt").write_text("to be removed")
+
+    gz = tmp_path / "gz.vegh"
+    create_snap(str(source), str(gz), codec="gzip", level=6)
+    big = os.urandom(3 * 1024 * 1024)
+    (source / "big.bin").write_bytes(big)
+    (source / "new.txt").write_text("added")
+    os.remove(source / "gone.txt")
+    changed = ["big.bin", "new.txt", "gone.txt"]
+    assert update_snap(str(gz), str(source), changed, level=6) == 3
+    meta = json.loads(get_metadata(str(gz)))
+    assert meta["codec"] == "gzip"
+    assert meta["parent"]
+
+    out = tmp_path / "out"
+    restore_snap(str(gz), str(out))
+    assert sorted(p.name for p in out.iterdir()) == ["big.bin", "keep.txt", "new.txt"]
+    assert (out / "big.bin").read_bytes() == big
+    assert (out / "keep.txt").read_text() == "unchanged"
+
+    enc = tmp_path / "enc.vegh"
+    create_snap(str(source), str(enc), password="pw")
+    (source / "new.txt").write_text("edited")
+    with pytest.raises(ValueError, match="password"):
+        update_snap(str(enc), str(source), ["new.txt"])
+    update_snap(str(enc), str(source), ["new.txt"], password="pw")
+    with pytest.raises(ValueError, match="password"):
+        restore_snap(str(enc), str(tmp_path / "locked"))
+    restore_snap(str(enc), str(tmp_path / "unlocked"), password="pw")
+    assert (tmp_path / "unlocked" / "new.txt").read_text() == "edited"
+
+
+def test_update_snap_keeps_directories_names_and_link_policy(tmp_path):
+    """update_snap keeps empty directories and raw names, and strips links leaving the tree."""
+    import os
+    import sys
+
+    from vegh import restore_snap, update_snap
+
+    source = tmp_path / "src"
+    (source / "empty").mkdir(parents=True)
+    (source / "a.txt").write_text("a")
+    raw = b"caf\xe9.txt"
+    raw_ok = sys.platform != "win32"
+    if raw_ok:
+        try:
+            with open(os.path.join(os.fsencode(source), raw), "wb") as f:
+                f.write(b"latte")
+        except OSError:
+            raw_ok = False
+
+    snap_file = tmp_path / "dirs.vegh"
+    create_snap(str(source), str(snap_file), no_cache=True, preserve_empty_dirs=True, interop=True)
+    (source / "a.txt").write_text("edited")
+    changed = ["a.txt"]
+    try:
+        os.symlink(str(tmp_path), source / "outside")
+        os.symlink("a.txt", source / "inside")
+        changed += ["outside", "inside"]
+    except (OSError, NotImplementedError):
+        pass
+    update_snap(str(snap_file), str(source), changed)
+
+    out = tmp_path / "out"
+    restore_snap(str(snap_file), str(out))
+    assert (out / "empty").is_dir()
+    assert (out / "a.txt").read_text() == "edited"
+    assert not os.path.lexists(out / "outside")
+    if "inside" in changed:
+        assert os.readlink(out / "inside") == "a.txt"
+    if raw_ok:
+        with open(os.path.join(os.fsencode(out), raw), "rb") as f:
+            assert f.read() == b"latte"
+
+
+def test_incremental_snapshot_round_trip(tmp_path):
+    """An incremental snapshot restores over its base with edits, additions and deletions."""
+    import pytest
+
+    from vegh import create_incremental_snap, restore_chain, restore_snap
+
+    source = tmp_path / "src"
+    (source / "old_dir").mkdir(parents=True)
+    (source / "keep.txt").write_text("keep")
+    (source / "edit.txt").write_text("before")
+    (source / "drop.txt").write_text("drop")
+    (source / "old_dir" / "inner.txt").write_text("inner")
+    snaps = tmp_path / "snaps"
+    snaps.mkdir()
+    base = snaps / "base.vegh"
+    create_snap(str(source), str(base), no_cache=True)
+
+    (source / "edit.txt").write_text("after")
+    (source / "new.txt").write_text("new")
+    (source / "drop.txt").unlink()
+    (source / "old_dir" / "inner.txt").unlink()
+    (source / "old_dir").rmdir()
+    inc = snaps / "inc.vegh"
+    create_incremental_snap(str(source), str(inc), str(base), no_cache=True)
+    # Only what changed is stored
+    assert sorted(list_files(str(inc))) == ["edit.txt", "new.txt"]
+
+    with pytest.raises(ValueError, match="incremental"):
+        restore_snap(str(inc), str(tmp_path / "alone"))
+
+    expected = {"keep.txt": "keep", "edit.txt": "after", "new.txt": "new"}
+    for out, restore in [
+        (tmp_path / "with_base", lambda out: restore_snap(str(inc), str(out), base=str(base))),
+        (tmp_path / "chain", lambda out: restore_chain(str(inc), str(out))),
+    ]:
+        restore(out)
+        assert sorted(p.name for p in out.iterdir()) == sorted(expected)
+        for name, text in expected.items():
+            assert (out / name).read_text() == text
+
+
+def test_incremental_against_reproducible_base_skips_unchanged(tmp_path):
+    """Normalized modes in a reproducible base do not make every file look changed."""
+    import os
+
+    from vegh import create_incremental_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("a")
+    (source / "run.sh").write_text("#!/bin/sh\n")
+    os.chmod(source / "a.txt", 0o664)
+    os.chmod(source / "run.sh", 0o775)
+    base = tmp_path / "base.vegh"
+    create_snap(str(source), str(base), reproducible=True, no_cache=True)
+
+    inc = tmp_path / "inc.vegh"
+    create_incremental_snap(str(source), str(inc), str(base), no_cache=True)
+    assert list_files(str(inc)) == []
+
+
+def test_sign_and_verify_snapshot(tmp_path):
+    """A detached signature verifies only for the untouched snapshot and the signer's key."""
+    import base64
+
+    from vegh import sign_snap, verify_signature
+
+    def pem(label, der):
+        body = base64.b64encode(der).decode()
+        return f"-----BEGIN {label}-----\n{body}\n-----END {label}-----\n"
+
+    # RFC 8032 test vectors 1 and 2
+    seed = bytes.fromhex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+    public = bytes.fromhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+    other = bytes.fromhex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c")
+    private_pem = pem("PRIVATE KEY", bytes.fromhex("302e020100300506032b657004220420") + seed)
+    public_pem = pem("PUBLIC KEY", bytes.fromhex("302a300506032b6570032100") + public)
+    other_pem = pem("PUBLIC KEY", bytes.fromhex("302a300506032b6570032100") + other)
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("signed content")
+    snap_file = tmp_path / "signed.vegh"
+    create_snap(str(source), str(snap_file))
+
+    sidecar = sign_snap(str(snap_file), private_pem)
+    assert sidecar == str(snap_file) + ".sig"
+    assert verify_signature(str(snap_file), public_pem) is True
+    assert verify_signature(str(snap_file), other_pem) is False
+
+    data = bytearray(snap_file.read_bytes())
+    data[len(data) // 2] ^= 0x01
+    snap_file.write_bytes(bytes(data))
+    assert verify_signature(str(snap_file), public_pem) is False
+
+
+def test_writer_functions_share_output_and_writer_exceptions(tmp_path):
+    """create_tar_to_writer is create_snap_to_writer with codec="none"; writer errors propagate as-is."""
+    import io
+    import tarfile
+
+    import pytest
+
+    from vegh import create_snap_to_writer, create_tar_to_writer
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("a")
+
+    plain = io.BytesIO()
+    assert create_tar_to_writer(str(source), plain) == 1
+    with tarfile.open(fileobj=io.BytesIO(plain.getvalue())) as tar:
+        names = tar.getnames()
+    assert ".vegh.json" in names and "manifest.json" in names
+
+    class Refused(Exception):
+        pass
+
+    class FailingWriter:
+        def write(self, _data):
+            raise Refused("disk on fire")
+
+    for write in (create_tar_to_writer, create_snap_to_writer):
+        with pytest.raises(Refused, match="disk on fire"):
+            write(str(source), FailingWriter())
+
+
+def test_verify_functions_share_one_scan(tmp_path):
+    """verify_snapshot, verify_contents and verify_snap agree, take a password and spot a bad blob."""
+    import os
+    import tempfile
+
+    import pytest
+
+    from vegh import verify_contents, verify_snap, verify_snapshot
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "big.bin").write_bytes(os.urandom(3 * 1024 * 1024))
+    (source / "small.txt").write_text("marker-" * 100)
+
+    locked = tmp_path / "locked.vegh"
+    create_snap(str(source), str(locked), verbose=False, no_cache=True, password="pw")
+    assert verify_snapshot(str(locked), password="pw") is None
+    assert sorted(verify_contents(str(locked), password="pw")) == [("big.bin", True), ("small.txt", True)]
+    assert verify_snap(str(locked), password="pw") == {"entries_ok": 2, "entries_total": 2, "errors": []}
+    with pytest.raises(ValueError, match="password"):
+        verify_contents(str(locked))
+
+    plain = tmp_path / "plain.vegh"
+    create_snap(str(source), str(plain), verbose=False, no_cache=True, codec="none")
+    raw = plain.read_bytes()
+    at = raw.index(b"marker-")
+    plain.write_bytes(raw[:at] + b"M" + raw[at + 1 :])
+    assert dict(verify_contents(str(plain))) == {"big.bin": True, "small.txt": False}
+    with pytest.raises(ValueError, match="does not match its hash"):
+        verify_snapshot(str(plain))
+    report = verify_snap(str(plain))
+    assert report["entries_ok"] == 1 and report["entries_total"] == 2
+    assert any("does not match its hash" in e for e in report["errors"])
+
+    leftovers = [n for n in os.listdir(tempfile.gettempdir()) if n.startswith(f".vegh-scan-{os.getpid()}-")]
+    assert leftovers == []
+
+
+def test_loc_reports_share_one_counter_and_binary_rule(tmp_path):
+    """count_locs, loc_tree, scan_locs_dir and count_locs_by_language count lines alike."""
+    import json
+
+    from vegh import count_locs, count_locs_by_language, loc_tree, scan_locs_dir
+
+    source = tmp_path / "src"
+    source.mkdir()
+    # Large enough to be stored in several chunks
+    body = "".join(f"value_{i} = {i * 7919}  # unique {i}\n" for i in range(60_000))
+    (source / "big.py").write_text("# header\n\n" + body)
+    (source / "late_nul.txt").write_bytes(b"line\n" * 2000 + b"\0")
+    (source / "image.bin").write_bytes(b"\x89PNG\0\0" + b"x\n" * 10)
+
+    snap_file = tmp_path / "locs.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    expected = {"big.py": 60_002, "late_nul.txt": 2001, "image.bin": 0}
+
+    assert dict(count_locs(str(snap_file))) == expected
+    assert dict(scan_locs_dir(str(source))) == expected
+    tree = json.loads(loc_tree(str(snap_file)))
+    assert {c["name"]: c["loc"] for c in tree["children"]} == expected
+
+    python = count_locs_by_language(str(snap_file))["Python"]
+    assert python == {"files": 1, "code": 60_000, "blank": 1, "comment": 1}
+
+
+def test_progress_false_cancels_create_snap_and_removes_output(tmp_path):
+    """A progress callback returning False stops create_snap and leaves no output behind."""
+    import pytest
+
+    source = tmp_path / "src"
+    source.mkdir()
+    for i in range(20):
+        (source / f"f{i:02}.txt").write_text(f"file {i}\n" * 50)
+    snap_file = tmp_path / "cancelled.vegh"
+
+    calls = []
+
+    def stop_early(files, nbytes):
+        calls.append(files)
+        return False
+
+    # The cache is on, so its open batch must be abandoned cleanly too
+    with pytest.raises(ValueError, match="cancelled"):
+        create_snap(str(source), str(snap_file), verbose=False, progress=stop_early, callback_interval=1)
+    assert calls == [1]
+    assert not snap_file.exists()
+
+    def boom(files, nbytes):
+        raise KeyError("stop")
+
+    with pytest.raises(KeyError):
+        create_snap(str(source), str(snap_file), verbose=False, progress=boom, callback_interval=1)
+    assert not snap_file.exists()
+
+    # Neither abort leaves the cache unusable
+    assert create_snap(str(source), str(snap_file), verbose=False) == 20
+    assert check_integrity(str(snap_file))
+
+
+def test_restore_should_cancel_and_verify(tmp_path):
+    """should_cancel stops a restore without half-written files; verify catches tampered content."""
+    import os
+
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    payloads = {f"f{i}.txt": f"file {i}\n".encode() * 100 for i in range(5)}
+    payloads["big.bin"] = os.urandom(3 * 1024 * 1024)
+    for name, data in payloads.items():
+        (source / name).write_bytes(data)
+    snap_file = tmp_path / "cancel.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    calls = []
+
+    def cancel_soon():
+        calls.append(1)
+        return len(calls) > 4
+
+    out = tmp_path / "out"
+    with pytest.raises(ValueError, match="cancelled"):
+        restore_snap(str(snap_file), str(out), should_cancel=cancel_soon)
+    written = {p.name: p.read_bytes() for p in out.rglob("*") if p.is_file()}
+    assert len(written) < len(payloads)
+    assert all(data == payloads[name] for name, data in written.items())
+
+    marker = b"MARKER-0123456789-ORIGINAL"
+    (source / "marked.txt").write_bytes(marker)
+    plain = tmp_path / "plain.vegh"
+    create_snap(str(source), str(plain), verbose=False, no_cache=True, codec="none")
+    raw = plain.read_bytes()
+    assert raw.count(marker) == 1
+    plain.write_bytes(raw.replace(marker, b"MARKER-0123456789-TAMPERED"))
+    with pytest.raises(OSError, match="Integrity check failed for 'marked.txt'"):
+        restore_snap(str(plain), str(tmp_path / "tampered"), verify=True)
+    assert not (tmp_path / "tampered" / "marked.txt").exists()
+
+
+def test_offset_index_reads_files_by_seeking(tmp_path):
+    """build_offset_index=True lets read_file_indexed fetch files; other readers ignore the index."""
+    import os
+    import struct
+
+    import pytest
+
+    from vegh import read_file_from_snap, read_file_indexed, restore_snap
+
+    source = tmp_path / "src"
+    (source / "pkg").mkdir(parents=True)
+    small = b"print('hi')\n"
+    large = os.urandom(3 * 1024 * 1024)
+    (source / "main.py").write_bytes(small)
+    (source / "pkg" / "large.bin").write_bytes(large)
+
+    indexed = tmp_path / "indexed.vegh"
+    create_snap(str(source), str(indexed), verbose=False, no_cache=True, build_offset_index=True)
+    assert read_file_indexed(str(indexed), "main.py") == small
+    assert read_file_indexed(str(indexed), "pkg/large.bin") == large
+    assert read_file_from_snap(str(indexed), "pkg/large.bin") == large
+    with pytest.raises(ValueError, match="not found"):
+        read_file_indexed(str(indexed), "missing.txt")
+
+    assert sorted(list_files(str(indexed))) == ["main.py", "pkg/large.bin"]
+    assert check_integrity(str(indexed))
+    out = tmp_path / "out"
+    assert restore_snap(str(indexed), str(out)) == 2
+    assert (out / "pkg" / "large.bin").read_bytes() == large
+    assert not (out / ".vegh.offsets").exists()
+
+    plain = tmp_path / "plain.vegh"
+    create_snap(str(source), str(plain), verbose=False, no_cache=True)
+    with pytest.raises(OSError, match="no offset index"):
+        read_file_indexed(str(plain), "main.py")
+    assert read_file_from_snap(str(plain), "main.py") == small
+
+    # Trailer: magic, payload length, index offset, index size, frame start
+    raw = indexed.read_bytes()
+    fields = list(struct.unpack("<QQQQ", raw[-32:]))
+    for field, value in [(2, fields[0] + 1), (1, 1 << 62), (3, len(raw) + 1)]:
+        tampered = list(fields)
+        tampered[field] = value
+        damaged = tmp_path / f"damaged-{field}.vegh"
+        damaged.write_bytes(raw[:-32] + struct.pack("<QQQQ", *tampered))
+        with pytest.raises(OSError, match="Corrupt offset index"):
+            read_file_indexed(str(damaged), "main.py")
+
+
+def test_seekable_frames_decode_independently(tmp_path):
+    """seekable=True restarts zstd frames, so a late file reads back even if the first frame is damaged."""
+    import json
+
+    from vegh import read_file_indexed
+
+    source = tmp_path / "src"
+    source.mkdir()
+    payloads = {
+        name: b"".join(b"%s line %d\n" % (name.encode(), i) for i in range(400_000))
+        for name in ("first.log", "second.log")
+    }
+    for name, data in payloads.items():
+        (source / name).write_bytes(data)
+
+    def damaged_reads(seekable, damage_end=None):
+        snap_file = tmp_path / f"seekable-{seekable}.vegh"
+        create_snap(
+            str(source),
+            str(snap_file),
+            verbose=False,
+            no_cache=True,
+            build_offset_index=True,
+            seekable=seekable,
+        )
+        raw = bytearray(snap_file.read_bytes())
+        frame_magic = b"\x28\xb5\x2f\xfd"
+        if seekable:
+            assert json.loads(get_metadata(str(snap_file)))["seekable"] is True
+            assert raw.count(frame_magic) >= 3
+            damage_end = raw.index(frame_magic, 4)
+        # Zero out the body of the first frame, leaving its header intact
+        raw[64:damage_end] = bytes(damage_end - 64)
+        snap_file.write_bytes(bytes(raw))
+
+        readable = []
+        for name, data in payloads.items():
+            try:
+                readable.append(read_file_indexed(str(snap_file), name) == data)
+            except OSError:
+                readable.append(False)
+        return readable, damage_end
+
+    readable, damage_end = damaged_reads(True)
+    assert any(readable)
+    # One frame: the same damage leaves nothing an indexed read can decode
+    readable, _ = damaged_reads(False, damage_end)
+    assert not any(readable)
+
+
+def test_snapshot_stats_summarizes_one_pass(tmp_path):
+    """snapshot_stats counts files, dedup savings and sizes, and reads encrypted snapshots."""
+    import json
+    import math
+
+    import pytest
+
+    from vegh import snapshot_stats
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("same content\n" * 100)
+    (source / "b.txt").write_text("same content\n" * 100)
+    (source / "c.txt").write_text("different\n")
+
+    snap_file = tmp_path / "stats.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+    stats = snapshot_stats(str(snap_file))
+    assert stats["entry_count"] == 3
+    assert stats["compressed_bytes"] == snap_file.stat().st_size
+    assert stats["uncompressed_bytes"] > 1300
+    assert math.isclose(
+        stats["compression_ratio"], stats["compressed_bytes"] / stats["uncompressed_bytes"]
+    )
+    # b.txt was stored once, as a.txt's blob
+    assert stats["dedup_saved_bytes"] == 1300
+    assert stats["timestamp"] == json.loads(get_metadata(str(snap_file)))["timestamp"]
+
+    locked = tmp_path / "locked.vegh"
+    create_snap(str(source), str(locked), verbose=False, no_cache=True, password="hunter2")
+    with pytest.raises(ValueError):
+        snapshot_stats(str(locked))
+    locked_stats = snapshot_stats(str(locked), password="hunter2")
+    assert locked_stats["entry_count"] == 3
+    assert locked_stats["dedup_saved_bytes"] == 1300
+    assert locked_stats["compressed_bytes"] == locked.stat().st_size
+
+
+def test_restore_overwrite_modes(tmp_path):
+    """overwrite="never" refuses up front, "skip" keeps existing files, "always" replaces them."""
+    import pytest
+
+    from vegh import restore_snap
+
+    source = tmp_path / "src"
+    source.mkdir()
+    (source / "a.txt").write_text("new a")
+    (source / "b.txt").write_text("new b")
+    (source / "c.txt").write_text("new c")
+    snap_file = tmp_path / "modes.vegh"
+    create_snap(str(source), str(snap_file), verbose=False, no_cache=True)
+
+    def prepared(name):
+        out = tmp_path / name
+        out.mkdir()
+        (out / "a.txt").write_text("old a")
+        (out / "b.txt").write_text("old b")
+        return out
+
+    out = prepared("never")
+    with pytest.raises(ValueError, match=r"(?=.*a\.txt)(?=.*b\.txt)"):
+        restore_snap(str(snap_file), str(out), overwrite="never")
+    assert (out / "a.txt").read_text() == "old a"
+    assert not (out / "c.txt").exists()
+
+    out = prepared("skip")
+    progress = []
+    written = restore_snap(
+        str(snap_file),
+        str(out),
+        overwrite="skip",
+        callback=lambda files, nbytes: progress.append(files),
+    )
+    assert written == 1
+    assert progress[-1] == 3
+    assert (out / "a.txt").read_text() == "old a"
+    assert (out / "b.txt").read_text() == "old b"
+    assert (out / "c.txt").read_text() == "new c"
+
+    out = prepared("always")
+    assert restore_snap(str(snap_file), str(out), overwrite="always") == 3
+    assert (out / "a.txt").read_text() == "new a"
+
+    with pytest.raises(ValueError):
+        restore_snap(str(snap_file), str(tmp_path / "bad"), overwrite="sometimes")
+
+
+def test_list_files_detailed_names_entry_types(tmp_path):
+    """list_files_detailed reports what each entry is; list_files_details its hash."""
+    import os
+
+    import pytest
+
+    from vegh._core import list_files_details, list_files_detailed
+
+    source = tmp_path / "src"
+    (source / "empty").mkdir(parents=True)
+    (source / "a.txt").write_text("alpha")
+    try:
+        os.symlink("a.txt", source / "link")
+    except (OSError, NotImplementedError):
+        pytest.skip("symlinks not supported here")
+
+    interop = tmp_path / "interop.vegh"
+    create_snap(str(source), str(interop), no_cache=True, preserve_empty_dirs=True, interop=True)
+    kinds = {path: kind for path, _, kind in list_files_detailed(str(interop))}
+    assert kinds["empty/"] == "dir"
+    assert kinds["a.txt"] == "file"
+    assert kinds["link"] == "symlink"
+    assert {h for path, _, h in list_files_details(str(interop)) if path == "a.txt"} == {""}
+
+    v3 = tmp_path / "v3.vegh"
+    create_snap(str(source), str(v3), no_cache=True, preserve_empty_dirs=True)
+    kinds = {path: kind for path, _, kind in list_files_detailed(str(v3))}
+    assert kinds["empty/"] == "dir"
+    assert kinds["a.txt"] == "file"
+    assert kinds["link"] == "symlink"
+    hashes = {path: h for path, _, h in list_files_details(str(v3))}
+    assert len(hashes["a.txt"]) == 64